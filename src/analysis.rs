@@ -1,19 +1,21 @@
 use anyhow::{Context, Result};
+use async_trait::async_trait;
 use async_openai::{
     types::{
         ChatCompletionRequestMessage, ChatCompletionRequestSystemMessageArgs,
-        ChatCompletionRequestUserMessageArgs, CreateChatCompletionRequest,
+        ChatCompletionRequestUserMessageArgs, CreateChatCompletionRequest, CreateChatCompletionResponse,
         ChatCompletionResponseFormat, ChatCompletionResponseFormatType,
     },
-    Client, config::OpenAIConfig,
 };
-use log::debug;
+use log::{debug, warn};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::env;
+use std::time::Duration;
 
-use crate::config::AppConfig;
-use crate::data_collector::CollectedData;
+use crate::config::{AnalyzerKind, AppConfig, Bucket, FallbackActivity, Language, PrivacyMode};
+use crate::data_collector::{self, BlockAnalysis, CollectedData};
+use crate::rules::RulesAnalyzer;
+use crate::utils::{truncate_string_safely, AuthPause};
 
 /// 分析結果
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -33,10 +35,23 @@ pub struct AnalysisResult {
     /// 現在のウィンドウタイトル（タグ付け用）
     pub window_title: Option<String>,
     
+    /// 現在のウィンドウクラス（プライベートブラウジング判定用）
+    #[serde(default)]
+    pub window_class: Option<String>,
+    
     /// 関連するカレンダーイベント（タグ付け用）
     pub calendar_event: Option<crate::data_collector::CalendarEvent>,
     
-    /// OpenAI APIキー（類似度評価用）
+    /// OpenAIを使わずローカルで分析した結果かどうか
+    #[serde(default)]
+    pub analyzed_locally: bool,
+    
+    /// 登録先のTogglプロジェクト名（ルールで指定された場合のみ、未指定なら推論する）
+    #[serde(default)]
+    pub project: Option<String>,
+    
+    /// OpenAI APIキー（類似度評価用、シリアライズ時は出力しない）
+    #[serde(skip_serializing, default)]
     pub openai_api_key: Option<String>,
 }
 
@@ -50,6 +65,262 @@ pub struct ActivityCandidate {
     pub confidence: f64,
 }
 
+/// 収集データから活動を推定する分析エンジン
+#[async_trait]
+pub trait Analyzer: Send + Sync {
+    /// ログに表示する分析エンジンの名前
+    fn name(&self) -> &'static str;
+
+    /// 収集データを分析して活動を推定する
+    async fn analyze(&self, data: &[CollectedData]) -> Result<AnalysisResult>;
+}
+
+/// OpenAIで分析し、失敗時はローカル分析にフォールバックする分析エンジン
+pub struct GptAnalyzer<'a> {
+    config: &'a AppConfig,
+}
+
+impl<'a> GptAnalyzer<'a> {
+    pub fn new(config: &'a AppConfig) -> Self {
+        Self { config }
+    }
+}
+
+#[async_trait]
+impl Analyzer for GptAnalyzer<'_> {
+    fn name(&self) -> &'static str {
+        "GPT-4o mini"
+    }
+
+    async fn analyze(&self, data: &[CollectedData]) -> Result<AnalysisResult> {
+        analyze_with_fallback(self.config, data).await
+    }
+}
+
+/// キーワードに基づいてローカルで分析する分析エンジン
+pub struct LocalAnalyzer {
+    language: Language,
+    fallback: FallbackActivity,
+    full_confidence_samples: usize,
+}
+
+impl LocalAnalyzer {
+    pub fn new(language: Language, fallback: FallbackActivity, full_confidence_samples: usize) -> Self {
+        Self { language, fallback, full_confidence_samples }
+    }
+}
+
+#[async_trait]
+impl Analyzer for LocalAnalyzer {
+    fn name(&self) -> &'static str {
+        "local analysis engine"
+    }
+
+    async fn analyze(&self, data: &[CollectedData]) -> Result<AnalysisResult> {
+        analyze_locally(data, self.language, self.fallback, self.full_confidence_samples)
+    }
+}
+
+/// カレンダーの予定が時間ブロック全体を占める場合は予定を活動とし、それ以外は別の分析エンジンに任せる分析エンジン
+/// （`general.calendar_first`）
+///
+/// 予定が活動の根拠になるため確度は1.0とし、OpenAIは呼ばない
+pub struct CalendarFirstAnalyzer<'a> {
+    config: &'a AppConfig,
+    inner: Box<dyn Analyzer + 'a>,
+}
+
+impl<'a> CalendarFirstAnalyzer<'a> {
+    pub fn new(config: &'a AppConfig, inner: Box<dyn Analyzer + 'a>) -> Self {
+        Self { config, inner }
+    }
+}
+
+#[async_trait]
+impl Analyzer for CalendarFirstAnalyzer<'_> {
+    fn name(&self) -> &'static str {
+        "calendar-first analysis"
+    }
+
+    async fn analyze(&self, data: &[CollectedData]) -> Result<AnalysisResult> {
+        let Some(latest) = data.first() else {
+            return self.inner.analyze(data).await;
+        };
+        let (block_start, block_end) = self.config.general.block_bounds(latest.timestamp);
+        let Some(event) = covering_calendar_event(data, block_start, block_end) else {
+            debug!("No calendar event covers the block, using {}", self.inner.name());
+            return self.inner.analyze(data).await;
+        };
+
+        debug!("Calendar event '{}' covers the block, recording it", event.title);
+        Ok(AnalysisResult {
+            activity: event.title.clone(),
+            confidence: 1.0,
+            timestamp: latest.timestamp,
+            alternatives: Vec::new(),
+            window_title: None,
+            window_class: None,
+            calendar_event: Some(event),
+            analyzed_locally: true,
+            project: None,
+            openai_api_key: None,
+        })
+    }
+}
+
+/// 時間ブロック全体を占める、辞退していないカレンダーの予定を選ぶ（複数ある場合は期間の短いもの）
+fn covering_calendar_event(
+    data: &[CollectedData],
+    block_start: chrono::DateTime<chrono::Utc>,
+    block_end: chrono::DateTime<chrono::Utc>,
+) -> Option<data_collector::CalendarEvent> {
+    data.iter()
+        .flat_map(|item| &item.calendar_events)
+        .filter(|event| event.response_status.as_deref() != Some("declined"))
+        .filter(|event| event.start_time <= block_start && event.end_time >= block_end)
+        .min_by_key(|event| event.end_time - event.start_time)
+        .cloned()
+}
+
+/// 設定に応じて使用する分析エンジンを選択する
+pub fn analyzer_from_config(config: &AppConfig) -> Result<Box<dyn Analyzer + '_>> {
+    let analyzer: Box<dyn Analyzer + '_> = match config.general.analyzer {
+        AnalyzerKind::Auto if config.openai_config().is_some() => Box::new(GptAnalyzer::new(config)),
+        AnalyzerKind::Auto | AnalyzerKind::Local => {
+            Box::new(LocalAnalyzer::new(
+                config.general.language,
+                config.general.fallback_activity,
+                config.general.full_confidence_samples,
+            ))
+        }
+        AnalyzerKind::Openai => {
+            if config.general.privacy == PrivacyMode::LocalOnly {
+                return Err(anyhow::anyhow!("general.analyzer is \"openai\" but general.privacy is \"local_only\""));
+            }
+            if config.openai.is_none() {
+                return Err(anyhow::anyhow!("general.analyzer is \"openai\" but [openai] is not configured"));
+            }
+            Box::new(GptAnalyzer::new(config))
+        }
+        AnalyzerKind::Rules => Box::new(RulesAnalyzer::new(
+            &config.rules,
+            config.general.language,
+            config.general.fallback_activity,
+            config.general.full_confidence_samples,
+        )?),
+    };
+    if config.general.calendar_first {
+        return Ok(Box::new(CalendarFirstAnalyzer::new(config, analyzer)));
+    }
+    Ok(analyzer)
+}
+
+/// 直前の時間ブロックと同じ活動が続いている場合に確度を引き上げる
+///
+/// 直前のブロックの確度が `threshold` 以上かつ今回より高い場合のみ、`smoothing` の割合で直前の確度へ近づける
+pub fn smooth_confidence(
+    result: &mut AnalysisResult,
+    previous: Option<&BlockAnalysis>,
+    smoothing: f64,
+    threshold: f64,
+) {
+    let Some(previous) = previous else {
+        return;
+    };
+    if previous.activity != result.activity
+        || previous.confidence < threshold
+        || previous.confidence <= result.confidence
+    {
+        return;
+    }
+
+    let smoothed = smoothing * previous.confidence + (1.0 - smoothing) * result.confidence;
+    debug!(
+        "Smoothed confidence for '{}' from {:.2} to {:.2} (previous block: {:.2})",
+        result.activity, result.confidence, smoothed, previous.confidence
+    );
+    result.confidence = smoothed;
+}
+
+/// 記録済みの直前の時間ブロックの分析結果を使って確度を平滑化する（登録判断の前に呼ぶ）
+pub fn smooth_with_previous_block(config: &AppConfig, block_start: chrono::DateTime<chrono::Utc>, result: &mut AnalysisResult) {
+    if config.general.confidence_smoothing <= 0.0 {
+        return;
+    }
+
+    let previous_start = block_start - chrono::Duration::minutes(config.general.block_minutes() as i64);
+    match data_collector::get_block_analysis(config.general.data_dir_path(), previous_start) {
+        Ok(previous) => smooth_confidence(
+            result,
+            previous.as_ref(),
+            config.general.confidence_smoothing,
+            config.general.confidence_threshold,
+        ),
+        Err(e) => warn!("Failed to load the previous block analysis: {:#}", e),
+    }
+}
+
+/// OpenAI分析の再試行の初回待機時間（ミリ秒）
+const OPENAI_RETRY_INITIAL_BACKOFF_MS: u64 = 1000;
+
+/// 認証エラー後のOpenAI APIの休止状態
+pub(crate) static OPENAI_AUTH_PAUSE: AuthPause = AuthPause::new("OpenAI");
+
+/// OpenAI APIのエラーレスポンス（ステータスで認証エラーを区別するため）
+#[derive(Debug, thiserror::Error)]
+#[error("OpenAI API error: HTTP status {status}, response: {body}")]
+pub(crate) struct OpenAIHttpError {
+    pub(crate) status: reqwest::StatusCode,
+    pub(crate) body: String,
+}
+
+/// APIキーが無効で拒否されたエラー（HTTP 401）かどうか
+pub(crate) fn is_openai_unauthorized(error: &anyhow::Error) -> bool {
+    error.downcast_ref::<OpenAIHttpError>()
+        .is_some_and(|error| error.status == reqwest::StatusCode::UNAUTHORIZED)
+}
+
+/// OpenAIで分析し、失敗した場合は間隔を空けて再試行する
+///
+/// 再試行を使い切った場合はローカル分析にフォールバックし、ブロックを未分類のまま失わないようにする
+pub async fn analyze_with_fallback(
+    config: &AppConfig,
+    data: &[CollectedData],
+) -> Result<AnalysisResult> {
+    if let Some(remaining) = OPENAI_AUTH_PAUSE.remaining() {
+        warn!("OpenAI is paused after an authentication failure ({} minutes remaining), using local analysis",
+              remaining.as_secs().div_ceil(60));
+        return analyze_locally(data, config.general.language, config.general.fallback_activity, config.general.full_confidence_samples);
+    }
+    
+    let max_retries = config.openai_config().map_or(0, |openai| openai.max_retries);
+    let mut backoff = Duration::from_millis(OPENAI_RETRY_INITIAL_BACKOFF_MS);
+    let mut attempt = 0;
+    
+    let last_error = loop {
+        match analyze_with_gpt(config, data).await {
+            Ok(result) => return Ok(result),
+            // 無効なAPIキーは再試行しても回復しない
+            Err(e) if is_openai_unauthorized(&e) => {
+                OPENAI_AUTH_PAUSE.trip();
+                break e;
+            }
+            Err(e) if attempt < max_retries => {
+                attempt += 1;
+                warn!("OpenAI analysis failed: {}. Retrying in {}ms (retry {}/{})",
+                      e, backoff.as_millis(), attempt, max_retries);
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+            Err(e) => break e,
+        }
+    };
+    
+    warn!("OpenAI analysis failed after {} attempts, falling back to local analysis: {}",
+          attempt + 1, last_error);
+    analyze_locally(data, config.general.language, config.general.fallback_activity, config.general.full_confidence_samples)
+}
+
 /// GPT-4o miniを使って分析を実行
 pub async fn analyze_with_gpt(
     config: &AppConfig,
@@ -59,31 +330,73 @@ pub async fn analyze_with_gpt(
         return Err(anyhow::anyhow!("No data to analyze"));
     }
     
-    if config.openai.is_none() {
+    // privacy = "local_only" の場合も未設定として扱い、OpenAIには何も送らない
+    let Some(openai_config) = config.openai_config() else {
         return Err(anyhow::anyhow!("OpenAI configuration is missing"));
-    }
+    };
+
+    // 分析用のプロンプトを構築
+    let prompt = build_analysis_prompt(data, config.general.language, config.general.max_prompt_samples, config.general.privacy);
+    debug!("Analysis prompt: {}", prompt);
     
-    let openai_config = config.openai.as_ref().unwrap();
+    let content = request_gpt_analysis(openai_config, prompt, config.general.language).await?;
+    debug!("GPT response: {}", content);
     
-    // APIキーを環境変数にセット
-    env::set_var("OPENAI_API_KEY", &openai_config.api_key);
+    // レスポンスをパースして分析結果を抽出し、OpenAI APIキーを設定
+    let mut result = parse_gpt_response(&content, data)?;
+    result.openai_api_key = Some(openai_config.api_key.clone());
+    Ok(result)
+}
+
+/// `--explain` 用に、分析に使うプロンプト・モデルの生のレスポンス・パース結果をまとめたもの
+pub struct AnalysisExplanation {
+    /// モデルに送るプロンプト
+    pub prompt: String,
     
-    // 分析用のプロンプトを構築
-    let prompt = build_analysis_prompt(data);
-    debug!("Analysis prompt: {}", prompt);
+    /// モデルの生のレスポンス（OpenAI未設定でローカル分析した場合はNone）
+    pub raw_response: Option<String>,
+    
+    /// パースした分析結果
+    pub result: AnalysisResult,
+}
+
+/// 登録は行わずに、分析の過程（プロンプトとレスポンス）を取得する
+pub async fn explain_analysis(config: &AppConfig, data: &[CollectedData]) -> Result<AnalysisExplanation> {
+    if data.is_empty() {
+        return Err(anyhow::anyhow!("No data to analyze"));
+    }
+    
+    let prompt = build_analysis_prompt(data, config.general.language, config.general.max_prompt_samples, config.general.privacy);
+
+    let Some(openai_config) = config.openai_config() else {
+        return Ok(AnalysisExplanation {
+            prompt,
+            raw_response: None,
+            result: analyze_locally(data, config.general.language, config.general.fallback_activity, config.general.full_confidence_samples)?,
+        });
+    };
     
-    // OpenAI クライアントの初期化
-    let config = OpenAIConfig::new().with_api_key(openai_config.api_key.clone());
-    let client = Client::with_config(config);
+    let raw_response = request_gpt_analysis(openai_config, prompt.clone(), config.general.language).await?;
+    let result = parse_gpt_response(&raw_response, data)?;
     
+    Ok(AnalysisExplanation {
+        prompt,
+        raw_response: Some(raw_response),
+        result,
+    })
+}
+
+/// プロンプトをモデルに送り、レスポンスの本文をそのまま返す
+async fn request_gpt_analysis(
+    openai_config: &crate::config::OpenAIConfig,
+    prompt: String,
+    language: Language,
+) -> Result<String> {
     // チャットメッセージを作成
     let messages = vec![
         ChatCompletionRequestMessage::System(
             ChatCompletionRequestSystemMessageArgs::default()
-                .content("あなたはLinuxデスクトップ環境でのユーザーの活動を分析するAIアシスタントです。\
-                ウィンドウタイトルやカレンダーイベントの情報から、ユーザーが何をしていたかを推定し、\
-                その確度（0.0-1.0の値）を判断してください。\
-                また、確度が低い場合は候補となる活動のリストも提供してください。")
+                .content(prompt_text(language).system)
                 .build()?
         ),
         ChatCompletionRequestMessage::User(
@@ -107,24 +420,37 @@ pub async fn analyze_with_gpt(
         ..Default::default()
     };
     
-    // APIリクエストを送信
-    let response = client.chat().create(request).await?;
-    
-    if let Some(choice) = response.choices.first() {
-        let content = choice.message.content.as_deref().unwrap_or("");
-        debug!("GPT response: {}", content);
-        
-        // レスポンスをパースして分析結果を抽出し、OpenAI APIキーを設定
-        let mut result = parse_gpt_response(content, data)?;
-        result.openai_api_key = Some(openai_config.api_key.clone());
-        Ok(result)
-    } else {
-        Err(anyhow::anyhow!("No response from GPT"))
+    // APIリクエストを送信（認証エラーを区別できるよう、ステータスを見てからレスポンスを解析する）
+    let url = format!("{}/chat/completions", openai_config.api_base.trim_end_matches('/'));
+    let mut http_request = crate::utils::http_client().post(&url).json(&request);
+    if !openai_config.api_key.is_empty() {
+        http_request = http_request.bearer_auth(&openai_config.api_key);
+    }
+    let http_response = http_request.send().await
+        .context("OpenAI API request failed")?;
+    let status = http_response.status();
+    if !status.is_success() {
+        let body = http_response.text().await.unwrap_or_default();
+        return Err(OpenAIHttpError { status, body }.into());
     }
+    let response: CreateChatCompletionResponse = http_response.json().await
+        .context("Failed to parse OpenAI API response")?;
+    
+    response.choices.first()
+        .map(|choice| choice.message.content.clone().unwrap_or_default())
+        .ok_or_else(|| anyhow::anyhow!("No response from GPT"))
 }
 
 /// ローカルな推論エンジンで分析を実行（オフライン時に使用）
-pub fn analyze_locally(data: &[CollectedData]) -> Result<AnalysisResult> {
+///
+/// キーワードから分類できなかった場合の活動名は `fallback` で決める。
+/// サンプルが `full_confidence_samples` 未満のブロックは確度を下げる
+pub fn analyze_locally(
+    data: &[CollectedData],
+    language: Language,
+    fallback: FallbackActivity,
+    full_confidence_samples: usize,
+) -> Result<AnalysisResult> {
     if data.is_empty() {
         return Err(anyhow::anyhow!("No data to analyze"));
     }
@@ -148,18 +474,19 @@ pub fn analyze_locally(data: &[CollectedData]) -> Result<AnalysisResult> {
         }
     }
     
-    // 信頼度を計算（最も頻度が高いタイトルの占める割合）
-    let confidence = most_frequent.1 as f64 / data.len() as f64;
+    // 信頼度を計算（最も頻度が高いタイトルの占める割合を、サンプル数に応じて下げる）
+    let volume = sample_volume_factor(data.len(), full_confidence_samples);
+    let confidence = most_frequent.1 as f64 / data.len() as f64 * volume;
     
     // 簡易的なキーワードマッチングでカテゴリを推定
-    let activity = categorize_by_keywords(&most_frequent.0);
+    let activity = categorize_by_keywords(&most_frequent.0, data, language, fallback);
     
     // 候補リストを作成（上位3つまで）
     let mut alternatives = Vec::new();
     for (title, count) in title_counts.iter().filter(|(t, _)| *t != &most_frequent.0) {
-        let conf = *count as f64 / data.len() as f64;
+        let conf = *count as f64 / data.len() as f64 * volume;
         alternatives.push(ActivityCandidate {
-            activity: categorize_by_keywords(title),
+            activity: categorize_by_keywords(title, data, language, fallback),
             confidence: conf,
         });
         
@@ -168,19 +495,13 @@ pub fn analyze_locally(data: &[CollectedData]) -> Result<AnalysisResult> {
         }
     }
     
-    // 現在時刻に重なるカレンダーイベントを抽出
-    let mut calendar_event = None;
-    for item in data {
-        for event in &item.calendar_events {
-            if event.start_time <= timestamp && event.end_time >= timestamp {
-                calendar_event = Some(event.clone());
-                break;
-            }
-        }
-        if calendar_event.is_some() {
-            break;
-        }
-    }
+    // 現在時刻に重なるカレンダーイベントのうち、最も関連が深いものを選ぶ
+    let calendar_event = select_calendar_event(data, timestamp, &[&most_frequent.0]);
+    
+    // 最も頻度が高いタイトルのウィンドウクラスを取得
+    let window_class = data.iter()
+        .find(|item| item.window.title.to_lowercase() == most_frequent.0)
+        .and_then(|item| item.window.class.clone());
     
     // 最も頻度が高いウィンドウタイトルから活動を推定
     Ok(AnalysisResult {
@@ -189,72 +510,478 @@ pub fn analyze_locally(data: &[CollectedData]) -> Result<AnalysisResult> {
         timestamp,
         alternatives,
         window_title: Some(most_frequent.0),
+        window_class,
         calendar_event,
+        analyzed_locally: true,
+        project: None,
         openai_api_key: None,
     })
 }
 
+/// 時刻に重なるカレンダーイベントから、活動に最も関連が深いものを選ぶ
+///
+/// 予定が重なっている場合は、タイトルの単語が活動（ウィンドウタイトルなど）に多く含まれるもの、
+/// 次に期間の短い（より具体的な）もの、次に出席の回答が確定しているものを優先する
+fn select_calendar_event(
+    data: &[CollectedData],
+    timestamp: chrono::DateTime<chrono::Utc>,
+    context: &[&str],
+) -> Option<data_collector::CalendarEvent> {
+    let context = context.join(" ").to_lowercase();
+    let title_score = |event: &data_collector::CalendarEvent| {
+        event.title.to_lowercase()
+            .split_whitespace()
+            .filter(|word| word.chars().any(char::is_alphanumeric) && context.contains(word))
+            .count()
+    };
+    // 承諾済み（自分の予定を含む）、未回答、仮承諾、辞退の順
+    let response_rank = |event: &data_collector::CalendarEvent| match event.response_status.as_deref() {
+        None | Some("accepted") => 0,
+        Some("needsAction") => 1,
+        Some("tentative") => 2,
+        Some(_) => 3,
+    };
+    
+    data.iter()
+        .flat_map(|item| &item.calendar_events)
+        .filter(|event| event.start_time <= timestamp && event.end_time >= timestamp)
+        .min_by_key(|event| (
+            std::cmp::Reverse(title_score(event)),
+            event.end_time - event.start_time,
+            response_rank(event),
+        ))
+        .cloned()
+}
+
+/// キーワードで推定する組み込みの活動カテゴリ
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Category {
+    Email,
+    Document,
+    Schedule,
+    Video,
+    Chat,
+    WebBrowsing,
+    Terminal,
+    Programming,
+    Office,
+    ImageEditing,
+    Meeting,
+    Other,
+}
+
+impl Category {
+    const ALL: [Category; 12] = [
+        Category::Email,
+        Category::Document,
+        Category::Schedule,
+        Category::Video,
+        Category::Chat,
+        Category::WebBrowsing,
+        Category::Terminal,
+        Category::Programming,
+        Category::Office,
+        Category::ImageEditing,
+        Category::Meeting,
+        Category::Other,
+    ];
+    
+    const LANGUAGES: [Language; 2] = [Language::Ja, Language::En];
+    
+    /// カテゴリ名（いずれかの言語）に一致するカテゴリ
+    fn from_label(activity: &str) -> Option<Category> {
+        Category::ALL.into_iter()
+            .find(|category| Category::LANGUAGES.iter().any(|&language| category.label(language) == activity))
+    }
+    
+    /// 記録済みの活動内容のカテゴリ（カテゴリ名ならそのカテゴリ、それ以外はキーワードで判定する）
+    pub fn from_activity(activity: &str) -> Category {
+        Category::from_label(activity).unwrap_or_else(|| classify_by_keywords(activity))
+    }
+    
+    /// 活動内容がカテゴリ名の場合、他の言語でのカテゴリ名（プロジェクト名との照合に使う）
+    pub fn translated_labels(activity: &str) -> Vec<&'static str> {
+        Category::from_label(activity)
+            .map(|category| Category::LANGUAGES.iter()
+                .map(|&language| category.label(language))
+                .filter(|label| *label != activity)
+                .collect())
+            .unwrap_or_default()
+    }
+    
+    /// `[buckets]` で指定しない場合の集計区分
+    pub fn default_bucket(self) -> Bucket {
+        match self {
+            Category::Document
+            | Category::Terminal
+            | Category::Programming
+            | Category::Office
+            | Category::ImageEditing => Bucket::Focus,
+            Category::Email | Category::Schedule | Category::Chat | Category::Meeting => Bucket::Communication,
+            Category::Video => Bucket::Break,
+            Category::WebBrowsing | Category::Other => Bucket::Other,
+        }
+    }
+    
+    /// 設定された言語でのカテゴリ名
+    fn label(self, language: Language) -> &'static str {
+        match language {
+            Language::Ja => match self {
+                Category::Email => "メール確認",
+                Category::Document => "ドキュメント作成",
+                Category::Schedule => "スケジュール確認",
+                Category::Video => "動画視聴",
+                Category::Chat => "チャット/コミュニケーション",
+                Category::WebBrowsing => "ウェブブラウジング",
+                Category::Terminal => "ターミナル作業",
+                Category::Programming => "プログラミング",
+                Category::Office => "オフィス作業",
+                Category::ImageEditing => "画像編集",
+                Category::Meeting => "ミーティング",
+                Category::Other => "その他の活動",
+            },
+            Language::En => match self {
+                Category::Email => "Email",
+                Category::Document => "Writing documents",
+                Category::Schedule => "Checking schedule",
+                Category::Video => "Watching videos",
+                Category::Chat => "Chat/communication",
+                Category::WebBrowsing => "Web browsing",
+                Category::Terminal => "Terminal work",
+                Category::Programming => "Programming",
+                Category::Office => "Office work",
+                Category::ImageEditing => "Image editing",
+                Category::Meeting => "Meeting",
+                Category::Other => "Other activity",
+            },
+        }
+    }
+}
+
+/// サンプル数に応じた確度の係数（`full_confidence_samples` 未満のブロックはサンプル数に比例して小さくする）
+///
+/// 1〜2サンプルしかないブロックでは最頻のタイトルの割合が1.0になりやすく、そのまま自動登録されてしまうため
+fn sample_volume_factor(samples: usize, full_confidence_samples: usize) -> f64 {
+    if full_confidence_samples <= 1 {
+        return 1.0;
+    }
+    samples.min(full_confidence_samples) as f64 / full_confidence_samples as f64
+}
+
 /// キーワードベースで活動カテゴリを推定する簡易関数
-fn categorize_by_keywords(title: &str) -> String {
+///
+/// 分類できなかった場合は `fallback` に応じて、同じタイトルのウィンドウのタイトルやクラスを活動名にする
+/// （値が空の場合はカテゴリ名）
+fn categorize_by_keywords(title: &str, data: &[CollectedData], language: Language, fallback: FallbackActivity) -> String {
+    // 集計ではタイトルを小文字にしているため、元のウィンドウから表記を取り出す
+    let window = data.iter()
+        .map(|item| &item.window)
+        .find(|window| window.title.to_lowercase() == title);
+
+    // ターミナルでエディタやビルドツールを実行していた場合はプログラミングとみなす
+    let terminal_command = window
+        .and_then(|window| window.terminal.as_ref())
+        .and_then(|terminal| terminal.command.as_deref());
+    if terminal_command.is_some_and(is_programming_command) {
+        return Category::Programming.label(language).to_string();
+    }
+
+    let category = classify_by_keywords(title);
+    if category != Category::Other {
+        return category.label(language).to_string();
+    }
+
+    let name = match fallback {
+        FallbackActivity::Other => None,
+        FallbackActivity::WindowTitle => window.map(|window| window.title.trim()),
+        FallbackActivity::WindowClass => window.and_then(|window| window.class.as_deref()).map(str::trim),
+    };
+    name.filter(|name| !name.is_empty())
+        .map_or_else(|| category.label(language).to_string(), str::to_string)
+}
+
+/// ターミナルでプログラミング中とみなすコマンド（エディタ、ビルドツール、バージョン管理）
+const PROGRAMMING_COMMANDS: &[&str] = &[
+    "vim", "nvim", "vi", "emacs", "nano", "hx", "helix", "micro", "kak",
+    "cargo", "rustc", "make", "cmake", "ninja", "gcc", "clang", "go", "npm", "yarn", "pnpm",
+    "mvn", "gradle", "git", "tig", "lazygit",
+];
+
+/// ターミナルの前面のコマンドがプログラミング作業のものかどうか
+fn is_programming_command(command: &str) -> bool {
+    PROGRAMMING_COMMANDS.contains(&command.to_lowercase().as_str())
+}
+
+/// ウィンドウタイトルのキーワードからカテゴリを判定する
+fn classify_by_keywords(title: &str) -> Category {
     let title = title.to_lowercase();
     
     // キーワードマッチング（非常に簡易的な実装）
     if title.contains("firefox") || title.contains("chrome") || title.contains("edge") {
         if title.contains("gmail") || title.contains("mail") {
-            return "メール確認".to_string();
+            Category::Email
         } else if title.contains("google doc") || title.contains("document") {
-            return "ドキュメント作成".to_string();
+            Category::Document
         } else if title.contains("calendar") {
-            return "スケジュール確認".to_string();
+            Category::Schedule
         } else if title.contains("youtube") || title.contains("video") {
-            return "動画視聴".to_string();
+            Category::Video
         } else if title.contains("chat") || title.contains("slack") || title.contains("discord") {
-            return "チャット/コミュニケーション".to_string();
+            Category::Chat
         } else {
-            return "ウェブブラウジング".to_string();
+            Category::WebBrowsing
         }
     } else if title.contains("terminal") || title.contains("console") || title.contains("bash") {
-        return "ターミナル作業".to_string();
+        Category::Terminal
     } else if title.contains("code") || title.contains("vscode") || title.contains("intellij") {
-        return "プログラミング".to_string();
+        Category::Programming
     } else if title.contains("libreoffice") || title.contains("calc") || title.contains("writer") {
-        return "オフィス作業".to_string();
+        Category::Office
     } else if title.contains("gimp") || title.contains("photoshop") || title.contains("illustrator") {
-        return "画像編集".to_string();
+        Category::ImageEditing
     } else if title.contains("meeting") || title.contains("zoom") || title.contains("teams") {
-        return "ミーティング".to_string();
+        Category::Meeting
+    } else {
+        // デフォルト
+        Category::Other
+    }
+}
+
+/// OpenAIへの指示文（言語ごと）
+struct PromptText {
+    system: &'static str,
+    intro: &'static str,
+    window_header: &'static str,
+    window_columns: &'static str,
+    unknown_class: &'static str,
+    redacted_title: &'static str,
+    visible_header: &'static str,
+    visible_columns: &'static str,
+    calendar_header: &'static str,
+    calendar_columns: &'static str,
+    response_instruction: &'static str,
+    activity_placeholder: &'static str,
+    confidence_placeholder: &'static str,
+    candidate_placeholder: &'static str,
+}
+
+const JA_PROMPT: PromptText = PromptText {
+    system: "あなたはLinuxデスクトップ環境でのユーザーの活動を分析するAIアシスタントです。\
+        ウィンドウタイトルやカレンダーイベントの情報から、ユーザーが何をしていたかを推定し、\
+        その確度（0.0-1.0の値）を判断してください。\
+        また、確度が低い場合は候補となる活動のリストも提供してください。\
+        回答は日本語で記述してください。",
+    intro: "以下のLinuxデスクトップのウィンドウ情報とカレンダーイベントから、ユーザーの活動内容を推定し、その確度（0.0-1.0）を評価してください。\n\n",
+    window_header: "### ウィンドウ情報 ###\n",
+    window_columns: "タイムスタンプ | ウィンドウタイトル | クラス\n",
+    unknown_class: "不明",
+    redacted_title: "（非公開）",
+    visible_header: "\n### 同時に表示されていた他のウィンドウ ###\n",
+    visible_columns: "ウィンドウタイトル | クラス | 表示されていたサンプル数\n",
+    calendar_header: "\n### カレンダーイベント ###\n",
+    calendar_columns: "タイトル | 開始時間 | 終了時間\n",
+    response_instruction: "\nこの情報を元に、活動内容と候補を日本語で記述し、以下の形式でJSON形式で回答してください：\n",
+    activity_placeholder: "推定される活動内容",
+    confidence_placeholder: "0.0～1.0の値",
+    candidate_placeholder: "候補",
+};
+
+const EN_PROMPT: PromptText = PromptText {
+    system: "You are an assistant that analyzes a user's activity on a Linux desktop. \
+        From window titles and calendar events, infer what the user was doing \
+        and judge the confidence of your estimate (a value from 0.0 to 1.0). \
+        If the confidence is low, also provide a list of candidate activities. \
+        Answer in English.",
+    intro: "From the following Linux desktop window information and calendar events, infer the user's activity and rate its confidence (0.0-1.0).\n\n",
+    window_header: "### Windows ###\n",
+    window_columns: "Timestamp | Window title | Class\n",
+    unknown_class: "unknown",
+    redacted_title: "(redacted)",
+    visible_header: "\n### Other visible windows ###\n",
+    visible_columns: "Window title | Class | Samples visible\n",
+    calendar_header: "\n### Calendar events ###\n",
+    calendar_columns: "Title | Start | End\n",
+    response_instruction: "\nBased on this information, write the activity and candidates in English and answer in JSON using the following format:\n",
+    activity_placeholder: "estimated activity",
+    confidence_placeholder: "a value from 0.0 to 1.0",
+    candidate_placeholder: "candidate ",
+};
+
+fn prompt_text(language: Language) -> &'static PromptText {
+    match language {
+        Language::Ja => &JA_PROMPT,
+        Language::En => &EN_PROMPT,
+    }
+}
+
+/// プロンプトに含めるウィンドウタイトルの最大長（バイト）
+const MAX_PROMPT_TITLE_LEN: usize = 200;
+
+/// プロンプトに含めるウィンドウタイトルを切り詰める（切り詰めたかどうかも返す）
+fn truncate_prompt_title(title: &str) -> (String, bool) {
+    if title.len() <= MAX_PROMPT_TITLE_LEN {
+        return (title.to_string(), false);
+    }
+    (truncate_string_safely(title, MAX_PROMPT_TITLE_LEN), true)
+}
+
+/// 分析の前にサンプルを整える（短時間のウィンドウの切り替えを前後の活動にまとめる）
+///
+/// 保存済みのサンプルを分析・プロンプト・登録に使う前に、必ずこの関数を通す
+pub fn compact_samples(data: &mut [CollectedData], config: &AppConfig) {
+    debounce_window_switches(data, config.general.activity_debounce_secs);
+}
+
+/// 短時間だけ別のウィンドウに切り替えたサンプルを、前後の活動のウィンドウとみなす（`general.activity_debounce_secs`）
+///
+/// 切り替えの前後が同じウィンドウで、切り替えていた時間（次のウィンドウに戻るまで）が `debounce_secs` 未満の場合のみ置き換える。
+/// `debounce_secs` が0の場合は何もしない
+fn debounce_window_switches(data: &mut [CollectedData], debounce_secs: u64) {
+    if debounce_secs == 0 {
+        return;
+    }
+
+    let same_window = |a: &data_collector::WindowInfo, b: &data_collector::WindowInfo| {
+        a.title == b.title && a.class == b.class
+    };
+
+    // 古い順に並べたサンプルの位置と、同じウィンドウが続く区間（先頭と末尾の位置）
+    let mut order: Vec<usize> = (0..data.len()).collect();
+    order.sort_by_key(|&index| data[index].timestamp);
+    let mut runs: Vec<(usize, usize)> = Vec::new();
+    for position in 0..order.len() {
+        match runs.last_mut() {
+            Some((_, end)) if same_window(&data[order[*end]].window, &data[order[position]].window) => *end = position,
+            _ => runs.push((position, position)),
+        }
+    }
+
+    for run in 1..runs.len().saturating_sub(1) {
+        let (start, end) = runs[run];
+        let before = data[order[runs[run - 1].1]].window.clone();
+        let after = &data[order[runs[run + 1].0]];
+        let switched_secs = (after.timestamp - data[order[start]].timestamp).num_seconds();
+        if !same_window(&before, &after.window) || switched_secs >= debounce_secs as i64 {
+            continue;
+        }
+
+        debug!("Absorbing {}s switch to '{}' into '{}'", switched_secs, data[order[start]].window.title, before.title);
+        for position in start..=end {
+            let sample = &mut data[order[position]];
+            sample.window = data_collector::WindowInfo {
+                timestamp: sample.window.timestamp,
+                ..before.clone()
+            };
+        }
+    }
+}
+
+/// プロンプトに含めるサンプルを上限数まで選ぶ（`max_samples` が0なら全件）
+///
+/// ウィンドウごとの直近のサンプルを出現回数の多い順に選び、残りの枠は直近のサンプルで埋める。
+/// 選んだサンプルは元の順序（新しい順）のまま返す
+fn select_prompt_samples(data: &[CollectedData], max_samples: usize) -> Vec<&CollectedData> {
+    if max_samples == 0 || data.len() <= max_samples {
+        return data.iter().collect();
     }
     
-    // デフォルト
-    "その他の活動".to_string()
+    // ウィンドウ（タイトルとクラス）ごとの直近のサンプルの位置と出現回数
+    let mut windows: Vec<(usize, usize)> = Vec::new();
+    for (index, item) in data.iter().enumerate() {
+        let same_window = |latest: &usize| {
+            data[*latest].window.title == item.window.title && data[*latest].window.class == item.window.class
+        };
+        match windows.iter_mut().find(|(latest, _)| same_window(latest)) {
+            Some((_, count)) => *count += 1,
+            None => windows.push((index, 1)),
+        }
+    }
+    windows.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+    
+    let mut selected: Vec<usize> = windows.iter()
+        .map(|(latest, _)| *latest)
+        .take(max_samples)
+        .collect();
+    for index in 0..data.len() {
+        if selected.len() >= max_samples {
+            break;
+        }
+        if !selected.contains(&index) {
+            selected.push(index);
+        }
+    }
+    selected.sort_unstable();
+    
+    warn!("Prompt limited to {} of {} samples (general.max_prompt_samples)", selected.len(), data.len());
+    selected.into_iter().map(|index| &data[index]).collect()
 }
 
 /// 分析用のプロンプトを構築
-fn build_analysis_prompt(data: &[CollectedData]) -> String {
-    let mut prompt = String::from(
-        "以下のLinuxデスクトップのウィンドウ情報とカレンダーイベントから、ユーザーの活動内容を推定し、その確度（0.0-1.0）を評価してください。\n\n"
-    );
+///
+/// `privacy` が `class_only` の場合はウィンドウタイトルを伏せ、ウィンドウクラスとカレンダーの予定名だけを含める
+fn build_analysis_prompt(data: &[CollectedData], language: Language, max_samples: usize, privacy: PrivacyMode) -> String {
+    let redact_titles = privacy == PrivacyMode::ClassOnly;
+    let text = prompt_text(language);
+    let mut prompt = String::from(text.intro);
     
     // データ形式を説明
-    prompt.push_str("### ウィンドウ情報 ###\n");
-    prompt.push_str("タイムスタンプ | ウィンドウタイトル | クラス\n");
+    prompt.push_str(text.window_header);
+    prompt.push_str(text.window_columns);
     
-    // ウィンドウ情報を追加
-    for item in data {
+    // ウィンドウ情報を追加（長すぎるタイトルは切り詰める）
+    let mut truncated_titles = 0;
+    for item in select_prompt_samples(data, max_samples) {
+        let title = if redact_titles {
+            text.redacted_title.to_string()
+        } else {
+            let (title, truncated) = truncate_prompt_title(&item.window.title);
+            if truncated {
+                truncated_titles += 1;
+            }
+            title
+        };
+        // ターミナルでは前面のコマンドと作業ディレクトリを添える（タイトルを伏せる場合は作業ディレクトリ名も送らない）
+        let title = match item.window.terminal.as_ref().filter(|_| !redact_titles) {
+            Some(terminal) => format!("{} [{}]", title, terminal.describe()),
+            None => title,
+        };
         prompt.push_str(&format!(
             "{} | {} | {}\n",
             item.timestamp.format("%Y-%m-%d %H:%M:%S"),
-            item.window.title,
-            item.window.class.as_deref().unwrap_or("不明")
+            title,
+            item.window.class.as_deref().unwrap_or(text.unknown_class)
         ));
     }
+    if truncated_titles > 0 {
+        warn!("Truncated {} window title(s) longer than {} bytes in the prompt", truncated_titles, MAX_PROMPT_TITLE_LEN);
+    }
+    
+    // 表示中だった他のウィンドウがあれば、表示されていたサンプル数の多い順に追加
+    let mut visible_counts: Vec<((&str, &str), usize)> = Vec::new();
+    for visible in data.iter().flat_map(|item| &item.visible_windows) {
+        let title = if redact_titles { text.redacted_title } else { visible.title.as_str() };
+        let key = (title, visible.class.as_deref().unwrap_or(text.unknown_class));
+        match visible_counts.iter_mut().find(|(existing, _)| *existing == key) {
+            Some((_, count)) => *count += 1,
+            None => visible_counts.push((key, 1)),
+        }
+    }
+    if !visible_counts.is_empty() {
+        visible_counts.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        prompt.push_str(text.visible_header);
+        prompt.push_str(text.visible_columns);
+        for ((title, class), count) in visible_counts {
+            prompt.push_str(&format!("{} | {} | {}\n", title, class, count));
+        }
+    }
     
     // カレンダーイベント情報があれば追加
     // 現在時刻にかぶっているイベントだけをフィルタリングして重複を除く
     let has_calendar_events = data.iter().any(|d| !d.calendar_events.is_empty());
     if has_calendar_events {
-        prompt.push_str("\n### カレンダーイベント ###\n");
-        prompt.push_str("タイトル | 開始時間 | 終了時間\n");
+        prompt.push_str(text.calendar_header);
+        prompt.push_str(text.calendar_columns);
         
         // 重複を避けるためにイベントIDをキーとするマップを使用
         let mut seen_events = std::collections::HashSet::new();
@@ -289,19 +1016,77 @@ fn build_analysis_prompt(data: &[CollectedData]) -> String {
     }
     
     // 出力形式の指定
-    prompt.push_str("\nこの情報を元に、以下の形式でJSON形式で回答してください：\n");
+    prompt.push_str(text.response_instruction);
     prompt.push_str("{\n");
-    prompt.push_str("  \"activity\": \"推定される活動内容\",\n");
-    prompt.push_str("  \"confidence\": 0.0～1.0の値,\n");
+    prompt.push_str(&format!("  \"activity\": \"{}\",\n", text.activity_placeholder));
+    prompt.push_str(&format!("  \"confidence\": {},\n", text.confidence_placeholder));
     prompt.push_str("  \"alternatives\": [\n");
-    prompt.push_str("    { \"activity\": \"候補1\", \"confidence\": 0.0～1.0の値 },\n");
-    prompt.push_str("    { \"activity\": \"候補2\", \"confidence\": 0.0～1.0の値 }\n");
+    prompt.push_str(&format!(
+        "    {{ \"activity\": \"{}1\", \"confidence\": {} }},\n",
+        text.candidate_placeholder, text.confidence_placeholder
+    ));
+    prompt.push_str(&format!(
+        "    {{ \"activity\": \"{}2\", \"confidence\": {} }}\n",
+        text.candidate_placeholder, text.confidence_placeholder
+    ));
     prompt.push_str("  ]\n");
     prompt.push_str("}\n");
     
     prompt
 }
 
+/// GPTのレスポンスのJSON形式
+#[derive(Debug, Deserialize)]
+struct GptResponse {
+    activity: String,
+    confidence: f64,
+    #[serde(default)]
+    alternatives: Vec<GptAlternative>,
+}
+
+/// GPTのレスポンス中の活動候補（欠落したフィールドがあっても全体のパースは失敗させない）
+#[derive(Debug, Deserialize)]
+struct GptAlternative {
+    activity: Option<String>,
+    confidence: Option<f64>,
+}
+
+/// テキスト中の最初の釣り合いの取れた `{...}` を取り出す
+///
+/// モデルがJSONを ```json フェンスで囲んだり、前後に説明文を付けた場合に対応する
+fn extract_json_object(text: &str) -> Option<&str> {
+    let start = text.find('{')?;
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+    
+    for (i, c) in text[start..].char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        
+        match c {
+            '"' => in_string = true,
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(&text[start..start + i + 1]);
+                }
+            }
+            _ => {}
+        }
+    }
+    
+    None
+}
+
 /// GPTのレスポンスをパースして分析結果を抽出
 fn parse_gpt_response(
     response: &str,
@@ -314,49 +1099,37 @@ fn parse_gpt_response(
     // 最新のデータポイントのタイムスタンプとウィンドウタイトルを使用
     let timestamp = data.first().unwrap().timestamp;
     let window_title = data.first().map(|d| d.window.title.clone());
+    let window_class = data.first().and_then(|d| d.window.class.clone());
     
-    // 現在時刻に重なるカレンダーイベントを抽出
-    let mut calendar_event = None;
-    for item in data {
-        for event in &item.calendar_events {
-            if event.start_time <= timestamp && event.end_time >= timestamp {
-                calendar_event = Some(event.clone());
-                break;
-            }
-        }
-        if calendar_event.is_some() {
-            break;
-        }
-    }
-    
-    // JSONレスポンスをパース
-    let parsed: serde_json::Value = serde_json::from_str(response)
+    // JSONレスポンスをパース（コードフェンスや前後の説明文は取り除く）
+    let json = extract_json_object(response)
+        .ok_or_else(|| anyhow::anyhow!("No JSON object in GPT response"))?;
+    let parsed: GptResponse = serde_json::from_str(json)
         .context("Failed to parse GPT response as JSON")?;
     
-    // 活動内容を抽出
-    let activity = parsed["activity"].as_str()
-        .ok_or_else(|| anyhow::anyhow!("No activity in response"))?
-        .to_string();
+    let activity = parsed.activity.trim().to_string();
+    if activity.is_empty() {
+        return Err(anyhow::anyhow!("No activity in response"));
+    }
     
-    // 確度を抽出
-    let confidence = parsed["confidence"].as_f64()
-        .ok_or_else(|| anyhow::anyhow!("No confidence in response"))?;
+    // 確度は0.0-1.0に収める
+    let confidence = parsed.confidence.clamp(0.0, 1.0);
     
-    // 候補リストを抽出
-    let mut alternatives = Vec::new();
-    if let Some(alts) = parsed["alternatives"].as_array() {
-        for alt in alts {
-            if let (Some(alt_activity), Some(alt_confidence)) = (
-                alt["activity"].as_str(),
-                alt["confidence"].as_f64(),
-            ) {
-                alternatives.push(ActivityCandidate {
-                    activity: alt_activity.to_string(),
-                    confidence: alt_confidence,
-                });
-            }
-        }
-    }
+    // 現在時刻に重なるカレンダーイベントのうち、推定した活動に最も関連が深いものを選ぶ
+    let context = [Some(activity.as_str()), window_title.as_deref()];
+    let calendar_event = select_calendar_event(data, timestamp, &context.into_iter().flatten().collect::<Vec<_>>());
+    
+    // 候補リストを抽出（不完全な候補は無視）
+    let alternatives = parsed.alternatives
+        .into_iter()
+        .filter_map(|alt| match (alt.activity, alt.confidence) {
+            (Some(activity), Some(confidence)) => Some(ActivityCandidate {
+                activity,
+                confidence: confidence.clamp(0.0, 1.0),
+            }),
+            _ => None,
+        })
+        .collect();
     
     Ok(AnalysisResult {
         activity,
@@ -364,7 +1137,517 @@ fn parse_gpt_response(
         timestamp,
         alternatives,
         window_title,
+        window_class,
         calendar_event,
+        analyzed_locally: false,
+        project: None,
         openai_api_key: None,
     })
-} 
\ No newline at end of file
+} 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::default_full_confidence_samples;
+    use crate::data_collector::WindowInfo;
+
+    fn window_data(title: &str) -> CollectedData {
+        let timestamp = chrono::Utc::now();
+        CollectedData {
+            timestamp,
+            window: WindowInfo {
+                id: "0x1".to_string(),
+                title: title.to_string(),
+                class: Some("firefox".to_string()),
+                pid: None,
+                timestamp,
+                geometry: None,
+                terminal: None,
+            },
+            calendar_events: Vec::new(),
+            is_idle: false,
+            visible_windows: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn overlapping_calendar_event_matching_the_activity_is_selected() {
+        let mut data = window_data("Sprint planning - Jira - Mozilla Firefox");
+        let event = |id: &str, title: &str, minutes: i64, response_status: Option<&str>| data_collector::CalendarEvent {
+            id: id.to_string(),
+            title: title.to_string(),
+            start_time: data.timestamp - chrono::Duration::minutes(5),
+            end_time: data.timestamp + chrono::Duration::minutes(minutes),
+            calendar_id: "primary".to_string(),
+            description: None,
+            location: None,
+            organizer_email: None,
+            is_video_call: false,
+            response_status: response_status.map(str::to_string),
+        };
+        let all_hands = event("all-hands", "All hands", 25, Some("accepted"));
+        let planning = event("planning", "Sprint planning", 55, Some("tentative"));
+        data.calendar_events = vec![all_hands, planning];
+
+        let selected = select_calendar_event(std::slice::from_ref(&data), data.timestamp, &["sprint planning - jira"]);
+        assert_eq!(selected.unwrap().id, "planning");
+
+        // タイトルが一致しない場合は短い予定、同じ長さなら承諾済みの予定を選ぶ
+        let selected = select_calendar_event(std::slice::from_ref(&data), data.timestamp, &["terminal"]);
+        assert_eq!(selected.unwrap().id, "all-hands");
+        data.calendar_events = vec![
+            event("tentative", "Design review", 25, Some("tentative")),
+            event("accepted", "1on1", 25, Some("accepted")),
+        ];
+        let selected = select_calendar_event(std::slice::from_ref(&data), data.timestamp, &["terminal"]);
+        assert_eq!(selected.unwrap().id, "accepted");
+    }
+
+    #[tokio::test]
+    async fn calendar_first_records_meetings_covering_the_whole_block() {
+        let mut config = crate::config::create_default_config();
+        config.general.analyzer = AnalyzerKind::Local;
+        config.general.calendar_first = true;
+        let analyzer = analyzer_from_config(&config).unwrap();
+
+        let mut data = window_data("Inbox - Mozilla Firefox");
+        let (block_start, block_end) = config.general.block_bounds(data.timestamp);
+        let meeting = |title: &str, start, end, response_status: Option<&str>| data_collector::CalendarEvent {
+            id: title.to_string(),
+            title: title.to_string(),
+            start_time: start,
+            end_time: end,
+            calendar_id: "primary".to_string(),
+            description: None,
+            location: None,
+            organizer_email: None,
+            is_video_call: true,
+            response_status: response_status.map(str::to_string),
+        };
+
+        // ブロック全体を占める予定は、ウィンドウによらず確度1.0で予定名を記録する
+        let hour = chrono::Duration::hours(1);
+        data.calendar_events = vec![
+            meeting("Quarterly review", block_start - hour, block_end + hour, Some("accepted")),
+            meeting("Vendor call", block_start, block_end, Some("declined")),
+        ];
+        let result = analyzer.analyze(std::slice::from_ref(&data)).await.unwrap();
+        assert_eq!(result.activity, "Quarterly review");
+        assert_eq!(result.confidence, 1.0);
+        assert_eq!(result.calendar_event.unwrap().id, "Quarterly review");
+    }
+
+    #[tokio::test]
+    async fn calendar_first_analyzes_windows_outside_meetings() {
+        let mut config = crate::config::create_default_config();
+        config.general.analyzer = AnalyzerKind::Local;
+        config.general.calendar_first = true;
+        let analyzer = analyzer_from_config(&config).unwrap();
+
+        let mut data = window_data("main.rs - Visual Studio Code");
+        let (block_start, block_end) = config.general.block_bounds(data.timestamp);
+        let event = data_collector::CalendarEvent {
+            id: "standup".to_string(),
+            title: "Standup".to_string(),
+            start_time: block_start,
+            end_time: block_end - chrono::Duration::minutes(1),
+            calendar_id: "primary".to_string(),
+            description: None,
+            location: None,
+            organizer_email: None,
+            is_video_call: true,
+            response_status: Some("accepted".to_string()),
+        };
+        data.calendar_events = vec![event];
+
+        // ブロックの一部にしか予定がない場合はウィンドウを分析する
+        let result = analyzer.analyze(std::slice::from_ref(&data)).await.unwrap();
+        let local = analyze_locally(std::slice::from_ref(&data), config.general.language, FallbackActivity::Other, default_full_confidence_samples()).unwrap();
+        assert_eq!(result.activity, local.activity);
+        assert_ne!(result.activity, "Standup");
+    }
+
+    #[test]
+    fn analysis_prompt_contains_collected_window_titles() {
+        let data = vec![
+            window_data("Pull Request #42 - GitHub"),
+            window_data("analysis.rs - toggl_linux_rs - Visual Studio Code"),
+        ];
+
+        let prompt = build_analysis_prompt(&data, Language::Ja, 0, PrivacyMode::Full);
+
+        assert!(prompt.contains("Pull Request #42 - GitHub"));
+        assert!(prompt.contains("analysis.rs - toggl_linux_rs - Visual Studio Code"));
+    }
+
+    #[test]
+    fn class_only_prompt_redacts_window_titles() {
+        let mut data = vec![
+            window_data("Salary review 2024.xlsx - LibreOffice Calc"),
+            window_data("Re: Offer letter - Inbox - Mozilla Thunderbird"),
+        ];
+        data[1].window.class = Some("thunderbird".to_string());
+        data[0].visible_windows = vec![WindowInfo {
+            title: "Diagnosis results.pdf - Document Viewer".to_string(),
+            class: Some("evince".to_string()),
+            ..data[0].window.clone()
+        }];
+        data[0].calendar_events = vec![data_collector::CalendarEvent {
+            id: "planning".to_string(),
+            title: "Sprint planning".to_string(),
+            start_time: data[0].timestamp - chrono::Duration::minutes(5),
+            end_time: data[0].timestamp + chrono::Duration::minutes(25),
+            calendar_id: "primary".to_string(),
+            description: None,
+            location: None,
+            organizer_email: None,
+            is_video_call: false,
+            response_status: None,
+        }];
+
+        let prompt = build_analysis_prompt(&data, Language::Ja, 0, PrivacyMode::ClassOnly);
+        for title in ["Salary review", "Offer letter", "Diagnosis results"] {
+            assert!(!prompt.contains(title), "{} leaked into the prompt:\n{}", title, prompt);
+        }
+        for expected in ["firefox", "thunderbird", "evince", "Sprint planning", "（非公開）"] {
+            assert!(prompt.contains(expected), "{} missing from the prompt:\n{}", expected, prompt);
+        }
+    }
+
+    #[tokio::test]
+    async fn rejected_openai_key_is_detected_from_the_http_status() {
+        let api_base = crate::event::tests::spawn_toggl_server(|_, path, _| {
+            // エラーの本文の形式によらず、401なら認証エラーとして扱う
+            match path {
+                "/unauthorized/chat/completions" => ("401 Unauthorized", "Unauthorized".to_string()),
+                _ => ("500 Internal Server Error", r#"{"error":{"message":"oops","type":"server_error","param":null,"code":null}}"#.to_string()),
+            }
+        }).await;
+        let mut openai = crate::config::create_default_config().openai.unwrap();
+
+        openai.api_base = format!("{}/unauthorized", api_base);
+        let error = request_gpt_analysis(&openai, "prompt".to_string(), Language::Ja).await.unwrap_err();
+        assert!(is_openai_unauthorized(&error), "{:#}", error);
+
+        openai.api_base = format!("{}/broken", api_base);
+        let error = request_gpt_analysis(&openai, "prompt".to_string(), Language::Ja).await.unwrap_err();
+        assert!(!is_openai_unauthorized(&error), "{:#}", error);
+    }
+
+    #[tokio::test]
+    async fn local_only_never_calls_openai() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counted = requests.clone();
+        let api_base = crate::event::tests::spawn_toggl_server(move |_, _, _| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            ("500 Internal Server Error", String::new())
+        }).await;
+        let mut config = crate::config::create_default_config();
+        config.openai.as_mut().unwrap().api_base = api_base;
+        config.openai.as_mut().unwrap().max_retries = 0;
+        config.general.privacy = PrivacyMode::LocalOnly;
+        let data = vec![window_data("analysis.rs - toggl_linux_rs - Visual Studio Code")];
+
+        let analyzer = analyzer_from_config(&config).unwrap();
+        assert_eq!(analyzer.name(), LocalAnalyzer::new(Language::Ja, FallbackActivity::Other, default_full_confidence_samples()).name());
+        assert!(analyzer.analyze(&data).await.unwrap().analyzed_locally);
+        assert!(analyze_with_fallback(&config, &data).await.unwrap().analyzed_locally);
+        assert!(explain_analysis(&config, &data).await.unwrap().raw_response.is_none());
+        drop(analyzer);
+
+        config.general.analyzer = AnalyzerKind::Openai;
+        let error = analyzer_from_config(&config).err().unwrap();
+        assert!(error.to_string().contains("local_only"), "{}", error);
+
+        assert_eq!(requests.load(Ordering::SeqCst), 0);
+    }
+
+    /// OpenAIが一時的に失敗するサーバー（最初の `failures` 回は500を返し、その後は分析結果を返す）
+    async fn spawn_flaky_openai(failures: usize) -> (String, std::sync::Arc<std::sync::atomic::AtomicUsize>) {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counted = requests.clone();
+        let api_base = crate::event::tests::spawn_toggl_server(move |_, _, _| {
+            if counted.fetch_add(1, Ordering::SeqCst) < failures {
+                return ("500 Internal Server Error", String::new());
+            }
+            let content = serde_json::json!({ "activity": "Coding", "confidence": 0.8 }).to_string();
+            let body = serde_json::json!({
+                "id": "chatcmpl-1",
+                "object": "chat.completion",
+                "created": 0,
+                "model": "gpt-4o-mini",
+                "choices": [{
+                    "index": 0,
+                    "message": { "role": "assistant", "content": content },
+                    "finish_reason": "stop",
+                    "logprobs": null
+                }]
+            });
+            ("200 OK", body.to_string())
+        }).await;
+        (api_base, requests)
+    }
+
+    #[tokio::test]
+    async fn openai_failure_is_retried_until_it_succeeds() {
+        use std::sync::atomic::Ordering;
+
+        let (api_base, requests) = spawn_flaky_openai(1).await;
+        let mut config = crate::config::create_default_config();
+        config.openai.as_mut().unwrap().api_base = api_base;
+        config.openai.as_mut().unwrap().max_retries = 1;
+        let data = vec![window_data("analysis.rs - toggl_linux_rs - Visual Studio Code")];
+
+        let result = analyze_with_fallback(&config, &data).await.unwrap();
+        assert_eq!(result.activity, "Coding");
+        assert!(!result.analyzed_locally);
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn openai_failure_falls_back_to_local_analysis_after_the_retries() {
+        use std::sync::atomic::Ordering;
+
+        let (api_base, requests) = spawn_flaky_openai(usize::MAX).await;
+        let mut config = crate::config::create_default_config();
+        config.openai.as_mut().unwrap().api_base = api_base;
+        config.openai.as_mut().unwrap().max_retries = 1;
+        let data = vec![window_data("analysis.rs - toggl_linux_rs - Visual Studio Code")];
+
+        let result = analyze_with_fallback(&config, &data).await.unwrap();
+        assert!(result.analyzed_locally);
+        assert!(!result.activity.is_empty());
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn gpt_and_local_analyzers_share_the_analyzer_interface() {
+        let data = vec![window_data("analysis.rs - toggl_linux_rs - Visual Studio Code")];
+        // OpenAI設定がない場合、GptAnalyzerはネットワークに触れずにローカル分析へフォールバックする
+        let mut config = crate::config::create_default_config();
+        config.openai = None;
+
+        let analyzers: Vec<Box<dyn Analyzer + '_>> = vec![
+            Box::new(GptAnalyzer::new(&config)),
+            Box::new(LocalAnalyzer::new(Language::Ja, FallbackActivity::Other, default_full_confidence_samples())),
+        ];
+        for analyzer in &analyzers {
+            let result = analyzer.analyze(&data).await.unwrap();
+            assert!(result.analyzed_locally, "{} did not analyze locally", analyzer.name());
+            assert!(!result.activity.is_empty());
+        }
+
+        assert_eq!(analyzer_from_config(&config).unwrap().name(), LocalAnalyzer::new(Language::Ja, FallbackActivity::Other, default_full_confidence_samples()).name());
+    }
+
+    fn analysis(activity: &str, confidence: f64) -> AnalysisResult {
+        AnalysisResult {
+            activity: activity.to_string(),
+            confidence,
+            ..analyze_locally(&[window_data("analysis.rs - Visual Studio Code")], Language::Ja, FallbackActivity::Other, default_full_confidence_samples()).unwrap()
+        }
+    }
+
+    #[test]
+    fn same_activity_after_confident_block_is_boosted() {
+        let previous = BlockAnalysis { activity: "Coding".to_string(), confidence: 0.9 };
+        let mut result = analysis("Coding", 0.4);
+
+        smooth_confidence(&mut result, Some(&previous), 0.5, 0.5);
+
+        assert!((result.confidence - 0.65).abs() < 1e-9, "confidence: {}", result.confidence);
+    }
+
+    #[test]
+    fn different_or_unconfident_previous_block_does_not_boost() {
+        let mut result = analysis("Coding", 0.4);
+        let other = BlockAnalysis { activity: "Meeting".to_string(), confidence: 0.9 };
+        smooth_confidence(&mut result, Some(&other), 0.5, 0.5);
+        assert_eq!(result.confidence, 0.4);
+
+        let unconfident = BlockAnalysis { activity: "Coding".to_string(), confidence: 0.45 };
+        smooth_confidence(&mut result, Some(&unconfident), 0.5, 0.5);
+        assert_eq!(result.confidence, 0.4);
+
+        smooth_confidence(&mut result, None, 0.5, 0.5);
+        assert_eq!(result.confidence, 0.4);
+    }
+
+    #[test]
+    fn sparse_blocks_are_not_analyzed_with_high_confidence() {
+        let full = default_full_confidence_samples();
+
+        // 1サンプルだけなら、タイトルが1種類でも自動登録の閾値に届かない
+        let single = analyze_locally(&[window_data("main.rs - Visual Studio Code")], Language::Ja, FallbackActivity::Other, full).unwrap();
+        assert!(single.confidence < 0.5, "{}", single.confidence);
+        assert!((single.confidence - 0.2).abs() < 1e-9, "{}", single.confidence);
+
+        let two = vec![window_data("main.rs - Visual Studio Code"); 2];
+        assert!(analyze_locally(&two, Language::Ja, FallbackActivity::Other, full).unwrap().confidence < 0.5);
+
+        // 14サンプルが同じ作業なら確度は下げない
+        let consistent = vec![window_data("main.rs - Visual Studio Code"); 14];
+        let result = analyze_locally(&consistent, Language::Ja, FallbackActivity::Other, full).unwrap();
+        assert!((result.confidence - 1.0).abs() < 1e-9, "{}", result.confidence);
+
+        // 0または1で無効
+        assert_eq!(analyze_locally(&[window_data("main.rs - Visual Studio Code")], Language::Ja, FallbackActivity::Other, 0).unwrap().confidence, 1.0);
+    }
+
+    #[test]
+    fn unclassified_windows_use_the_configured_fallback_activity() {
+        let mut data = vec![window_data("Quarterly Plan.kra - Krita"), window_data("Quarterly Plan.kra - Krita")];
+        for item in &mut data {
+            item.window.class = Some("krita".to_string());
+        }
+        let activity = |fallback, language| analyze_locally(&data, language, fallback, default_full_confidence_samples()).unwrap().activity;
+
+        assert_eq!(activity(FallbackActivity::Other, Language::Ja), "その他の活動");
+        assert_eq!(activity(FallbackActivity::Other, Language::En), "Other activity");
+        assert_eq!(activity(FallbackActivity::WindowTitle, Language::Ja), "Quarterly Plan.kra - Krita");
+        assert_eq!(activity(FallbackActivity::WindowClass, Language::Ja), "krita");
+
+        // キーワードで分類できる場合はカテゴリ名のまま
+        let coding = vec![window_data("main.rs - Visual Studio Code")];
+        assert_eq!(analyze_locally(&coding, Language::Ja, FallbackActivity::WindowTitle, default_full_confidence_samples()).unwrap().activity, "プログラミング");
+
+        // クラスがない場合はカテゴリ名に戻す
+        for item in &mut data {
+            item.window.class = None;
+        }
+        assert_eq!(analyze_locally(&data, Language::En, FallbackActivity::WindowClass, default_full_confidence_samples()).unwrap().activity, "Other activity");
+    }
+
+    #[test]
+    fn english_language_uses_english_category_labels() {
+        let data = vec![window_data("Hacker News - Mozilla Firefox")];
+
+        assert_eq!(analyze_locally(&data, Language::Ja, FallbackActivity::Other, default_full_confidence_samples()).unwrap().activity, "ウェブブラウジング");
+        assert_eq!(analyze_locally(&data, Language::En, FallbackActivity::Other, default_full_confidence_samples()).unwrap().activity, "Web browsing");
+        assert!(build_analysis_prompt(&data, Language::En, 0, PrivacyMode::Full).starts_with("From the following Linux desktop"));
+    }
+
+    #[test]
+    fn prompt_and_output_language_follow_config() {
+        let data = vec![window_data("analysis.rs - toggl_linux_rs - Visual Studio Code")];
+        let mut config = crate::config::create_default_config();
+
+        let prompt = build_analysis_prompt(&data, config.general.language, config.general.max_prompt_samples, config.general.privacy);
+        assert!(prompt.contains("日本語で記述し"));
+        assert!(prompt_text(config.general.language).system.contains("日本語で"));
+
+        config.general.language = Language::En;
+        let prompt = build_analysis_prompt(&data, config.general.language, config.general.max_prompt_samples, config.general.privacy);
+        assert!(prompt.contains("write the activity and candidates in English"));
+        assert!(!prompt.contains("日本語"));
+        assert!(prompt_text(config.general.language).system.contains("Answer in English"));
+    }
+
+    #[test]
+    fn large_sample_set_is_capped_in_the_prompt() {
+        // 新しい順: 直近20件はそれぞれ別のウィンドウ、それより前の40件は同じエディタ
+        let long_title = format!("{} - Mozilla Firefox", "very long title ".repeat(40));
+        let mut data: Vec<CollectedData> = (0..20)
+            .map(|i| window_data(&if i == 0 { long_title.clone() } else { format!("Tab {} - Mozilla Firefox", i) }))
+            .collect();
+        data.extend((0..40).map(|_| window_data("main.rs - toggl_linux_rs - Visual Studio Code")));
+
+        let prompt = build_analysis_prompt(&data, Language::Ja, 10, PrivacyMode::Full);
+        let rows: Vec<&str> = prompt.lines().filter(|line| line.ends_with("| firefox")).collect();
+
+        assert_eq!(rows.len(), 10);
+        assert!(rows.iter().any(|row| row.contains("main.rs - toggl_linux_rs - Visual Studio Code")));
+        assert!(rows[0].contains(&format!("| {} |", truncate_string_safely(&long_title, MAX_PROMPT_TITLE_LEN))));
+        assert!(!rows[0].contains(&long_title));
+        assert_eq!(build_analysis_prompt(&data, Language::Ja, 0, PrivacyMode::Full).lines().filter(|line| line.ends_with("| firefox")).count(), 60);
+    }
+
+    #[test]
+    fn truncated_prompt_titles_are_reported_even_when_the_length_is_unchanged() {
+        assert_eq!(truncate_prompt_title("main.rs"), ("main.rs".to_string(), false));
+        assert!(!truncate_prompt_title(&"a".repeat(MAX_PROMPT_TITLE_LEN)).1);
+
+        // 末尾の「...」で元と同じ長さになっても切り詰めたものとして数える
+        let title = "a".repeat(MAX_PROMPT_TITLE_LEN + 3);
+        let (truncated, was_truncated) = truncate_prompt_title(&title);
+        assert_eq!(truncated.len(), title.len());
+        assert_ne!(truncated, title);
+        assert!(was_truncated);
+    }
+
+    #[test]
+    fn short_window_switch_is_absorbed_into_surrounding_activity() {
+        let start = chrono::Utc::now() - chrono::Duration::minutes(10);
+        // 10秒間隔で10分間のエディタ作業の途中に、20秒だけチャットを確認した
+        let mut data: Vec<CollectedData> = (0..60)
+            .map(|i| {
+                let title = if (30..32).contains(&i) { "Slack | general" } else { "main.rs - Visual Studio Code" };
+                let mut sample = window_data(title);
+                sample.timestamp = start + chrono::Duration::seconds(i * 10);
+                sample.window.timestamp = sample.timestamp;
+                sample
+            })
+            .rev()
+            .collect();
+
+        let mut config = crate::config::create_default_config();
+        config.general.activity_debounce_secs = 0;
+        let mut undebounced = data.clone();
+        compact_samples(&mut undebounced, &config);
+        assert_eq!(undebounced.iter().filter(|d| d.window.title.starts_with("Slack")).count(), 2);
+
+        config.general.activity_debounce_secs = 30;
+        compact_samples(&mut data, &config);
+        assert!(data.iter().all(|d| d.window.title == "main.rs - Visual Studio Code"));
+        assert!(data.iter().all(|d| d.window.timestamp == d.timestamp));
+
+        // 20秒以下の閾値では吸収しない
+        let mut kept = undebounced.clone();
+        debounce_window_switches(&mut kept, 20);
+        assert_eq!(kept.iter().filter(|d| d.window.title.starts_with("Slack")).count(), 2);
+    }
+
+    #[test]
+    fn fenced_gpt_response_is_parsed() {
+        let data = vec![window_data("main.rs - Visual Studio Code")];
+        let response = "```json\n{\"activity\": \"Coding\", \"confidence\": 0.8, \"alternatives\": [{\"activity\": \"Review\", \"confidence\": 0.1}]}\n```";
+
+        let result = parse_gpt_response(response, &data).unwrap();
+        assert_eq!(result.activity, "Coding");
+        assert_eq!(result.confidence, 0.8);
+        assert_eq!(result.alternatives.len(), 1);
+        assert_eq!(result.alternatives[0].activity, "Review");
+    }
+
+    #[test]
+    fn gpt_response_with_surrounding_prose_is_parsed() {
+        let data = vec![window_data("main.rs - Visual Studio Code")];
+        let response = "Here is the result:\n{\"activity\": \"Writing {docs}\", \"confidence\": 0.7}\nLet me know if {anything} else is needed.";
+
+        let result = parse_gpt_response(response, &data).unwrap();
+        assert_eq!(result.activity, "Writing {docs}");
+        assert!(result.alternatives.is_empty());
+
+        assert!(parse_gpt_response("I could not determine the activity.", &data).is_err());
+    }
+
+    #[test]
+    fn out_of_range_gpt_confidence_is_clamped() {
+        let data = vec![window_data("main.rs - Visual Studio Code")];
+        let response = r#"{"activity": "Coding", "confidence": 1.7, "alternatives": [
+            {"activity": "Review", "confidence": -0.2},
+            {"activity": "Meeting"}
+        ]}"#;
+
+        let result = parse_gpt_response(response, &data).unwrap();
+        assert_eq!(result.confidence, 1.0);
+        // 確度のない候補は捨てる
+        assert_eq!(result.alternatives.len(), 1);
+        assert_eq!(result.alternatives[0].confidence, 0.0);
+    }
+}
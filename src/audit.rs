@@ -0,0 +1,124 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::fs::OpenOptions;
+use std::io::Write;
+
+use crate::analysis::AnalysisResult;
+use crate::config::AppConfig;
+
+/// 登録をスキップした理由
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    /// アイドル等で分析対象のデータがない
+    NoData,
+    
+    /// ブロック全体がアイドルだった
+    Idle,
+    
+    /// ブロック内の活動時間が下限に満たない
+    InsufficientActivity,
+    
+    /// 信頼度がしきい値未満
+    LowConfidence,
+    
+    /// プライベートブラウジング中
+    PrivateBrowsing,
+
+    /// ブロック全体が他のアプリで作成されたエントリで記録済み（`toggl.avoid_overlaps`）
+    AlreadyTracked,
+}
+
+/// 1回の分析サイクルでの登録判断の結果
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "decision", rename_all = "snake_case")]
+pub enum RegistrationOutcome {
+    /// 新しいエントリを作成した
+    Created {
+        entry_id: u64,
+        project_id: Option<u64>,
+        project_name: Option<String>,
+    },
+    
+    /// 直前のエントリに統合した
+    Merged {
+        entry_id: u64,
+        project_id: Option<u64>,
+        project_name: Option<String>,
+    },
+    
+    /// 実行中のエントリを継続した（ストリームモード）
+    Continued {
+        entry_id: u64,
+        project_id: Option<u64>,
+        project_name: Option<String>,
+    },
+    
+    /// 同じ時間ブロックのエントリが登録済みだったため作成しなかった（クラッシュ後の再登録など）
+    Duplicate {
+        entry_id: u64,
+        project_id: Option<u64>,
+        project_name: Option<String>,
+    },
+    
+    /// 登録をスキップした
+    Skipped { reason: SkipReason },
+    
+    /// 登録処理がエラーで失敗した
+    Failed { error: String },
+}
+
+impl RegistrationOutcome {
+    /// Togglにエントリが記録された（作成・統合・継続・登録済み）かどうか
+    pub fn is_registered(&self) -> bool {
+        matches!(
+            self,
+            RegistrationOutcome::Created { .. }
+                | RegistrationOutcome::Merged { .. }
+                | RegistrationOutcome::Continued { .. }
+                | RegistrationOutcome::Duplicate { .. }
+        )
+    }
+}
+
+/// 監査ログの1行分のレコード
+#[derive(Debug, Serialize)]
+struct AuditRecord<'a> {
+    timestamp: DateTime<Utc>,
+    activity: Option<&'a str>,
+    confidence: Option<f64>,
+    #[serde(flatten)]
+    outcome: &'a RegistrationOutcome,
+}
+
+/// 登録判断をJSON Lines形式の監査ログに追記する（監査ログ未設定の場合は何もしない）
+pub fn write_audit_record(
+    config: &AppConfig,
+    analysis: Option<&AnalysisResult>,
+    outcome: &RegistrationOutcome,
+) -> Result<()> {
+    let Some(path) = &config.general.audit_log_path else {
+        return Ok(());
+    };
+    
+    let record = AuditRecord {
+        timestamp: Utc::now(),
+        activity: analysis.map(|a| a.activity.as_str()),
+        confidence: analysis.map(|a| a.confidence),
+        outcome,
+    };
+    
+    let line = serde_json::to_string(&record)
+        .context("Failed to serialize audit record")?;
+    
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open audit log: {}", path))?;
+    
+    writeln!(file, "{}", line).context("Failed to write audit record")?;
+    
+    Ok(())
+}
@@ -0,0 +1,386 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local, NaiveDate, Utc};
+use log::{error, info, warn};
+use std::time::Duration;
+
+use crate::analysis::{self, AnalysisResult, Analyzer};
+use crate::audit::{self, RegistrationOutcome, SkipReason};
+use crate::config::AppConfig;
+use crate::data_collector;
+use crate::event;
+
+/// Toggl APIへの登録の間隔（レート制限に掛からないようにする）
+const BACKFILL_REQUEST_INTERVAL: Duration = Duration::from_secs(1);
+
+/// バックフィルの結果
+#[derive(Debug, Default)]
+pub struct BackfillSummary {
+    /// 新しく登録したブロック数
+    pub registered: usize,
+    
+    /// 登録済みのため飛ばしたブロック数
+    pub already_registered: usize,
+    
+    /// 活動時間や信頼度が足りず登録しなかったブロック数
+    pub skipped: usize,
+    
+    /// 登録に失敗したブロック数
+    pub failed: usize,
+}
+
+/// 保存済みのデータから `from` 〜 `to`（`general.timezone` での日付、両端を含む）の時間ブロックをTogglに登録する
+///
+/// 登録済みのブロックは `data_analysis` テーブルで判定して飛ばすため、再実行しても重複しない
+pub async fn run_backfill(config: &AppConfig, from: NaiveDate, to: NaiveDate) -> Result<BackfillSummary> {
+    backfill_with_client(config, &event::TogglClient::from_config(config), from, to).await
+}
+
+async fn backfill_with_client(
+    config: &AppConfig,
+    toggl_client: &event::TogglClient,
+    from: NaiveDate,
+    to: NaiveDate,
+) -> Result<BackfillSummary> {
+    let blocks = past_blocks(config, from, to)?;
+    
+    let analyzer = analysis::analyzer_from_config(config)?;
+    info!("Using {} for analysis", analyzer.name());
+    
+    let mut summary = BackfillSummary::default();
+    for (block_start, block_end) in blocks {
+        backfill_block(config, toggl_client, analyzer.as_ref(), block_start, block_end, &mut summary).await?;
+    }
+    
+    Ok(summary)
+}
+
+/// `from` 〜 `to`（`general.timezone` での日付、両端を含む）の時間ブロックの開始・終了時刻
+///
+/// 現在進行中のブロックは通常の分析に任せるため含めない
+fn past_blocks(config: &AppConfig, from: NaiveDate, to: NaiveDate) -> Result<Vec<(DateTime<Utc>, DateTime<Utc>)>> {
+    if from > to {
+        return Err(anyhow::anyhow!("--from ({}) must not be after --to ({})", from, to));
+    }
+    
+    let block_minutes = config.general.block_minutes();
+    let block = chrono::Duration::minutes(block_minutes as i64);
+    
+    let (current_block_start, _) = config.general.block_bounds(Utc::now());
+    let (_, end_of_to) = config.general.day_bounds(to);
+    let end = end_of_to.min(current_block_start);
+    let (start_of_from, _) = config.general.day_bounds(from);
+    let (mut block_start, _) = config.general.block_bounds(start_of_from);
+    
+    info!("Processing {} - {} in {}-minute blocks", block_start.to_rfc3339(), end.to_rfc3339(), block_minutes);
+    
+    let mut blocks = Vec::new();
+    while block_start < end {
+        blocks.push((block_start, block_start + block));
+        block_start += block;
+    }
+    Ok(blocks)
+}
+
+/// `--replay` で再分析した時間ブロック
+#[derive(Debug, Clone)]
+pub struct ReplayedBlock {
+    /// ブロックの開始時刻
+    pub block_start: DateTime<Utc>,
+    
+    /// 現在の分析エンジンでの活動内容と確度
+    pub analysis: AnalysisResult,
+    
+    /// 前回記録した活動内容（未分析の場合はNone）
+    pub previous_activity: Option<String>,
+}
+
+/// 保存済みのデータを現在の分析エンジンで時間ブロックごとに再分析する
+///
+/// `register` がfalseの場合はTogglにも `data_analysis` にも書き込まない。
+/// trueの場合は未登録のブロックをバックフィルと同じ手順で登録する
+pub async fn run_replay(
+    config: &AppConfig,
+    from: NaiveDate,
+    to: NaiveDate,
+    register: bool,
+) -> Result<(Vec<ReplayedBlock>, BackfillSummary)> {
+    let blocks = past_blocks(config, from, to)?;
+    let data_dir = config.general.data_dir_path();
+    
+    let analyzer = analysis::analyzer_from_config(config)?;
+    info!("Replaying with {}", analyzer.name());
+    
+    let toggl_client = event::TogglClient::from_config(config);
+    let mut replayed = Vec::new();
+    let mut summary = BackfillSummary::default();
+    for (block_start, block_end) in blocks {
+        let mut data = data_collector::get_data_between(data_dir, block_start, block_end)?;
+        analysis::compact_samples(&mut data, config);
+        if data.is_empty() {
+            continue;
+        }
+        
+        let previous_activity = data_collector::get_block_analysis(data_dir, block_start)?
+            .map(|previous| previous.activity);
+        let analysis = analyzer.analyze(&data).await?;
+        
+        if register {
+            if data_collector::is_block_registered(data_dir, block_start)? {
+                summary.already_registered += 1;
+            } else if analysis.confidence < config.general.confidence_threshold {
+                summary.skipped += 1;
+            } else {
+                register_block(config, &toggl_client, block_start, &analysis, &mut summary).await?;
+            }
+        }
+        
+        replayed.push(ReplayedBlock { block_start, analysis, previous_activity });
+    }
+    
+    Ok((replayed, summary))
+}
+
+/// 再分析の結果を1ブロック1行で表示する（前回と異なる活動内容は併記する）
+pub fn print_replay(blocks: &[ReplayedBlock]) {
+    for block in blocks {
+        let mut line = format!(
+            "{} | {} ({:.2})",
+            block.block_start.with_timezone(&Local).format("%Y-%m-%d %H:%M"),
+            block.analysis.activity,
+            block.analysis.confidence,
+        );
+        if let Some(previous) = block.previous_activity.as_ref().filter(|previous| **previous != block.analysis.activity) {
+            line.push_str(&format!(" <- 前回: {}", previous));
+        }
+        println!("{}", line);
+    }
+    
+    let changed = blocks.iter()
+        .filter(|block| block.previous_activity.as_ref().is_some_and(|previous| *previous != block.analysis.activity))
+        .count();
+    println!("再分析 {} ブロック（前回と異なる活動内容 {} 件）", blocks.len(), changed);
+}
+
+/// 1つの時間ブロックを分析して登録する
+async fn backfill_block(
+    config: &AppConfig,
+    toggl_client: &event::TogglClient,
+    analyzer: &dyn Analyzer,
+    block_start: DateTime<Utc>,
+    block_end: DateTime<Utc>,
+    summary: &mut BackfillSummary,
+) -> Result<()> {
+    let data_dir = config.general.data_dir_path();
+    let mut data = data_collector::get_data_between(data_dir, block_start, block_end)?;
+    analysis::compact_samples(&mut data, config);
+    if data.is_empty() {
+        return Ok(());
+    }
+    
+    if data_collector::is_block_registered(data_dir, block_start)? {
+        summary.already_registered += 1;
+        return Ok(());
+    }
+    
+    let active_secs = data.iter().filter(|d| !d.is_idle).count() as u64
+        * config.general.collect_interval_secs;
+    if active_secs == 0 || active_secs < config.general.min_active_secs {
+        summary.skipped += 1;
+        let reason = if active_secs == 0 { SkipReason::Idle } else { SkipReason::InsufficientActivity };
+        record_audit(config, None, &RegistrationOutcome::Skipped { reason });
+        return Ok(());
+    }
+    
+    let mut analysis = analyzer.analyze(&data).await?;
+    analysis::smooth_with_previous_block(config, block_start, &mut analysis);
+    
+    if analysis.confidence < config.general.confidence_threshold {
+        info!("Block {} skipped: low confidence ({:.2}) for '{}'",
+              block_start.to_rfc3339(), analysis.confidence, analysis.activity);
+        summary.skipped += 1;
+        record_audit(config, Some(&analysis), &RegistrationOutcome::Skipped { reason: SkipReason::LowConfidence });
+        return Ok(());
+    }
+    
+    register_block(config, toggl_client, block_start, &analysis, summary).await
+}
+
+/// 分析済みの時間ブロックをTogglに登録し、登録済みとして記録する
+async fn register_block(
+    config: &AppConfig,
+    toggl_client: &event::TogglClient,
+    block_start: DateTime<Utc>,
+    analysis: &AnalysisResult,
+    summary: &mut BackfillSummary,
+) -> Result<()> {
+    let data_dir = config.general.data_dir_path();
+    // 認証エラー後はトークンが直るまでTogglを呼ばない
+    let registration = event::TOGGL_AUTH_PAUSE.guard(
+        event::register_to_toggl(toggl_client, config, analysis),
+        event::is_unauthorized,
+    );
+    let outcome = match registration.await {
+        Ok(outcome) => outcome,
+        Err(e) => {
+            error!("Failed to backfill block {}: {}", block_start.to_rfc3339(), e);
+            RegistrationOutcome::Failed { error: e.to_string() }
+        }
+    };
+    
+    if outcome.is_registered() {
+        info!("Block {} registered as '{}'", block_start.to_rfc3339(), analysis.activity);
+        data_collector::record_block_registration(data_dir, block_start, &analysis.activity, analysis.confidence)
+            .context("Failed to record backfilled block")?;
+        summary.registered += 1;
+    } else if matches!(outcome, RegistrationOutcome::Failed { .. }) {
+        summary.failed += 1;
+    } else {
+        summary.skipped += 1;
+    }
+    record_audit(config, Some(analysis), &outcome);
+    
+    tokio::time::sleep(BACKFILL_REQUEST_INTERVAL).await;
+    Ok(())
+}
+
+/// 監査ログへの書き込み（失敗してもバックフィルは継続する）
+fn record_audit(config: &AppConfig, analysis: Option<&AnalysisResult>, outcome: &RegistrationOutcome) {
+    if let Err(e) = audit::write_audit_record(config, analysis, outcome) {
+        warn!("Failed to write audit log: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{create_default_config, AnalysisRule, AnalyzerKind};
+    use crate::data_collector::{CollectedData, DataCollector, WindowInfo};
+    use chrono::TimeZone;
+
+    fn rule(activity: &str) -> AnalysisRule {
+        AnalysisRule {
+            window_class: Some("(?i)code".to_string()),
+            window_title: None,
+            in_calendar_event: None,
+            after: None,
+            before: None,
+            activity: activity.to_string(),
+            confidence: 0.9,
+            project: None,
+        }
+    }
+
+    /// `dir` をデータディレクトリとし、ルールで分析する設定を作る
+    fn config_in(dir: &std::path::Path, activity: &str) -> AppConfig {
+        data_collector::init_storage(dir).unwrap();
+        let mut config = create_default_config();
+        config.general.data_dir = dir.to_string_lossy().into_owned();
+        config.general.timezone = Some("UTC".to_string());
+        config.general.analyzer = AnalyzerKind::Rules;
+        config.rules = vec![rule(activity)];
+        config
+    }
+
+    /// `block_start` のブロックにVisual Studio Codeのサンプルを保存する
+    fn save_samples(config: &AppConfig, block_start: DateTime<Utc>, minutes: &[i64]) {
+        let collector = DataCollector::new(config.clone()).unwrap();
+        for &minute in minutes {
+            let timestamp = block_start + chrono::Duration::minutes(minute);
+            collector.save_data(&CollectedData {
+                timestamp,
+                window: WindowInfo {
+                    id: "0x1".to_string(),
+                    title: "backfill.rs - toggl_linux_rs - Visual Studio Code".to_string(),
+                    class: Some("Code".to_string()),
+                    pid: None,
+                    timestamp,
+                    geometry: None,
+                    terminal: None,
+                },
+                calendar_events: Vec::new(),
+                is_idle: false,
+                visible_windows: Vec::new(),
+            }).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn backfilling_twice_creates_a_single_entry() {
+        let (api_base, entries) = crate::event::tests::spawn_stateful_toggl_server().await;
+        let client = event::TogglClient::new("token", 43)
+            .with_api_base(&api_base)
+            .with_rate_limiter(std::sync::Arc::new(event::RateLimiter::new(1000.0, 1000.0)));
+        let temp = tempfile::tempdir().unwrap();
+        let mut config = config_in(temp.path(), "toggl_linux_rs development");
+        config.openai = None;
+        config.toggl.workspace_id = 43;
+        let block_start = Utc.with_ymd_and_hms(2024, 1, 1, 9, 0, 0).unwrap();
+        save_samples(&config, block_start, &[1, 3, 5, 7, 10]);
+        let day = block_start.date_naive();
+
+        let summary = backfill_with_client(&config, &client, day, day).await.unwrap();
+        assert_eq!(summary.registered, 1, "{:?}", summary);
+        assert_eq!(entries.lock().unwrap().len(), 1);
+
+        // 再実行しても登録済みのブロックは登録しない
+        let summary = backfill_with_client(&config, &client, day, day).await.unwrap();
+        assert_eq!((summary.registered, summary.already_registered), (0, 1), "{:?}", summary);
+
+        // ローカルの登録記録が失われていても、Toggl側の重複防止キーで同じエントリを作らない
+        let other = tempfile::tempdir().unwrap();
+        let config = AppConfig { general: config_in(other.path(), "").general, ..config };
+        save_samples(&config, block_start, &[1, 3, 5, 7, 10]);
+        let summary = backfill_with_client(&config, &client, day, day).await.unwrap();
+        assert_eq!(summary.registered, 1, "{:?}", summary);
+
+        let entries = entries.lock().unwrap();
+        assert_eq!(entries.len(), 1, "{:?}", entries);
+        assert_eq!(entries[0]["description"], "toggl_linux_rs development");
+        assert_eq!(entries[0]["start"], "2024-01-01T09:00:00Z");
+    }
+
+    #[tokio::test]
+    async fn backfill_skips_blocks_below_the_configured_confidence_threshold() {
+        let (api_base, entries) = crate::event::tests::spawn_stateful_toggl_server().await;
+        let client = event::TogglClient::new("token", 67)
+            .with_api_base(&api_base)
+            .with_rate_limiter(std::sync::Arc::new(event::RateLimiter::new(1000.0, 1000.0)));
+        let temp = tempfile::tempdir().unwrap();
+        let mut config = config_in(temp.path(), "toggl_linux_rs development");
+        config.openai = None;
+        config.toggl.workspace_id = 67;
+        config.general.confidence_threshold = 0.95;
+        let block_start = Utc.with_ymd_and_hms(2024, 1, 1, 9, 0, 0).unwrap();
+        save_samples(&config, block_start, &[1, 3, 5, 7, 10]);
+        let day = block_start.date_naive();
+
+        let summary = backfill_with_client(&config, &client, day, day).await.unwrap();
+        assert_eq!((summary.registered, summary.skipped), (0, 1), "{:?}", summary);
+        assert!(entries.lock().unwrap().is_empty());
+
+        config.general.confidence_threshold = 0.3;
+        let summary = backfill_with_client(&config, &client, day, day).await.unwrap();
+        assert_eq!(summary.registered, 1, "{:?}", summary);
+    }
+
+    #[tokio::test]
+    async fn replay_uses_the_current_rules_without_recording() {
+        let temp = tempfile::tempdir().unwrap();
+        let mut config = config_in(temp.path(), "toggl_linux_rs development");
+        let block_start = Utc.with_ymd_and_hms(2024, 1, 1, 9, 0, 0).unwrap();
+        save_samples(&config, block_start, &[1, 5, 10]);
+        let day = block_start.date_naive();
+
+        let (blocks, _) = run_replay(&config, day, day, false).await.unwrap();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].block_start, block_start);
+        assert_eq!(blocks[0].analysis.activity, "toggl_linux_rs development");
+
+        // ルールを変更すると再分析の結果も変わる
+        config.rules = vec![rule("Open source maintenance")];
+        let (blocks, _) = run_replay(&config, day, day, false).await.unwrap();
+        assert_eq!(blocks[0].analysis.activity, "Open source maintenance");
+        assert_eq!(blocks[0].previous_activity, None);
+        assert!(data_collector::get_block_analysis(temp.path(), block_start).unwrap().is_none());
+    }
+}
@@ -1,8 +1,15 @@
 use anyhow::{Context, Result};
+use chrono::{DateTime, FixedOffset, Local, NaiveDate, Offset, Utc};
+use chrono_tz::Tz;
+use regex::Regex;
 use serde::Deserialize;
 use serde::Serialize;
+use std::collections::HashMap;
 use std::fs::read_to_string;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+
+use crate::analysis::Category;
+use crate::utils::{get_home_dir, local_day_bounds, mask_token};
 
 /// アプリケーション全体の設定
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -16,20 +23,40 @@ pub struct AppConfig {
     /// OpenAI API の設定（オプション）
     pub openai: Option<OpenAIConfig>,
     
-    /// Google Calendar API の設定（オプション）
-    pub google_calendar: Option<GoogleCalendarConfig>,
+    /// Google Calendar API の設定（オプション、複数アカウント可）
+    pub google_calendar: Option<GoogleCalendarSettings>,
+    
+    /// HTTP(S)プロキシの設定（オプション、未設定の場合は環境変数のプロキシを使う）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<ProxyConfig>,
+    
+    /// ルールベース分析のルール（`[[rule]]`、上から順に評価）
+    #[serde(default, rename = "rule", skip_serializing_if = "Vec::is_empty")]
+    pub rules: Vec<AnalysisRule>,
+    
+    /// 活動カテゴリごとの集計区分（`[buckets]`、指定しないカテゴリは既定の区分）
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub buckets: HashMap<Category, Bucket>,
+
+    /// プロジェクト名ごとの自動登録に必要な最低確度（`[project_confidence]`、全体の閾値より高い場合のみ使う）
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub project_confidence: HashMap<String, f64>,
 }
 
 /// 一般設定
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GeneralConfig {
-    /// データ保存ディレクトリ
+    /// データ保存ディレクトリ（`~/` はホーム、相対パスはXDGデータディレクトリ基準。空の場合はXDGデータディレクトリ）
     pub data_dir: String,
     
     /// 自動登録の信頼度しきい値（0.0-1.0）
     #[serde(default = "default_confidence_threshold")]
     pub confidence_threshold: f64,
     
+    /// 直前の時間ブロックと同じ活動の場合に確度を引き上げる平滑化係数（0.0で無効、1.0で直前の確度をそのまま使う）
+    #[serde(default = "default_confidence_smoothing")]
+    pub confidence_smoothing: f64,
+    
     /// データ収集時間間隔（秒）
     #[serde(default = "default_collect_interval")]
     pub collect_interval_secs: u64,
@@ -38,34 +65,397 @@ pub struct GeneralConfig {
     #[serde(default = "default_time_block_division")]
     pub time_block_division: u8,
     
+    /// データ収集タイミングに加えるランダムなずれの最大秒数（±、0で無効）
+    #[serde(default)]
+    pub collect_jitter_secs: u64,
+    
+    /// 分析タイミングを境界から遅らせるランダムな秒数の最大値（0で無効）
+    #[serde(default)]
+    pub analysis_jitter_secs: u64,
+    
     #[serde(default = "default_idle_threshold")]
     pub idle_threshold_secs: u64,
+
+    /// 時間ブロック内のアイドル時間の扱い（skip=登録しない / record=アイドル用のエントリとして登録する）
+    #[serde(default)]
+    pub idle_behavior: IdleBehavior,
+
+    /// `idle_behavior = "record"` で登録するエントリの説明
+    #[serde(default = "default_idle_description")]
+    pub idle_description: String,
+
+    /// `idle_behavior = "record"` で登録するエントリのプロジェクト名（省略時はプロジェクトなし）
+    #[serde(default)]
+    pub idle_project: Option<String>,
+    
+    /// 時間ブロック内でこの秒数以上活動していない場合は登録しない（0で無効）
+    #[serde(default)]
+    pub min_active_secs: u64,
+
+    /// この秒数未満だけ別のウィンドウに切り替えて戻った場合は、前後の活動の一部とみなす（0で無効）
+    #[serde(default)]
+    pub activity_debounce_secs: u64,
+    
+    /// 収集間隔がこの秒数以上空いた場合はサスペンドとみなす（収集間隔の2倍未満の値は切り上げ）
+    #[serde(default = "default_suspend_gap_threshold")]
+    pub suspend_gap_threshold_secs: u64,
+    
+    /// プライベートブラウジング中の活動を登録しない
+    #[serde(default = "default_true")]
+    pub skip_private_browsing: bool,
+    
+    /// プライベートブラウジングとみなすウィンドウタイトル・クラスの文字列（大文字小文字を区別しない）
+    #[serde(default = "default_private_browsing_markers")]
+    pub private_browsing_markers: Vec<String>,
+    
+    /// 記録しないウィンドウのクラス名・タイトルの文字列（大文字小文字を区別しない部分一致）
+    #[serde(default)]
+    pub ignore_windows: Vec<String>,
+    
+    /// フォーカスされたウィンドウに加えて、表示中のすべてのウィンドウを記録する（タイル型WM向け、X11のみ）
+    #[serde(default)]
+    pub collect_visible_windows: bool,
+
+    /// フォーカスされたウィンドウの位置・大きさ・全画面表示かどうかを記録する（X11のみ）
+    #[serde(default)]
+    pub collect_window_geometry: bool,
+
+    /// ターミナルのウィンドウでは、前面で実行中のコマンド名と作業ディレクトリ名を記録する（`/proc` を使用）
+    #[serde(default)]
+    pub collect_terminal_context: bool,
+    
+    /// OpenAIのプロンプトに含めるサンプル数の上限（0で無制限）
+    #[serde(default = "default_max_prompt_samples")]
+    pub max_prompt_samples: usize,
+
+    /// ローカル分析で確度を下げないサンプル数（これ未満のブロックはサンプル数に比例して確度を下げる、0または1で無効）
+    #[serde(default = "default_full_confidence_samples")]
+    pub full_confidence_samples: usize,
+    
+    /// カレンダーイベントが時間ブロックの過半を占める場合に予定名をエントリの説明に使う
+    #[serde(default)]
+    pub prefer_calendar_title: bool,
+
+    /// 辞退していないカレンダーの予定が時間ブロック全体を占める場合は、ウィンドウの分析によらず予定を記録する
+    #[serde(default)]
+    pub calendar_first: bool,
+    
+    /// Togglへの記録方式（block または stream）
+    #[serde(default)]
+    pub tracking_mode: TrackingMode,
+    
+    /// 使用する分析エンジン（auto / openai / local / rules）
+    #[serde(default)]
+    pub analyzer: AnalyzerKind,
+
+    /// OpenAIに送る情報の範囲（full / class_only / local_only）
+    #[serde(default)]
+    pub privacy: PrivacyMode,
+
+    /// ローカル分析でキーワードから分類できなかった場合の活動名（other / window_title / window_class）
+    #[serde(default)]
+    pub fallback_activity: FallbackActivity,
+    
+    /// 登録判断を記録するJSON Lines監査ログのパス（未設定の場合は出力しない）
+    #[serde(default)]
+    pub audit_log_path: Option<String>,
+    
+    /// デーモンの稼働状況を書き出すハートビートファイルのパス（未設定の場合はデータディレクトリの heartbeat.json）
+    #[serde(default)]
+    pub heartbeat_path: Option<String>,
+    
+    /// 組み込みの活動カテゴリ名とOpenAIへの指示の言語（ja または en）
+    #[serde(default)]
+    pub language: Language,
+    
+    /// ログのタイムスタンプのタイムゾーン（local または utc）
+    #[serde(default)]
+    pub log_timezone: LogTimezone,
+    
+    /// 日付の境界と時間ブロックの区切りに使うタイムゾーン（IANA名、例: "Asia/Tokyo"。未設定の場合はシステムのローカルタイム）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
+    
+    /// ログ出力時にシークレットの前後に表示する文字数（0で完全に隠す）
+    #[serde(default = "default_mask_visible_chars")]
+    pub mask_visible_chars: usize,
+    
+    /// Toggl・OpenAI・GoogleへのHTTP接続のタイムアウト（秒）
+    #[serde(default = "default_http_connect_timeout")]
+    pub http_connect_timeout_secs: u64,
+    
+    /// HTTPリクエスト全体のタイムアウト（秒）
+    #[serde(default = "default_http_timeout")]
+    pub http_timeout_secs: u64,
+}
+
+/// Togglへの記録方式
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TrackingMode {
+    /// 時間ブロックごとに完了済みのエントリを作成する
+    #[default]
+    Block,
+    
+    /// 実行中のエントリを維持し、活動が変わったときに停止・再開する
+    Stream,
+}
+
+/// 時間ブロック内のアイドル時間の扱い
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IdleBehavior {
+    /// アイドル時間は登録しない
+    #[default]
+    Skip,
+
+    /// アイドル時間を `idle_description` のエントリとして登録する（`tracking_mode = "block"` のみ）
+    Record,
+}
+
+/// 活動の分析に使用するエンジン
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AnalyzerKind {
+    /// OpenAIの設定があればOpenAI、なければローカル分析
+    #[default]
+    Auto,
+    
+    /// OpenAIで分析する（失敗時はローカル分析にフォールバック）
+    Openai,
+    
+    /// キーワードによるローカル分析
+    Local,
+    
+    /// `[[rule]]` のルールで分析し、一致しなければキーワードによるローカル分析
+    Rules,
+}
+
+/// OpenAIに送る情報の範囲
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PrivacyMode {
+    /// ウィンドウタイトル・クラス・カレンダーの予定名を送る
+    #[default]
+    Full,
+
+    /// ウィンドウクラスとカレンダーの予定名だけを送り、ウィンドウタイトルは送らない
+    ClassOnly,
+
+    /// OpenAIを使わない（キーワードによるローカル分析かルールで分析する）
+    LocalOnly,
+}
+
+/// ローカル分析でキーワードから分類できなかった場合の活動名
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FallbackActivity {
+    /// 「その他の活動」（`language` に合わせたカテゴリ名）
+    #[default]
+    Other,
+
+    /// ウィンドウタイトルをそのまま使う
+    WindowTitle,
+
+    /// ウィンドウクラス（アプリケーション名）を使う
+    WindowClass,
+}
+
+/// ルールベース分析のルール
+///
+/// 指定した条件をすべて満たすサンプルが時間ブロックの過半を占める場合に一致する
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AnalysisRule {
+    /// ウィンドウクラスに一致する正規表現
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub window_class: Option<String>,
+    
+    /// ウィンドウタイトルに一致する正規表現
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub window_title: Option<String>,
+    
+    /// trueならカレンダーの予定と重なるとき、falseなら重ならないときに一致
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub in_calendar_event: Option<bool>,
+    
+    /// 一致する時間帯の開始（ローカルタイム、"HH:MM"）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub after: Option<String>,
+    
+    /// 一致する時間帯の終了（ローカルタイム、"HH:MM"、開始より前なら日付をまたぐ）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub before: Option<String>,
+    
+    /// 一致したときの活動内容
+    pub activity: String,
+    
+    /// 一致したときの確度（0.0-1.0）
+    #[serde(default = "default_rule_confidence")]
+    pub confidence: f64,
+    
+    /// 一致したときに使うTogglのプロジェクト名（省略時は推論）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub project: Option<String>,
+}
+
+/// 組み込みの活動カテゴリ名とOpenAIへの指示に使う言語
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Language {
+    /// 日本語
+    #[default]
+    Ja,
+    
+    /// 英語
+    En,
+}
+
+/// 作業時間の集計区分（`--summary`）
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Bucket {
+    /// 集中して作業していた時間
+    Focus,
+    
+    /// メール・チャット・会議などのやり取り
+    Communication,
+    
+    /// 休憩
+    Break,
+    
+    /// その他
+    Other,
+}
+
+/// ログのタイムスタンプに使用するタイムゾーン
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogTimezone {
+    /// ローカルタイムゾーン
+    #[default]
+    Local,
+    
+    /// UTC（保存データと同じ）
+    Utc,
 }
 
 /// Toggl API 設定
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TogglConfig {
-    /// Toggl API トークン
+    /// Toggl API トークン（`api_token_file` を指定した場合は読み込み時にその内容が入る）
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub api_token: String,
+
+    /// Toggl API トークンを読み込むファイルのパス（`api_token` の代わりに指定する）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_token_file: Option<String>,
     
     /// ワークスペースID
     pub workspace_id: u64,
+    
+    /// プロジェクト一覧をキャッシュする秒数（0でキャッシュしない）
+    #[serde(default = "default_project_cache_ttl")]
+    pub project_cache_ttl_secs: u64,
+    
+    /// 新しいエントリを請求対象（billable）にするかどうかの既定値
+    #[serde(default)]
+    pub default_billable: bool,
+    
+    /// エントリの長さをこの分数の倍数に丸める（0で丸めない）
+    #[serde(default)]
+    pub round_duration_minutes: u32,
+    
+    /// 丸め方（up=切り上げ、nearest=四捨五入）
+    #[serde(default)]
+    pub round_duration_mode: RoundingMode,
+    
+    /// エントリの説明のテンプレート（{activity}, {window_title}, {window_class}, {calendar_title}, {confidence}）
+    #[serde(default = "default_description_template")]
+    pub description_template: String,
+
+    /// 直前のエントリと説明が同じで途切れずに続いている場合は、経過時間に関係なくそのエントリを延長する
+    #[serde(default)]
+    pub continuous_same_activity: bool,
+
+    /// 登録（作成・統合）に成功するたびに控えめなデスクトップ通知を送る
+    #[serde(default)]
+    pub notify_on_register: bool,
+
+    /// 手動の記録など、他のアプリで作成されたエントリと重なる部分は登録しない
+    #[serde(default)]
+    pub avoid_overlaps: bool,
+
+    /// 作成するエントリの `created_with`（未設定の場合は `toggl_linux_rs/<バージョン>`）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_with: Option<String>,
+}
+
+/// エントリの長さの丸め方
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RoundingMode {
+    /// 切り上げる
+    #[default]
+    Up,
+    
+    /// 最も近い倍数に丸める
+    Nearest,
 }
 
 /// OpenAI API 設定
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct OpenAIConfig {
-    /// OpenAI API キー
+    /// OpenAI API キー（`api_key_file` を指定した場合は読み込み時にその内容が入る）
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub api_key: String,
+
+    /// OpenAI API キーを読み込むファイルのパス（`api_key` の代わりに指定する）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_key_file: Option<String>,
     
     /// 使用するモデル
     #[serde(default = "default_model")]
     pub model: String,
+    
+    /// APIのベースURL（OpenAI互換のローカルサーバーなどを使う場合に変更）
+    #[serde(default = "default_openai_api_base")]
+    pub api_base: String,
+    
+    /// 分析に失敗した場合の再試行回数（使い切るとローカル分析にフォールバック）
+    #[serde(default = "default_openai_max_retries")]
+    pub max_retries: u32,
+}
+
+impl OpenAIConfig {
+    /// APIキーが必須かどうか（OpenAIのAPIを使う場合のみ。互換のローカルサーバーなどはキーなしでもよい）
+    pub fn requires_api_key(&self) -> bool {
+        url::Url::parse(&self.api_base)
+            .is_ok_and(|url| url.host_str().is_some_and(|host| host.eq_ignore_ascii_case("api.openai.com")))
+    }
+}
+
+/// HTTP(S)プロキシ設定
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProxyConfig {
+    /// プロキシのURL（例: "http://proxy.example.com:8080"）
+    pub url: String,
+    
+    /// プロキシ認証のユーザー名
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    
+    /// プロキシ認証のパスワード
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
 }
 
 /// Google Calendar API 設定
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GoogleCalendarConfig {
+    /// カレンダーの予定を取得するか（false にすると認証情報を残したまま取得をやめる）
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    
     /// クライアントID
     pub client_id: String,
     
@@ -77,10 +467,74 @@ pub struct GoogleCalendarConfig {
     
     /// カレンダーID（カンマ区切りで複数指定可能）
     pub calendar_ids: String,
+    
+    /// カレンダーIDごとにエントリへ付けるタグ（例: 会議用カレンダー → "meeting"）
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub calendar_tags: HashMap<String, String>,
+    
+    /// 分析に使わないイベントのタイトルの正規表現（例: "^Lunch$", "(?i)focus time"）
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude_event_patterns: Vec<String>,
+}
+
+impl GoogleCalendarConfig {
+    /// `exclude_event_patterns` をコンパイルする
+    pub fn exclude_event_regexes(&self) -> Result<Vec<Regex>> {
+        self.exclude_event_patterns.iter()
+            .map(|pattern| Regex::new(pattern)
+                .with_context(|| format!("Invalid google_calendar.exclude_event_patterns entry '{}'", pattern)))
+            .collect()
+    }
+}
+
+/// Google Calendar の設定（単一アカウントの旧形式と複数アカウントの両方を受け付ける）
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum GoogleCalendarSettings {
+    /// `[[google_calendar]]` で複数のアカウントを指定
+    Multiple(Vec<GoogleCalendarConfig>),
+    
+    /// `[google_calendar]` で単一のアカウントを指定
+    Single(GoogleCalendarConfig),
+}
+
+impl GoogleCalendarSettings {
+    /// 設定されているすべてのアカウント
+    pub fn accounts(&self) -> &[GoogleCalendarConfig] {
+        match self {
+            GoogleCalendarSettings::Multiple(accounts) => accounts,
+            GoogleCalendarSettings::Single(account) => std::slice::from_ref(account),
+        }
+    }
+    
+    /// 予定を取得するアカウントが1つ以上あるか
+    pub fn is_enabled(&self) -> bool {
+        self.accounts().iter().any(|account| account.enabled)
+    }
+    
+    /// カレンダーIDに対応付けられたタグ
+    pub fn tag_for_calendar(&self, calendar_id: &str) -> Option<&str> {
+        self.accounts()
+            .iter()
+            .find_map(|account| account.calendar_tags.get(calendar_id))
+            .map(String::as_str)
+    }
+    
+    /// すべてのアカウントへの可変参照
+    fn accounts_mut(&mut self) -> &mut [GoogleCalendarConfig] {
+        match self {
+            GoogleCalendarSettings::Multiple(accounts) => accounts,
+            GoogleCalendarSettings::Single(account) => std::slice::from_mut(account),
+        }
+    }
 }
 
 // デフォルト値
-fn default_confidence_threshold() -> f64 {
+pub fn default_confidence_threshold() -> f64 {
+    0.5
+}
+
+fn default_confidence_smoothing() -> f64 {
     0.5
 }
 
@@ -96,51 +550,491 @@ fn default_idle_threshold() -> u64 {
     300 // デフォルトは5分
 }
 
+pub fn default_idle_description() -> String {
+    "AFK".to_string()
+}
+
+fn default_suspend_gap_threshold() -> u64 {
+    300 // 5分
+}
+
+pub fn default_openai_api_base() -> String {
+    "https://api.openai.com/v1".to_string()
+}
+
+fn default_openai_max_retries() -> u32 {
+    3
+}
+
+fn default_project_cache_ttl() -> u64 {
+    600 // 10分
+}
+
+pub(crate) fn default_description_template() -> String {
+    "{activity}".to_string()
+}
+
+fn default_rule_confidence() -> f64 {
+    0.9
+}
+
+fn default_http_connect_timeout() -> u64 {
+    5
+}
+
+fn default_http_timeout() -> u64 {
+    30
+}
+
+fn default_mask_visible_chars() -> usize {
+    4
+}
+
+fn default_max_prompt_samples() -> usize {
+    30
+}
+
+pub fn default_full_confidence_samples() -> usize {
+    5 // 1サンプルで0.2倍、2サンプルで0.4倍
+}
+
+fn default_true() -> bool {
+    true
+}
+
+pub fn default_private_browsing_markers() -> Vec<String> {
+    [
+        "privat",       // private / privat (de) / privato (it) / privado (es, pt)
+        "privé",        // fr
+        "incognito",
+        "inkognito",    // de
+        "inprivate",    // Edge
+        "プライベート",
+        "シークレット",
+        "隐私",
+        "無痕",
+        "시크릿",
+    ]
+    .iter()
+    .map(|s| s.to_string())
+    .collect()
+}
+
 fn default_model() -> String {
     "gpt-4o-mini".to_string()
 }
 
+impl GeneralConfig {
+    /// 1つの時間ブロックの長さ（分）
+    pub fn block_minutes(&self) -> u64 {
+        60 / self.time_block_division.max(1) as u64
+    }
+    
+    /// 収集間隔と時間ブロックの設定を検証する
+    ///
+    /// 収集間隔はブロックの長さと独立して設定できるが、1ブロックに少なくとも2回は収集できる必要がある
+    pub fn validate(&self) -> Result<()> {
+        if self.time_block_division == 0 || 60 % self.time_block_division as u64 != 0 {
+            return Err(anyhow::anyhow!(
+                "general.time_block_division must divide 60 (got {})", self.time_block_division
+            ));
+        }
+        
+        let block_secs = self.block_minutes() * 60;
+        if self.collect_interval_secs == 0 || self.collect_interval_secs >= block_secs {
+            return Err(anyhow::anyhow!(
+                "general.collect_interval_secs ({}) must be between 1 and the block length ({}s)",
+                self.collect_interval_secs, block_secs
+            ));
+        }
+        
+        if !(0.0..=1.0).contains(&self.confidence_smoothing) {
+            return Err(anyhow::anyhow!(
+                "general.confidence_smoothing must be between 0.0 and 1.0 (got {})", self.confidence_smoothing
+            ));
+        }
+        
+        if self.idle_behavior == IdleBehavior::Record && self.idle_description.trim().is_empty() {
+            return Err(anyhow::anyhow!("general.idle_description must not be empty when idle_behavior = \"record\""));
+        }
+
+        if self.http_connect_timeout_secs == 0 || self.http_timeout_secs == 0 {
+            return Err(anyhow::anyhow!(
+                "general.http_connect_timeout_secs and general.http_timeout_secs must be greater than 0"
+            ));
+        }
+        
+        self.parsed_timezone()?;
+        
+        Ok(())
+    }
+    
+    /// `timezone` を解析する（未設定の場合はNone）
+    fn parsed_timezone(&self) -> Result<Option<Tz>> {
+        self.timezone.as_deref()
+            .map(|name| name.parse::<Tz>()
+                .map_err(|e| anyhow::anyhow!("Invalid general.timezone '{}': {}", name, e)))
+            .transpose()
+    }
+    
+    /// 設定したタイムゾーンでの今日の日付
+    pub fn today(&self) -> NaiveDate {
+        let now = Utc::now();
+        match self.parsed_timezone().ok().flatten() {
+            Some(tz) => now.with_timezone(&tz).date_naive(),
+            None => now.with_timezone(&Local).date_naive(),
+        }
+    }
+    
+    /// 設定したタイムゾーンでの日付の開始・終了時刻（UTC）
+    pub fn day_bounds(&self, date: NaiveDate) -> (DateTime<Utc>, DateTime<Utc>) {
+        match self.parsed_timezone().ok().flatten() {
+            Some(tz) => local_day_bounds(date, &tz),
+            None => local_day_bounds(date, &Local),
+        }
+    }
+    
+    /// 設定したタイムゾーンでの、指定した時刻のUTCからのオフセット
+    pub fn utc_offset_at(&self, timestamp: DateTime<Utc>) -> FixedOffset {
+        match self.parsed_timezone().ok().flatten() {
+            Some(tz) => timestamp.with_timezone(&tz).offset().fix(),
+            None => timestamp.with_timezone(&Local).offset().fix(),
+        }
+    }
+
+    /// タイムスタンプを含む時間ブロックの開始・終了時刻（UTC、境界は設定したタイムゾーンのローカル時刻で揃える）
+    pub fn block_bounds(&self, timestamp: DateTime<Utc>) -> (DateTime<Utc>, DateTime<Utc>) {
+        crate::event::block_bounds(timestamp, self.block_minutes(), self.utc_offset_at(timestamp))
+    }
+
+    /// データ保存ディレクトリのパス（起動時に絶対パスへ解決済み）
+    pub fn data_dir_path(&self) -> &Path {
+        Path::new(&self.data_dir)
+    }
+}
+
+impl AppConfig {
+    /// 設定値の整合性を確認する
+    pub fn validate(&self) -> Result<()> {
+        self.general.validate()?;
+        
+        if let Some(proxy) = &self.proxy {
+            reqwest::Proxy::all(&proxy.url)
+                .with_context(|| format!("Invalid proxy.url '{}'", proxy.url))?;
+        }
+        
+        if let Some(settings) = &self.google_calendar {
+            for account in settings.accounts() {
+                account.exclude_event_regexes()?;
+            }
+        }
+        
+        for (project, confidence) in &self.project_confidence {
+            if !(0.0..=1.0).contains(confidence) {
+                return Err(anyhow::anyhow!(
+                    "project_confidence.\"{}\" must be between 0.0 and 1.0 (got {})", project, confidence
+                ));
+            }
+        }
+        
+        Ok(())
+    }
+    
+    /// プロジェクトに登録するのに必要な最低確度（`[project_confidence]` の指定が全体の閾値より高い場合はそちら）
+    ///
+    /// プロジェクト名は大文字・小文字を区別せずに照合する
+    pub fn min_confidence_for_project(&self, project_name: &str) -> f64 {
+        let global = self.general.confidence_threshold;
+        self.project_confidence.iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(project_name))
+            .map_or(global, |(_, &confidence)| confidence.max(global))
+    }
+
+    /// 活動カテゴリの集計区分（`[buckets]` の指定がなければ既定の区分）
+    pub fn bucket_for(&self, category: Category) -> Bucket {
+        self.buckets.get(&category).copied().unwrap_or_else(|| category.default_bucket())
+    }
+    
+    /// 分析や類似度評価に使うOpenAIの設定（未設定または `privacy = "local_only"` の場合は None）
+    pub fn openai_config(&self) -> Option<&OpenAIConfig> {
+        self.openai.as_ref().filter(|_| self.general.privacy != PrivacyMode::LocalOnly)
+    }
+
+    /// 活動の類似度評価に使うOpenAIの設定（`privacy = "full"` の場合のみ）
+    ///
+    /// 比較する説明にはウィンドウタイトルが含まれうるため、それ以外の設定では送らない
+    pub fn similarity_openai_config(&self) -> Option<&OpenAIConfig> {
+        self.openai_config().filter(|_| self.general.privacy == PrivacyMode::Full)
+    }
+
+    /// 予定を取得するGoogle Calendarの設定（未設定またはすべて無効の場合は None）
+    pub fn calendar_settings(&self) -> Option<&GoogleCalendarSettings> {
+        self.google_calendar.as_ref().filter(|settings| settings.is_enabled())
+    }
+    
+    /// すべてのアカウントでカレンダーの予定の取得を止める（`--no-calendar`）
+    pub fn disable_calendar(&mut self) {
+        if let Some(settings) = self.google_calendar.as_mut() {
+            for account in settings.accounts_mut() {
+                account.enabled = false;
+            }
+        }
+    }
+    
+    /// `api_token_file` / `api_key_file` からシークレットを読み込む
+    fn resolve_secret_files(&mut self) -> Result<()> {
+        resolve_secret("toggl.api_token", &mut self.toggl.api_token, self.toggl.api_token_file.as_deref(), true)?;
+        if let Some(openai) = self.openai.as_mut() {
+            let required = openai.requires_api_key();
+            resolve_secret("openai.api_key", &mut openai.api_key, openai.api_key_file.as_deref(), required)?;
+        }
+        Ok(())
+    }
+
+    /// ファイルから読み込んだシークレットを除いた設定のコピーを返す（設定ファイルへの保存用）
+    pub fn without_file_secrets(&self) -> AppConfig {
+        let mut config = self.clone();
+        if config.toggl.api_token_file.is_some() {
+            config.toggl.api_token.clear();
+        }
+        if let Some(openai) = config.openai.as_mut().filter(|openai| openai.api_key_file.is_some()) {
+            openai.api_key.clear();
+        }
+        config
+    }
+
+    /// シークレットをマスクした設定のコピーを返す（表示・ログ出力用）
+    pub fn redacted(&self) -> AppConfig {
+        let mut config = self.clone();
+        config.toggl.api_token = mask_token(&config.toggl.api_token);
+        if let Some(openai) = config.openai.as_mut() {
+            openai.api_key = mask_token(&openai.api_key);
+        }
+        if let Some(password) = config.proxy.as_mut().and_then(|proxy| proxy.password.as_mut()) {
+            *password = mask_token(password);
+        }
+        if let Some(settings) = config.google_calendar.as_mut() {
+            for calendar in settings.accounts_mut() {
+                calendar.client_secret = mask_token(&calendar.client_secret);
+                calendar.refresh_token = mask_token(&calendar.refresh_token);
+            }
+        }
+        config
+    }
+}
+
+/// インラインの値とファイルのどちらか一方で指定するシークレットを解決する
+///
+/// ファイルの内容は前後の空白を除いて `value` に入れる。`~/` はホームディレクトリを基準にする。
+/// `required` でない場合は、どちらも指定しなければキーなしとして扱う
+fn resolve_secret(name: &str, value: &mut String, file: Option<&str>, required: bool) -> Result<()> {
+    let Some(file) = file else {
+        if value.is_empty() && required {
+            return Err(anyhow::anyhow!("Either {} or {}_file must be set", name, name));
+        }
+        return Ok(());
+    };
+
+    if !value.is_empty() {
+        return Err(anyhow::anyhow!("Set only one of {} and {}_file", name, name));
+    }
+
+    let path = match file.strip_prefix("~/") {
+        Some(rest) => get_home_dir()?.join(rest),
+        None => PathBuf::from(file),
+    };
+    let secret = read_to_string(&path)
+        .with_context(|| format!("Failed to read {}_file '{}'", name, path.display()))?;
+    let secret = secret.trim();
+    if secret.is_empty() {
+        return Err(anyhow::anyhow!("{}_file '{}' is empty", name, path.display()));
+    }
+
+    *value = secret.to_string();
+    Ok(())
+}
+
 /// 設定ファイルを読み込む
 pub fn load_config<P: AsRef<Path>>(path: P) -> Result<AppConfig> {
     let config_str = read_to_string(path)
         .context("Failed to read config file")?;
     
-    let config: AppConfig = toml::from_str(&config_str)
+    let mut config: AppConfig = toml::from_str(&config_str)
         .context("Failed to parse config file")?;
+    config.resolve_secret_files()?;
     
     Ok(config)
 }
 
 /// デフォルトの設定を作成する
+#[cfg(test)]
 pub fn create_default_config() -> AppConfig {
     AppConfig {
         general: GeneralConfig {
-            data_dir: "./data".to_string(),
+            data_dir: "~/.local/share/toggl_linux_rs".to_string(),
             confidence_threshold: default_confidence_threshold(),
+            confidence_smoothing: default_confidence_smoothing(),
             collect_interval_secs: default_collect_interval(),
             time_block_division: default_time_block_division(),
+            collect_jitter_secs: 0,
+            analysis_jitter_secs: 0,
             idle_threshold_secs: default_idle_threshold(),
+            idle_behavior: IdleBehavior::default(),
+            idle_description: default_idle_description(),
+            idle_project: None,
+            min_active_secs: 0,
+            activity_debounce_secs: 0,
+            suspend_gap_threshold_secs: default_suspend_gap_threshold(),
+            skip_private_browsing: true,
+            private_browsing_markers: default_private_browsing_markers(),
+            prefer_calendar_title: false,
+            calendar_first: false,
+            tracking_mode: TrackingMode::default(),
+            analyzer: AnalyzerKind::default(),
+            privacy: PrivacyMode::default(),
+            fallback_activity: FallbackActivity::default(),
+            audit_log_path: None,
+            heartbeat_path: None,
+            language: Language::default(),
+            log_timezone: LogTimezone::default(),
+            timezone: None,
+            mask_visible_chars: default_mask_visible_chars(),
+            http_connect_timeout_secs: default_http_connect_timeout(),
+            http_timeout_secs: default_http_timeout(),
+            ignore_windows: Vec::new(),
+            collect_visible_windows: false,
+            collect_window_geometry: false,
+            collect_terminal_context: false,
+            max_prompt_samples: default_max_prompt_samples(),
+            full_confidence_samples: default_full_confidence_samples(),
         },
         toggl: TogglConfig {
             api_token: "your_toggl_api_token".to_string(),
+            api_token_file: None,
             workspace_id: 0,
+            project_cache_ttl_secs: default_project_cache_ttl(),
+            default_billable: false,
+            round_duration_minutes: 0,
+            round_duration_mode: RoundingMode::default(),
+            description_template: default_description_template(),
+            continuous_same_activity: false,
+            notify_on_register: false,
+            avoid_overlaps: false,
+            created_with: None,
         },
         openai: Some(OpenAIConfig {
             api_key: "your_openai_api_key".to_string(),
+            api_key_file: None,
             model: default_model(),
+            api_base: default_openai_api_base(),
+            max_retries: default_openai_max_retries(),
         }),
         google_calendar: None,
+        proxy: None,
+        rules: Vec::new(),
+        buckets: HashMap::new(),
+        project_confidence: HashMap::new(),
     }
 }
 
-/// サンプル設定ファイルを作成する
-pub fn generate_sample_config<P: AsRef<Path>>(path: P) -> Result<()> {
-    let config = create_default_config();
-    let toml_str = toml::to_string_pretty(&config)
-        .context("Failed to serialize config")?;
-    
-    std::fs::write(path, toml_str)
-        .context("Failed to write sample config file")?;
-    
-    Ok(())
-} 
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collect_interval_must_be_shorter_than_block() {
+        let mut general = create_default_config().general;
+        general.time_block_division = 6;
+
+        general.collect_interval_secs = 15;
+        assert!(general.validate().is_ok());
+
+        general.collect_interval_secs = 600;
+        assert!(general.validate().is_err());
+
+        general.collect_interval_secs = 0;
+        assert!(general.validate().is_err());
+    }
+
+    #[test]
+    fn time_block_division_must_divide_an_hour() {
+        let mut general = create_default_config().general;
+        general.time_block_division = 7;
+        assert!(general.validate().is_err());
+    }
+
+    #[test]
+    fn invalid_exclude_event_pattern_is_rejected_when_validating_config() {
+        let mut config = create_default_config();
+        config.google_calendar = Some(GoogleCalendarSettings::Single(GoogleCalendarConfig {
+            enabled: true,
+            client_id: String::new(),
+            client_secret: String::new(),
+            refresh_token: String::new(),
+            calendar_ids: "primary".to_string(),
+            calendar_tags: HashMap::new(),
+            exclude_event_patterns: vec!["(unclosed".to_string()],
+        }));
+
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn secrets_can_be_read_from_files() {
+        let temp = tempfile::tempdir().unwrap();
+        let token_file = temp.path().join("toggl_token");
+        std::fs::write(&token_file, "  toggl-secret\n").unwrap();
+        let key_file = temp.path().join("openai_key");
+        std::fs::write(&key_file, "sk-secret\n").unwrap();
+
+        let mut config = create_default_config();
+        config.toggl.api_token.clear();
+        config.toggl.api_token_file = Some(token_file.to_string_lossy().into_owned());
+        let openai = config.openai.as_mut().unwrap();
+        openai.api_key.clear();
+        openai.api_key_file = Some(key_file.to_string_lossy().into_owned());
+        config.resolve_secret_files().unwrap();
+        assert_eq!(config.toggl.api_token, "toggl-secret");
+        assert_eq!(config.openai.as_ref().unwrap().api_key, "sk-secret");
+
+        // 保存する設定にはファイルから読み込んだ値を書き出さない
+        let saved = toml::to_string(&config.without_file_secrets()).unwrap();
+        assert!(!saved.contains("toggl-secret") && !saved.contains("sk-secret"), "{}", saved);
+        assert!(saved.contains("api_token_file"), "{}", saved);
+    }
+
+    #[test]
+    fn secret_file_must_exist_and_exclude_inline_value() {
+        let temp = tempfile::tempdir().unwrap();
+        let missing = temp.path().join("missing");
+        let mut config = create_default_config();
+        config.toggl.api_token.clear();
+        config.toggl.api_token_file = Some(missing.to_string_lossy().into_owned());
+        let error = config.resolve_secret_files().unwrap_err();
+        assert!(error.to_string().contains("toggl.api_token_file"), "{}", error);
+
+        let empty = temp.path().join("empty");
+        std::fs::write(&empty, " \n").unwrap();
+        config.toggl.api_token_file = Some(empty.to_string_lossy().into_owned());
+        let error = config.resolve_secret_files().unwrap_err();
+        assert!(error.to_string().contains("is empty"), "{}", error);
+
+        let mut config = create_default_config();
+        config.toggl.api_token_file = Some(empty.to_string_lossy().into_owned());
+        let error = config.resolve_secret_files().unwrap_err();
+        assert!(error.to_string().contains("Set only one of toggl.api_token and toggl.api_token_file"), "{}", error);
+    }
+
+    #[test]
+    fn openai_key_is_only_required_for_the_openai_endpoint() {
+        let mut config = create_default_config();
+        config.openai.as_mut().unwrap().api_key.clear();
+        let error = config.resolve_secret_files().unwrap_err();
+        assert!(error.to_string().contains("Either openai.api_key or openai.api_key_file must be set"), "{}", error);
+
+        // OpenAI互換のローカルサーバーはキーなしでよい
+        config.openai.as_mut().unwrap().api_base = "http://localhost:11434/v1".to_string();
+        config.resolve_secret_files().unwrap();
+        assert_eq!(config.openai.as_ref().unwrap().api_key, "");
+    }
+}
@@ -0,0 +1,68 @@
+use anyhow::{Context, Result};
+use log::{debug, warn};
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::ffi::OsString;
+use std::path::Path;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time;
+
+/// 最後の変更からこの時間だけ変更がなければ書き込みが終わったとみなす
+pub const CONFIG_WATCH_DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// 設定ファイルの変更を監視する（`--watch-config`）
+///
+/// エディタが別名で保存してから置き換える場合にも追従できるよう、設定ファイルのあるディレクトリを監視し、
+/// 設定ファイル名への変更だけを通知する
+pub struct ConfigWatcher {
+    /// 監視を続けるために保持する（破棄すると監視が止まる）
+    _watcher: RecommendedWatcher,
+    changes: mpsc::UnboundedReceiver<()>,
+    debounce: Duration,
+}
+
+impl ConfigWatcher {
+    /// 設定ファイルの監視を開始する
+    pub fn new(path: &Path, debounce: Duration) -> Result<Self> {
+        let path = std::fs::canonicalize(path)
+            .with_context(|| format!("Failed to resolve config file path {:?}", path))?;
+        let (Some(dir), Some(file_name)) = (path.parent(), path.file_name()) else {
+            return Err(anyhow::anyhow!("Config file path {:?} has no parent directory", path));
+        };
+        let file_name: OsString = file_name.to_owned();
+
+        let (sender, changes) = mpsc::unbounded_channel();
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+            match event {
+                Ok(event) => {
+                    if matches!(event.kind, EventKind::Access(_)) {
+                        return;
+                    }
+                    if event.paths.iter().any(|changed| changed.file_name() == Some(file_name.as_os_str())) {
+                        debug!("Config file changed: {:?}", event.kind);
+                        let _ = sender.send(());
+                    }
+                }
+                Err(e) => warn!("Error watching config file: {}", e),
+            }
+        }).context("Failed to create config file watcher")?;
+        watcher.watch(dir, RecursiveMode::NonRecursive)
+            .with_context(|| format!("Failed to watch {:?}", dir))?;
+
+        Ok(Self { _watcher: watcher, changes, debounce })
+    }
+
+    /// 設定ファイルが変更され、書き込みが落ち着くまで待つ（監視が止まった場合はNone）
+    ///
+    /// 書き込み途中の内容を読まないよう、`debounce` の間に続いた変更はまとめて1回として扱う
+    pub async fn changed(&mut self) -> Option<()> {
+        self.changes.recv().await?;
+        loop {
+            match time::timeout(self.debounce, self.changes.recv()).await {
+                Ok(Some(())) => continue,
+                Ok(None) => return None,
+                Err(_) => return Some(()),
+            }
+        }
+    }
+}
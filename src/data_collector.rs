@@ -1,19 +1,17 @@
 use anyhow::{Context, Result};
 use chrono::{DateTime, Utc};
-use log::{debug, error, info, warn};
-use rusqlite::{params, Connection};
+use log::{debug, error, info, trace, warn};
+use rusqlite::{params, Connection, ErrorCode, OptionalExtension};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use yup_oauth2::InstalledFlowAuthenticator;
-use url;
-use urlencoding;
 use user_idle::UserIdle;
 use std::time::{Duration, Instant};
 
-use crate::config::{AppConfig, GoogleCalendarConfig};
+use crate::config::{AppConfig, GoogleCalendarConfig, GoogleCalendarSettings, IdleBehavior};
+use crate::utils::{create_private_dir, mask_token, restrict_file_permissions};
 
 /// ウィンドウ情報
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -32,6 +30,57 @@ pub struct WindowInfo {
     
     /// 取得時刻
     pub timestamp: DateTime<Utc>,
+
+    /// ウィンドウの位置と大きさ（`collect_window_geometry` が有効で、取得できた場合のみ）
+    #[serde(default)]
+    pub geometry: Option<WindowGeometry>,
+
+    /// ターミナルで前面に実行中のコマンドと作業ディレクトリ（`collect_terminal_context` が有効で、取得できた場合のみ）
+    #[serde(default)]
+    pub terminal: Option<TerminalContext>,
+}
+
+/// ターミナルで前面に実行中のコマンドと作業ディレクトリ
+///
+/// 引数やフルパスには秘密情報が含まれうるため、コマンド名と作業ディレクトリの末尾の名前だけを持つ
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TerminalContext {
+    /// 実行中のコマンド名（例: "cargo", "vim"）
+    pub command: Option<String>,
+
+    /// 作業ディレクトリの末尾の名前（例: "toggl_linux_rs"）
+    pub cwd: Option<String>,
+}
+
+impl TerminalContext {
+    /// 分析に渡す表記（例: "cargo @ toggl_linux_rs"）
+    pub fn describe(&self) -> String {
+        match (&self.command, &self.cwd) {
+            (Some(command), Some(cwd)) => format!("{} @ {}", command, cwd),
+            (Some(command), None) => command.clone(),
+            (None, Some(cwd)) => format!("@ {}", cwd),
+            (None, None) => String::new(),
+        }
+    }
+}
+
+/// ウィンドウの位置と大きさ
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WindowGeometry {
+    /// 左上のX座標
+    pub x: i32,
+
+    /// 左上のY座標
+    pub y: i32,
+
+    /// 幅
+    pub width: u32,
+
+    /// 高さ
+    pub height: u32,
+
+    /// 全画面表示かどうか（`_NET_WM_STATE_FULLSCREEN`）
+    pub fullscreen: bool,
 }
 
 /// カレンダーイベント
@@ -54,6 +103,22 @@ pub struct CalendarEvent {
     
     /// 説明
     pub description: Option<String>,
+
+    /// 場所
+    #[serde(default)]
+    pub location: Option<String>,
+
+    /// 主催者のメールアドレス
+    #[serde(default)]
+    pub organizer_email: Option<String>,
+
+    /// ビデオ会議（hangoutLink / conferenceData）が設定されているか
+    #[serde(default)]
+    pub is_video_call: bool,
+
+    /// 自分の出欠の回答（"accepted", "tentative", "needsAction", "declined"、自分が参加者にいない予定はNone）
+    #[serde(default)]
+    pub response_status: Option<String>,
 }
 
 /// 収集データ
@@ -70,38 +135,87 @@ pub struct CollectedData {
 
     /// システムがアイドル状態かどうか
     pub is_idle: bool,
+
+    /// フォーカスされたウィンドウ以外に表示されていたウィンドウ（`collect_visible_windows` が有効な場合のみ）
+    #[serde(default)]
+    pub visible_windows: Vec<WindowInfo>,
 }
 
 pub struct DataCollector {
     conn: Connection,
     config: AppConfig,
     idle_threshold: Duration,
-    last_active: Instant,
     idle_start: Option<Instant>,
     total_idle_time: Duration,
-    block_start: Instant,
+    block_end: DateTime<Utc>,
+    last_collected_at: Option<DateTime<Utc>>,
 }
 
 impl DataCollector {
+    /// `data_dir` が `:memory:` の場合はインメモリのデータベースを使う（テスト用）
     pub fn new(config: AppConfig) -> Result<Self> {
-        let data_dir = Path::new(&config.general.data_dir);
-        let db_path = data_dir.join("activity.db");
-        let conn = Connection::open(&db_path).context("Failed to open database")?;
+        let conn = open_database(&config.general.data_dir)?;
+        let block_end = current_block_end(&config);
+        let idle_threshold = Duration::from_secs(config.general.idle_threshold_secs);
 
         Ok(Self {
             conn,
             config,
-            idle_threshold: Duration::from_secs(300), // 5分のアイドルしきい値
-            last_active: Instant::now(),
+            idle_threshold,
             idle_start: None,
             total_idle_time: Duration::from_secs(0),
-            block_start: Instant::now(),
+            block_end,
+            last_collected_at: None,
         })
     }
 
+    /// 設定の再読み込みを反映する
+    ///
+    /// 前回の収集時刻やアイドルの集計は引き継ぐ。保存先と時間ブロックの設定は再起動まで変わらない前提とする
+    pub fn apply_config(&mut self, config: AppConfig) {
+        self.idle_threshold = Duration::from_secs(config.general.idle_threshold_secs);
+        self.config = config;
+    }
+
+    /// アイドル時間の集計を現在の時間ブロックから再開する
+    fn reset_idle_block(&mut self) {
+        self.block_end = current_block_end(&self.config);
+        self.total_idle_time = Duration::from_secs(0);
+        self.idle_start = None;
+    }
+
+    /// 前回の収集からの壁時計の経過時間を確認し、サスペンド等による長い空白を検出する
+    ///
+    /// `Instant` はサスペンド中に進むかどうかがカーネル依存のため、`Utc::now()` の差分で判定する
+    fn detect_wall_clock_gap(&mut self, now: DateTime<Utc>) -> bool {
+        let gap_detected = match self.last_collected_at {
+            Some(last) => {
+                let gap_secs = (now - last).num_seconds();
+                let threshold_secs = self.config.general.suspend_gap_threshold_secs
+                    .max(self.config.general.collect_interval_secs * 2) as i64;
+                gap_secs > threshold_secs
+            }
+            None => false,
+        };
+        self.last_collected_at = Some(now);
+        gap_detected
+    }
+
+    /// 壁時計の空白を検出した場合は、アイドル集計をリセットし、空白をまたぐブロックを処理済みにする（破棄したかどうかを返す）
+    fn discard_block_after_gap(&mut self, now: DateTime<Utc>) -> Result<bool> {
+        if !self.detect_wall_clock_gap(now) {
+            return Ok(false);
+        }
+        warn!("Detected a wall-clock gap since the last collection (suspend/resume?), discarding the current block");
+        self.reset_idle_block();
+        set_last_analyzed_block_end(self.config.general.data_dir_path(), self.block_end)
+            .context("Failed to mark the spanning block as processed")?;
+        Ok(true)
+    }
+
     fn is_idle(&mut self) -> bool {
-        if let Ok(idle_time) = UserIdle::get_time() {
-            let is_idle = idle_time.as_milliseconds() as u64 > self.idle_threshold.as_millis() as u64;
+        if let Some(idle_time) = idle_duration() {
+            let is_idle = idle_time > self.idle_threshold;
             
             // アイドル状態の開始時刻を記録
             if is_idle && self.idle_start.is_none() {
@@ -113,11 +227,9 @@ impl DataCollector {
                 }
             }
 
-            // 15分経過したらブロックをリセット
-            if self.block_start.elapsed() >= Duration::from_secs(900) {
-                self.block_start = Instant::now();
-                self.total_idle_time = Duration::from_secs(0);
-                self.idle_start = None;
+            // 時間ブロックの境界を過ぎたらリセット（再起動後も壁時計の境界に揃える）
+            if Utc::now() >= self.block_end {
+                self.reset_idle_block();
             }
 
             is_idle
@@ -127,6 +239,10 @@ impl DataCollector {
     }
 
     pub async fn collect(&mut self) -> Result<()> {
+        // サスペンドからの復帰などで収集間隔が大きく空いた場合は、
+        // アイドル集計をリセットし、空白をまたぐ時間ブロックは登録しない
+        self.discard_block_after_gap(Utc::now())?;
+        
         // アイドル状態をチェック
         let is_idle = self.is_idle();
 
@@ -137,30 +253,74 @@ impl DataCollector {
             self.total_idle_time
         };
 
-        // 15分の半分（7.5分 = 450秒）以上がアイドル状態なら記録しない
-        if current_idle_time >= Duration::from_secs(450) {
-            debug!("More than half of the 15-minute block is idle ({}s), skipping data collection", 
-                   current_idle_time.as_secs());
+        // 時間ブロックの半分以上がアイドル状態なら記録しない（アイドル時間を登録する場合は割合を知るため記録を続ける）
+        let block_minutes = self.config.general.block_minutes();
+        if self.config.general.idle_behavior == IdleBehavior::Skip
+            && current_idle_time >= Duration::from_secs(block_minutes * 30)
+        {
+            debug!("More than half of the {}-minute block is idle ({}s), skipping data collection", 
+                   block_minutes, current_idle_time.as_secs());
             return Ok(());
         }
 
         // アクティブウィンドウの情報を取得
         let window = get_active_window().context("Failed to get active window info")?;
         
+        // 除外対象のウィンドウはカレンダー取得や保存の前にスキップする
+        let Some(mut window) = recordable_window(window, &self.config.general.ignore_windows) else {
+            debug!("Active window matches ignore_windows or has neither a title nor a class, skipping data collection");
+            return Ok(());
+        };
+
+        // ウィンドウの位置と大きさを取得（失敗しても記録を続ける）
+        if self.config.general.collect_window_geometry {
+            match get_window_geometry(&window.id) {
+                Ok(geometry) => window.geometry = Some(geometry),
+                Err(e) => warn!("Failed to get window geometry: {:#}", e),
+            }
+        }
+        
+        // ターミナルなら前面のコマンドと作業ディレクトリを取得（取得できなくても記録を続ける）
+        if self.config.general.collect_terminal_context && is_terminal_window(&window) {
+            if let Some(pid) = window.pid {
+                window.terminal = read_terminal_context(Path::new("/proc"), pid);
+                if window.terminal.is_none() {
+                    debug!("No foreground process found for terminal window (pid {})", pid);
+                }
+            }
+        }
+
         // カレンダーイベントを取得
-        let calendar_events = if let Some(calendar_config) = &self.config.google_calendar {
-            get_calendar_events(calendar_config)
+        let calendar_events = if let Some(calendar_settings) = self.config.calendar_settings() {
+            get_all_calendar_events(calendar_settings, &self.config.general.data_dir)
                 .await
                 .context("Failed to get calendar events")?
         } else {
             Vec::new()
         };
 
+        // 表示中の他のウィンドウを取得（失敗してもフォーカスされたウィンドウだけで記録を続ける）
+        let visible_windows = if self.config.general.collect_visible_windows {
+            match get_visible_windows() {
+                Ok(windows) => windows.into_iter()
+                    .filter(|visible| visible.id != window.id)
+                    .filter_map(|visible| recordable_window(visible, &self.config.general.ignore_windows))
+                    .collect(),
+                Err(e) => {
+                    warn!("Failed to get visible windows: {:#}", e);
+                    Vec::new()
+                }
+            }
+        } else {
+            Vec::new()
+        };
+
         let data = CollectedData {
             timestamp: Utc::now(),
             window,
             calendar_events,
             is_idle,
+            visible_windows,
         };
 
         // データを保存
@@ -169,26 +329,50 @@ impl DataCollector {
         Ok(())
     }
 
-    fn save_data(&self, data: &CollectedData) -> Result<()> {
+    /// 収集したデータを保存する
+    pub fn save_data(&self, data: &CollectedData) -> Result<()> {
         // ウィンドウデータを保存
+        let geometry = data.window.geometry.as_ref();
+        let terminal = data.window.terminal.as_ref();
         self.conn.execute(
-            "INSERT INTO window_data (timestamp, window_id, window_title, window_class, pid)
-             VALUES (?, ?, ?, ?, ?)",
+            "INSERT INTO window_data (timestamp, window_id, window_title, window_class, pid,
+                                      geometry_x, geometry_y, geometry_width, geometry_height, is_fullscreen,
+                                      terminal_command, terminal_cwd, is_idle)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
             params![
                 data.window.timestamp.to_rfc3339(),
                 data.window.id,
                 data.window.title,
                 data.window.class,
                 data.window.pid,
+                geometry.map(|g| g.x),
+                geometry.map(|g| g.y),
+                geometry.map(|g| g.width),
+                geometry.map(|g| g.height),
+                geometry.map(|g| g.fullscreen),
+                terminal.and_then(|t| t.command.as_deref()),
+                terminal.and_then(|t| t.cwd.as_deref()),
+                data.is_idle,
             ],
         ).context("Failed to insert window data")?;
 
+        // 表示中の他のウィンドウをサンプルに紐づけて保存
+        let sample_id = self.conn.last_insert_rowid();
+        for visible in &data.visible_windows {
+            self.conn.execute(
+                "INSERT INTO visible_windows (sample_id, window_id, window_title, window_class, pid)
+                 VALUES (?, ?, ?, ?, ?)",
+                params![sample_id, visible.id, visible.title, visible.class, visible.pid],
+            ).context("Failed to insert visible window")?;
+        }
+
         // カレンダーイベントを保存
         for event in &data.calendar_events {
             self.conn.execute(
                 "INSERT OR REPLACE INTO calendar_events 
-                 (event_id, title, start_time, end_time, calendar_id, description)
-                 VALUES (?, ?, ?, ?, ?, ?)",
+                 (event_id, title, start_time, end_time, calendar_id, description,
+                  location, organizer_email, is_video_call, response_status)
+                 VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
                 params![
                     event.id,
                     event.title,
@@ -196,6 +380,10 @@ impl DataCollector {
                     event.end_time.to_rfc3339(),
                     event.calendar_id,
                     event.description,
+                    event.location,
+                    event.organizer_email,
+                    event.is_video_call,
+                    event.response_status,
                 ],
             ).context("Failed to insert calendar event")?;
         }
@@ -204,16 +392,378 @@ impl DataCollector {
     }
 }
 
+/// アイドル時間の取得方法
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum IdleBackend {
+    /// GNOME (Mutter) のIdleMonitor D-Busインターフェース
+    GnomeMutter,
+    
+    /// KDE (KWin) のScreenSaver D-Busインターフェース
+    KdeScreenSaver,
+    
+    /// Waylandのext-idle-notify-v1プロトコル（Hyprland・swayなどwlrootsベースのコンポジタ）
+    ExtIdleNotify,
+    
+    /// X11のスクリーンセーバー拡張（user_idleクレート）
+    UserIdle,
+}
+
+/// セッションの種類とデスクトップ環境から、試行するアイドル検出方法を優先順に選ぶ
+fn select_idle_backends(session_type: Option<&str>, wayland_display: bool, desktop: Option<&str>) -> Vec<IdleBackend> {
+    let is_wayland = session_type.is_some_and(|t| t.eq_ignore_ascii_case("wayland")) || wayland_display;
+    if !is_wayland {
+        return vec![IdleBackend::UserIdle];
+    }
+    
+    let desktop = desktop.unwrap_or("").to_lowercase();
+    let mut backends = if desktop.contains("kde") {
+        vec![IdleBackend::KdeScreenSaver, IdleBackend::ExtIdleNotify, IdleBackend::GnomeMutter]
+    } else if desktop.contains("gnome") {
+        vec![IdleBackend::GnomeMutter, IdleBackend::ExtIdleNotify, IdleBackend::KdeScreenSaver]
+    } else {
+        // Hyprland・swayなどD-Busのインターフェースを持たないコンポジタ
+        vec![IdleBackend::ExtIdleNotify, IdleBackend::GnomeMutter, IdleBackend::KdeScreenSaver]
+    };
+    // XWayland経由で取得できる場合に備えて最後にuser_idleを試す
+    backends.push(IdleBackend::UserIdle);
+    backends
+}
+
+/// gdbusの戻り値（例: `(uint64 12345,)`）から数値を取り出す
+fn parse_gdbus_uint(output: &str) -> Option<u64> {
+    output
+        .trim()
+        .trim_start_matches('(')
+        .trim_end_matches(')')
+        .trim_end_matches(',')
+        .split_whitespace()
+        .last()
+        .and_then(|value| value.parse::<u64>().ok())
+}
+
+/// gdbusでセッションバスのメソッドを呼び出し、数値の戻り値を取得する
+fn call_gdbus_uint(dest: &str, object_path: &str, method: &str) -> Option<u64> {
+    let output = Command::new("gdbus")
+        .args(["call", "--session", "--dest", dest, "--object-path", object_path, "--method", method])
+        .output()
+        .ok()?;
+    
+    if !output.status.success() {
+        return None;
+    }
+    
+    parse_gdbus_uint(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// 指定した方法でアイドル時間を取得する
+fn idle_duration_from(backend: IdleBackend) -> Option<Duration> {
+    match backend {
+        IdleBackend::GnomeMutter => call_gdbus_uint(
+            "org.gnome.Mutter.IdleMonitor",
+            "/org/gnome/Mutter/IdleMonitor/Core",
+            "org.gnome.Mutter.IdleMonitor.GetIdletime",
+        ).map(Duration::from_millis),
+        // KWinの実装はミリ秒を返す
+        IdleBackend::KdeScreenSaver => call_gdbus_uint(
+            "org.kde.screensaver",
+            "/ScreenSaver",
+            "org.freedesktop.ScreenSaver.GetSessionIdleTime",
+        ).map(Duration::from_millis),
+        IdleBackend::ExtIdleNotify => crate::wayland_idle::idle_duration(),
+        IdleBackend::UserIdle => UserIdle::get_time()
+            .ok()
+            .map(|idle| Duration::from_millis(idle.as_milliseconds() as u64)),
+    }
+}
+
+/// ユーザーのアイドル時間を取得する（Waylandの場合はD-Bus経由、X11の場合はuser_idle）
+pub fn idle_duration() -> Option<Duration> {
+    let session_type = std::env::var("XDG_SESSION_TYPE").ok();
+    let desktop = std::env::var("XDG_CURRENT_DESKTOP").ok();
+    let wayland_display = std::env::var_os("WAYLAND_DISPLAY").is_some();
+    
+    select_idle_backends(session_type.as_deref(), wayland_display, desktop.as_deref())
+        .into_iter()
+        .find_map(|backend| {
+            let idle = idle_duration_from(backend);
+            if idle.is_some() {
+                trace!("Idle time obtained via {:?}", backend);
+            }
+            idle
+        })
+}
+
+/// ウィンドウのクラス名またはタイトルが除外リストに一致するか（大文字小文字を区別しない）
+pub fn is_ignored_window(window: &WindowInfo, ignore_windows: &[String]) -> bool {
+    let title = window.title.to_lowercase();
+    let class = window.class.as_deref().map(str::to_lowercase);
+    
+    ignore_windows.iter()
+        .map(|pattern| pattern.trim().to_lowercase())
+        .filter(|pattern| !pattern.is_empty())
+        .any(|pattern| title.contains(&pattern) || class.as_deref().is_some_and(|class| class.contains(&pattern)))
+}
+
+/// 記録するウィンドウ（除外リストに一致する場合と、タイトルもクラス名もない場合はNone）
+pub(crate) fn recordable_window(window: WindowInfo, ignore_windows: &[String]) -> Option<WindowInfo> {
+    if is_ignored_window(&window, ignore_windows) {
+        return None;
+    }
+    with_fallback_title(window)
+}
+
+/// タイトルが空（空白のみ）のウィンドウは、クラス名をタイトルの代わりに使う
+///
+/// スプラッシュ画面や一部のダイアログは `_NET_WM_NAME` が空のため、クラス名もない場合は記録しない
+fn with_fallback_title(window: WindowInfo) -> Option<WindowInfo> {
+    if !window.title.trim().is_empty() {
+        return Some(window);
+    }
+    let class = window.class.as_deref().map(str::trim).filter(|class| !class.is_empty())?.to_string();
+    Some(WindowInfo { title: class, ..window })
+}
+
+/// 現在の時間ブロックの終了時刻を求める
+fn current_block_end(config: &AppConfig) -> DateTime<Utc> {
+    config.general.block_bounds(Utc::now()).1
+}
+
+/// インメモリのデータベースを使う場合の `data_dir` の値
+pub const IN_MEMORY_DATA_DIR: &str = ":memory:";
+
+/// データディレクトリのデータベースを開く（`:memory:` の場合はスキーマを作成したインメモリのデータベース）
+fn open_database(data_dir: &str) -> Result<Connection> {
+    if data_dir == IN_MEMORY_DATA_DIR {
+        let conn = Connection::open_in_memory().context("Failed to open in-memory database")?;
+        create_schema(&conn)?;
+        return Ok(conn);
+    }
+    
+    open_writable_database(Path::new(data_dir))
+}
+
+/// データベースを開いてスキーマを作成し、書き込めない場合は対処方法を含むエラーを返す
+fn open_writable_database(data_dir: &Path) -> Result<Connection> {
+    let db_path = data_dir.join("activity.db");
+    Connection::open(&db_path)
+        .context("Failed to open database")
+        .and_then(|conn| {
+            create_schema(&conn)?;
+            verify_writable(&conn)?;
+            Ok(conn)
+        })
+        .map_err(|e| unwritable_storage_error(data_dir, e))
+}
+
+/// 試しに書き込んで、データベースが書き込み可能であることを確認する
+fn verify_writable(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO daemon_state (key, value) VALUES ('last_write_check', ?1)",
+        params![Utc::now().to_rfc3339()],
+    ).context("Failed to write to database")?;
+    Ok(())
+}
+
+/// 保存先への書き込みが永続的に失敗している（読み取り専用・容量不足・権限不足）かどうか
+///
+/// ロック競合などの一時的な失敗と区別し、同じ障害を繰り返し通知しないために使う
+pub fn is_persistent_storage_error(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| match cause.downcast_ref::<rusqlite::Error>() {
+        Some(rusqlite::Error::SqliteFailure(e, _)) => matches!(
+            e.code,
+            ErrorCode::ReadOnly | ErrorCode::DiskFull | ErrorCode::CannotOpen | ErrorCode::PermissionDenied
+        ),
+        _ => false,
+    })
+}
+
+/// 永続的な書き込み失敗に対処方法を添える
+fn unwritable_storage_error(data_dir: &Path, error: anyhow::Error) -> anyhow::Error {
+    if !is_persistent_storage_error(&error) {
+        return error;
+    }
+    error.context(format!(
+        "Data directory {:?} is not writable (read-only, disk full or permission denied). \
+         Fix its permissions, free up disk space, or set general.data_dir to a writable directory",
+        data_dir
+    ))
+}
+
 /// 保存先を初期化する
-pub fn init_storage() -> Result<()> {
+///
+/// ウィンドウタイトルなどを含むため、ディレクトリは0700、データベースは0600で作成する
+pub fn init_storage(data_dir: &Path) -> Result<()> {
+    if data_dir == Path::new(IN_MEMORY_DATA_DIR) {
+        return Ok(());
+    }
+    
     // データディレクトリを作成
-    let data_dir = Path::new("./data");
-    fs::create_dir_all(data_dir).context("Failed to create data directory")?;
+    create_private_dir(data_dir).context("Failed to create data directory")?;
     
     // SQLiteデータベースを初期化
+    open_writable_database(data_dir)?;
+    let db_path = data_dir.join("activity.db");
+    restrict_file_permissions(&db_path)?;
+    
+    info!("Database initialized at {:?}", db_path);
+    Ok(())
+}
+
+/// 破損したデータベースを退避して作り直した結果
+#[derive(Debug)]
+pub struct DatabaseRecovery {
+    /// 退避した破損ファイルのパス
+    pub backup_path: PathBuf,
+    
+    /// 破損ファイルから読み出せた行数
+    pub recovered_rows: usize,
+}
+
+/// データベースの破損を示すエラーかどうか
+fn is_corruption_error(error: &rusqlite::Error) -> bool {
+    matches!(
+        error,
+        rusqlite::Error::SqliteFailure(e, _) if matches!(e.code, ErrorCode::DatabaseCorrupt | ErrorCode::NotADatabase)
+    )
+}
+
+/// `PRAGMA integrity_check` でデータベースが破損しているか確認する
+///
+/// ロック中などの破損以外の理由で確認できない場合はエラーを返す
+fn is_database_corrupt(db_path: &Path) -> Result<bool> {
+    let result = Connection::open(db_path).and_then(|conn| {
+        conn.query_row("PRAGMA integrity_check", [], |row| row.get::<_, String>(0))
+    });
+    match result {
+        Ok(status) => Ok(status != "ok"),
+        Err(e) if is_corruption_error(&e) => Ok(true),
+        Err(e) => Err(e).context("Failed to check database integrity"),
+    }
+}
+
+/// 起動時にデータベースの破損を確認し、破損していれば退避して作り直す
+///
+/// 破損ファイルは `activity.db.corrupt-<時刻>` に移し、読み出せるテーブルの行は新しいデータベースへ移す。
+/// 何も読み出せない場合も空のデータベースで起動を続けられる。破損していない場合はNoneを返す
+pub fn recover_corrupt_database(data_dir: &Path) -> Result<Option<DatabaseRecovery>> {
+    let db_path = data_dir.join("activity.db");
+    if data_dir == Path::new(IN_MEMORY_DATA_DIR) || !db_path.exists() || !is_database_corrupt(&db_path)? {
+        return Ok(None);
+    }
+    
+    let backup_path = data_dir.join(format!("activity.db.corrupt-{}", Utc::now().format("%Y%m%d%H%M%S")));
+    warn!("Database {:?} is corrupted, moving it to {:?}", db_path, backup_path);
+    std::fs::rename(&db_path, &backup_path)
+        .with_context(|| format!("Failed to move the corrupted database to {:?}", backup_path))?;
+    // ジャーナルは破損したデータベースと一緒に退避する（新しいデータベースに適用されないように）
+    for suffix in ["-journal", "-wal", "-shm"] {
+        let sidecar = data_dir.join(format!("activity.db{}", suffix));
+        if sidecar.exists() {
+            let target = PathBuf::from(format!("{}{}", backup_path.display(), suffix));
+            if let Err(e) = std::fs::rename(&sidecar, &target) {
+                warn!("Failed to move {:?} aside: {}", sidecar, e);
+            }
+        }
+    }
+    
+    let conn = open_writable_database(data_dir)?;
+    restrict_file_permissions(&db_path)?;
+    let recovered_rows = salvage_rows(&conn, &backup_path);
+    info!("Recreated database {:?}, recovered {} row(s) from the corrupted file", db_path, recovered_rows);
+    
+    Ok(Some(DatabaseRecovery { backup_path, recovered_rows }))
+}
+
+/// 破損したデータベースから読み出せる行を新しいデータベースへコピーする（読み出せないテーブルは飛ばす）
+fn salvage_rows(conn: &Connection, corrupt_path: &Path) -> usize {
+    if let Err(e) = conn.execute("ATTACH DATABASE ?1 AS corrupt", params![corrupt_path.to_string_lossy()]) {
+        warn!("Failed to open the corrupted database for recovery: {}", e);
+        return 0;
+    }
+    
+    let tables = [
+        "window_data",
+        "visible_windows",
+        "calendar_events",
+        "data_analysis",
+        "daemon_state",
+    ];
+    let mut recovered = 0;
+    for table in tables {
+        // 古いバージョンのデータベースでは列が足りない場合があるため、共通の列だけをコピーする
+        let columns = |schema: &str| -> rusqlite::Result<Vec<String>> {
+            let mut stmt = conn.prepare(&format!("SELECT name FROM {}.pragma_table_info('{}')", schema, table))?;
+            let columns = stmt.query_map([], |row| row.get(0))?.collect();
+            columns
+        };
+        let copied = columns("main")
+            .and_then(|main| {
+                let corrupt = columns("corrupt")?;
+                let shared: Vec<String> = main.into_iter().filter(|column| corrupt.contains(column)).collect();
+                if shared.is_empty() {
+                    return Ok(0);
+                }
+                let list = shared.join(", ");
+                conn.execute(
+                    &format!("INSERT OR IGNORE INTO main.{table} ({list}) SELECT {list} FROM corrupt.{table}"),
+                    [],
+                )
+            });
+        match copied {
+            Ok(rows) => recovered += rows,
+            Err(e) => warn!("Could not recover table {} from the corrupted database: {}", table, e),
+        }
+    }
+    
+    if let Err(e) = conn.execute("DETACH DATABASE corrupt", []) {
+        debug!("Failed to detach the corrupted database: {}", e);
+    }
+    recovered
+}
+
+/// 旧バージョンがカレントディレクトリに作成していたデータディレクトリ
+pub const LEGACY_DATA_DIR: &str = "./data";
+
+/// 旧バージョンの `./data/activity.db` をデータディレクトリへ移行する
+///
+/// 移行先にデータベースが既にある場合は何もしない。移行した場合はtrueを返す
+pub fn migrate_legacy_storage(legacy_dir: &Path, data_dir: &Path) -> Result<bool> {
+    let legacy_db = legacy_dir.join("activity.db");
     let db_path = data_dir.join("activity.db");
-    let conn = Connection::open(&db_path).context("Failed to open database")?;
     
+    if data_dir == Path::new(IN_MEMORY_DATA_DIR) || !legacy_db.exists() {
+        return Ok(false);
+    }
+    
+    // 同じファイルを指している場合（data_dirに./dataを指定している場合など）は移行不要
+    if let (Ok(legacy), Ok(current)) = (legacy_db.canonicalize(), db_path.canonicalize()) {
+        if legacy == current {
+            return Ok(false);
+        }
+    }
+    
+    if db_path.exists() {
+        debug!("Legacy database {:?} found but {:?} already exists, skipping migration", legacy_db, db_path);
+        return Ok(false);
+    }
+    
+    create_private_dir(data_dir).context("Failed to create data directory")?;
+    
+    // 別のファイルシステムへの移動はrenameできないため、コピーして元のファイルを移行済みとして残す
+    if std::fs::rename(&legacy_db, &db_path).is_err() {
+        std::fs::copy(&legacy_db, &db_path)
+            .with_context(|| format!("Failed to copy {:?} to {:?}", legacy_db, db_path))?;
+        std::fs::rename(&legacy_db, legacy_dir.join("activity.db.migrated"))
+            .context("Failed to mark the legacy database as migrated")?;
+    }
+    restrict_file_permissions(&db_path)?;
+    
+    info!("Migrated legacy database {:?} to {:?}", legacy_db, db_path);
+    Ok(true)
+}
+
+/// テーブルを作成する（既に存在する場合は何もしない）
+fn create_schema(conn: &Connection) -> Result<()> {
     conn.execute(
         "CREATE TABLE IF NOT EXISTS window_data (
             id INTEGER PRIMARY KEY,
@@ -221,10 +771,40 @@ pub fn init_storage() -> Result<()> {
             window_id TEXT NOT NULL,
             window_title TEXT NOT NULL,
             window_class TEXT,
-            pid INTEGER
+            pid INTEGER,
+            geometry_x INTEGER,
+            geometry_y INTEGER,
+            geometry_width INTEGER,
+            geometry_height INTEGER,
+            is_fullscreen INTEGER,
+            terminal_command TEXT,
+            terminal_cwd TEXT,
+            is_idle INTEGER NOT NULL DEFAULT 0
         )",
         [],
     ).context("Failed to create window_data table")?;
+    add_missing_columns(conn, "window_data", &[
+        ("geometry_x", "INTEGER"),
+        ("geometry_y", "INTEGER"),
+        ("geometry_width", "INTEGER"),
+        ("geometry_height", "INTEGER"),
+        ("is_fullscreen", "INTEGER"),
+        ("terminal_command", "TEXT"),
+        ("terminal_cwd", "TEXT"),
+        ("is_idle", "INTEGER NOT NULL DEFAULT 0"),
+    ])?;
+    
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS visible_windows (
+            id INTEGER PRIMARY KEY,
+            sample_id INTEGER NOT NULL REFERENCES window_data(id),
+            window_id TEXT NOT NULL,
+            window_title TEXT NOT NULL,
+            window_class TEXT,
+            pid INTEGER
+        )",
+        [],
+    ).context("Failed to create visible_windows table")?;
     
     conn.execute(
         "CREATE TABLE IF NOT EXISTS calendar_events (
@@ -234,10 +814,15 @@ pub fn init_storage() -> Result<()> {
             start_time TEXT NOT NULL,
             end_time TEXT NOT NULL,
             calendar_id TEXT NOT NULL,
-            description TEXT
+            description TEXT,
+            location TEXT,
+            organizer_email TEXT,
+            is_video_call INTEGER NOT NULL DEFAULT 0,
+            response_status TEXT
         )",
         [],
     ).context("Failed to create calendar_events table")?;
+    migrate_calendar_events_columns(conn)?;
     
     conn.execute(
         "CREATE TABLE IF NOT EXISTS data_analysis (
@@ -250,39 +835,245 @@ pub fn init_storage() -> Result<()> {
         [],
     ).context("Failed to create data_analysis table")?;
     
-    info!("Database initialized at {:?}", db_path);
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS daemon_state (
+            key TEXT PRIMARY KEY,
+            value TEXT NOT NULL
+        )",
+        [],
+    ).context("Failed to create daemon_state table")?;
+    
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS calendar_sync (
+            account TEXT NOT NULL,
+            calendar_id TEXT NOT NULL,
+            sync_token TEXT NOT NULL,
+            synced_until TEXT NOT NULL,
+            PRIMARY KEY (account, calendar_id)
+        )",
+        [],
+    ).context("Failed to create calendar_sync table")?;
+    
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS calendar_event_cache (
+            account TEXT NOT NULL,
+            calendar_id TEXT NOT NULL,
+            event_id TEXT NOT NULL,
+            event TEXT NOT NULL,
+            PRIMARY KEY (account, calendar_id, event_id)
+        )",
+        [],
+    ).context("Failed to create calendar_event_cache table")?;
+    
+    Ok(())
+}
+
+/// 既存のcalendar_eventsテーブルに後から追加された列を補う
+fn migrate_calendar_events_columns(conn: &Connection) -> Result<()> {
+    add_missing_columns(conn, "calendar_events", &[
+        ("location", "TEXT"),
+        ("organizer_email", "TEXT"),
+        ("is_video_call", "INTEGER NOT NULL DEFAULT 0"),
+        ("response_status", "TEXT"),
+    ])
+}
+
+/// 既存のテーブルに後から追加された列（列名と定義）を補う
+fn add_missing_columns(conn: &Connection, table: &str, columns: &[(&str, &str)]) -> Result<()> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))
+        .with_context(|| format!("Failed to read {} schema", table))?;
+    let existing: Vec<String> = stmt.query_map([], |row| row.get::<_, String>(1))?
+        .collect::<rusqlite::Result<_>>()?;
+
+    for (name, definition) in columns {
+        if !existing.iter().any(|c| c == name) {
+            info!("Adding column {} to {}", name, table);
+            conn.execute(
+                &format!("ALTER TABLE {} ADD COLUMN {} {}", table, name, definition),
+                [],
+            ).with_context(|| format!("Failed to add column {} to {}", name, table))?;
+        }
+    }
+
     Ok(())
 }
 
+/// アクティブウィンドウの取得方法
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WindowBackend {
+    /// GNOME ShellのD-Bus（拡張機能「Window Calls」、またはunsafe modeでの `org.gnome.Shell.Eval`）
+    GnomeShell,
+
+    /// xdotool / xprop（X11、またはWayland上のXWayland）
+    X11,
+}
+
+/// セッションの種類とデスクトップ環境から、試行するアクティブウィンドウの取得方法を優先順に選ぶ
+fn select_window_backends(session_type: Option<&str>, wayland_display: bool, desktop: Option<&str>) -> Vec<WindowBackend> {
+    let is_wayland = session_type.is_some_and(|t| t.eq_ignore_ascii_case("wayland")) || wayland_display;
+    let is_gnome = desktop.unwrap_or("").to_lowercase().contains("gnome");
+    if is_wayland && is_gnome {
+        // 拡張機能がない場合はXWaylandのウィンドウだけでも取得する
+        vec![WindowBackend::GnomeShell, WindowBackend::X11]
+    } else {
+        vec![WindowBackend::X11]
+    }
+}
+
 /// アクティブウィンドウの情報を取得する
 pub fn get_active_window() -> Result<WindowInfo> {
-    // xdotoolを使用してアクティブウィンドウIDを取得
-    let output = Command::new("xdotool")
-        .arg("getactivewindow")
+    let session_type = std::env::var("XDG_SESSION_TYPE").ok();
+    let desktop = std::env::var("XDG_CURRENT_DESKTOP").ok();
+    let wayland_display = std::env::var_os("WAYLAND_DISPLAY").is_some();
+
+    let mut last_error = None;
+    for backend in select_window_backends(session_type.as_deref(), wayland_display, desktop.as_deref()) {
+        let window = match backend {
+            WindowBackend::GnomeShell => get_active_gnome_window(),
+            WindowBackend::X11 => get_active_x11_window(),
+        };
+        match window {
+            Ok(window) => {
+                trace!("Active window obtained via {:?}", backend);
+                return Ok(window);
+            }
+            Err(e) => {
+                debug!("Failed to get the active window via {:?}: {:#}", backend, e);
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error.unwrap_or_else(|| anyhow::anyhow!("No window backend available")))
+}
+
+/// GNOME Shellの拡張機能「Window Calls」のD-Busインターフェース
+const GNOME_WINDOWS_OBJECT_PATH: &str = "/org/gnome/Shell/Extensions/Windows";
+const GNOME_WINDOWS_INTERFACE: &str = "org.gnome.Shell.Extensions.Windows";
+
+/// unsafe modeの `org.gnome.Shell.Eval` で評価する、フォーカスされたウィンドウをJSONで返すスクリプト
+const GNOME_EVAL_FOCUS_WINDOW: &str = "(() => { const w = global.display.focus_window; \
+    return w ? JSON.stringify({id: w.get_id(), title: w.get_title(), wm_class: w.get_wm_class(), pid: w.get_pid(), focus: true}) : ''; })()";
+
+/// GNOME ShellのD-Bus経由でフォーカスされたウィンドウを取得する（GNOME Wayland用）
+///
+/// 拡張機能「Window Calls」を優先し、ない場合はunsafe modeでのみ使える `org.gnome.Shell.Eval` を試す
+fn get_active_gnome_window() -> Result<WindowInfo> {
+    let list_method = format!("{}.List", GNOME_WINDOWS_INTERFACE);
+    let window = match call_gdbus(&[GNOME_WINDOWS_OBJECT_PATH, &list_method]) {
+        Ok(output) => {
+            let json = parse_gdbus_string(&output)
+                .ok_or_else(|| anyhow::anyhow!("Unexpected output from {}: {}", list_method, output.trim()))?;
+            let windows: Vec<serde_json::Value> = serde_json::from_str(&json)
+                .with_context(|| format!("Failed to parse window list from {}", list_method))?;
+            windows.into_iter().find(|window| window["focus"] == true)
+        }
+        Err(extension_error) => {
+            debug!("GNOME Shell extension Window Calls is not available: {:#}", extension_error);
+            let output = call_gdbus(&["/org/gnome/Shell", "org.gnome.Shell.Eval", GNOME_EVAL_FOCUS_WINDOW])
+                .context("Install the GNOME Shell extension Window Calls to track native Wayland windows")?;
+            parse_gnome_eval_output(&output)?
+        }
+    };
+    let window = window.ok_or_else(|| anyhow::anyhow!("No focused window"))?;
+
+    let id = window["id"].as_u64()
+        .ok_or_else(|| anyhow::anyhow!("Window from GNOME Shell has no id"))?
+        .to_string();
+    // 古いバージョンの拡張機能は一覧にタイトルを含めない
+    let title = match window["title"].as_str() {
+        Some(title) => title.to_string(),
+        None => {
+            let method = format!("{}.GetTitle", GNOME_WINDOWS_INTERFACE);
+            let output = call_gdbus(&[GNOME_WINDOWS_OBJECT_PATH, &method, &id])?;
+            parse_gdbus_string(&output)
+                .ok_or_else(|| anyhow::anyhow!("Unexpected output from {}: {}", method, output.trim()))?
+        }
+    };
+
+    Ok(WindowInfo {
+        id,
+        title,
+        class: window["wm_class"].as_str().map(str::to_string),
+        pid: window["pid"].as_u64().and_then(|pid| u32::try_from(pid).ok()),
+        timestamp: Utc::now(),
+        geometry: None,
+        terminal: None,
+    })
+}
+
+/// gdbusでGNOME Shellのメソッドを呼び出し、出力をそのまま返す（引数はオブジェクトパス、メソッド、メソッドの引数の順）
+fn call_gdbus(args: &[&str]) -> Result<String> {
+    let [object_path, method, method_args @ ..] = args else {
+        return Err(anyhow::anyhow!("gdbus requires an object path and a method"));
+    };
+    let output = Command::new("gdbus")
+        .args(["call", "--session", "--dest", "org.gnome.Shell", "--object-path", object_path, "--method", method])
+        .args(method_args)
         .output()
-        .context("Failed to execute xdotool")?;
-    
+        .context("Failed to execute gdbus")?;
+
     if !output.status.success() {
-        return Err(anyhow::anyhow!("xdotool command failed"));
+        return Err(anyhow::anyhow!(
+            "gdbus call {} failed: {}", method, String::from_utf8_lossy(&output.stderr).trim()
+        ));
     }
-    
-    let window_id = String::from_utf8(output.stdout)
-        .context("Failed to parse window ID")?
-        .trim()
-        .to_string();
-    
+
+    String::from_utf8(output.stdout).context("Failed to parse gdbus output")
+}
+
+/// gdbusの戻り値の最後の文字列（例: `('[{"id": 1}]',)` や `(true, 'value')`）を取り出す
+///
+/// GVariantのテキスト形式では、文字列は `'` か `"` で囲まれ、`\` でエスケープされる
+fn parse_gdbus_string(output: &str) -> Option<String> {
+    let inner = output.trim().strip_prefix('(')?.strip_suffix(')')?.trim_end_matches(',');
+    let quote = inner.chars().last().filter(|c| *c == '\'' || *c == '"')?;
+    let start = inner.find(quote)?;
+    let quoted = inner.get(start + 1..inner.len() - 1)?;
+
+    let mut value = String::with_capacity(quoted.len());
+    let mut chars = quoted.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            value.push(c);
+            continue;
+        }
+        match chars.next()? {
+            'n' => value.push('\n'),
+            't' => value.push('\t'),
+            'r' => value.push('\r'),
+            escaped => value.push(escaped),
+        }
+    }
+    Some(value)
+}
+
+/// `org.gnome.Shell.Eval` の戻り値（`(true, '"{...}"')`）からフォーカスされたウィンドウを取り出す
+///
+/// unsafe modeでない場合は `(false, '')` が返る
+fn parse_gnome_eval_output(output: &str) -> Result<Option<serde_json::Value>> {
+    if !output.trim_start_matches('(').trim_start().starts_with("true") {
+        return Err(anyhow::anyhow!(
+            "org.gnome.Shell.Eval is disabled (GNOME Shell is not in unsafe mode); install the Window Calls extension"
+        ));
+    }
+    let result = parse_gdbus_string(output)
+        .ok_or_else(|| anyhow::anyhow!("Unexpected output from org.gnome.Shell.Eval: {}", output.trim()))?;
+    // Evalは評価結果をJSONにして返すため、スクリプトが返したJSON文字列をさらに解析する
+    let json: String = serde_json::from_str(&result).context("Failed to parse org.gnome.Shell.Eval result")?;
+    if json.is_empty() {
+        return Ok(None);
+    }
+    serde_json::from_str(&json).map(Some).context("Failed to parse focused window from org.gnome.Shell.Eval")
+}
+
+/// xdotool / xprop でアクティブウィンドウの情報を取得する
+fn get_active_x11_window() -> Result<WindowInfo> {
+    let window_id = get_active_window_id()?;
+
     debug!("Active window ID: {}", window_id);
     
     // ウィンドウタイトルを取得
-    let title_output = Command::new("xdotool")
-        .args(["getwindowname", &window_id])
-        .output()
-        .context("Failed to get window title")?;
-    
-    let title = String::from_utf8(title_output.stdout)
-        .context("Failed to parse window title")?
-        .trim()
-        .to_string();
+    let title = get_window_title(&window_id)?;
     
     // プロセスIDを取得（オプション）
     let pid = get_window_pid(&window_id).ok();
@@ -296,25 +1087,283 @@ pub fn get_active_window() -> Result<WindowInfo> {
         class,
         pid,
         timestamp: Utc::now(),
+        geometry: None,
+        terminal: None,
     })
 }
 
-/// ウィンドウのプロセスIDを取得
-fn get_window_pid(window_id: &str) -> Result<u32> {
-    let output = Command::new("xdotool")
-        .args(["getwindowpid", window_id])
+/// アクティブウィンドウのID（10進数）を取得する
+///
+/// xdotoolで取得できない場合は `xprop -root _NET_ACTIVE_WINDOW` を使う
+fn get_active_window_id() -> Result<String> {
+    let xdotool = Command::new("xdotool")
+        .arg("getactivewindow")
         .output()
-        .context("Failed to get window PID")?;
+        .context("Failed to execute xdotool")
+        .and_then(|output| {
+            if !output.status.success() {
+                return Err(anyhow::anyhow!("xdotool command failed"));
+            }
+            let window_id = String::from_utf8(output.stdout).context("Failed to parse window ID")?;
+            Ok(window_id.trim().to_string())
+        });
+    let xdotool_error = match xdotool {
+        Ok(window_id) if !window_id.is_empty() => return Ok(window_id),
+        Ok(_) => anyhow::anyhow!("xdotool returned no window ID"),
+        Err(e) => e,
+    };
+    debug!("Falling back to xprop for the active window: {:#}", xdotool_error);
+
+    let output = Command::new("xprop")
+        .args(["-root", "_NET_ACTIVE_WINDOW"])
+        .output()
+        .context("Failed to execute xprop")?;
+    let stdout = String::from_utf8(output.stdout)
+        .context("Failed to parse xprop output")?;
+
+    match parse_active_window_id(&stdout)? {
+        Some(window_id) => Ok(window_id.to_string()),
+        None => Err(anyhow::anyhow!("No active window")),
+    }
+}
+
+/// `xprop -root _NET_ACTIVE_WINDOW` の出力からウィンドウIDを取り出す
+///
+/// 出力は `_NET_ACTIVE_WINDOW(WINDOW): window id # 0x3a00007` の形式（WMによっては `, 0x0` が続く）。
+/// アクティブなウィンドウがない場合（`0x0`）はNoneを返す
+fn parse_active_window_id(output: &str) -> Result<Option<u64>> {
+    let hex_id = output.split_once('#')
+        .and_then(|(_, ids)| ids.split(',').next())
+        .map(str::trim)
+        .and_then(|id| id.strip_prefix("0x"))
+        .ok_or_else(|| anyhow::anyhow!("Unexpected xprop _NET_ACTIVE_WINDOW output: {}", output.trim()))?;
+    let window_id = u64::from_str_radix(hex_id, 16)
+        .with_context(|| format!("Invalid window ID in xprop output: {}", output.trim()))?;
+
+    Ok((window_id != 0).then_some(window_id))
+}
+
+/// 表示中のウィンドウを一度に記録する最大数（パネルやデスクトップなどを含め過ぎないようにする）
+const MAX_VISIBLE_WINDOWS: usize = 16;
+
+/// 画面に表示されているすべてのウィンドウの情報を取得（タイトルのないウィンドウは除く）
+///
+/// X11の `xdotool search --onlyvisible` を使うため、Waylandネイティブのウィンドウは取得できない
+pub fn get_visible_windows() -> Result<Vec<WindowInfo>> {
+    let output = Command::new("xdotool")
+        .args(["search", "--onlyvisible", "--name", "."])
+        .output()
+        .context("Failed to execute xdotool search")?;
     
-    if !output.status.success() {
-        return Err(anyhow::anyhow!("xdotool getwindowpid command failed"));
+    // 一致するウィンドウがない場合も終了コードが1になるため、出力が空なら空の一覧として扱う
+    if !output.status.success() && !output.stdout.is_empty() {
+        return Err(anyhow::anyhow!("xdotool search command failed"));
     }
     
-    let binding = String::from_utf8(output.stdout)
-        .context("Failed to parse PID")?;
-    let pid_str = binding.trim();
+    let stdout = String::from_utf8(output.stdout)
+        .context("Failed to parse window IDs")?;
+    
+    let timestamp = Utc::now();
+    let mut windows = Vec::new();
+    for window_id in parse_window_ids(&stdout) {
+        let title = match get_window_title(&window_id) {
+            Ok(title) if !title.is_empty() => title,
+            _ => continue,
+        };
+        windows.push(WindowInfo {
+            pid: get_window_pid(&window_id).ok(),
+            class: get_window_class(&window_id).ok(),
+            id: window_id,
+            title,
+            timestamp,
+            geometry: None,
+            terminal: None,
+        });
+        if windows.len() >= MAX_VISIBLE_WINDOWS {
+            break;
+        }
+    }
+    
+    Ok(windows)
+}
+
+/// `xdotool search` の出力（1行に1つの10進数のウィンドウID）を解析する（重複は除く）
+fn parse_window_ids(output: &str) -> Vec<String> {
+    let mut ids: Vec<String> = Vec::new();
+    for line in output.lines().map(str::trim) {
+        if line.parse::<u64>().is_ok() && !ids.iter().any(|id| id == line) {
+            ids.push(line.to_string());
+        }
+    }
+    ids
+}
+
+/// ウィンドウのタイトルを取得
+fn get_window_title(window_id: &str) -> Result<String> {
+    let output = Command::new("xdotool")
+        .args(["getwindowname", window_id])
+        .output()
+        .context("Failed to get window title")?;
+    
+    let title = String::from_utf8(output.stdout)
+        .context("Failed to parse window title")?
+        .trim()
+        .to_string();
     
-    pid_str.parse::<u32>().context("Failed to parse PID as integer")
+    Ok(title)
+}
+
+/// ウィンドウのプロセスIDを取得（xdotoolで取得できない場合はxpropの `_NET_WM_PID` を使う）
+fn get_window_pid(window_id: &str) -> Result<u32> {
+    let output = Command::new("xdotool")
+        .args(["getwindowpid", window_id])
+        .output()
+        .context("Failed to get window PID")?;
+
+    if output.status.success() {
+        let binding = String::from_utf8(output.stdout)
+            .context("Failed to parse PID")?;
+        if let Ok(pid) = binding.trim().parse::<u32>() {
+            return Ok(pid);
+        }
+    }
+
+    let output = Command::new("xprop")
+        .args(["-id", window_id, "_NET_WM_PID"])
+        .output()
+        .context("Failed to get PID for window")?;
+    let stdout = String::from_utf8(output.stdout)
+        .context("Failed to parse PID")?;
+
+    parse_wm_pid(&stdout)
+}
+
+/// `xprop -id <window> _NET_WM_PID` の出力（`_NET_WM_PID(CARDINAL) = 12345`）からプロセスIDを取り出す
+fn parse_wm_pid(output: &str) -> Result<u32> {
+    output.split_once('=')
+        .map(|(_, pid)| pid.trim())
+        .ok_or_else(|| anyhow::anyhow!("Unexpected xprop _NET_WM_PID output: {}", output.trim()))?
+        .parse::<u32>()
+        .context("Failed to parse PID as integer")
+}
+
+/// ウィンドウの位置と大きさを取得（`xdotool getwindowgeometry --shell` と `_NET_WM_STATE`）
+fn get_window_geometry(window_id: &str) -> Result<WindowGeometry> {
+    let output = Command::new("xdotool")
+        .args(["getwindowgeometry", "--shell", window_id])
+        .output()
+        .context("Failed to execute xdotool getwindowgeometry")?;
+
+    if !output.status.success() {
+        return Err(anyhow::anyhow!("xdotool getwindowgeometry command failed"));
+    }
+
+    let stdout = String::from_utf8(output.stdout)
+        .context("Failed to parse window geometry")?;
+    let mut geometry = parse_window_geometry(&stdout)?;
+
+    // 全画面表示かどうかは取得できなくても位置と大きさは記録する
+    match Command::new("xprop").args(["-id", window_id, "_NET_WM_STATE"]).output() {
+        Ok(output) => {
+            geometry.fullscreen = String::from_utf8_lossy(&output.stdout).contains("_NET_WM_STATE_FULLSCREEN");
+        }
+        Err(e) => debug!("Failed to get _NET_WM_STATE: {}", e),
+    }
+
+    Ok(geometry)
+}
+
+/// `xdotool getwindowgeometry --shell` の出力（`X=10` のような1行1項目）を解析する
+fn parse_window_geometry(output: &str) -> Result<WindowGeometry> {
+    let value = |name: &str| {
+        output.lines()
+            .find_map(|line| line.trim().strip_prefix(name)?.strip_prefix('='))
+            .ok_or_else(|| anyhow::anyhow!("Missing {} in xdotool getwindowgeometry output", name))
+    };
+
+    Ok(WindowGeometry {
+        x: value("X")?.parse().context("Failed to parse window X position")?,
+        y: value("Y")?.parse().context("Failed to parse window Y position")?,
+        width: value("WIDTH")?.parse().context("Failed to parse window width")?,
+        height: value("HEIGHT")?.parse().context("Failed to parse window height")?,
+        fullscreen: false,
+    })
+}
+
+/// ターミナルエミュレーターとみなすウィンドウクラス（大文字小文字を区別しない部分一致）
+const TERMINAL_CLASSES: &[&str] = &[
+    "terminal", "konsole", "xterm", "rxvt", "alacritty", "kitty", "terminator",
+    "tilix", "wezterm", "foot", "ghostty", "st-256color",
+];
+
+/// ターミナルエミュレーターのウィンドウかどうか
+fn is_terminal_window(window: &WindowInfo) -> bool {
+    window.class.as_deref().is_some_and(|class| {
+        let class = class.to_lowercase();
+        TERMINAL_CLASSES.iter().any(|terminal| class.contains(terminal))
+    })
+}
+
+/// `/proc/<pid>/stat` のうちターミナルの前面プロセスを探すのに使う項目
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ProcStat {
+    /// 親プロセスのID
+    ppid: u32,
+
+    /// 制御端末の前面プロセスグループのID（端末がない場合は-1）
+    tpgid: i32,
+
+    /// 起動時刻（システム起動からのクロック数）
+    start_time: u64,
+}
+
+/// `/proc/<pid>/stat` を解析する（コマンド名に空白や括弧を含む場合があるため、最後の `)` 以降を項目として読む）
+fn parse_proc_stat(stat: &str) -> Option<ProcStat> {
+    let fields: Vec<&str> = stat.get(stat.rfind(')')? + 1..)?.split_whitespace().collect();
+    Some(ProcStat {
+        ppid: fields.get(1)?.parse().ok()?,
+        tpgid: fields.get(5)?.parse().ok()?,
+        start_time: fields.get(19)?.parse().ok()?,
+    })
+}
+
+/// ターミナルのプロセス（ウィンドウのPID）から、前面で実行中のコマンドと作業ディレクトリを読む（`proc_root` は通常 `/proc`）
+///
+/// ターミナルの子のシェル（タブが複数ある場合は最後に起動したもの）の前面プロセスグループを前面のプロセスとする。
+/// コマンド名は `cmdline` の先頭のファイル名（`comm` は15文字で切られるため）、読めない場合は `comm` を使い、引数は読まない
+fn read_terminal_context(proc_root: &Path, pid: u32) -> Option<TerminalContext> {
+    let processes: HashMap<u32, ProcStat> = std::fs::read_dir(proc_root).ok()?
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let pid = entry.file_name().to_str()?.parse().ok()?;
+            let stat = parse_proc_stat(&std::fs::read_to_string(entry.path().join("stat")).ok()?)?;
+            Some((pid, stat))
+        })
+        .collect();
+
+    let (shell, shell_stat) = processes.iter()
+        .filter(|(_, stat)| stat.ppid == pid)
+        .max_by_key(|(child, stat)| (stat.start_time, **child))?;
+    let foreground = u32::try_from(shell_stat.tpgid).ok()
+        .filter(|tpgid| processes.contains_key(tpgid))
+        .unwrap_or(*shell);
+
+    let process = proc_root.join(foreground.to_string());
+    let command = std::fs::read(process.join("cmdline")).ok()
+        .and_then(|cmdline| {
+            let program = cmdline.split(|byte| *byte == 0).next()?;
+            let name = Path::new(std::str::from_utf8(program).ok()?).file_name()?;
+            Some(name.to_string_lossy().into_owned())
+        })
+        .or_else(|| std::fs::read_to_string(process.join("comm")).ok().map(|comm| comm.trim().to_string()))
+        .filter(|command| !command.is_empty());
+    let cwd = std::fs::read_link(process.join("cwd")).ok()
+        .and_then(|cwd| cwd.file_name().map(|name| name.to_string_lossy().into_owned()));
+
+    if command.is_none() && cwd.is_none() {
+        return None;
+    }
+    Some(TerminalContext { command, cwd })
 }
 
 /// ウィンドウのクラス名を取得
@@ -344,30 +1393,230 @@ fn get_window_class(window_id: &str) -> Result<String> {
     Ok(class.to_string())
 }
 
-// Google Calendar APIのレスポンス構造体
+/// Google OAuthトークンエンドポイント
+const GOOGLE_TOKEN_URL: &str = "https://oauth2.googleapis.com/token";
+
+/// トークン更新の最大試行回数
+const TOKEN_REFRESH_MAX_ATTEMPTS: u32 = 4;
+
+/// トークン更新の初回リトライ待機時間（ミリ秒、以降は倍々に増加）
+const TOKEN_REFRESH_INITIAL_BACKOFF_MS: u64 = 1000;
+
+/// Googleトークン更新のエラー
+#[derive(Debug, thiserror::Error)]
+pub enum TokenRefreshError {
+    /// リフレッシュトークンが失効・取り消し済み（再認証が必要）
+    #[error("Google refresh token is expired or revoked ({0}). Please re-authenticate by running with --wizard")]
+    InvalidGrant(String),
+    
+    /// リトライしても解消しなかった一時的なエラー
+    #[error("Google token refresh failed after {attempts} attempts: {message}")]
+    Transient { attempts: u32, message: String },
+    
+    /// その他の復旧不能なエラー
+    #[error("Google token refresh failed: {0}")]
+    Fatal(String),
+}
+
+/// Googleのエラーレスポンス（`error`, `error_description`）
 #[derive(Debug, Deserialize)]
-struct EventTime {
-    #[serde(rename = "dateTime")]
-    date_time: Option<String>,
-    date: Option<String>,
+struct GoogleErrorResponse {
+    error: String,
+    #[serde(default)]
+    error_description: Option<String>,
+}
+
+impl GoogleErrorResponse {
+    fn message(&self) -> String {
+        match &self.error_description {
+            Some(description) => format!("{}: {}", self.error, description),
+            None => self.error.clone(),
+        }
+    }
+}
+
+/// Googleアカウントの認証情報でアクセストークンを取得できるか確認する（`--diagnose` 用）
+pub async fn verify_google_credentials(config: &GoogleCalendarConfig) -> std::result::Result<(), TokenRefreshError> {
+    refresh_access_token(GOOGLE_TOKEN_URL, &config.client_id, &config.client_secret, &config.refresh_token)
+        .await
+        .map(|_| ())
+}
+
+/// トークン更新リクエストの内容をログ出力用に整形する（認証情報はすべてマスクする）
+fn describe_refresh_form(client_id: &str, client_secret: &str, refresh_token: &str) -> String {
+    format!(
+        "Form data: client_id={}, client_secret={}, refresh_token={}",
+        mask_token(client_id),
+        mask_token(client_secret),
+        mask_token(refresh_token)
+    )
+}
+
+/// リフレッシュトークンを使ってアクセストークンを取得する（`token_url` は通常 `GOOGLE_TOKEN_URL`、テストではモックサーバー）
+///
+/// 5xxや429、通信エラーは指数バックオフでリトライし、`invalid_grant` は再認証を促すエラーを返す
+async fn refresh_access_token(
+    token_url: &str,
+    client_id: &str,
+    client_secret: &str,
+    refresh_token: &str,
+) -> std::result::Result<String, TokenRefreshError> {
+    debug!("Sending token refresh request to {}", token_url);
+    
+    let form_data = [
+        ("client_id", client_id),
+        ("client_secret", client_secret),
+        ("refresh_token", refresh_token),
+        ("grant_type", "refresh_token"),
+    ];
+    
+    debug!("{}", describe_refresh_form(client_id, client_secret, refresh_token));
+    
+    let client = crate::utils::http_client();
+    let mut backoff = Duration::from_millis(TOKEN_REFRESH_INITIAL_BACKOFF_MS);
+    let mut last_error = String::new();
+    
+    for attempt in 1..=TOKEN_REFRESH_MAX_ATTEMPTS {
+        if attempt > 1 {
+            warn!("Retrying token refresh in {}ms (attempt {}/{})", 
+                  backoff.as_millis(), attempt, TOKEN_REFRESH_MAX_ATTEMPTS);
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+        
+        let res = match client.post(token_url).form(&form_data).send().await {
+            Ok(res) => res,
+            Err(e) => {
+                error!("Failed to send token refresh request: {}", e);
+                last_error = e.to_string();
+                continue;
+            }
+        };
+        
+        let status = res.status();
+        let body = res.text().await.unwrap_or_default();
+        
+        if status.is_success() {
+            debug!("Token refresh request successful: {}", status);
+            
+            let json_response: serde_json::Value = serde_json::from_str(&body)
+                .map_err(|e| TokenRefreshError::Fatal(format!("Failed to parse token response: {}", e)))?;
+            
+            // JSONレスポンスをデバッグ出力（トークン自体は隠す）
+            let keys = json_response.as_object()
+                .map(|obj| obj.keys().cloned().collect::<Vec<_>>())
+                .unwrap_or_default();
+            debug!("Token response keys: {:?}", keys);
+            
+            return match json_response.get("access_token").and_then(|t| t.as_str()) {
+                Some(token_str) => {
+                    debug!("Successfully obtained access token");
+                    Ok(token_str.to_string())
+                }
+                None => {
+                    // レスポンス本体にはトークンが含まれ得るためキーのみ出力
+                    error!("No access_token in response (keys: {:?})", keys);
+                    Err(TokenRefreshError::Fatal("No access_token in token response".to_string()))
+                }
+            };
+        }
+        
+        let google_error = serde_json::from_str::<GoogleErrorResponse>(&body).ok();
+        let message = google_error
+            .as_ref()
+            .map(|e| e.message())
+            .unwrap_or_else(|| format!("HTTP {}", status));
+        error!("Token refresh request failed: {} - {}", status, message);
+        
+        if google_error.as_ref().is_some_and(|e| e.error == "invalid_grant") {
+            return Err(TokenRefreshError::InvalidGrant(message));
+        }
+        
+        if status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            last_error = message;
+            continue;
+        }
+        
+        return Err(TokenRefreshError::Fatal(message));
+    }
+    
+    Err(TokenRefreshError::Transient {
+        attempts: TOKEN_REFRESH_MAX_ATTEMPTS,
+        message: last_error,
+    })
+}
+
+/// 設定されたすべてのGoogleアカウントからカレンダーイベントを取得し、重複を除いて結合する
+///
+/// 一部のアカウントで失敗しても他のアカウントの結果は返す（すべて失敗した場合のみエラー）
+///
+/// 取得したイベントは `data_dir` のデータベースに保存し、次回からは変更分だけを取得する
+pub async fn get_all_calendar_events(settings: &GoogleCalendarSettings, data_dir: &str) -> Result<Vec<CalendarEvent>> {
+    let mut results = Vec::new();
+    
+    for account in settings.accounts() {
+        if !account.enabled {
+            continue;
+        }
+        results.push(get_calendar_events(account, data_dir).await);
+    }
+    
+    merge_account_events(results)
+}
+
+/// アカウントごとの取得結果を結合する（イベントID + カレンダーIDで重複を除く）
+fn merge_account_events(results: Vec<Result<Vec<CalendarEvent>>>) -> Result<Vec<CalendarEvent>> {
+    let mut all_events: Vec<CalendarEvent> = Vec::new();
+    let mut seen = std::collections::HashSet::new();
+    let mut last_error = None;
+    let mut succeeded = 0;
+    
+    for (i, result) in results.into_iter().enumerate() {
+        match result {
+            Ok(events) => {
+                succeeded += 1;
+                for event in events {
+                    if seen.insert((event.id.clone(), event.calendar_id.clone())) {
+                        all_events.push(event);
+                    }
+                }
+            }
+            Err(e) => {
+                error!("Failed to get calendar events for account #{}: {:#}", i + 1, e);
+                last_error = Some(e);
+            }
+        }
+    }
+    
+    match last_error {
+        Some(e) if succeeded == 0 => Err(e),
+        _ => Ok(all_events),
+    }
 }
 
-/// カレンダーイベントを取得
-pub async fn get_calendar_events(config: &GoogleCalendarConfig) -> Result<Vec<CalendarEvent>> {
+/// カレンダーイベントを取得（同期トークンを使い、前回からの変更分だけを取得する）
+pub async fn get_calendar_events(config: &GoogleCalendarConfig, data_dir: &str) -> Result<Vec<CalendarEvent>> {
     debug!("Getting calendar events from Google Calendar API");
     
-    // 認証情報のデバッグ出力
-    debug!("Calendar IDs: {}", config.calendar_ids);
+    // 認証情報のデバッグ出力（カレンダーIDはメールアドレスを含むためマスク）
+    debug!("Calendar IDs: {}", config.calendar_ids
+        .split(',')
+        .map(|id| mask_token(id.trim()))
+        .collect::<Vec<_>>()
+        .join(","));
     
     // 現在時刻を取得
     let now = Utc::now();
     
-    // 開始時刻（1時間前）と終了時刻（24時間後）を設定
+    // 開始時刻（1時間前）と終了時刻（24時間後）を設定（この範囲のイベントを返す）
     let time_min = now - chrono::Duration::hours(1);
     let time_max = now + chrono::Duration::hours(24);
     
     debug!("Time range: {} to {}", time_min.to_rfc3339(), time_max.to_rfc3339());
     
+    // 除外パターン（設定の読み込み時に検証済み）
+    let exclude_patterns = config.exclude_event_regexes()?;
+    
     // OAuth2認証情報を構築
     let client_id = config.client_id.clone();
     let client_secret = config.client_secret.clone();
@@ -399,69 +1648,7 @@ pub async fn get_calendar_events(config: &GoogleCalendarConfig) -> Result<Vec<Ca
     let token = if !refresh_token.is_empty() {
         debug!("Attempting to get access token using refresh token");
         
-        // リフレッシュトークンを使ってアクセストークンを取得
-        let token_url = "https://oauth2.googleapis.com/token";
-        debug!("Sending token refresh request to {}", token_url);
-        
-        let form_data = [
-            ("client_id", secret.client_id.as_str()),
-            ("client_secret", secret.client_secret.as_str()),
-            ("refresh_token", refresh_token.as_str()),
-            ("grant_type", "refresh_token"),
-        ];
-        
-        debug!("Form data: client_id={}, refresh_token={}...", 
-               &secret.client_id, 
-               &refresh_token.chars().take(10).collect::<String>());
-        
-        let response = reqwest::Client::new()
-            .post(token_url)
-            .form(&form_data)
-            .send()
-            .await;
-        
-        match response {
-            Ok(res) => {
-                let status = res.status();
-                if status.is_success() {
-                    debug!("Token refresh request successful: {}", status);
-                    
-                    let json_response = res.json::<serde_json::Value>().await;
-                    match json_response {
-                        Ok(access_token) => {
-                            // JSONレスポンスをデバッグ出力（トークン自体は隠す）
-                            debug!("Token response keys: {:?}", 
-                                   access_token.as_object()
-                                       .map(|obj| obj.keys().collect::<Vec<_>>())
-                                       .unwrap_or_default());
-                            
-                            if let Some(token_str) = access_token.get("access_token").and_then(|t| t.as_str()) {
-                                debug!("Successfully obtained access token");
-                                token_str.to_string()
-                            } else {
-                                error!("No access_token in response: {:?}", access_token);
-                                return Err(anyhow::anyhow!("Failed to get access token from refresh token"));
-                            }
-                        }
-                        Err(e) => {
-                            error!("Failed to parse token response: {}", e);
-                            return Err(anyhow::anyhow!("Failed to parse token response: {}", e));
-                        }
-                    }
-                } else {
-                    let error_text = match res.text().await {
-                        Ok(text) => text,
-                        Err(_) => "Failed to read error response".to_string()
-                    };
-                    error!("Token refresh request failed: {} - {}", status, error_text);
-                    return Err(anyhow::anyhow!("Token refresh request failed with status: {}", status));
-                }
-            }
-            Err(e) => {
-                error!("Failed to send token refresh request: {}", e);
-                return Err(anyhow::anyhow!("Failed to send token refresh request: {}", e));
-            }
-        }
+        refresh_access_token(GOOGLE_TOKEN_URL, &secret.client_id, &secret.client_secret, &refresh_token).await?
     } else {
         // リフレッシュトークンがない場合は、通常のブラウザフロー認証
         debug!("No refresh token available, using browser authentication flow");
@@ -478,9 +1665,6 @@ pub async fn get_calendar_events(config: &GoogleCalendarConfig) -> Result<Vec<Ca
     
     debug!("Access token obtained, length: {}", token.len());
     
-    // HTTP クライアントを初期化
-    let client = reqwest::Client::new();
-    
     // カレンダーIDのリストを取得（カンマ区切り文字列から）
     let calendar_ids_str = config.calendar_ids.trim();
     let calendar_ids: Vec<&str> = if calendar_ids_str.is_empty() {
@@ -493,9 +1677,10 @@ pub async fn get_calendar_events(config: &GoogleCalendarConfig) -> Result<Vec<Ca
     
     debug!("Fetching events from {} calendars", calendar_ids.len());
     
+    let account = calendar_account_key(config);
     let mut all_events = Vec::new();
     
-    // 各カレンダーからイベントを取得
+    // 各カレンダーの変更を同期し、保存済みのイベントから取得期間内のものを取り出す
     for calendar_id in calendar_ids {
         let calendar_id = calendar_id.trim();
         if calendar_id.is_empty() {
@@ -503,125 +1688,22 @@ pub async fn get_calendar_events(config: &GoogleCalendarConfig) -> Result<Vec<Ca
             continue;
         }
         
-        debug!("Fetching events from calendar: {}", calendar_id);
-        
-        // Google Calendar API URLを構築
-        let endpoint = format!(
-            "https://www.googleapis.com/calendar/v3/calendars/{}/events",
-            urlencoding::encode(calendar_id)
-        );
-        
-        let mut url = url::Url::parse(&endpoint)?;
-        url.query_pairs_mut()
-            .append_pair("timeMin", &time_min.to_rfc3339())
-            .append_pair("timeMax", &time_max.to_rfc3339())
-            .append_pair("singleEvents", "true")
-            .append_pair("orderBy", "startTime")
-            .append_pair("maxResults", "100");
-        
-        debug!("Calendar API URL: {}", url);
+        debug!("Syncing events from calendar: {}", mask_token(calendar_id));
         
-        // APIリクエストを実行
-        let response = client.get(url)
-            .header("Authorization", format!("Bearer {}", token))
-            .send()
-            .await;
+        // 同期に失敗した場合も前回までに保存したイベントを使う
+        if let Err(e) = sync_calendar(GOOGLE_CALENDAR_API_BASE, &token, data_dir, &account, calendar_id, now).await {
+            error!("Failed to sync calendar {}: {:#}", mask_token(calendar_id), e);
+        }
         
-        match response {
-            Ok(res) => {
-                let status = res.status();
-                if status.is_success() {
-                    debug!("Calendar API request successful: {}", status);
-                    
-                    // レスポンスをテキストとして保存
-                    let response_text = res.text().await
-                        .context("Failed to get response text")?;
-                    
-                    debug!("Response length: {} bytes", response_text.len());
-                    
-                    // 最初の1000文字だけデバッグ出力
-                    if response_text.len() > 1000 {
-                        debug!("Response preview: {}...", &response_text[..1000]);
-                    } else {
-                        debug!("Full response: {}", response_text);
-                    }
-                    
-                    // テキストからJSONにパース
-                    let parse_result: Result<serde_json::Value, _> = serde_json::from_str(&response_text);
-                    
-                    match parse_result {
-                        Ok(json_response) => {
-                            // イベント数を確認
-                            if let Some(items) = json_response.get("items").and_then(|i| i.as_array()) {
-                                debug!("Retrieved {} events from calendar {}", items.len(), calendar_id);
-                                
-                                for event in items {
-                                    debug!("Processing event: {}", 
-                                           event.get("summary")
-                                               .and_then(|s| s.as_str())
-                                               .unwrap_or("Unknown"));
-                                    
-                                    let id = event.get("id").and_then(|v| v.as_str());
-                                    let summary = event.get("summary").and_then(|v| v.as_str());
-                                    
-                                    if let (Some(id), Some(summary)) = (id, summary) {
-                                        let start_obj = event.get("start");
-                                        let end_obj = event.get("end");
-                                        
-                                        // 開始時刻と終了時刻のデバッグ出力
-                                        debug!("Event time data - start: {:?}, end: {:?}", start_obj, end_obj);
-                                        
-                                        // 開始時刻と終了時刻をパース
-                                        match (parse_event_time_from_json(start_obj), parse_event_time_from_json(end_obj)) {
-                                            (Ok(start_time), Ok(end_time)) => {
-                                                debug!("Successfully parsed event times: {} to {}", 
-                                                       start_time.to_rfc3339(), 
-                                                       end_time.to_rfc3339());
-                                                
-                                                // カレンダーイベントを作成
-                                                let calendar_event = CalendarEvent {
-                                                    id: id.to_string(),
-                                                    title: summary.to_string(),
-                                                    start_time,
-                                                    end_time,
-                                                    calendar_id: calendar_id.to_string(),
-                                                    description: event.get("description")
-                                                        .and_then(|v| v.as_str())
-                                                        .map(|s| s.to_string()),
-                                                };
-                                                
-                                                all_events.push(calendar_event);
-                                            }
-                                            (Err(e1), _) => {
-                                                error!("Failed to parse event start time: {}", e1);
-                                            }
-                                            (_, Err(e2)) => {
-                                                error!("Failed to parse event end time: {}", e2);
-                                            }
-                                        }
-                                    } else {
-                                        warn!("Event missing id or summary: {:?}", event);
-                                    }
-                                }
-                            } else {
-                                warn!("No 'items' field in response or not an array");
-                            }
-                        }
-                        Err(e) => {
-                            error!("Failed to parse response as JSON: {}", e);
-                        }
-                    }
-                } else {
-                    let error_text = match res.text().await {
-                        Ok(text) => text,
-                        Err(_) => "Failed to read error response".to_string()
-                    };
-                    error!("Calendar API request failed: {} - {}", status, error_text);
-                }
+        for calendar_event in load_cached_calendar_events(data_dir, &account, calendar_id)? {
+            if calendar_event.end_time <= time_min || calendar_event.start_time >= time_max {
+                continue;
             }
-            Err(e) => {
-                error!("Failed to send request to Google Calendar API: {}", e);
+            if is_excluded_event(&calendar_event, &exclude_patterns) {
+                debug!("Excluding calendar event '{}' matched by exclude_event_patterns", calendar_event.title);
+                continue;
             }
+            all_events.push(calendar_event);
         }
     }
     
@@ -643,112 +1725,654 @@ pub async fn get_calendar_events(config: &GoogleCalendarConfig) -> Result<Vec<Ca
     Ok(all_events)
 }
 
-/// JSONからイベント時間を解析
-fn parse_event_time_from_json(time_obj: Option<&serde_json::Value>) -> Result<DateTime<Utc>> {
-    if let Some(time_obj) = time_obj {
-        // dateTimeフィールドを確認
-        if let Some(date_time) = time_obj.get("dateTime").and_then(|dt| dt.as_str()) {
-            debug!("Parsing dateTime: {}", date_time);
-            // RFC3339形式の日時文字列をパース
-            let dt = DateTime::parse_from_rfc3339(date_time)
-                .context("Failed to parse event datetime")?
-                .with_timezone(&Utc);
-            Ok(dt)
-        } 
-        // dateフィールドを確認（終日イベント）
-        else if let Some(date) = time_obj.get("date").and_then(|d| d.as_str()) {
-            debug!("Parsing date: {}", date);
-            // 終日イベントの場合は日付のみが設定されている
-            // この場合、時間は00:00:00として処理
-            let naive_date = chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d")
-                .context("Failed to parse event date")?;
-            let naive_datetime = naive_date.and_hms_opt(0, 0, 0)
-                .ok_or_else(|| anyhow::anyhow!("Invalid time"))?;
-            let dt = DateTime::<Utc>::from_naive_utc_and_offset(naive_datetime, Utc);
-            Ok(dt)
-        } else {
-            Err(anyhow::anyhow!("Event time object has neither dateTime nor date: {:?}", time_obj))
-        }
-    } else {
-        Err(anyhow::anyhow!("Event has no timing information"))
-    }
+/// Google Calendar APIのベースURL
+const GOOGLE_CALENDAR_API_BASE: &str = "https://www.googleapis.com/calendar/v3";
+
+/// フル同期で取得する期間（現在から何日先までか）
+///
+/// 取得期間の終わりが24時間以内に迫ったら、期間をずらしてフル同期し直す
+const CALENDAR_FULL_SYNC_DAYS: i64 = 7;
+
+/// 1回の同期で取得するページ数の上限（APIが nextPageToken を返し続けた場合の安全策）
+const CALENDAR_MAX_PAGES: usize = 20;
+
+/// 同期状態を保存するアカウントの識別子（複数のアカウントの "primary" を区別する）
+///
+/// リフレッシュトークンそのものは保存しない。値が変わってもフル同期し直すだけで済む
+fn calendar_account_key(config: &GoogleCalendarConfig) -> String {
+    use std::hash::{Hash, Hasher};
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    (&config.client_id, &config.refresh_token).hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
 }
 
-/// イベント時間をUTC DateTimeに変換
-fn parse_event_time(event_time: &Option<EventTime>) -> Result<DateTime<Utc>> {
-    if let Some(event_time) = event_time {
-        if let Some(date_time) = &event_time.date_time {
-            // RFC3339形式の日時文字列をパース
-            let dt = DateTime::parse_from_rfc3339(date_time)
-                .context("Failed to parse event datetime")?
-                .with_timezone(&Utc);
-            Ok(dt)
-        } else if let Some(date) = &event_time.date {
-            // 終日イベントの場合は日付のみが設定されている
-            // この場合、時間は00:00:00として処理
-            let naive_date = chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d")
-                .context("Failed to parse event date")?;
-            let naive_datetime = naive_date.and_hms_opt(0, 0, 0)
-                .ok_or_else(|| anyhow::anyhow!("Invalid time"))?;
-            let dt = DateTime::<Utc>::from_naive_utc_and_offset(naive_datetime, Utc);
-            Ok(dt)
-        } else {
-            Err(anyhow::anyhow!("Event has neither date_time nor date"))
-        }
-    } else {
-        Err(anyhow::anyhow!("Event has no timing information"))
-    }
+/// Calendar APIへのイベント一覧のリクエストの種類
+enum CalendarSyncRequest<'a> {
+    /// 期間を指定してすべてのイベントを取得する
+    Full {
+        time_min: DateTime<Utc>,
+        time_max: DateTime<Utc>,
+    },
+    
+    /// 同期トークン以降に変更されたイベントだけを取得する
+    Incremental(&'a str),
 }
 
-/// 最近のデータを取得
-pub fn get_recent_data() -> Result<Vec<CollectedData>> {
-    let db_path = Path::new("./data").join("activity.db");
-    if !db_path.exists() {
-        return Ok(Vec::new());
+/// Calendar APIのイベント一覧の1ページ分のレスポンス
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CalendarEventsPage {
+    #[serde(default)]
+    items: Vec<serde_json::Value>,
+    next_page_token: Option<String>,
+    next_sync_token: Option<String>,
+}
+
+/// 全ページ分のイベントと、次回の差分取得に使う同期トークン
+#[derive(Debug, Default)]
+struct CalendarChanges {
+    items: Vec<serde_json::Value>,
+    next_sync_token: Option<String>,
+}
+
+/// カレンダーの変更をGoogleから取得し、保存済みのイベントに反映する
+///
+/// 同期トークンがあれば前回からの変更分だけを取得する。トークンが失効した場合（410 Gone）や
+/// フル同期した期間の終わりが近づいた場合は、期間を指定してすべてのイベントを取得し直す
+async fn sync_calendar(
+    api_base: &str,
+    token: &str,
+    data_dir: &str,
+    account: &str,
+    calendar_id: &str,
+    now: DateTime<Utc>,
+) -> Result<()> {
+    let sync_token = load_calendar_sync_state(data_dir, account, calendar_id)?
+        .filter(|(_, synced_until)| *synced_until >= now + chrono::Duration::hours(24))
+        .map(|(sync_token, _)| sync_token);
+    
+    if let Some(sync_token) = sync_token {
+        match fetch_calendar_changes(api_base, token, calendar_id, CalendarSyncRequest::Incremental(&sync_token)).await? {
+            Some(changes) => {
+                debug!("Retrieved {} changed events from calendar {}", changes.items.len(), mask_token(calendar_id));
+                return apply_calendar_changes(data_dir, account, calendar_id, &changes, None);
+            }
+            None => warn!("Sync token for calendar {} has expired, performing a full sync", mask_token(calendar_id)),
+        }
     }
     
-    let conn = Connection::open(&db_path)
-        .context("Failed to open database")?;
-    
-    // 直近15分のウィンドウデータを取得
-    let cutoff_time = (Utc::now() - chrono::Duration::minutes(15))
-        .to_rfc3339();
-    
-    let mut stmt = conn.prepare(
-        "SELECT timestamp, window_id, window_title, window_class, pid 
-         FROM window_data 
-         WHERE timestamp > ?1
-         ORDER BY timestamp DESC"
-    ).context("Failed to prepare statement")?;
-    
-    let window_rows = stmt.query_map(params![cutoff_time], |row| {
-        let timestamp: String = row.get(0)?;
-        let timestamp = DateTime::parse_from_rfc3339(&timestamp)
-            .map(|dt| dt.with_timezone(&Utc))
-            .unwrap_or_else(|_| Utc::now());
-        
-        Ok(WindowInfo {
-            id: row.get(1)?,
-            title: row.get(2)?,
-            class: row.get(3)?,
+    let time_min = now - chrono::Duration::hours(1);
+    let time_max = now + chrono::Duration::days(CALENDAR_FULL_SYNC_DAYS);
+    let changes = fetch_calendar_changes(api_base, token, calendar_id, CalendarSyncRequest::Full { time_min, time_max })
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("Calendar API returned 410 Gone for a full sync"))?;
+    debug!("Retrieved {} events from calendar {} (full sync)", changes.items.len(), mask_token(calendar_id));
+    apply_calendar_changes(data_dir, account, calendar_id, &changes, Some(time_max))
+}
+
+/// Calendar APIからイベント一覧を全ページ取得する（同期トークンが失効している場合はNone）
+///
+/// `CALENDAR_MAX_PAGES` で打ち切った場合は同期トークンを返さないため、次回はフル同期になる
+async fn fetch_calendar_changes(
+    api_base: &str,
+    token: &str,
+    calendar_id: &str,
+    request: CalendarSyncRequest<'_>,
+) -> Result<Option<CalendarChanges>> {
+    let client = crate::utils::http_client();
+    let endpoint = format!(
+        "{}/calendars/{}/events",
+        api_base.trim_end_matches('/'),
+        urlencoding::encode(calendar_id)
+    );
+    
+    let mut changes = CalendarChanges::default();
+    let mut page_token: Option<String> = None;
+    for page_number in 1..=CALENDAR_MAX_PAGES {
+        let mut url = url::Url::parse(&endpoint)?;
+        {
+            let mut query = url.query_pairs_mut();
+            query.append_pair("singleEvents", "true")
+                .append_pair("maxResults", "250");
+            // 同期トークンは timeMin/timeMax/orderBy と併用できない
+            match &request {
+                CalendarSyncRequest::Full { time_min, time_max } => {
+                    query.append_pair("timeMin", &time_min.to_rfc3339())
+                        .append_pair("timeMax", &time_max.to_rfc3339());
+                }
+                CalendarSyncRequest::Incremental(sync_token) => {
+                    query.append_pair("syncToken", sync_token);
+                }
+            }
+            if let Some(page_token) = &page_token {
+                query.append_pair("pageToken", page_token);
+            }
+        }
+        
+        trace!("Calendar API URL: {}", url);
+        
+        let res = client.get(url)
+            .bearer_auth(token)
+            .send()
+            .await
+            .context("Failed to send request to Google Calendar API")?;
+        
+        let status = res.status();
+        if status == reqwest::StatusCode::GONE {
+            return Ok(None);
+        }
+        
+        let response_text = res.text().await
+            .context("Failed to get response text")?;
+        if !status.is_success() {
+            anyhow::bail!("Calendar API request failed: {} - {}", status, response_text);
+        }
+        
+        trace!("Response length: {} bytes", response_text.len());
+        
+        let page: CalendarEventsPage = serde_json::from_str(&response_text)
+            .context("Failed to parse Calendar API response")?;
+        changes.items.extend(page.items);
+        
+        trace!("Calendar API page {}: {} events", page_number, changes.items.len());
+
+        match page.next_page_token {
+            Some(next_page_token) => page_token = Some(next_page_token),
+            None => {
+                changes.next_sync_token = page.next_sync_token;
+                return Ok(Some(changes));
+            }
+        }
+    }
+
+    warn!(
+        "Calendar {} has more than {} pages of events, using the first {} events",
+        mask_token(calendar_id), CALENDAR_MAX_PAGES, changes.items.len()
+    );
+    Ok(Some(changes))
+}
+
+/// 取得した変更を保存済みのイベントに反映し、同期トークンを保存する
+///
+/// `full_sync_until` を指定した場合はフル同期の結果として、保存済みのイベントを置き換える
+fn apply_calendar_changes(
+    data_dir: &str,
+    account: &str,
+    calendar_id: &str,
+    changes: &CalendarChanges,
+    full_sync_until: Option<DateTime<Utc>>,
+) -> Result<()> {
+    let mut conn = open_database(data_dir)?;
+    let tx = conn.transaction().context("Failed to start transaction")?;
+    
+    if full_sync_until.is_some() {
+        tx.execute(
+            "DELETE FROM calendar_event_cache WHERE account = ?1 AND calendar_id = ?2",
+            params![account, calendar_id],
+        ).context("Failed to clear cached calendar events")?;
+    }
+    
+    for item in &changes.items {
+        let Some(event_id) = item.get("id").and_then(|id| id.as_str()) else {
+            warn!("Skipping calendar event without id");
+            continue;
+        };
+        
+        // 削除されたイベントは status が cancelled で返される
+        if item.get("status").and_then(|status| status.as_str()) == Some("cancelled") {
+            tx.execute(
+                "DELETE FROM calendar_event_cache WHERE account = ?1 AND calendar_id = ?2 AND event_id = ?3",
+                params![account, calendar_id, event_id],
+            ).context("Failed to delete cancelled calendar event")?;
+            continue;
+        }
+        
+        match parse_calendar_event(item, calendar_id) {
+            Ok(event) => {
+                tx.execute(
+                    "INSERT OR REPLACE INTO calendar_event_cache (account, calendar_id, event_id, event)
+                     VALUES (?1, ?2, ?3, ?4)",
+                    params![account, calendar_id, event_id, serde_json::to_string(&event)?],
+                ).context("Failed to cache calendar event")?;
+            }
+            Err(e) => warn!("Skipping calendar event: {:#}", e),
+        }
+    }
+    
+    match (&changes.next_sync_token, full_sync_until) {
+        (Some(sync_token), Some(synced_until)) => {
+            tx.execute(
+                "INSERT OR REPLACE INTO calendar_sync (account, calendar_id, sync_token, synced_until)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![account, calendar_id, sync_token, synced_until.to_rfc3339()],
+            ).context("Failed to save calendar sync token")?;
+        }
+        (Some(sync_token), None) => {
+            tx.execute(
+                "UPDATE calendar_sync SET sync_token = ?3 WHERE account = ?1 AND calendar_id = ?2",
+                params![account, calendar_id, sync_token],
+            ).context("Failed to save calendar sync token")?;
+        }
+        (None, _) => {
+            warn!("Calendar API returned no sync token for {}, the next sync will be a full sync", mask_token(calendar_id));
+            tx.execute(
+                "DELETE FROM calendar_sync WHERE account = ?1 AND calendar_id = ?2",
+                params![account, calendar_id],
+            ).context("Failed to clear calendar sync token")?;
+        }
+    }
+    
+    tx.commit().context("Failed to commit calendar changes")?;
+    Ok(())
+}
+
+/// 保存済みの同期トークンと、フル同期した期間の終わり
+fn load_calendar_sync_state(data_dir: &str, account: &str, calendar_id: &str) -> Result<Option<(String, DateTime<Utc>)>> {
+    let conn = open_database(data_dir)?;
+    let state: Option<(String, String)> = conn.query_row(
+        "SELECT sync_token, synced_until FROM calendar_sync WHERE account = ?1 AND calendar_id = ?2",
+        params![account, calendar_id],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    ).optional().context("Failed to query calendar sync state")?;
+    
+    Ok(state.and_then(|(sync_token, synced_until)| {
+        let synced_until = DateTime::parse_from_rfc3339(&synced_until).ok()?.with_timezone(&Utc);
+        Some((sync_token, synced_until))
+    }))
+}
+
+/// 同期済みのカレンダーのイベントを読み込む
+fn load_cached_calendar_events(data_dir: &str, account: &str, calendar_id: &str) -> Result<Vec<CalendarEvent>> {
+    let conn = open_database(data_dir)?;
+    let mut stmt = conn.prepare(
+        "SELECT event FROM calendar_event_cache WHERE account = ?1 AND calendar_id = ?2",
+    ).context("Failed to prepare statement")?;
+    let rows = stmt.query_map(params![account, calendar_id], |row| row.get::<_, String>(0))
+        .context("Failed to query cached calendar events")?;
+    
+    let mut events = Vec::new();
+    for row in rows {
+        let json = row.context("Failed to read cached calendar event")?;
+        match serde_json::from_str::<CalendarEvent>(&json) {
+            Ok(event) => events.push(event),
+            Err(e) => warn!("Skipping unreadable cached calendar event: {}", e),
+        }
+    }
+    events.sort_by_key(|event| event.start_time);
+    Ok(events)
+}
+
+/// イベントのタイトルが除外パターンのいずれかに一致するかどうか
+fn is_excluded_event(event: &CalendarEvent, exclude_patterns: &[regex::Regex]) -> bool {
+    exclude_patterns.iter().any(|pattern| pattern.is_match(&event.title))
+}
+
+/// Google Calendar APIのイベントJSONをCalendarEventに変換する
+fn parse_calendar_event(event: &serde_json::Value, calendar_id: &str) -> Result<CalendarEvent> {
+    let str_field = |value: Option<&serde_json::Value>| {
+        value.and_then(|v| v.as_str()).map(|s| s.to_string())
+    };
+
+    let id = str_field(event.get("id"))
+        .ok_or_else(|| anyhow::anyhow!("Event missing id: {:?}", event))?;
+    let title = str_field(event.get("summary"))
+        .ok_or_else(|| anyhow::anyhow!("Event missing summary: {:?}", event))?;
+
+    let start_obj = event.get("start");
+    let end_obj = event.get("end");
+    debug!("Event time data - start: {:?}, end: {:?}", start_obj, end_obj);
+
+    let start_time = parse_event_time_from_json(start_obj)
+        .context("Failed to parse event start time")?;
+    let end_time = parse_event_time_from_json(end_obj)
+        .context("Failed to parse event end time")?;
+    debug!("Successfully parsed event times: {} to {}",
+           start_time.to_rfc3339(),
+           end_time.to_rfc3339());
+
+    let is_video_call = event.get("hangoutLink").is_some_and(|v| !v.is_null())
+        || event.get("conferenceData").is_some_and(|v| !v.is_null());
+
+    // 参加者のうち self が true のものが自分の回答
+    let response_status = event.get("attendees")
+        .and_then(|attendees| attendees.as_array())
+        .and_then(|attendees| attendees.iter()
+            .find(|attendee| attendee.get("self").and_then(|v| v.as_bool()) == Some(true)))
+        .and_then(|attendee| str_field(attendee.get("responseStatus")));
+
+    Ok(CalendarEvent {
+        id,
+        title,
+        start_time,
+        end_time,
+        calendar_id: calendar_id.to_string(),
+        description: str_field(event.get("description")),
+        location: str_field(event.get("location")),
+        organizer_email: str_field(event.get("organizer").and_then(|o| o.get("email"))),
+        is_video_call,
+        response_status,
+    })
+}
+
+/// JSONからイベント時間を解析
+fn parse_event_time_from_json(time_obj: Option<&serde_json::Value>) -> Result<DateTime<Utc>> {
+    if let Some(time_obj) = time_obj {
+        // dateTimeフィールドを確認
+        if let Some(date_time) = time_obj.get("dateTime").and_then(|dt| dt.as_str()) {
+            debug!("Parsing dateTime: {}", date_time);
+            // RFC3339形式の日時文字列をパース
+            let dt = DateTime::parse_from_rfc3339(date_time)
+                .context("Failed to parse event datetime")?
+                .with_timezone(&Utc);
+            Ok(dt)
+        } 
+        // dateフィールドを確認（終日イベント）
+        else if let Some(date) = time_obj.get("date").and_then(|d| d.as_str()) {
+            debug!("Parsing date: {}", date);
+            // 終日イベントの場合は日付のみが設定されている
+            // この場合、時間は00:00:00として処理
+            let naive_date = chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d")
+                .context("Failed to parse event date")?;
+            let naive_datetime = naive_date.and_hms_opt(0, 0, 0)
+                .ok_or_else(|| anyhow::anyhow!("Invalid time"))?;
+            let dt = DateTime::<Utc>::from_naive_utc_and_offset(naive_datetime, Utc);
+            Ok(dt)
+        } else {
+            Err(anyhow::anyhow!("Event time object has neither dateTime nor date: {:?}", time_obj))
+        }
+    } else {
+        Err(anyhow::anyhow!("Event has no timing information"))
+    }
+}
+
+/// 時間ブロックがTogglに登録済みかどうか（ブロックの開始時刻で判定）
+pub fn is_block_registered(data_dir: &Path, block_start: DateTime<Utc>) -> Result<bool> {
+    let db_path = data_dir.join("activity.db");
+    if !db_path.exists() {
+        return Ok(false);
+    }
+    
+    let conn = Connection::open(&db_path)
+        .context("Failed to open database")?;
+    
+    let registered: Option<i64> = conn.query_row(
+        "SELECT registered FROM data_analysis WHERE timestamp = ?1 AND registered = 1 LIMIT 1",
+        params![block_start.to_rfc3339()],
+        |row| row.get(0),
+    ).optional().context("Failed to query data analysis")?;
+    
+    Ok(registered.is_some())
+}
+
+/// 時間ブロックの分析結果を登録済みとして記録する（バックフィルの重複登録防止に使う）
+pub fn record_block_registration(
+    data_dir: &Path,
+    block_start: DateTime<Utc>,
+    activity: &str,
+    confidence: f64,
+) -> Result<()> {
+    record_block_analysis(data_dir, block_start, activity, confidence, true)
+}
+
+/// 時間ブロックの分析結果を記録する（次のブロックの確度の平滑化に使う）
+pub fn record_block_analysis(
+    data_dir: &Path,
+    block_start: DateTime<Utc>,
+    activity: &str,
+    confidence: f64,
+    registered: bool,
+) -> Result<()> {
+    let db_path = data_dir.join("activity.db");
+    let conn = Connection::open(&db_path)
+        .context("Failed to open database")?;
+    
+    conn.execute(
+        "INSERT INTO data_analysis (timestamp, activity, confidence, registered) VALUES (?1, ?2, ?3, ?4)",
+        params![block_start.to_rfc3339(), activity, confidence, registered],
+    ).context("Failed to record block analysis")?;
+    
+    Ok(())
+}
+
+/// 指定した時刻以降にTogglへ登録した活動名の一覧（`--delete-last` で自分が登録したエントリを見分けるために使う）
+pub fn get_registered_activities_since(data_dir: &Path, since: DateTime<Utc>) -> Result<Vec<String>> {
+    let db_path = data_dir.join("activity.db");
+    if !db_path.exists() {
+        return Ok(Vec::new());
+    }
+    
+    let conn = Connection::open(&db_path)
+        .context("Failed to open database")?;
+    
+    let mut stmt = conn.prepare(
+        "SELECT DISTINCT activity FROM data_analysis WHERE registered = 1 AND timestamp >= ?1"
+    ).context("Failed to prepare data analysis query")?;
+    let activities = stmt.query_map(params![since.to_rfc3339()], |row| row.get(0))
+        .context("Failed to query data analysis")?
+        .collect::<rusqlite::Result<Vec<String>>>()
+        .context("Failed to read data analysis")?;
+    
+    Ok(activities)
+}
+
+/// 記録済みの時間ブロックの分析結果
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockAnalysis {
+    /// 推定された活動内容
+    pub activity: String,
+    
+    /// 推定の確度（平滑化後）
+    pub confidence: f64,
+}
+
+/// 時間ブロックの最新の分析結果を取得（ブロックの開始時刻で判定）
+pub fn get_block_analysis(data_dir: &Path, block_start: DateTime<Utc>) -> Result<Option<BlockAnalysis>> {
+    let db_path = data_dir.join("activity.db");
+    if !db_path.exists() {
+        return Ok(None);
+    }
+    
+    let conn = Connection::open(&db_path)
+        .context("Failed to open database")?;
+    
+    conn.query_row(
+        "SELECT activity, confidence FROM data_analysis WHERE timestamp = ?1 ORDER BY id DESC LIMIT 1",
+        params![block_start.to_rfc3339()],
+        |row| Ok(BlockAnalysis { activity: row.get(0)?, confidence: row.get(1)? }),
+    ).optional().context("Failed to query data analysis")
+}
+
+/// 期間内に開始した時間ブロックの分析結果（ブロックごとに最新のもの、開始時刻順）
+pub fn get_block_analyses_between(data_dir: &Path, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<BlockAnalysis>> {
+    let db_path = data_dir.join("activity.db");
+    if !db_path.exists() {
+        return Ok(Vec::new());
+    }
+    
+    let conn = Connection::open(&db_path)
+        .context("Failed to open database")?;
+    
+    let mut stmt = conn.prepare(
+        "SELECT activity, confidence FROM data_analysis
+         WHERE id IN (
+             SELECT MAX(id) FROM data_analysis
+             WHERE timestamp >= ?1 AND timestamp < ?2
+             GROUP BY timestamp
+         )
+         ORDER BY timestamp"
+    ).context("Failed to prepare data analysis query")?;
+    let analyses = stmt.query_map(params![start.to_rfc3339(), end.to_rfc3339()], |row| {
+            Ok(BlockAnalysis { activity: row.get(0)?, confidence: row.get(1)? })
+        })
+        .context("Failed to query data analysis")?
+        .collect::<rusqlite::Result<Vec<_>>>()
+        .context("Failed to read data analysis")?;
+    
+    Ok(analyses)
+}
+
+/// 最後に分析した時間ブロックの終了時刻を保存するキー
+const LAST_ANALYZED_BLOCK_END_KEY: &str = "last_analyzed_block_end";
+
+/// 最後に分析した時間ブロックの終了時刻を取得
+pub fn get_last_analyzed_block_end(data_dir: &Path) -> Result<Option<DateTime<Utc>>> {
+    let db_path = data_dir.join("activity.db");
+    let conn = Connection::open(&db_path)
+        .context("Failed to open database")?;
+    
+    let value: Option<String> = conn.query_row(
+        "SELECT value FROM daemon_state WHERE key = ?1",
+        params![LAST_ANALYZED_BLOCK_END_KEY],
+        |row| row.get(0),
+    ).optional().context("Failed to query daemon state")?;
+    
+    Ok(value
+        .and_then(|v| DateTime::parse_from_rfc3339(&v).ok())
+        .map(|dt| dt.with_timezone(&Utc)))
+}
+
+/// 最後に分析した時間ブロックの終了時刻を保存
+pub fn set_last_analyzed_block_end(data_dir: &Path, block_end: DateTime<Utc>) -> Result<()> {
+    let db_path = data_dir.join("activity.db");
+    let conn = Connection::open(&db_path)
+        .context("Failed to open database")?;
+    
+    conn.execute(
+        "INSERT OR REPLACE INTO daemon_state (key, value) VALUES (?1, ?2)",
+        params![LAST_ANALYZED_BLOCK_END_KEY, block_end.to_rfc3339()],
+    ).context("Failed to save daemon state")?;
+    
+    Ok(())
+}
+
+/// 最近のデータを取得
+///
+/// 収集間隔に関係なく、直近の1時間ブロック分（`block_minutes`）のデータを返す
+pub fn get_recent_data(data_dir: &Path, block_minutes: u64) -> Result<Vec<CollectedData>> {
+    let db_path = data_dir.join("activity.db");
+    if !db_path.exists() {
+        return Ok(Vec::new());
+    }
+    
+    let conn = Connection::open(&db_path)
+        .context("Failed to open database")?;
+    
+    load_recent_data(&conn, block_minutes)
+}
+
+/// 指定した期間（`start` より後、`end` 以前）の保存済みデータを取得
+pub fn get_data_between(data_dir: &Path, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<CollectedData>> {
+    let db_path = data_dir.join("activity.db");
+    if !db_path.exists() {
+        return Ok(Vec::new());
+    }
+    
+    let conn = Connection::open(&db_path)
+        .context("Failed to open database")?;
+    
+    load_data_between(&conn, start, end)
+}
+
+/// 接続済みのデータベースから最近のデータを読み込む
+fn load_recent_data(conn: &Connection, block_minutes: u64) -> Result<Vec<CollectedData>> {
+    // 直近1ブロック分のウィンドウデータを取得
+    let now = Utc::now();
+    load_data_between(conn, now - chrono::Duration::minutes(block_minutes as i64), now)
+}
+
+/// 接続済みのデータベースから指定した期間のデータを読み込む
+fn load_data_between(conn: &Connection, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<CollectedData>> {
+    let mut stmt = conn.prepare(
+        "SELECT timestamp, window_id, window_title, window_class, pid, id,
+                geometry_x, geometry_y, geometry_width, geometry_height, is_fullscreen,
+                terminal_command, terminal_cwd, is_idle
+         FROM window_data
+         WHERE timestamp > ?1 AND timestamp <= ?2
+         ORDER BY timestamp DESC"
+    ).context("Failed to prepare statement")?;
+    
+    let window_rows = stmt.query_map(params![start.to_rfc3339(), end.to_rfc3339()], |row| {
+        let timestamp: String = row.get(0)?;
+        let timestamp = DateTime::parse_from_rfc3339(&timestamp)
+            .map(|dt| dt.with_timezone(&Utc))
+            .unwrap_or_else(|_| Utc::now());
+        
+        let window = WindowInfo {
+            id: row.get(1)?,
+            title: row.get(2)?,
+            class: row.get(3)?,
             pid: row.get(4)?,
             timestamp,
-        })
+            geometry: match (row.get(6)?, row.get(7)?, row.get(8)?, row.get(9)?) {
+                (Some(x), Some(y), Some(width), Some(height)) => Some(WindowGeometry {
+                    x,
+                    y,
+                    width,
+                    height,
+                    fullscreen: row.get::<_, Option<bool>>(10)?.unwrap_or(false),
+                }),
+                _ => None,
+            },
+            terminal: match (row.get(11)?, row.get(12)?) {
+                (None, None) => None,
+                (command, cwd) => Some(TerminalContext { command, cwd }),
+            },
+        };
+        Ok((row.get::<_, i64>(5)?, window, row.get::<_, bool>(13)?))
     }).context("Failed to query window data")?;
     
     // ウィンドウ情報をまとめる
     let mut windows = Vec::new();
+    let mut sample_ids = Vec::new();
+    let mut idle_flags = Vec::new();
     for window_result in window_rows {
         match window_result {
-            Ok(window) => windows.push(window),
+            Ok((sample_id, window, is_idle)) => {
+                sample_ids.push(sample_id);
+                windows.push(window);
+                idle_flags.push(is_idle);
+            }
             Err(e) => error!("Error loading window data: {}", e),
         }
     }
     
+    // サンプルごとに表示中だった他のウィンドウを取得
+    let mut visible_stmt = conn.prepare(
+        "SELECT window_id, window_title, window_class, pid
+         FROM visible_windows
+         WHERE sample_id = ?1
+         ORDER BY id"
+    ).context("Failed to prepare visible windows statement")?;
+    
+    let mut visible_windows_list = Vec::with_capacity(windows.len());
+    for (sample_id, window) in sample_ids.iter().zip(&windows) {
+        let visible_rows = visible_stmt.query_map(params![sample_id], |row| {
+            Ok(WindowInfo {
+                id: row.get(0)?,
+                title: row.get(1)?,
+                class: row.get(2)?,
+                pid: row.get(3)?,
+                timestamp: window.timestamp,
+                geometry: None,
+                terminal: None,
+            })
+        }).context("Failed to query visible windows")?;
+        
+        let mut visible = Vec::new();
+        for visible_result in visible_rows {
+            match visible_result {
+                Ok(info) => visible.push(info),
+                Err(e) => error!("Error loading visible window: {}", e),
+            }
+        }
+        visible_windows_list.push(visible);
+    }
+    
     // カレンダーイベントを取得
     // 同じ時間枠のカレンダーイベントを検索
     let mut calendar_stmt = conn.prepare(
-        "SELECT event_id, title, start_time, end_time, calendar_id, description
+        "SELECT event_id, title, start_time, end_time, calendar_id, description,
+                location, organizer_email, is_video_call, response_status
          FROM calendar_events
          WHERE start_time <= ?1 AND end_time >= ?1"
     ).context("Failed to prepare calendar statement")?;
@@ -777,6 +2401,10 @@ pub fn get_recent_data() -> Result<Vec<CollectedData>> {
                 end_time,
                 calendar_id: row.get(4)?,
                 description: row.get(5)?,
+                location: row.get(6)?,
+                organizer_email: row.get(7)?,
+                is_video_call: row.get(8)?,
+                response_status: row.get(9)?,
             })
         }).context("Failed to query calendar events")?;
         
@@ -792,7 +2420,7 @@ pub fn get_recent_data() -> Result<Vec<CollectedData>> {
     }
     
     // CollectedDataオブジェクトを作成
-    let collected_data = windows.into_iter().map(|window| {
+    let collected_data = windows.into_iter().zip(visible_windows_list).zip(idle_flags).map(|((window, visible_windows), is_idle)| {
         let events = calendar_events_map.get(&window.timestamp)
             .cloned()
             .unwrap_or_default();
@@ -801,103 +2429,695 @@ pub fn get_recent_data() -> Result<Vec<CollectedData>> {
             timestamp: window.timestamp,
             window: window.clone(),
             calendar_events: events,
-            is_idle: false, // 過去のデータは非アイドル状態として扱う
+            is_idle,
+            visible_windows,
         }
     }).collect();
     
     Ok(collected_data)
 }
 
-/// データを収集する
-pub async fn collect_data(config: &AppConfig) -> Result<()> {
-    // アクティブウィンドウ情報を取得
-    let window_info = get_active_window()
-        .context("Failed to get active window information")?;
-    
-    debug!("Collected window info: {}", window_info.title);
-    
-    // データベースに保存
-    let db_path = Path::new(&config.general.data_dir).join("activity.db");
-    let conn = Connection::open(&db_path)
-        .context("Failed to open database")?;
-    
-    conn.execute(
-        "INSERT INTO window_data (timestamp, window_id, window_title, window_class, pid)
-         VALUES (?, ?, ?, ?, ?)",
-        params![
-            window_info.timestamp.to_rfc3339(),
-            window_info.id,
-            window_info.title,
-            window_info.class,
-            window_info.pid,
-        ],
-    ).context("Failed to insert window data")?;
-    
-    // カレンダー情報の収集（設定がある場合）
-    if let Some(calendar_config) = &config.google_calendar {
-        match get_calendar_events(calendar_config).await {
-            Ok(events) => {
-                debug!("Retrieved {} calendar events", events.len());
-                
-                // カレンダーイベントをデータベースに保存
-                for event in events {
-                    conn.execute(
-                        "INSERT OR REPLACE INTO calendar_events 
-                         (event_id, title, start_time, end_time, calendar_id, description)
-                         VALUES (?, ?, ?, ?, ?, ?)",
-                        params![
-                            event.id,
-                            event.title,
-                            event.start_time.to_rfc3339(),
-                            event.end_time.to_rfc3339(),
-                            event.calendar_id,
-                            event.description,
-                        ],
-                    ).context("Failed to insert calendar event")?;
-                }
-            }
-            Err(e) => {
-                error!("Failed to get calendar events: {}", e);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::create_default_config;
+
+    fn in_memory_collector() -> DataCollector {
+        let mut config = create_default_config();
+        config.general.data_dir = IN_MEMORY_DATA_DIR.to_string();
+        DataCollector::new(config).expect("in-memory collector")
+    }
+
+    #[test]
+    fn config_reload_keeps_collection_and_idle_state() {
+        let mut collector = in_memory_collector();
+        let last = Utc::now() - chrono::Duration::seconds(30);
+        collector.last_collected_at = Some(last);
+        collector.idle_start = Some(Instant::now());
+        collector.total_idle_time = Duration::from_secs(120);
+        let block_end = collector.block_end;
+
+        let mut config = collector.config.clone();
+        config.general.idle_threshold_secs = 600;
+        config.general.collect_window_geometry = !config.general.collect_window_geometry;
+        collector.apply_config(config.clone());
+
+        assert_eq!(collector.idle_threshold, Duration::from_secs(600));
+        assert_eq!(collector.config.general.collect_window_geometry, config.general.collect_window_geometry);
+        assert_eq!(collector.last_collected_at, Some(last));
+        assert!(collector.idle_start.is_some());
+        assert_eq!(collector.total_idle_time, Duration::from_secs(120));
+        assert_eq!(collector.block_end, block_end);
+    }
+
+    #[test]
+    fn token_refresh_log_line_contains_only_masked_credentials() {
+        let client_id = "1234567890-abcdefg.apps.googleusercontent.com";
+        let client_secret = "GOCSPX-very-secret-value";
+        let refresh_token = "1//0e-refresh-token-that-must-not-leak";
+
+        let line = describe_refresh_form(client_id, client_secret, refresh_token);
+
+        for secret in [client_id, client_secret, refresh_token] {
+            assert!(!line.contains(secret), "{}", line);
+        }
+        assert!(!line.contains("very-secret"), "{}", line);
+        assert!(line.contains(&format!("refresh_token={}", mask_token(refresh_token))), "{}", line);
+        // 短すぎるシークレットは一部も表示しない
+        assert!(describe_refresh_form("id", "secret", "token").contains("client_secret=[TOKEN_TOO_SHORT_TO_MASK]"));
+    }
+
+    #[tokio::test]
+    async fn revoked_refresh_token_asks_for_reauthentication() {
+        let api_base = crate::event::tests::spawn_toggl_server(|_, _, body| {
+            assert!(body.contains("grant_type=refresh_token"), "{}", body);
+            ("400 Bad Request", r#"{"error":"invalid_grant","error_description":"Token has been expired or revoked."}"#.to_string())
+        }).await;
+
+        let error = refresh_access_token(&format!("{}/token", api_base), "client", "secret", "refresh").await.unwrap_err();
+        assert!(matches!(error, TokenRefreshError::InvalidGrant(_)), "{:?}", error);
+        assert!(error.to_string().contains("--wizard"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn transient_token_endpoint_errors_are_retried() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let counted = attempts.clone();
+        let api_base = crate::event::tests::spawn_toggl_server(move |_, _, _| {
+            match counted.fetch_add(1, Ordering::SeqCst) {
+                0 => ("503 Service Unavailable", r#"{"error":"backend_error"}"#.to_string()),
+                _ => ("200 OK", r#"{"access_token":"fresh-token","expires_in":3599}"#.to_string()),
             }
+        }).await;
+
+        let token = refresh_access_token(&format!("{}/token", api_base), "client", "secret", "refresh").await.unwrap();
+        assert_eq!(token, "fresh-token");
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn idle_backends_are_selected_from_the_session() {
+        use IdleBackend::*;
+        let cases = [
+            (Some("x11"), false, Some("XFCE"), vec![UserIdle]),
+            (None, false, None, vec![UserIdle]),
+            (Some("wayland"), true, Some("ubuntu:GNOME"), vec![GnomeMutter, ExtIdleNotify, KdeScreenSaver, UserIdle]),
+            (Some("wayland"), true, Some("KDE"), vec![KdeScreenSaver, ExtIdleNotify, GnomeMutter, UserIdle]),
+            (Some("wayland"), true, Some("Hyprland"), vec![ExtIdleNotify, GnomeMutter, KdeScreenSaver, UserIdle]),
+            (Some("wayland"), true, Some("sway"), vec![ExtIdleNotify, GnomeMutter, KdeScreenSaver, UserIdle]),
+            // XDG_SESSION_TYPEが設定されていなくてもWAYLAND_DISPLAYがあればWayland
+            (None, true, None, vec![ExtIdleNotify, GnomeMutter, KdeScreenSaver, UserIdle]),
+        ];
+        for (session_type, wayland_display, desktop, expected) in cases {
+            assert_eq!(
+                select_idle_backends(session_type, wayland_display, desktop),
+                expected,
+                "{:?} {} {:?}", session_type, wayland_display, desktop
+            );
         }
     }
-    
-    Ok(())
-}
 
-pub fn get_active_window_pid() -> Result<u32> {
-    let output = Command::new("xprop")
-        .args(["-root", "_NET_ACTIVE_WINDOW"])
-        .output()
-        .context("Failed to execute xprop")?;
+    fn sample_data(timestamp: DateTime<Utc>, title: &str, calendar_events: Vec<CalendarEvent>) -> CollectedData {
+        CollectedData {
+            timestamp,
+            window: WindowInfo {
+                id: "0x1".to_string(),
+                title: title.to_string(),
+                class: Some("code".to_string()),
+                pid: Some(42),
+                timestamp,
+                geometry: None,
+                terminal: None,
+            },
+            calendar_events,
+            is_idle: false,
+            visible_windows: Vec::new(),
+        }
+    }
 
-    let window_id_str = String::from_utf8(output.stdout)
-        .context("Failed to parse xprop output")?
-        .trim()
-        .split(' ')
-        .last()
-        .context("Failed to extract window ID")?
-        .to_string();
+    #[test]
+    fn in_memory_database_round_trips_collected_data() {
+        let collector = in_memory_collector();
+        let now = Utc::now();
+        let event = CalendarEvent {
+            id: "event-1".to_string(),
+            title: "Design review".to_string(),
+            start_time: now - chrono::Duration::minutes(5),
+            end_time: now + chrono::Duration::minutes(25),
+            calendar_id: "primary".to_string(),
+            description: None,
+            location: Some("Room 3F".to_string()),
+            organizer_email: Some("lead@example.com".to_string()),
+            is_video_call: true,
+            response_status: None,
+        };
 
-    let output = Command::new("xprop")
-        .args(["-id", &window_id_str, "_NET_WM_PID"])
-        .output()
-        .context("Failed to get PID for window")?;
+        collector.save_data(&sample_data(now, "main.rs - toggl_linux_rs", vec![event])).unwrap();
+        let idle = CollectedData { is_idle: true, ..sample_data(now - chrono::Duration::minutes(1), "main.rs - toggl_linux_rs", Vec::new()) };
+        collector.save_data(&idle).unwrap();
 
-    let pid_output = String::from_utf8(output.stdout)
-        .context("Failed to parse PID")?;
-    let pid_str = pid_output.trim();
+        let data = load_recent_data(&collector.conn, 15).unwrap();
+        assert_eq!(data.len(), 2);
+        assert_eq!(data[0].window.title, "main.rs - toggl_linux_rs");
+        assert_eq!(data[0].window.pid, Some(42));
+        // アイドル状態も読み込む（アイドル時間の集計に使う）
+        assert!(!data[0].is_idle);
+        assert!(data[1].is_idle);
+        assert_eq!(data[0].calendar_events.len(), 1);
+        assert_eq!(data[0].calendar_events[0].title, "Design review");
+        assert_eq!(data[0].calendar_events[0].location.as_deref(), Some("Room 3F"));
+        assert_eq!(data[0].calendar_events[0].organizer_email.as_deref(), Some("lead@example.com"));
+        assert!(data[0].calendar_events[0].is_video_call);
+    }
 
-    pid_str.parse::<u32>().context("Failed to parse PID as integer")
-}
+    #[test]
+    fn ignored_windows_match_class_or_title_case_insensitively() {
+        let data = sample_data(Utc::now(), "Vault - KeePassXC", Vec::new());
+        let ignore = vec!["keepassxc".to_string()];
+        assert!(is_ignored_window(&data.window, &ignore));
 
-// Helper function to mask tokens in logs
-fn mask_token(token: &str) -> String {
-    if token.len() <= 8 {
-        return "[TOKEN_TOO_SHORT_TO_MASK]".to_string();
+        let ignore = vec!["CODE".to_string()];
+        assert!(is_ignored_window(&data.window, &ignore));
+
+        let ignore = vec!["signal".to_string(), " ".to_string()];
+        assert!(!is_ignored_window(&data.window, &ignore));
     }
-    
-    let visible_prefix = &token[0..4];
-    let visible_suffix = &token[token.len() - 4..];
-    format!("{}...{}", visible_prefix, visible_suffix)
-} 
\ No newline at end of file
+
+    #[test]
+    fn legacy_database_is_migrated_exactly_once() {
+        let temp = tempfile::tempdir().unwrap();
+        let legacy_dir = temp.path().join("data");
+        let data_dir = temp.path().join("xdg").join("toggl_linux_rs");
+        std::fs::create_dir_all(&legacy_dir).unwrap();
+        std::fs::write(legacy_dir.join("activity.db"), b"legacy").unwrap();
+
+        assert!(migrate_legacy_storage(&legacy_dir, &data_dir).unwrap());
+        assert_eq!(std::fs::read(data_dir.join("activity.db")).unwrap(), b"legacy");
+        assert!(!legacy_dir.join("activity.db").exists());
+
+        // 移行済みの場合は何もしない
+        std::fs::write(legacy_dir.join("activity.db"), b"newer legacy").unwrap();
+        assert!(!migrate_legacy_storage(&legacy_dir, &data_dir).unwrap());
+        assert_eq!(std::fs::read(data_dir.join("activity.db")).unwrap(), b"legacy");
+    }
+
+    #[test]
+    fn corrupted_database_is_moved_aside_and_recreated() {
+        let temp = tempfile::tempdir().unwrap();
+        init_storage(temp.path()).unwrap();
+        assert!(recover_corrupt_database(temp.path()).unwrap().is_none());
+
+        // 電源断でヘッダーごと壊れたファイル
+        let db_path = temp.path().join("activity.db");
+        std::fs::write(&db_path, b"not a sqlite database ".repeat(256)).unwrap();
+
+        let recovery = recover_corrupt_database(temp.path()).unwrap().expect("corruption should be detected");
+        assert_eq!(recovery.recovered_rows, 0);
+        assert_eq!(std::fs::read(&recovery.backup_path).unwrap(), b"not a sqlite database ".repeat(256));
+        assert!(recovery.backup_path.file_name().unwrap().to_string_lossy().starts_with("activity.db.corrupt-"));
+
+        // 作り直したデータベースはそのまま使える
+        assert!(recover_corrupt_database(temp.path()).unwrap().is_none());
+        record_block_analysis(temp.path(), Utc::now(), "Coding", 0.9, true).unwrap();
+    }
+
+    #[test]
+    fn resuming_after_an_hour_discards_the_spanning_block() {
+        let temp = tempfile::tempdir().unwrap();
+        let mut config = create_default_config();
+        config.general.data_dir = temp.path().to_string_lossy().into_owned();
+        let mut collector = DataCollector::new(config).unwrap();
+        let now = Utc::now();
+
+        // 通常の収集間隔では何もしない
+        assert!(!collector.discard_block_after_gap(now - chrono::Duration::minutes(61)).unwrap());
+        assert!(!collector.discard_block_after_gap(now - chrono::Duration::minutes(60)).unwrap());
+
+        // サスペンド前のアイドル集計は捨て、空白をまたぐブロックは分析済みとして登録しない
+        collector.total_idle_time = Duration::from_secs(600);
+        collector.idle_start = Some(Instant::now());
+        assert!(collector.discard_block_after_gap(now).unwrap());
+        assert_eq!(collector.total_idle_time, Duration::ZERO);
+        assert!(collector.idle_start.is_none());
+        let last = get_last_analyzed_block_end(temp.path()).unwrap();
+        assert_eq!(last, Some(collector.block_end));
+        assert!(collector.block_end > now);
+
+        assert!(!collector.discard_block_after_gap(now + chrono::Duration::seconds(60)).unwrap());
+    }
+
+    #[test]
+    fn recent_data_window_follows_block_length_not_sampling_interval() {
+        let mut config = create_default_config();
+        config.general.data_dir = IN_MEMORY_DATA_DIR.to_string();
+        config.general.collect_interval_secs = 15;
+        config.general.time_block_division = 6;
+        config.general.validate().unwrap();
+        assert_eq!(config.general.block_minutes(), 10);
+
+        let collector = DataCollector::new(config).unwrap();
+        let now = Utc::now();
+        // 15秒ごとに12分間分のサンプルを保存
+        for i in 0..48 {
+            let timestamp = now - chrono::Duration::seconds(i * 15);
+            collector.save_data(&sample_data(timestamp, &format!("sample {}", i), Vec::new())).unwrap();
+        }
+
+        let data = load_recent_data(&collector.conn, collector.config.general.block_minutes()).unwrap();
+        assert_eq!(data.len(), 40);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    #[ignore = "root ignores directory permissions; run with --ignored as a regular user"]
+    fn read_only_data_dir_is_rejected_up_front() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp = tempfile::tempdir().unwrap();
+        std::fs::set_permissions(temp.path(), std::fs::Permissions::from_mode(0o500)).unwrap();
+        assert!(std::fs::write(temp.path().join("probe"), b"").is_err(), "the data dir must be read-only for this test");
+
+        let mut config = create_default_config();
+        config.general.data_dir = temp.path().to_string_lossy().into_owned();
+        let error = DataCollector::new(config).err().expect("read-only data dir must be rejected");
+        std::fs::set_permissions(temp.path(), std::fs::Permissions::from_mode(0o700)).unwrap();
+
+        assert!(is_persistent_storage_error(&error));
+        assert!(format!("{:#}", error).contains("is not writable"), "{:#}", error);
+    }
+
+    #[test]
+    fn unopenable_database_is_rejected_up_front() {
+        // 権限によらず開けないよう、データベースの場所にディレクトリを置く
+        let temp = tempfile::tempdir().unwrap();
+        std::fs::create_dir(temp.path().join("activity.db")).unwrap();
+
+        let mut config = create_default_config();
+        config.general.data_dir = temp.path().to_string_lossy().into_owned();
+        let error = DataCollector::new(config).err().expect("unopenable database must be rejected");
+
+        assert!(is_persistent_storage_error(&error), "{:#}", error);
+        assert!(format!("{:#}", error).contains("is not writable"), "{:#}", error);
+    }
+
+    #[test]
+    fn registered_blocks_are_remembered_for_backfill() {
+        let temp = tempfile::tempdir().unwrap();
+        init_storage(temp.path()).unwrap();
+        let block_start = crate::config::create_default_config().general.block_bounds(Utc::now()).0;
+
+        assert!(!is_block_registered(temp.path(), block_start).unwrap());
+        record_block_registration(temp.path(), block_start, "Coding", 0.9).unwrap();
+        assert!(is_block_registered(temp.path(), block_start).unwrap());
+        assert!(!is_block_registered(temp.path(), block_start + chrono::Duration::minutes(15)).unwrap());
+    }
+
+    #[test]
+    fn in_memory_database_excludes_data_older_than_block() {
+        let collector = in_memory_collector();
+        let now = Utc::now();
+
+        collector.save_data(&sample_data(now - chrono::Duration::minutes(30), "old", Vec::new())).unwrap();
+        collector.save_data(&sample_data(now, "recent", Vec::new())).unwrap();
+
+        let data = load_recent_data(&collector.conn, 15).unwrap();
+        assert_eq!(data.len(), 1);
+        assert_eq!(data[0].window.title, "recent");
+    }
+
+    #[test]
+    fn parses_google_calendar_event_fixture() {
+        let json: serde_json::Value = serde_json::from_str(
+            include_str!("../tests/fixtures/google_calendar_event.json")
+        ).unwrap();
+
+        let event = parse_calendar_event(&json, "primary").unwrap();
+        assert_eq!(event.id, "5q2k3h0c9v7m1n4b8d6f");
+        assert_eq!(event.title, "Weekly sync with Acme");
+        assert_eq!(event.start_time.to_rfc3339(), "2024-05-13T01:00:00+00:00");
+        assert_eq!(event.end_time.to_rfc3339(), "2024-05-13T01:30:00+00:00");
+        assert_eq!(event.location.as_deref(), Some("Shibuya Office 3F"));
+        assert_eq!(event.organizer_email.as_deref(), Some("pm@acme.example.com"));
+        assert!(event.is_video_call);
+        assert_eq!(event.response_status.as_deref(), Some("tentative"));
+
+        let mut plain = json.clone();
+        let fields = plain.as_object_mut().unwrap();
+        fields.remove("hangoutLink");
+        fields.remove("conferenceData");
+        fields.remove("location");
+        let event = parse_calendar_event(&plain, "primary").unwrap();
+        assert!(!event.is_video_call);
+        assert!(event.location.is_none());
+    }
+
+    #[test]
+    fn excluded_event_patterns_drop_blocker_events() {
+        let account = GoogleCalendarConfig {
+            enabled: true,
+            client_id: String::new(),
+            client_secret: String::new(),
+            refresh_token: String::new(),
+            calendar_ids: "primary".to_string(),
+            calendar_tags: HashMap::new(),
+            exclude_event_patterns: vec!["^Lunch$".to_string(), "(?i)focus time".to_string()],
+        };
+        let patterns = account.exclude_event_regexes().unwrap();
+        let json: serde_json::Value = serde_json::from_str(
+            include_str!("../tests/fixtures/google_calendar_event.json")
+        ).unwrap();
+        let meeting = parse_calendar_event(&json, "primary").unwrap();
+        let lunch = CalendarEvent { title: "Lunch".to_string(), ..meeting.clone() };
+        let focus = CalendarEvent { title: "Focus Time".to_string(), ..meeting.clone() };
+
+        assert!(is_excluded_event(&lunch, &patterns));
+        assert!(is_excluded_event(&focus, &patterns));
+        assert!(!is_excluded_event(&meeting, &patterns));
+    }
+
+    #[test]
+    fn migrates_calendar_events_table_without_new_columns() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute(
+            "CREATE TABLE calendar_events (
+                id INTEGER PRIMARY KEY,
+                event_id TEXT NOT NULL,
+                title TEXT NOT NULL,
+                start_time TEXT NOT NULL,
+                end_time TEXT NOT NULL,
+                calendar_id TEXT NOT NULL,
+                description TEXT
+            )",
+            [],
+        ).unwrap();
+
+        create_schema(&conn).unwrap();
+        create_schema(&conn).unwrap();
+
+        let columns: Vec<String> = conn.prepare("PRAGMA table_info(calendar_events)").unwrap()
+            .query_map([], |row| row.get(1)).unwrap()
+            .collect::<rusqlite::Result<_>>().unwrap();
+        for name in ["location", "organizer_email", "is_video_call", "response_status"] {
+            assert!(columns.iter().any(|c| c == name), "missing column {}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn no_calendar_flag_suppresses_calendar_fetching() {
+        let mut config = create_default_config();
+        config.google_calendar = Some(GoogleCalendarSettings::Single(GoogleCalendarConfig {
+            enabled: true,
+            client_id: "client".to_string(),
+            client_secret: "secret".to_string(),
+            // トークン更新のリクエストを送ると失敗する認証情報
+            refresh_token: "revoked".to_string(),
+            calendar_ids: "primary".to_string(),
+            calendar_tags: HashMap::new(),
+            exclude_event_patterns: Vec::new(),
+        }));
+        assert!(config.calendar_settings().is_some());
+
+        config.disable_calendar();
+        assert!(config.calendar_settings().is_none());
+
+        // 無効なアカウントにはリクエストを送らないため、認証情報が無効でもエラーにならない
+        let settings = config.google_calendar.as_ref().unwrap();
+        let events = get_all_calendar_events(settings, IN_MEMORY_DATA_DIR).await.unwrap();
+        assert!(events.is_empty());
+    }
+
+    #[tokio::test]
+    async fn incremental_sync_merges_changes_into_cached_events() {
+        use chrono::TimeZone;
+        use std::sync::{Arc, Mutex};
+
+        let temp = tempfile::tempdir().unwrap();
+        init_storage(temp.path()).unwrap();
+        let data_dir = temp.path().to_str().unwrap();
+
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        let api_base = crate::event::tests::spawn_toggl_server(move |_, path, _| {
+            recorded.lock().unwrap().push(path.to_string());
+            if path.contains("syncToken=token-1") {
+                ("200 OK", r#"{"items":[{"id":"review","summary":"Design review","start":{"dateTime":"2024-01-01T11:00:00Z"},"end":{"dateTime":"2024-01-01T12:00:00Z"}}],"nextSyncToken":"token-2"}"#.to_string())
+            } else if path.contains("syncToken=token-2") {
+                ("410 Gone", r#"{"error":{"code":410,"message":"Sync token is no longer valid"}}"#.to_string())
+            } else {
+                ("200 OK", r#"{"items":[{"id":"standup","summary":"Standup","start":{"dateTime":"2024-01-01T10:00:00Z"},"end":{"dateTime":"2024-01-01T10:15:00Z"}}],"nextSyncToken":"token-1"}"#.to_string())
+            }
+        }).await;
+        let titles = || load_cached_calendar_events(data_dir, "account", "primary").unwrap()
+            .into_iter()
+            .map(|event| event.title)
+            .collect::<Vec<_>>();
+        let now = Utc.with_ymd_and_hms(2024, 1, 1, 9, 0, 0).unwrap();
+
+        // 初回は期間を指定したフル同期
+        sync_calendar(&api_base, "access", data_dir, "account", "primary", now).await.unwrap();
+        assert_eq!(titles(), vec!["Standup"]);
+
+        // 2回目は同期トークンで変更分だけを取得し、保存済みのイベントに追加する
+        sync_calendar(&api_base, "access", data_dir, "account", "primary", now).await.unwrap();
+        assert_eq!(titles(), vec!["Standup", "Design review"]);
+        assert_eq!(load_calendar_sync_state(data_dir, "account", "primary").unwrap().unwrap().0, "token-2");
+
+        // 同期トークンが失効したらフル同期し直す
+        sync_calendar(&api_base, "access", data_dir, "account", "primary", now).await.unwrap();
+        assert_eq!(titles(), vec!["Standup"]);
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 4);
+        assert!(requests[0].contains("timeMin=") && !requests[0].contains("syncToken="));
+        assert!(requests[1].contains("syncToken=token-1") && !requests[1].contains("timeMin="));
+        assert!(requests[3].contains("timeMin="));
+    }
+
+    #[tokio::test]
+    async fn calendar_events_are_fetched_across_pages() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let event = |id: &str| format!(
+            r#"{{"id":"{}","summary":"{}","start":{{"dateTime":"2024-01-01T10:00:00Z"}},"end":{{"dateTime":"2024-01-01T10:15:00Z"}}}}"#,
+            id, id
+        );
+        let first_page = format!(r#"{{"items":[{},{}],"nextPageToken":"page-2"}}"#, event("standup"), event("review"));
+        let second_page = format!(r#"{{"items":[{}],"nextSyncToken":"token-1"}}"#, event("retro"));
+        let api_base = crate::event::tests::spawn_toggl_server(move |_, path, _| {
+            if path.contains("pageToken=page-2") {
+                ("200 OK", second_page.clone())
+            } else {
+                ("200 OK", first_page.clone())
+            }
+        }).await;
+        let time_min = Utc::now();
+        let request = || CalendarSyncRequest::Full { time_min, time_max: time_min + chrono::Duration::days(1) };
+
+        let changes = fetch_calendar_changes(&api_base, "access", "primary", request()).await.unwrap().unwrap();
+        let ids: Vec<_> = changes.items.iter().map(|item| item["id"].as_str().unwrap()).collect();
+        assert_eq!(ids, vec!["standup", "review", "retro"]);
+        assert_eq!(changes.next_sync_token.as_deref(), Some("token-1"));
+
+        // nextPageToken を返し続けても上限のページ数で打ち切り、次回はフル同期する
+        let pages = Arc::new(AtomicUsize::new(0));
+        let counted = pages.clone();
+        let endless = format!(r#"{{"items":[{}],"nextPageToken":"more"}}"#, event("standup"));
+        let api_base = crate::event::tests::spawn_toggl_server(move |_, _, _| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            ("200 OK", endless.clone())
+        }).await;
+        let changes = fetch_calendar_changes(&api_base, "access", "primary", request()).await.unwrap().unwrap();
+        assert_eq!(pages.load(Ordering::SeqCst), CALENDAR_MAX_PAGES);
+        assert_eq!(changes.items.len(), CALENDAR_MAX_PAGES);
+        assert!(changes.next_sync_token.is_none());
+    }
+
+    #[test]
+    fn terminal_context_is_read_from_the_foreground_process_of_the_newest_shell() {
+        let temp = tempfile::tempdir().unwrap();
+        let process = |pid: u32, comm: &str, ppid: u32, tpgid: i32, start_time: u64, cmdline: &str, cwd: &str| {
+            let dir = temp.path().join(pid.to_string());
+            std::fs::create_dir(&dir).unwrap();
+            std::fs::write(dir.join("stat"), format!(
+                "{} ({}) S {} {} {} 34816 {} 4194560 0 0 0 0 0 0 0 0 20 0 1 0 {} 0 0\n",
+                pid, comm, ppid, pid, pid, tpgid, start_time
+            )).unwrap();
+            std::fs::write(dir.join("comm"), format!("{}\n", comm)).unwrap();
+            std::fs::write(dir.join("cmdline"), cmdline.replace(' ', "\0")).unwrap();
+            std::os::unix::fs::symlink(cwd, dir.join("cwd")).unwrap();
+        };
+        process(100, "gnome-terminal-", 1, -1, 100, "/usr/libexec/gnome-terminal-server", "/home/user");
+        // 古いタブのシェル
+        process(200, "bash", 100, 200, 400, "bash", "/home/user/notes");
+        // 新しいタブのシェルと、その前面で実行中のビルド
+        process(201, "bash", 100, 300, 500, "bash", "/home/user/src/toggl_linux_rs");
+        process(300, "cargo", 201, 300, 600, "/home/user/.cargo/bin/cargo build --release", "/home/user/src/toggl_linux_rs");
+        std::fs::create_dir(temp.path().join("self")).unwrap();
+
+        let context = read_terminal_context(temp.path(), 100).unwrap();
+        assert_eq!(context, TerminalContext {
+            command: Some("cargo".to_string()),
+            cwd: Some("toggl_linux_rs".to_string()),
+        });
+        assert_eq!(context.describe(), "cargo @ toggl_linux_rs");
+
+        // 前面にコマンドがなければシェル自身、シェルがなければ取得しない
+        assert_eq!(read_terminal_context(temp.path(), 200), None);
+        std::fs::write(temp.path().join("201/stat"), "201 (bash) S 100 201 201 34816 201 4194560 0 0 0 0 0 0 0 0 20 0 1 0 500 0 0\n").unwrap();
+        assert_eq!(read_terminal_context(temp.path(), 100).unwrap().command.as_deref(), Some("bash"));
+
+        assert_eq!(
+            parse_proc_stat("42 (tmux: server (1)) S 7 42 42 0 -1 4194560 0 0 0 0 0 0 0 0 20 0 1 0 900 0 0"),
+            Some(ProcStat { ppid: 7, tpgid: -1, start_time: 900 })
+        );
+    }
+
+    #[test]
+    fn parses_window_geometry_from_xdotool_shell_output() {
+        let output = "WINDOW=62914563\nX=-8\nY=27\nWIDTH=1920\nHEIGHT=1053\nSCREEN=0\n";
+        assert_eq!(parse_window_geometry(output).unwrap(), WindowGeometry {
+            x: -8,
+            y: 27,
+            width: 1920,
+            height: 1053,
+            fullscreen: false,
+        });
+
+        assert!(parse_window_geometry("WINDOW=62914563\nX=0\nY=0\n").is_err());
+        assert!(parse_window_geometry("X=0\nY=0\nWIDTH=wide\nHEIGHT=10\n").is_err());
+    }
+
+    #[test]
+    fn window_geometry_is_stored_with_the_sample() {
+        let collector = in_memory_collector();
+        let mut data = sample_data(Utc::now() - chrono::Duration::minutes(1), "YouTube - Mozilla Firefox", Vec::new());
+        let geometry = WindowGeometry { x: 0, y: 0, width: 2560, height: 1440, fullscreen: true };
+        data.window.geometry = Some(geometry.clone());
+        collector.save_data(&data).unwrap();
+        collector.save_data(&sample_data(Utc::now(), "Slack", Vec::new())).unwrap();
+
+        let loaded = load_recent_data(&collector.conn, 15).unwrap();
+        assert_eq!(loaded[0].window.geometry, None);
+        assert_eq!(loaded[1].window.geometry, Some(geometry));
+    }
+
+    #[test]
+    fn gnome_wayland_prefers_the_gnome_shell_window_backend() {
+        assert_eq!(
+            select_window_backends(Some("wayland"), true, Some("ubuntu:GNOME")),
+            vec![WindowBackend::GnomeShell, WindowBackend::X11]
+        );
+        assert_eq!(
+            select_window_backends(None, true, Some("GNOME")),
+            vec![WindowBackend::GnomeShell, WindowBackend::X11]
+        );
+        // GNOMEでもX11のセッション、Waylandでも他のデスクトップではxdotool / xpropを使う
+        assert_eq!(select_window_backends(Some("x11"), false, Some("GNOME")), vec![WindowBackend::X11]);
+        assert_eq!(select_window_backends(Some("wayland"), true, Some("KDE")), vec![WindowBackend::X11]);
+        assert_eq!(select_window_backends(None, false, None), vec![WindowBackend::X11]);
+
+        let list = r#"('[{"wm_class":"org.gnome.Nautilus","pid":4242,"id":2401,"focus":false},{"wm_class":"firefox","pid":5151,"id":2402,"focus":true,"title":"It\'s a \\\\test - Mozilla Firefox"}]',)"#;
+        let windows: Vec<serde_json::Value> = serde_json::from_str(&parse_gdbus_string(list).unwrap()).unwrap();
+        let focused = windows.iter().find(|window| window["focus"] == true).unwrap();
+        assert_eq!(focused["wm_class"], "firefox");
+        assert_eq!(focused["title"], "It's a \\test - Mozilla Firefox");
+        assert_eq!(parse_gdbus_string("('Inbox - Mozilla Thunderbird',)\n").as_deref(), Some("Inbox - Mozilla Thunderbird"));
+
+        let eval = r#"(true, '"{\\"id\\":7,\\"title\\":\\"Terminal\\",\\"wm_class\\":\\"org.gnome.Terminal\\"}"')"#;
+        assert_eq!(parse_gnome_eval_output(eval).unwrap().unwrap()["wm_class"], "org.gnome.Terminal");
+        assert_eq!(parse_gnome_eval_output(r#"(true, '""')"#).unwrap(), None);
+        assert!(parse_gnome_eval_output("(false, '')").is_err());
+    }
+
+    #[test]
+    fn parses_active_window_id_from_xprop() {
+        let parse = |output: &str| parse_active_window_id(output).unwrap();
+        assert_eq!(parse("_NET_ACTIVE_WINDOW(WINDOW): window id # 0x3a00007\n"), Some(0x3a00007));
+        assert_eq!(parse("_NET_ACTIVE_WINDOW(WINDOW): window id # 0x4c0000a, 0x0\n"), Some(0x4c0000a));
+
+        // アクティブなウィンドウがない
+        assert_eq!(parse("_NET_ACTIVE_WINDOW(WINDOW): window id # 0x0\n"), None);
+
+        assert!(parse_active_window_id("_NET_ACTIVE_WINDOW:  not found.\n").is_err());
+        assert!(parse_active_window_id("_NET_ACTIVE_WINDOW(WINDOW): window id # 0xzz\n").is_err());
+        assert!(parse_active_window_id("").is_err());
+
+        assert_eq!(parse_wm_pid("_NET_WM_PID(CARDINAL) = 4242\n").unwrap(), 4242);
+        assert!(parse_wm_pid("_NET_WM_PID:  not found.\n").is_err());
+    }
+
+    #[test]
+    fn parses_multiple_window_ids_from_xdotool_search() {
+        let output = "62914563\n62914571\n\n  48234500  \n62914563\nDefaulting to search window name\n";
+
+        assert_eq!(parse_window_ids(output), vec!["62914563", "62914571", "48234500"]);
+        assert!(parse_window_ids("").is_empty());
+    }
+
+    #[test]
+    fn untitled_window_falls_back_to_class_or_is_skipped() {
+        let window = WindowInfo {
+            id: "62914563".to_string(),
+            title: " \t".to_string(),
+            class: Some("gimp".to_string()),
+            pid: None,
+            timestamp: Utc::now(),
+            geometry: None,
+            terminal: None,
+        };
+
+        assert_eq!(with_fallback_title(window.clone()).unwrap().title, "gimp");
+        assert!(with_fallback_title(WindowInfo { class: None, ..window.clone() }).is_none());
+        assert!(with_fallback_title(WindowInfo { class: Some(String::new()), ..window }).is_none());
+    }
+
+    #[test]
+    fn events_from_two_accounts_are_merged_without_duplicates() {
+        #[derive(Deserialize)]
+        struct Wrapper {
+            google_calendar: GoogleCalendarSettings,
+        }
+        let settings = toml::from_str::<Wrapper>(r#"
+            [[google_calendar]]
+            client_id = "work-id"
+            client_secret = "work-secret"
+            refresh_token = "work-token"
+            calendar_ids = "primary,team@example.com"
+
+            [[google_calendar]]
+            client_id = "personal-id"
+            client_secret = "personal-secret"
+            refresh_token = "personal-token"
+            calendar_ids = "team@example.com"
+        "#).unwrap().google_calendar;
+        assert_eq!(settings.accounts().len(), 2);
+
+        let json: serde_json::Value = serde_json::from_str(
+            include_str!("../tests/fixtures/google_calendar_event.json")
+        ).unwrap();
+        let event = |id: &str, calendar_id: &str| CalendarEvent {
+            id: id.to_string(),
+            ..parse_calendar_event(&json, calendar_id).unwrap()
+        };
+        let work = vec![event("standup", "team@example.com"), event("review", "primary")];
+        let personal = vec![event("standup", "team@example.com"), event("review", "team@example.com")];
+
+        let merged = merge_account_events(vec![Ok(work.clone()), Ok(personal)]).unwrap();
+        let keys: Vec<(&str, &str)> = merged.iter()
+            .map(|e| (e.id.as_str(), e.calendar_id.as_str()))
+            .collect();
+        assert_eq!(keys, vec![
+            ("standup", "team@example.com"),
+            ("review", "primary"),
+            ("review", "team@example.com"),
+        ]);
+
+        let partial = merge_account_events(vec![Err(anyhow::anyhow!("invalid_grant")), Ok(work)]).unwrap();
+        assert_eq!(partial.len(), 2);
+        assert!(merge_account_events(vec![Err(anyhow::anyhow!("invalid_grant")), Err(anyhow::anyhow!("timeout"))]).is_err());
+    }
+}
@@ -0,0 +1,312 @@
+use std::env;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+
+use crate::config::AppConfig;
+use crate::data_collector;
+use crate::event::{self, TogglClient};
+use crate::utils;
+
+/// 環境の確認に必要なコマンドと、見つからない場合の対処方法
+const REQUIRED_BINARIES: [(&str, &str); 3] = [
+    ("xdotool", "Install xdotool (e.g. `sudo apt install xdotool`); it is used to read the active window"),
+    ("xprop", "Install xprop (e.g. `sudo apt install x11-utils`); it is used to read the window class"),
+    ("notify-send", "Install notify-send (e.g. `sudo apt install libnotify-bin`); it is used for desktop notifications"),
+];
+
+/// 確認結果の状態
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckStatus {
+    /// 問題なし
+    Pass,
+
+    /// 問題あり
+    Fail,
+
+    /// 設定されていないため確認しなかった
+    Skip,
+}
+
+/// 1つの確認項目の結果
+#[derive(Debug, Clone)]
+pub struct CheckResult {
+    /// 確認項目の名前
+    pub name: String,
+
+    /// 状態
+    pub status: CheckStatus,
+
+    /// 確認結果の詳細
+    pub detail: String,
+
+    /// 失敗した場合の対処方法
+    pub hint: Option<String>,
+}
+
+impl CheckResult {
+    fn pass(name: &str, detail: impl Into<String>) -> Self {
+        Self { name: name.to_string(), status: CheckStatus::Pass, detail: detail.into(), hint: None }
+    }
+
+    fn fail(name: &str, detail: impl Into<String>, hint: impl Into<String>) -> Self {
+        Self { name: name.to_string(), status: CheckStatus::Fail, detail: detail.into(), hint: Some(hint.into()) }
+    }
+
+    fn skip(name: &str, detail: impl Into<String>) -> Self {
+        Self { name: name.to_string(), status: CheckStatus::Skip, detail: detail.into(), hint: None }
+    }
+}
+
+/// すべての確認項目を実行する
+pub async fn run_diagnostics(config: &AppConfig) -> Vec<CheckResult> {
+    let mut results = check_binaries(env::var_os("PATH").as_deref());
+    results.push(check_session_type(
+        env::var("XDG_SESSION_TYPE").ok().as_deref(),
+        env::var_os("WAYLAND_DISPLAY").is_some(),
+        env::var_os("DISPLAY").is_some(),
+    ));
+    results.push(check_internet());
+    results.push(check_toggl(config).await);
+    results.push(check_openai(config).await);
+    results.extend(check_google_calendar(config).await);
+    results.push(check_data_dir(config.general.data_dir_path()));
+    results
+}
+
+/// 確認結果を表示する
+pub fn print_report(results: &[CheckResult]) {
+    for result in results {
+        let label = match result.status {
+            CheckStatus::Pass => "PASS",
+            CheckStatus::Fail => "FAIL",
+            CheckStatus::Skip => "SKIP",
+        };
+        println!("[{}] {}: {}", label, result.name, result.detail);
+        if let Some(hint) = &result.hint {
+            println!("       -> {}", hint);
+        }
+    }
+
+    let failed = results.iter().filter(|result| result.status == CheckStatus::Fail).count();
+    println!();
+    if failed == 0 {
+        println!("すべての確認項目に問題はありませんでした");
+    } else {
+        println!("{} 件の確認項目で問題が見つかりました", failed);
+    }
+}
+
+/// `PATH` から実行可能なファイルを探す
+fn find_executable(name: &str, path: Option<&OsStr>) -> Option<PathBuf> {
+    env::split_paths(path?)
+        .map(|dir| dir.join(name))
+        .find(|candidate| is_executable(candidate))
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    path.metadata().is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+/// 必要なコマンドが `PATH` にあるか確認する
+fn check_binaries(path: Option<&OsStr>) -> Vec<CheckResult> {
+    REQUIRED_BINARIES.iter()
+        .map(|(name, hint)| match find_executable(name, path) {
+            Some(found) => CheckResult::pass(name, format!("found at {}", found.display())),
+            None => CheckResult::fail(name, "not found in PATH", *hint),
+        })
+        .collect()
+}
+
+/// ウィンドウ情報を取得できるセッションか確認する
+fn check_session_type(session_type: Option<&str>, wayland_display: bool, x_display: bool) -> CheckResult {
+    const NAME: &str = "session";
+    let is_wayland = session_type.is_some_and(|t| t.eq_ignore_ascii_case("wayland")) || wayland_display;
+
+    match (is_wayland, x_display) {
+        (false, true) => CheckResult::pass(NAME, "X11 session"),
+        (true, true) => CheckResult::fail(
+            NAME,
+            "Wayland session with XWayland; only XWayland windows can be detected",
+            "Log in with an X11 (Xorg) session to track native Wayland windows",
+        ),
+        (true, false) => CheckResult::fail(
+            NAME,
+            "Wayland session without XWayland; the active window cannot be detected",
+            "Log in with an X11 (Xorg) session or enable XWayland",
+        ),
+        (false, false) => CheckResult::fail(
+            NAME,
+            "no graphical session detected (DISPLAY is not set)",
+            "Run toggl_linux_rs from within your desktop session",
+        ),
+    }
+}
+
+/// インターネットに接続できるか確認する
+fn check_internet() -> CheckResult {
+    const NAME: &str = "internet";
+    if utils::check_internet_connection() {
+        CheckResult::pass(NAME, "reachable")
+    } else {
+        CheckResult::fail(NAME, "8.8.8.8 is not reachable", "Check your network connection or proxy settings")
+    }
+}
+
+/// Toggl APIトークンが有効か確認する
+async fn check_toggl(config: &AppConfig) -> CheckResult {
+    const NAME: &str = "Toggl";
+    let client = TogglClient::new(&config.toggl.api_token, config.toggl.workspace_id);
+
+    match client.get_workspaces().await {
+        Ok(workspaces) => CheckResult::pass(NAME, format!("authenticated, {} workspace(s) available", workspaces.len())),
+        Err(e) if event::is_unauthorized(&e) => {
+            CheckResult::fail(
+                NAME,
+                "the API token was rejected",
+                "Copy the token from https://track.toggl.com/profile and run --reconfigure toggl",
+            )
+        }
+        Err(e) => CheckResult::fail(NAME, format!("{:#}", e), "Check your network connection and try again"),
+    }
+}
+
+/// OpenAI APIキーが有効か確認する（モデル一覧を取得するだけの小さなリクエスト）
+async fn check_openai(config: &AppConfig) -> CheckResult {
+    const NAME: &str = "OpenAI";
+    if config.openai.is_some() && config.openai_config().is_none() {
+        return CheckResult::skip(NAME, "general.privacy is \"local_only\", OpenAI will not be used");
+    }
+    let Some(openai) = config.openai_config() else {
+        return CheckResult::skip(NAME, "not configured, the local analyzer will be used");
+    };
+
+    let url = format!("{}/models", openai.api_base.trim_end_matches('/'));
+    // ローカルのOpenAI互換サーバーではAPIキーを空にできる
+    let mut request = utils::http_client().get(&url);
+    if !openai.api_key.is_empty() {
+        request = request.bearer_auth(&openai.api_key);
+    }
+    let response = request.send().await;
+
+    match response {
+        Ok(res) if res.status().is_success() => CheckResult::pass(NAME, format!("authenticated against {}", openai.api_base)),
+        Ok(res) if res.status() == reqwest::StatusCode::UNAUTHORIZED => CheckResult::fail(
+            NAME,
+            "the API key was rejected",
+            "Check the key at https://platform.openai.com/api-keys and run --reconfigure openai",
+        ),
+        Ok(res) => CheckResult::fail(
+            NAME,
+            format!("unexpected response from {}: {}", url, res.status()),
+            "Check openai.api_base in the config",
+        ),
+        Err(e) => CheckResult::fail(NAME, format!("{}", e), "Check your network connection and openai.api_base"),
+    }
+}
+
+/// Googleのリフレッシュトークンが有効か確認する
+async fn check_google_calendar(config: &AppConfig) -> Vec<CheckResult> {
+    const NAME: &str = "Google Calendar";
+    let Some(settings) = &config.google_calendar else {
+        return vec![CheckResult::skip(NAME, "not configured")];
+    };
+
+    let mut results = Vec::new();
+    for (i, account) in settings.accounts().iter().enumerate() {
+        let name = format!("{} #{}", NAME, i + 1);
+        if !account.enabled {
+            results.push(CheckResult::skip(&name, "disabled"));
+            continue;
+        }
+        results.push(match data_collector::verify_google_credentials(account).await {
+            Ok(()) => CheckResult::pass(&name, "access token refreshed"),
+            Err(data_collector::TokenRefreshError::InvalidGrant(detail)) => CheckResult::fail(
+                &name,
+                format!("the refresh token is expired or revoked ({})", detail),
+                "Run --reconfigure google-calendar to sign in again",
+            ),
+            Err(e) => CheckResult::fail(&name, e.to_string(), "Check client_id/client_secret and your network connection"),
+        });
+    }
+    results
+}
+
+/// データディレクトリに書き込めるか確認する
+///
+/// 診断だけでディレクトリを作成しないよう、存在しない場合は失敗として報告する
+fn check_data_dir(data_dir: &Path) -> CheckResult {
+    const NAME: &str = "data_dir";
+    if data_dir == Path::new(data_collector::IN_MEMORY_DATA_DIR) {
+        return CheckResult::skip(NAME, "data is kept in memory");
+    }
+    if !data_dir.is_dir() {
+        return CheckResult::fail(
+            NAME,
+            format!("{} does not exist", data_dir.display()),
+            "Start toggl_linux_rs once to create it, or set general.data_dir to an existing directory",
+        );
+    }
+    
+    // 一時ファイルを書いて消せるかで書き込み権限を確認する
+    let probe = data_dir.join(".diagnose-write-test");
+    match std::fs::write(&probe, b"").and_then(|()| std::fs::remove_file(&probe)) {
+        Ok(()) => CheckResult::pass(NAME, format!("{} is writable", data_dir.display())),
+        Err(e) => CheckResult::fail(
+            NAME,
+            format!("{} is not writable: {}", data_dir.display(), e),
+            "Fix the directory permissions, free up disk space, or set general.data_dir to a writable directory",
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn missing_binary_is_reported_as_failed_check() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let bin = tempfile::tempdir().unwrap();
+        let xdotool = bin.path().join("xdotool");
+        std::fs::write(&xdotool, "#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(&xdotool, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let results = check_binaries(Some(bin.path().as_os_str()));
+        let status = |name: &str| results.iter().find(|result| result.name == name).unwrap().status;
+
+        assert_eq!(status("xdotool"), CheckStatus::Pass);
+        assert_eq!(status("xprop"), CheckStatus::Fail);
+        assert_eq!(status("notify-send"), CheckStatus::Fail);
+        assert!(results.iter().filter(|result| result.status == CheckStatus::Fail).all(|result| result.hint.is_some()));
+    }
+
+    #[test]
+    fn wayland_without_xwayland_fails_session_check() {
+        assert_eq!(check_session_type(Some("x11"), false, true).status, CheckStatus::Pass);
+        assert_eq!(check_session_type(Some("wayland"), true, false).status, CheckStatus::Fail);
+    }
+
+    #[test]
+    fn missing_data_dir_is_reported_without_being_created() {
+        let temp = tempfile::tempdir().unwrap();
+        let missing = temp.path().join("toggl_linux_rs");
+
+        let result = check_data_dir(&missing);
+        assert_eq!(result.status, CheckStatus::Fail);
+        assert!(result.detail.contains("does not exist"), "{}", result.detail);
+        assert!(!missing.exists());
+
+        let result = check_data_dir(temp.path());
+        assert_eq!(result.status, CheckStatus::Pass);
+        assert_eq!(std::fs::read_dir(temp.path()).unwrap().count(), 0);
+    }
+}
@@ -1,13 +1,65 @@
 use anyhow::{Context, Result};
-use chrono::{DateTime, Duration, Timelike, Utc};
-use log::{info, debug, error};
+use chrono::{DateTime, Duration, FixedOffset, Timelike, Utc};
+use log::{info, debug, warn};
 use reqwest::header::{HeaderMap, HeaderValue, CONTENT_TYPE};
 use serde::{Deserialize, Serialize};
 use base64::Engine;
-use urlencoding;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Instant;
 
-use crate::analysis::AnalysisResult;
-use crate::config::AppConfig;
+use crate::analysis::{AnalysisResult, Category};
+use crate::audit::{RegistrationOutcome, SkipReason};
+use crate::config::{AppConfig, IdleBehavior, RoundingMode, TrackingMode};
+use crate::data_collector::CollectedData;
+use crate::utils::{send_notification, truncate_string_safely, AuthPause};
+
+/// 同一活動とみなす類似度の閾値
+const SIMILARITY_THRESHOLD: f32 = 0.10;
+
+/// カレンダーの予定名を優先する際に必要な時間ブロックとの重なりの割合
+const CALENDAR_OVERLAP_THRESHOLD: f64 = 0.5;
+
+/// 親プロジェクトより低くても子プロジェクトを優先するスコアの差
+const SUBPROJECT_PREFERENCE_MARGIN: f64 = 0.3;
+
+/// プロジェクトを推論で選ぶのに必要な最低の一致度
+const PROJECT_MATCH_THRESHOLD: f64 = 0.5;
+
+/// このアプリケーションが作成したエントリの `created_with` の名前（既定ではこれにバージョンを付ける）
+const CREATED_WITH: &str = "toggl_linux_rs";
+
+/// 既定の `created_with`（例: `toggl_linux_rs/0.1.0`）
+pub fn default_created_with() -> String {
+    format!("{}/{}", CREATED_WITH, env!("CARGO_PKG_VERSION"))
+}
+
+/// 直前のエントリに統合できる、そのエントリの終了時刻と現在のブロックの開始時刻の最大の差（秒）
+const MERGE_MAX_GAP_SECS: i64 = 900;
+
+/// 重なりを確認するとき、ブロックより前に始まったエントリを探す範囲（時間）
+const OVERLAP_LOOKBACK_HOURS: i64 = 12;
+
+/// 実行中のエントリを作成するときに送る期間（Toggl APIの規約）
+const RUNNING_ENTRY_DURATION: i64 = -1;
+
+/// `since` で差分取得できる最も古い時刻（日数）。Togglはこれより古い `since` を受け付けない
+const TOGGL_SINCE_MAX_AGE_DAYS: i64 = 90;
+
+/// 同じ活動が続いているとみなす、直前のエントリの終了から現在のブロックの開始までの最大の間隔（秒）
+const CONTINUOUS_MAX_GAP_SECS: i64 = 60;
+
+/// 同じ活動が続いているか確認するために遡る時間（長時間続いたエントリの開始時刻まで含める）
+const CONTINUOUS_LOOKBACK_HOURS: i64 = 24;
+
+/// 活動内容もウィンドウクラスも空の場合に使う説明
+const UNKNOWN_ACTIVITY: &str = "Unknown activity";
+
+/// Togglのエントリの説明の最大長（バイト数で数えるため、マルチバイト文字の場合は控えめになる）
+const MAX_DESCRIPTION_LEN: usize = 3000;
+
+/// 説明のテンプレートで値のない項目の前後に残った文字の整理に使う正規表現（空の括弧、区切り文字、連続する空白）
+static DESCRIPTION_SEPARATOR_RE: OnceLock<(regex::Regex, regex::Regex, regex::Regex)> = OnceLock::new();
 
 /// Togglのプロジェクト情報
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -23,6 +75,72 @@ pub struct TogglProject {
     
     /// クライアントID（オプション）
     pub cid: Option<u64>,
+    
+    /// 親プロジェクトのID（サブプロジェクトの場合のみ）
+    #[serde(default)]
+    pub parent_project_id: Option<u64>,
+    
+    /// クライアント名（`cid` から `/clients` を引いて解決する）
+    #[serde(default)]
+    pub client_name: Option<String>,
+    
+    /// プロジェクトの色（例: "#06aaf5"）
+    #[serde(default)]
+    pub color: Option<String>,
+    
+    /// 請求対象のプロジェクトかどうか（有料プランのみ）
+    #[serde(default)]
+    pub billable: Option<bool>,
+
+    /// 時間単価（有料プランのみ）
+    #[serde(default)]
+    pub rate: Option<f64>,
+
+    /// 時間単価の通貨（例: "USD"）
+    #[serde(default)]
+    pub currency: Option<String>,
+
+    /// 見積もり時間（有料プランのみ）
+    #[serde(default)]
+    pub estimated_hours: Option<f64>,
+    
+    /// アクティブなプロジェクトかどうか（アーカイブ済みはfalse）
+    #[serde(default = "default_project_active")]
+    pub active: bool,
+}
+
+fn default_project_active() -> bool {
+    true
+}
+
+impl TogglProject {
+    /// ログ表示用の名前（クライアントがある場合は「プロジェクト名 [クライアント名]」）
+    pub fn display_name(&self) -> String {
+        match &self.client_name {
+            Some(client) => format!("{} [{}]", self.name, client),
+            None => self.name.clone(),
+        }
+    }
+}
+
+/// Togglのクライアント（プロジェクトの発注元）
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TogglWorkspaceClient {
+    /// クライアントID
+    pub id: u64,
+    
+    /// クライアント名
+    pub name: String,
+}
+
+/// Togglのワークスペースのタグ
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TogglTag {
+    /// タグID
+    pub id: u64,
+    
+    /// タグ名
+    pub name: String,
 }
 
 /// Togglのタイムエントリ
@@ -32,20 +150,21 @@ pub struct TimeEntry {
     pub description: String,
     
     /// ワークスペースID
+    #[serde(rename = "workspace_id", alias = "wid")]
     pub wid: u64,
-    
+
     /// プロジェクトID（オプション）
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "project_id", alias = "pid", skip_serializing_if = "Option::is_none")]
     pub pid: Option<u64>,
-    
+
     /// 開始時刻
     pub start: String,
-    
-    /// 終了時刻（オプション）
+
+    /// 終了時刻（Noneの場合は実行中のエントリとして作成する）
     #[serde(skip_serializing_if = "Option::is_none")]
     pub stop: Option<String>,
-    
-    /// 期間（秒単位）
+
+    /// 期間（秒単位、実行中のエントリは-1）
     #[serde(skip_serializing_if = "Option::is_none")]
     pub duration: Option<i64>,
     
@@ -53,6 +172,10 @@ pub struct TimeEntry {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub tags: Option<Vec<String>>,
     
+    /// 請求対象かどうか（クライアントはプロジェクトに紐づくものが使われる）
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub billable: Option<bool>,
+    
     /// 作成方法
     #[serde(skip_serializing_if = "Option::is_none")]
     pub created_with: Option<String>,
@@ -62,29 +185,17 @@ pub struct TimeEntry {
     pub event_metadata: Option<serde_json::Value>,
 }
 
-/// TimeEntryの作成リクエスト
-#[derive(Debug, Serialize)]
-struct CreateTimeEntryRequest {
-    time_entry: TimeEntry,
-}
-
-/// TimeEntryの作成レスポンス
-#[derive(Debug, Deserialize)]
-struct CreateTimeEntryResponse {
-    data: TimeEntryData,
-}
-
-/// TimeEntryのデータ
-#[derive(Debug, Deserialize)]
-struct TimeEntryData {
-    id: u64,
-}
-
-/// プロジェクト一覧レスポンス
-#[derive(Debug, Deserialize)]
-struct ProjectsResponse {
-    #[serde(default)]
-    data: Vec<TogglProject>,
+impl TimeEntry {
+    /// Toggl APIに送るリクエストボディ
+    ///
+    /// 終了時刻がない場合は、Togglの実行中エントリの規約に合わせて `duration` を-1とし、`stop` は送らない
+    fn request_body(&self) -> Result<serde_json::Value> {
+        let mut entry = self.clone();
+        if entry.stop.is_none() {
+            entry.duration = Some(RUNNING_ENTRY_DURATION);
+        }
+        serde_json::to_value(entry).context("Failed to serialize time entry")
+    }
 }
 
 /// Toggl タイムエントリ情報
@@ -97,34 +208,297 @@ pub struct TogglTimeEntry {
     pub start: String,
     pub stop: Option<String>,
     pub duration: i64,
-    pub tags: Option<Vec<String>>,
+    /// 作成したアプリケーション（APIが返す場合のみ）
+    #[serde(default)]
+    pub created_with: Option<String>,
+    /// 削除された日時（`since` で取得した場合は削除済みのエントリも返る）
+    #[serde(default)]
+    pub server_deleted_at: Option<String>,
 }
 
 /// Toggl ワークスペース情報
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct TogglWorkspace {
     pub id: u64,
     pub name: String,
     pub organization_id: u64,
 }
 
+/// Togglのユーザー情報（`/me`）
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TogglUser {
+    /// ユーザーID
+    pub id: u64,
+    
+    /// 表示名
+    pub fullname: String,
+    
+    /// 既定のワークスペースID
+    #[serde(default)]
+    pub default_workspace_id: Option<u64>,
+    
+    /// プロフィールに設定されたタイムゾーン（IANA名）
+    #[serde(default)]
+    pub timezone: Option<String>,
+}
+
+/// 取得済みのプロジェクト一覧
+struct CachedProjects {
+    fetched_at: Instant,
+    projects: Vec<TogglProject>,
+}
+
+/// ワークスペースごとのプロジェクト一覧キャッシュ（登録のたびにクライアントを作り直すためプロセス全体で共有）
+static PROJECT_CACHE: OnceLock<Mutex<HashMap<u64, CachedProjects>>> = OnceLock::new();
+
+fn project_cache() -> std::sync::MutexGuard<'static, HashMap<u64, CachedProjects>> {
+    PROJECT_CACHE
+        .get_or_init(|| Mutex::new(HashMap::new()))
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// 認証エラー後のToggl APIの休止状態
+pub static TOGGL_AUTH_PAUSE: AuthPause = AuthPause::new("Toggl");
+
+/// Toggl API v9のベースURL
+const TOGGL_API_BASE: &str = "https://api.track.toggl.com/api/v9";
+
+/// Toggl APIへの既定の送信ペース（1秒あたりのリクエスト数、Togglの推奨は1秒に1回程度）
+const TOGGL_REQUESTS_PER_SEC: f64 = 1.0;
+
+/// 連続して送信できるリクエスト数の上限
+const TOGGL_REQUEST_BURST: f64 = 3.0;
+
+/// トークンバケット方式のレート制限
+///
+/// トークンを1つ消費して送信し、足りない場合は補充されるまで待つ。
+/// 429の `Retry-After` やクォータ切れのヘッダーを受け取った場合は指定された時間まで送信を止める
+pub struct RateLimiter {
+    rate_per_sec: f64,
+    burst: f64,
+    state: Mutex<RateLimiterState>,
+}
+
+struct RateLimiterState {
+    tokens: f64,
+    refilled_at: Instant,
+    blocked_until: Option<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(rate_per_sec: f64, burst: f64) -> Self {
+        Self {
+            rate_per_sec,
+            burst,
+            state: Mutex::new(RateLimiterState {
+                tokens: burst,
+                refilled_at: Instant::now(),
+                blocked_until: None,
+            }),
+        }
+    }
+
+    /// 送信できるようになるまで待つ
+    pub async fn acquire(&self) {
+        let wait = self.reserve(Instant::now());
+        if !wait.is_zero() {
+            debug!("Toggl APIのレート制限のため {:?} 待機します", wait);
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// トークンを1つ予約し、送信までに待つ時間を返す（不足分は前借りして後続の呼び出しを順に遅らせる）
+    fn reserve(&self, now: Instant) -> std::time::Duration {
+        let mut state = self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        let elapsed = now.saturating_duration_since(state.refilled_at).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * self.rate_per_sec).min(self.burst);
+        state.refilled_at = now;
+        state.tokens -= 1.0;
+
+        let refill_wait = if state.tokens >= 0.0 {
+            std::time::Duration::ZERO
+        } else {
+            std::time::Duration::from_secs_f64(-state.tokens / self.rate_per_sec)
+        };
+        let blocked_wait = state.blocked_until
+            .map(|until| until.saturating_duration_since(now))
+            .unwrap_or_default();
+
+        refill_wait.max(blocked_wait)
+    }
+
+    /// レスポンスのヘッダーから待機が必要か判断し、必要な場合は送信を止める
+    pub fn observe(&self, status: reqwest::StatusCode, headers: &HeaderMap) {
+        let Some(wait) = throttle_from_headers(status, headers) else {
+            return;
+        };
+
+        warn!("Toggl APIのレート制限に達したため {:?} 送信を止めます", wait);
+        let until = Instant::now() + wait;
+        let mut state = self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        state.blocked_until = Some(state.blocked_until.map_or(until, |current| current.max(until)));
+    }
+}
+
+/// 429の `Retry-After`、またはクォータを使い切ったことを示すヘッダーから待機時間を求める
+fn throttle_from_headers(status: reqwest::StatusCode, headers: &HeaderMap) -> Option<std::time::Duration> {
+    let seconds = |name: &str| -> Option<u64> {
+        headers.get(name)?.to_str().ok()?.trim().parse().ok()
+    };
+
+    if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+        // Retry-After がない場合は既定のペースで1回分待つ
+        let secs = seconds("retry-after").unwrap_or(1);
+        return Some(std::time::Duration::from_secs(secs));
+    }
+
+    if seconds("x-toggl-quota-remaining") == Some(0) {
+        return seconds("x-toggl-quota-resets-in").map(std::time::Duration::from_secs);
+    }
+
+    None
+}
+
+/// すべてのTogglクライアントで共有するレート制限（登録のたびにクライアントを作り直すためプロセス全体で共有）
+static TOGGL_RATE_LIMITER: OnceLock<Arc<RateLimiter>> = OnceLock::new();
+
+fn toggl_rate_limiter() -> Arc<RateLimiter> {
+    TOGGL_RATE_LIMITER
+        .get_or_init(|| Arc::new(RateLimiter::new(TOGGL_REQUESTS_PER_SEC, TOGGL_REQUEST_BURST)))
+        .clone()
+}
+
+/// Toggl APIのエラー
+#[derive(Debug, thiserror::Error)]
+pub enum TogglError {
+    /// APIトークンが無効または失効している
+    #[error("Toggl API token was rejected (HTTP 401): {0}")]
+    Unauthorized(String),
+}
+
+/// APIトークンが拒否されたエラー（`TogglError::Unauthorized`）かどうか
+pub fn is_unauthorized(error: &anyhow::Error) -> bool {
+    matches!(error.downcast_ref::<TogglError>(), Some(TogglError::Unauthorized(_)))
+}
+
+/// エラーレスポンスをエラーに変換する（401は認証エラーとして区別する）
+fn api_error(action: &str, status: reqwest::StatusCode, body: String) -> anyhow::Error {
+    if status == reqwest::StatusCode::UNAUTHORIZED {
+        return TogglError::Unauthorized(body).into();
+    }
+    anyhow::anyhow!("{}: HTTP status {}, response: {}", action, status, body)
+}
+
 /// TogglのAPIクライアント
 pub struct TogglClient {
     api_token: String,
     client: reqwest::Client,
     workspace_id: u64,
+    project_cache_ttl: std::time::Duration,
+    api_base: String,
+    rate_limiter: Arc<RateLimiter>,
+    created_with: String,
 }
 
 impl TogglClient {
     /// 新しいTogglクライアントを作成
     pub fn new(api_token: &str, workspace_id: u64) -> Self {
-        let client = reqwest::Client::new();
+        let client = crate::utils::http_client();
         
         TogglClient {
             client,
             api_token: api_token.to_string(),
             workspace_id,
+            project_cache_ttl: std::time::Duration::ZERO,
+            api_base: TOGGL_API_BASE.to_string(),
+            rate_limiter: toggl_rate_limiter(),
+            created_with: default_created_with(),
+        }
+    }
+    
+    /// 設定からTogglクライアントを作成（プロジェクト一覧のキャッシュを有効にする）
+    pub fn from_config(config: &AppConfig) -> Self {
+        let client = Self::new(&config.toggl.api_token, config.toggl.workspace_id)
+            .with_project_cache_ttl(std::time::Duration::from_secs(config.toggl.project_cache_ttl_secs));
+        match config.toggl.created_with.as_deref() {
+            Some(created_with) => client.with_created_with(created_with),
+            None => client,
+        }
+    }
+
+    /// 作成するエントリの `created_with` を設定
+    pub fn with_created_with(mut self, created_with: &str) -> Self {
+        self.created_with = created_with.to_string();
+        self
+    }
+
+    /// 作成するエントリの `created_with`
+    pub fn created_with(&self) -> &str {
+        &self.created_with
+    }
+    
+    /// プロジェクト一覧のキャッシュ有効期間を設定
+    pub fn with_project_cache_ttl(mut self, ttl: std::time::Duration) -> Self {
+        self.project_cache_ttl = ttl;
+        self
+    }
+
+    /// APIのベースURLを差し替える（テスト用のサーバーに向ける）
+    #[cfg(test)]
+    pub(crate) fn with_api_base(mut self, api_base: &str) -> Self {
+        self.api_base = api_base.trim_end_matches('/').to_string();
+        self
+    }
+
+    /// レート制限を差し替える
+    #[cfg(test)]
+    pub(crate) fn with_rate_limiter(mut self, rate_limiter: Arc<RateLimiter>) -> Self {
+        self.rate_limiter = rate_limiter;
+        self
+    }
+
+    /// レート制限に従ってリクエストを送信する（429の場合は指定された時間だけ待って1回だけ再送する）
+    async fn send(&self, request: reqwest::RequestBuilder) -> reqwest::Result<reqwest::Response> {
+        let retry = request.try_clone();
+
+        self.rate_limiter.acquire().await;
+        let response = request.send().await?;
+        self.rate_limiter.observe(response.status(), response.headers());
+
+        match retry {
+            Some(retry) if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS => {
+                self.rate_limiter.acquire().await;
+                let response = retry.send().await?;
+                self.rate_limiter.observe(response.status(), response.headers());
+                Ok(response)
+            }
+            _ => Ok(response),
+        }
+    }
+    
+    /// ワークスペース配下のAPIのURLを作成（ワークスペースIDが未設定の場合はリクエスト前にエラーにする）
+    fn workspace_url(&self, path: &str) -> Result<String> {
+        if self.workspace_id == 0 {
+            return Err(anyhow::anyhow!(
+                "Toggl workspace is not configured (workspace_id = 0); run --wizard or set toggl.workspace_id"
+            ));
+        }
+        Ok(format!("{}/workspaces/{}/{}", self.api_base, self.workspace_id, path))
+    }
+
+    /// 取得したエントリのワークスペースのAPIのURL（エントリのワークスペースが不明な場合は設定したワークスペース）
+    ///
+    /// Togglアプリで別のワークスペースに作成されたエントリは、設定したワークスペースのURLでは404になる
+    fn entry_url(&self, entry: &TogglTimeEntry, path: &str) -> Result<String> {
+        if entry.workspace_id == 0 {
+            return self.workspace_url(path);
+        }
+        if entry.workspace_id != self.workspace_id {
+            debug!("Time entry {} belongs to workspace {}, not {}", entry.id, entry.workspace_id, self.workspace_id);
         }
+        Ok(format!("{}/workspaces/{}/{}", self.api_base, entry.workspace_id, path))
     }
     
     /// 認証用ヘッダーを作成
@@ -147,14 +521,40 @@ impl TogglClient {
         headers
     }
     
+    /// APIトークンのユーザー情報（既定のワークスペースやタイムゾーン）を取得
+    pub async fn get_me(&self) -> Result<TogglUser> {
+        let url = format!("{}/me", self.api_base);
+        
+        let request = self.client
+            .get(&url)
+            .headers(self.auth_headers());
+        let response = self.send(request)
+            .await
+            .context("Failed to retrieve user profile")?;
+        
+        // レスポンスステータスのチェック
+        let status = response.status();
+        if !status.is_success() {
+            let err_text = response.text().await.unwrap_or_default();
+            return Err(api_error("Failed to retrieve user profile", status, err_text));
+        }
+        
+        let user: TogglUser = response
+            .json()
+            .await
+            .context("Failed to parse user profile response")?;
+        
+        Ok(user)
+    }
+    
     /// Togglのワークスペース一覧を取得
     pub async fn get_workspaces(&self) -> Result<Vec<TogglWorkspace>> {
-        let url = "https://api.track.toggl.com/api/v9/workspaces";
+        let url = format!("{}/workspaces", self.api_base);
         
-        let response = self.client
-            .get(url)
-            .headers(self.auth_headers())
-            .send()
+        let request = self.client
+            .get(&url)
+            .headers(self.auth_headers());
+        let response = self.send(request)
             .await
             .context("Failed to retrieve workspaces")?;
         
@@ -162,11 +562,7 @@ impl TogglClient {
         let status = response.status();
         if !status.is_success() {
             let err_text = response.text().await.unwrap_or_default();
-            return Err(anyhow::anyhow!(
-                "Failed to retrieve workspaces: HTTP status {}, response: {}",
-                status,
-                err_text
-            ));
+            return Err(api_error("Failed to retrieve workspaces", status, err_text));
         }
         
         let workspaces: Vec<TogglWorkspace> = response
@@ -178,13 +574,17 @@ impl TogglClient {
     }
     
     /// ワークスペースのプロジェクト一覧を取得
-    pub async fn get_projects(&self) -> Result<Vec<TogglProject>> {
-        let url = format!("https://api.track.toggl.com/api/v9/workspaces/{}/projects", self.workspace_id);
+    ///
+    /// `active_only` の場合はアーカイブ済みのプロジェクトを除く
+    pub async fn get_projects(&self, active_only: bool) -> Result<Vec<TogglProject>> {
+        let url = self.workspace_url("projects")?;
+        let active_filter = if active_only { "true" } else { "both" };
         
-        let response = self.client
+        let request = self.client
             .get(&url)
-            .headers(self.auth_headers())
-            .send()
+            .query(&[("active", active_filter)])
+            .headers(self.auth_headers());
+        let response = self.send(request)
             .await
             .context("Failed to retrieve projects")?;
         
@@ -192,43 +592,134 @@ impl TogglClient {
         let status = response.status();
         if !status.is_success() {
             let err_text = response.text().await.unwrap_or_default();
-            return Err(anyhow::anyhow!(
-                "Failed to retrieve projects: HTTP status {}, response: {}",
-                status,
-                err_text
-            ));
+            return Err(api_error("Failed to retrieve projects", status, err_text));
         }
         
-        let projects: Vec<TogglProject> = response
+        // プロジェクトがない場合、APIは空配列ではなくnullを返すことがある
+        let projects: Option<Vec<TogglProject>> = response
             .json()
             .await
             .context("Failed to parse projects response")?;
         
+        let mut projects = projects.unwrap_or_default();
+        if active_only {
+            projects.retain(|project| project.active);
+        }
+        
+        // 同名のプロジェクトを区別できるようにクライアント名を付ける
+        if projects.iter().any(|project| project.cid.is_some() && project.client_name.is_none()) {
+            match self.get_clients().await {
+                Ok(clients) => {
+                    for project in &mut projects {
+                        if project.client_name.is_none() {
+                            project.client_name = project.cid
+                                .and_then(|cid| clients.iter().find(|client| client.id == cid))
+                                .map(|client| client.name.clone());
+                        }
+                    }
+                }
+                Err(e) => debug!("Failed to resolve project client names: {:#}", e),
+            }
+        }
+        
+        Ok(projects)
+    }
+    
+    /// ワークスペースのクライアント一覧を取得
+    pub async fn get_clients(&self) -> Result<Vec<TogglWorkspaceClient>> {
+        let url = self.workspace_url("clients")?;
+        
+        let request = self.client
+            .get(&url)
+            .headers(self.auth_headers());
+        let response = self.send(request)
+            .await
+            .context("Failed to retrieve clients")?;
+        
+        let status = response.status();
+        if !status.is_success() {
+            let err_text = response.text().await.unwrap_or_default();
+            return Err(api_error("Failed to retrieve clients", status, err_text));
+        }
+        
+        // クライアントがない場合もnullが返る
+        let clients: Option<Vec<TogglWorkspaceClient>> = response
+            .json()
+            .await
+            .context("Failed to parse clients response")?;
+        
+        Ok(clients.unwrap_or_default())
+    }
+    
+    /// ワークスペースのタグ一覧を取得
+    pub async fn get_tags(&self) -> Result<Vec<TogglTag>> {
+        let url = self.workspace_url("tags")?;
+        
+        let request = self.client
+            .get(&url)
+            .headers(self.auth_headers());
+        let response = self.send(request)
+            .await
+            .context("Failed to retrieve tags")?;
+        
+        let status = response.status();
+        if !status.is_success() {
+            let err_text = response.text().await.unwrap_or_default();
+            return Err(api_error("Failed to retrieve tags", status, err_text));
+        }
+        
+        // タグがない場合もnullが返る
+        let tags: Option<Vec<TogglTag>> = response
+            .json()
+            .await
+            .context("Failed to parse tags response")?;
+        
+        Ok(tags.unwrap_or_default())
+    }
+    
+    /// キャッシュ済みのアクティブなプロジェクト一覧を取得（期限切れの場合のみAPIから再取得）
+    pub async fn get_projects_cached(&self) -> Result<Vec<TogglProject>> {
+        if self.project_cache_ttl.is_zero() {
+            return self.get_projects(true).await;
+        }
+        
+        if let Some(cached) = project_cache().get(&self.workspace_id) {
+            if cached.fetched_at.elapsed() < self.project_cache_ttl {
+                debug!("Using cached project list for workspace {}", self.workspace_id);
+                return Ok(cached.projects.clone());
+            }
+        }
+        
+        let projects = self.get_projects(true).await?;
+        project_cache().insert(self.workspace_id, CachedProjects {
+            fetched_at: Instant::now(),
+            projects: projects.clone(),
+        });
+        
         Ok(projects)
     }
     
+    /// プロジェクト一覧のキャッシュを破棄する
+    pub fn invalidate_project_cache(&self) {
+        project_cache().remove(&self.workspace_id);
+    }
+    
     /// 新しいタイムエントリを作成
     pub async fn create_time_entry(&self, entry: TimeEntry) -> Result<u64> {
-        let url = format!("https://api.track.toggl.com/api/v9/workspaces/{}/time_entries", self.workspace_id);
-        
-        // v9 APIではリクエストボディ形式が変更されているため、直接JSONを構築
-        let request_body = serde_json::json!({
-            "description": entry.description,
-            "project_id": entry.pid,
-            "start": entry.start,
-            "stop": entry.stop,
-            "duration": entry.duration,
-            "tags": entry.tags,
-            "created_with": entry.created_with,
-            "workspace_id": entry.wid,
-            "event_metadata": entry.event_metadata
-        });
+        let url = self.workspace_url("time_entries")?;
         
-        let response = self.client
+        // 作成元が未設定ならクライアントの作成元を使う（他の項目は未設定なら送らず、終了時刻がなければ実行中のエントリになる）
+        let entry = TimeEntry {
+            created_with: entry.created_with.or_else(|| Some(self.created_with.clone())),
+            ..entry
+        };
+        let request_body = entry.request_body()?;
+        
+        let request = self.client
             .post(&url)
             .headers(self.auth_headers())
-            .json(&request_body)
-            .send()
+            .json(&request_body);
+        let response = self.send(request)
             .await
             .context("Failed to send time entry request")?;
         
@@ -236,11 +727,7 @@ impl TogglClient {
         let status = response.status();
         if !status.is_success() {
             let err_text = response.text().await.unwrap_or_default();
-            return Err(anyhow::anyhow!(
-                "Failed to create time entry: HTTP status {}, {}",
-                status,
-                err_text
-            ));
+            return Err(api_error("Failed to create time entry", status, err_text));
         }
         
         // v9 APIではレスポンス形式が変更されているため、直接IDを抽出
@@ -251,26 +738,35 @@ impl TogglClient {
     }
     
     /// プロジェクト名からIDを検索
+    ///
+    /// キャッシュに見つからない場合は新しく作成されたプロジェクトの可能性があるため再取得する
     pub async fn find_project_by_name(&self, name: &str) -> Result<Option<u64>> {
-        let projects = self.get_projects().await?;
+        let find = |projects: &[TogglProject]| {
+            projects.iter()
+                .find(|project| project.name.to_lowercase() == name.to_lowercase())
+                .map(|project| project.id)
+        };
         
-        for project in projects {
-            if project.name.to_lowercase() == name.to_lowercase() {
-                return Ok(Some(project.id));
-            }
+        if let Some(id) = find(&self.get_projects_cached().await?) {
+            return Ok(Some(id));
         }
         
-        Ok(None)
+        if self.project_cache_ttl.is_zero() {
+            return Ok(None);
+        }
+        
+        self.invalidate_project_cache();
+        Ok(find(&self.get_projects_cached().await?))
     }
 
     /// 実行中のタイムエントリを取得
     pub async fn get_running_time_entry(&self) -> Result<Option<TogglTimeEntry>> {
-        let url = "https://api.track.toggl.com/api/v9/me/time_entries/current";
+        let url = format!("{}/me/time_entries/current", self.api_base);
         
-        let response = self.client
+        let request = self.client
             .get(url)
-            .headers(self.auth_headers())
-            .send()
+            .headers(self.auth_headers());
+        let response = self.send(request)
             .await
             .context("Failed to retrieve current time entry")?;
         
@@ -278,43 +774,49 @@ impl TogglClient {
         let status = response.status();
         if !status.is_success() {
             let err_text = response.text().await.unwrap_or_default();
-            return Err(anyhow::anyhow!(
-                "Failed to retrieve current time entry: HTTP status {}, response: {}",
-                status,
-                err_text
-            ));
+            return Err(api_error("Failed to retrieve current time entry", status, err_text));
         }
         
-        // ステータスコードが204の場合は実行中のエントリがない
+        // ステータスコードが204、またはボディが null の場合は実行中のエントリがない
         if status == reqwest::StatusCode::NO_CONTENT {
             return Ok(None);
         }
         
-        let time_entry: TogglTimeEntry = response
+        let time_entry: Option<TogglTimeEntry> = response
             .json()
             .await
             .context("Failed to parse time entry response")?;
         
-        Ok(Some(time_entry))
+        Ok(time_entry)
     }
 
-    pub async fn start_time_entry(&self, project_id: u64, description: &str) -> Result<TogglTimeEntry> {
-        let url = format!("https://api.track.toggl.com/api/v9/workspaces/{}/time_entries", self.workspace_id);
+    /// 現在時刻から実行中のタイムエントリを開始
+    pub async fn start_time_entry(
+        &self,
+        project_id: Option<u64>,
+        description: &str,
+        billable: bool,
+        tags: Option<Vec<String>>,
+    ) -> Result<TogglTimeEntry> {
+        let url = self.workspace_url("time_entries")?;
         
         let now = Utc::now();
         let body = serde_json::json!({
-            "created_with": "toggl_linux_rs",
+            "created_with": self.created_with,
             "description": description,
             "project_id": project_id,
+            "billable": billable,
+            "tags": tags,
             "start": format_datetime_for_toggl(&now),
+            "duration": RUNNING_ENTRY_DURATION,
             "workspace_id": self.workspace_id,
         });
         
-        let response = self.client
+        let request = self.client
             .post(&url)
             .headers(self.auth_headers())
-            .json(&body)
-            .send()
+            .json(&body);
+        let response = self.send(request)
             .await
             .context("Failed to start time entry")?;
         
@@ -322,11 +824,7 @@ impl TogglClient {
         let status = response.status();
         if !status.is_success() {
             let err_text = response.text().await.unwrap_or_default();
-            return Err(anyhow::anyhow!(
-                "Failed to start time entry: HTTP status {}, response: {}",
-                status,
-                err_text
-            ));
+            return Err(api_error("Failed to start time entry", status, err_text));
         }
         
         let time_entry: TogglTimeEntry = response
@@ -344,16 +842,16 @@ impl TogglClient {
         let end_date_str = urlencoding::encode(&end_date_fmt);
         
         let url = format!(
-            "https://api.track.toggl.com/api/v9/me/time_entries?start_date={}&end_date={}",
-            start_date_str, end_date_str
+            "{}/me/time_entries?start_date={}&end_date={}",
+            self.api_base, start_date_str, end_date_str
         );
         
         debug!("時間エントリ取得URL: {}", url);
         
-        let response = self.client
+        let request = self.client
             .get(&url)
-            .headers(self.auth_headers())
-            .send()
+            .headers(self.auth_headers());
+        let response = self.send(request)
             .await
             .context("Failed to retrieve time entries")?;
         
@@ -361,11 +859,7 @@ impl TogglClient {
         let status = response.status();
         if !status.is_success() {
             let err_text = response.text().await.unwrap_or_default();
-            return Err(anyhow::anyhow!(
-                "Failed to retrieve time entries: HTTP status {}, response: {}",
-                status,
-                err_text
-            ));
+            return Err(api_error("Failed to retrieve time entries", status, err_text));
         }
         
         let time_entries: Vec<TogglTimeEntry> = response
@@ -377,25 +871,61 @@ impl TogglClient {
         Ok(time_entries)
     }
 
-    pub async fn stop_time_entry(&self, time_entry_id: u64) -> Result<TogglTimeEntry> {
-        let url = format!("https://api.track.toggl.com/api/v9/workspaces/{}/time_entries/{}/stop", self.workspace_id, time_entry_id);
-        
-        let response = self.client
-            .patch(&url)
-            .headers(self.auth_headers())
-            .send()
+    /// `since` 以降に作成・更新されたタイムエントリを取得（削除済みのエントリは除く）
+    ///
+    /// 期間を指定した取得より軽い。Togglが受け付けない古い `since` は受け付ける最も古い時刻に切り上げる
+    pub async fn get_time_entries_since(&self, since: DateTime<Utc>) -> Result<Vec<TogglTimeEntry>> {
+        let oldest = Utc::now() - Duration::days(TOGGL_SINCE_MAX_AGE_DAYS);
+        if since < oldest {
+            debug!("since が古すぎるため {} に切り上げます", format_datetime_for_toggl(&oldest));
+        }
+        let since = since.max(oldest);
+
+        let url = format!("{}/me/time_entries?since={}", self.api_base, since.timestamp());
+        debug!("時間エントリ差分取得URL: {}", url);
+
+        let request = self.client
+            .get(&url)
+            .headers(self.auth_headers());
+        let response = self.send(request)
             .await
-            .context("Failed to stop time entry")?;
+            .context("Failed to retrieve time entries")?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let err_text = response.text().await.unwrap_or_default();
+            return Err(api_error("Failed to retrieve time entries", status, err_text));
+        }
+
+        let time_entries: Vec<TogglTimeEntry> = response
+            .json()
+            .await
+            .context("Failed to parse time entries response")?;
+
+        let time_entries: Vec<TogglTimeEntry> = time_entries.into_iter()
+            .filter(|entry| entry.server_deleted_at.is_none())
+            .collect();
+        debug!("取得した時間エントリ数（差分）: {}", time_entries.len());
+        Ok(time_entries)
+    }
+
+    /// IDを指定してタイムエントリを取得
+    #[cfg(test)]
+    pub async fn get_time_entry(&self, time_entry_id: u64) -> Result<TogglTimeEntry> {
+        let url = format!("{}/me/time_entries/{}", self.api_base, time_entry_id);
+        
+        let request = self.client
+            .get(&url)
+            .headers(self.auth_headers());
+        let response = self.send(request)
+            .await
+            .context("Failed to retrieve time entry")?;
         
         // レスポンスステータスのチェック
         let status = response.status();
         if !status.is_success() {
             let err_text = response.text().await.unwrap_or_default();
-            return Err(anyhow::anyhow!(
-                "Failed to stop time entry: HTTP status {}, response: {}",
-                status,
-                err_text
-            ));
+            return Err(api_error("Failed to retrieve time entry", status, err_text));
         }
         
         let time_entry: TogglTimeEntry = response
@@ -405,573 +935,4004 @@ impl TogglClient {
         
         Ok(time_entry)
     }
-}
-
-/// プロジェクトIDを推論する
-async fn infer_project_id(
-    toggl_client: &TogglClient, 
-    analysis: &AnalysisResult
-) -> Result<Option<u64>> {
-    debug!("プロジェクトID推論開始");
     
-    // プロジェクト一覧を取得
-    let projects = toggl_client.get_projects().await?;
-    debug!("取得したプロジェクト数: {}", projects.len());
-    
-    // 全プロジェクト一覧をデバッグ出力
-    debug!("利用可能なプロジェクト一覧:");
-    for (i, project) in projects.iter().enumerate() {
-        debug!("  {}. {} (ID: {})", i+1, project.name, project.id);
+    /// タイムエントリを部分更新（指定したフィールドのみ変更）
+    pub async fn update_time_entry(&self, entry: &TogglTimeEntry, patch: serde_json::Value) -> Result<TogglTimeEntry> {
+        let url = self.entry_url(entry, &format!("time_entries/{}", entry.id))?;
+        
+        let request = self.client
+            .put(&url)
+            .headers(self.auth_headers())
+            .json(&patch);
+        let response = self.send(request)
+            .await
+            .context("Failed to update time entry")?;
+        
+        // レスポンスステータスのチェック
+        let status = response.status();
+        if !status.is_success() {
+            let err_text = response.text().await.unwrap_or_default();
+            return Err(api_error("Failed to update time entry", status, err_text));
+        }
+        
+        let time_entry: TogglTimeEntry = response
+            .json()
+            .await
+            .context("Failed to parse time entry response")?;
+        
+        Ok(time_entry)
     }
-    
-    // プロジェクト推論データを収集
-    let mut match_candidates = Vec::new();
-    
-    // 活動名を小文字に変換
-    let activity_lower = analysis.activity.to_lowercase();
-    
-    // ウィンドウタイトルの情報を取得（あれば）
-    let window_title_lower = analysis.window_title
-        .as_ref()
-        .map(|wt| wt.to_lowercase());
-    
-    // カレンダーイベントの情報を取得（あれば）
-    let calendar_title_lower = analysis.calendar_event
-        .as_ref()
-        .map(|event| event.title.to_lowercase());
-    
-    debug!("推論に使用する情報:");
-    debug!("- 活動名: {}", activity_lower);
-    if let Some(ref wt) = window_title_lower {
-        debug!("- ウィンドウタイトル: {}", wt);
+
+    /// 実行中のタイムエントリを停止せずに更新する（実行中のエントリがない場合はNone）
+    ///
+    /// v9 APIの単一エントリの部分更新は `PUT /workspaces/{wid}/time_entries/{id}` で行う
+    pub async fn update_running_entry(&self, patch: serde_json::Value) -> Result<Option<TogglTimeEntry>> {
+        let Some(running) = self.get_running_time_entry().await? else {
+            debug!("No running time entry to update");
+            return Ok(None);
+        };
+        
+        self.update_time_entry(&running, patch).await.map(Some)
     }
-    if let Some(ref ct) = calendar_title_lower {
-        debug!("- カレンダーイベント: {}", ct);
+
+    /// タイムエントリを削除（すでに削除されている場合は何もしない）
+    pub async fn delete_time_entry(&self, time_entry_id: u64) -> Result<()> {
+        let url = self.workspace_url(&format!("time_entries/{}", time_entry_id))?;
+        self.delete_at(&url, time_entry_id).await.map(|_| ())
     }
-    
-    // 各プロジェクトとの類似度を計算
-    for project in &projects {
-        let project_name_lower = project.name.to_lowercase();
-        let mut score = 0.0;
-        let mut match_reasons = Vec::new();
+
+    /// 取得したタイムエントリをそのワークスペースで削除する（すでに削除されていた場合はfalse）
+    pub async fn delete_fetched_time_entry(&self, entry: &TogglTimeEntry) -> Result<bool> {
+        let url = self.entry_url(entry, &format!("time_entries/{}", entry.id))?;
+        self.delete_at(&url, entry.id).await
+    }
+
+    /// タイムエントリのURLにDELETEを送る（404の場合は削除済みとしてfalseを返す）
+    async fn delete_at(&self, url: &str, time_entry_id: u64) -> Result<bool> {
+        let request = self.client
+            .delete(url)
+            .headers(self.auth_headers());
+        let response = self.send(request)
+            .await
+            .context("Failed to delete time entry")?;
         
-        // 1. 完全一致の場合 (highest priority)
-        if project_name_lower == activity_lower {
-            score = 1.0;
-            match_reasons.push(format!("活動名と完全一致"));
-        }
-        // 2. 部分文字列マッチング
-        else if project_name_lower.contains(&activity_lower) {
-            score = 0.8;
-            match_reasons.push(format!("プロジェクト名が活動名を含む"));
-        }
-        else if activity_lower.contains(&project_name_lower) {
-            score = 0.7;
-            match_reasons.push(format!("活動名がプロジェクト名を含む"));
+        // レスポンスステータスのチェック（404はすでに削除済み）
+        let status = response.status();
+        if status == reqwest::StatusCode::NOT_FOUND {
+            warn!("Time entry {} was not found; it may have already been deleted", time_entry_id);
+            return Ok(false);
         }
-        // 3. 単語レベルでの一致を検出
-        else {
-            // 単語に分割して一致を検索
-            let project_words: Vec<&str> = project_name_lower.split_whitespace().collect();
-            let activity_words: Vec<&str> = activity_lower.split_whitespace().collect();
-            
-            let mut matching_words = 0;
-            for pword in &project_words {
-                if activity_words.contains(pword) {
-                    matching_words += 1;
-                }
-            }
-            
-            if matching_words > 0 {
-                // 一致する単語の割合でスコア付け
-                let word_match_score = matching_words as f64 / project_words.len().max(1) as f64;
-                score = word_match_score * 0.6; // 単語一致は完全一致より低い優先度
-                match_reasons.push(format!("{}個の単語が一致", matching_words));
-            }
+        if !status.is_success() {
+            let err_text = response.text().await.unwrap_or_default();
+            return Err(api_error("Failed to delete time entry", status, err_text));
         }
         
-        // 4. ウィンドウタイトルを考慮
-        if let Some(ref window_title) = window_title_lower {
-            if window_title.contains(&project_name_lower) {
-                score += 0.2;
-                match_reasons.push(format!("ウィンドウタイトルがプロジェクト名を含む"));
-            }
-        }
+        Ok(true)
+    }
+    
+    /// 実行中のタイムエントリを停止（エントリのワークスペースで停止する）
+    pub async fn stop_time_entry(&self, entry: &TogglTimeEntry) -> Result<TogglTimeEntry> {
+        let url = self.entry_url(entry, &format!("time_entries/{}/stop", entry.id))?;
         
-        // 5. カレンダーイベントを考慮
-        if let Some(ref calendar_title) = calendar_title_lower {
-            if calendar_title.contains(&project_name_lower) {
-                score += 0.3;
-                match_reasons.push(format!("カレンダーイベントがプロジェクト名を含む"));
-            }
+        let request = self.client
+            .patch(&url)
+            .headers(self.auth_headers());
+        let response = self.send(request)
+            .await
+            .context("Failed to stop time entry")?;
+        
+        // レスポンスステータスのチェック
+        let status = response.status();
+        if !status.is_success() {
+            let err_text = response.text().await.unwrap_or_default();
+            return Err(api_error("Failed to stop time entry", status, err_text));
         }
         
-        // 有意義なスコアがあれば候補に追加
-        if score > 0.0 {
-            match_candidates.push((project.id, project.name.clone(), score, match_reasons));
+        let time_entry: TogglTimeEntry = response
+            .json()
+            .await
+            .context("Failed to parse time entry response")?;
+        
+        Ok(time_entry)
+    }
+}
+
+/// 最近のエントリのうち、このアプリケーションが登録した最新のエントリを選ぶ
+///
+/// `created_with` が現在の値（`created_with`）か、バージョンの有無によらずこのアプリケーションの名前のものを対象にする。
+/// APIが `created_with` を返さない場合に備え、ローカルに記録した登録済みの活動名と説明が一致するものも対象にする
+pub fn find_last_own_entry<'a>(
+    entries: &'a [TogglTimeEntry],
+    registered_activities: &[String],
+    created_with: &str,
+) -> Option<&'a TogglTimeEntry> {
+    entries.iter()
+        .filter(|entry| {
+            is_own_entry(entry, created_with) || registered_activities.contains(&entry.description)
+        })
+        .max_by_key(|entry| DateTime::parse_from_rfc3339(&entry.start).ok())
+}
+
+/// 重複とみなすエントリのキー（開始時刻、終了時刻、説明、プロジェクト）
+type DuplicateKey<'a> = (DateTime<Utc>, DateTime<Utc>, &'a str, Option<u64>);
+
+/// このツールが作成した重複エントリのうち、削除するものを選ぶ（`--prune-duplicates`）
+///
+/// 開始・終了時刻、説明、プロジェクトがすべて同じエントリを1グループとし、各グループでIDが最も小さい
+/// （最初に作成された）エントリを残す。他のアプリで作成したエントリと実行中のエントリは対象にしない
+pub fn select_duplicate_entries<'a>(entries: &'a [TogglTimeEntry], created_with: &str) -> Vec<&'a TogglTimeEntry> {
+    let mut groups: HashMap<DuplicateKey, Vec<&TogglTimeEntry>> = HashMap::new();
+    for entry in entries {
+        if !is_own_entry(entry, created_with) || entry.server_deleted_at.is_some() {
+            continue;
         }
+        let parse = |time: &str| DateTime::parse_from_rfc3339(time).ok().map(|time| time.with_timezone(&Utc));
+        let (Some(start), Some(stop)) = (parse(&entry.start), entry.stop.as_deref().and_then(parse)) else {
+            continue;
+        };
+        groups.entry((start, stop, entry.description.trim(), entry.project_id)).or_default().push(entry);
     }
-    
-    // スコアの高い順にソート
-    match_candidates.sort_by(|a, b| b.2.partial_cmp(&a.2).unwrap_or(std::cmp::Ordering::Equal));
-    
-    // 候補をログに出力
-    debug!("プロジェクト候補リスト:");
-    for (i, (id, name, score, reasons)) in match_candidates.iter().enumerate() {
-        debug!("候補{}: {} (ID: {}, スコア: {:.2})", i+1, name, id, score);
-        for reason in reasons {
-            debug!("  - {}", reason);
+
+    let mut duplicates: Vec<&TogglTimeEntry> = groups.into_values()
+        .flat_map(|mut group| {
+            group.sort_by_key(|entry| entry.id);
+            group.into_iter().skip(1)
+        })
+        .collect();
+    duplicates.sort_by(|a, b| a.start.cmp(&b.start).then(a.id.cmp(&b.id)));
+    duplicates
+}
+
+/// このツールが作成したエントリかどうか（設定した `created_with`、またはバージョンの有無によらず "toggl_linux_rs"）
+fn is_own_entry(entry: &TogglTimeEntry, created_with: &str) -> bool {
+    entry.created_with.as_deref().is_some_and(|source| {
+        source == created_with
+            || source == CREATED_WITH
+            || source.strip_prefix(CREATED_WITH).is_some_and(|version| version.starts_with('/'))
+    })
+}
+
+/// 時間ブロックのうち、他のアプリで作成されたエントリと重ならない区間を求める（`toggl.avoid_overlaps`）
+///
+/// 重ならない区間が複数ある場合は最も長い区間を返し、ブロック全体が覆われている場合はNoneを返す。
+/// 実行中のエントリは `now` まで続いているものとして扱う
+fn uncovered_block_range(
+    entries: &[TogglTimeEntry],
+    start_time: DateTime<Utc>,
+    stop_time: DateTime<Utc>,
+    created_with: &str,
+    now: DateTime<Utc>,
+) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+    let parse = |time: &str| DateTime::parse_from_rfc3339(time).ok().map(|time| time.with_timezone(&Utc));
+    let mut covered: Vec<(DateTime<Utc>, DateTime<Utc>)> = entries.iter()
+        .filter(|entry| entry.server_deleted_at.is_none() && !is_own_entry(entry, created_with))
+        .filter_map(|entry| {
+            let start = parse(&entry.start)?;
+            let stop = match &entry.stop {
+                Some(stop) => parse(stop)?,
+                None => now,
+            };
+            (start < stop_time && stop > start_time).then_some((start.max(start_time), stop.min(stop_time)))
+        })
+        .collect();
+    covered.sort();
+
+    let mut longest: Option<(DateTime<Utc>, DateTime<Utc>)> = None;
+    let mut cursor = start_time;
+    for (start, stop) in covered.into_iter().chain(std::iter::once((stop_time, stop_time))) {
+        if start > cursor && longest.is_none_or(|(from, to)| start - cursor > to - from) {
+            longest = Some((cursor, start));
         }
+        cursor = cursor.max(stop);
     }
+    longest
+}
+
+/// `from` ～ `to` の間に、他のアプリで作成されたエントリが挟まっているかどうか
+///
+/// 統合や延長で手動の記録をまたいでしまわないよう確認する。実行中のエントリは `now` まで続いているものとして扱う
+fn has_foreign_entry_between(
+    entries: &[TogglTimeEntry],
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    created_with: &str,
+    now: DateTime<Utc>,
+) -> bool {
+    let parse = |time: &str| DateTime::parse_from_rfc3339(time).ok().map(|time| time.with_timezone(&Utc));
+    entries.iter()
+        .filter(|entry| entry.server_deleted_at.is_none() && !is_own_entry(entry, created_with))
+        .filter_map(|entry| {
+            let start = parse(&entry.start)?;
+            let stop = match &entry.stop {
+                Some(stop) => parse(stop)?,
+                None => now,
+            };
+            Some((start, stop))
+        })
+        .any(|(start, stop)| start < to && stop > from)
+}
+
+/// 時間ブロックの登録を識別する重複防止キー
+///
+/// ワークスペース・秒単位に丸めた開始時刻・エントリの説明から決定的に作るため、既存のエントリからも同じキーを計算できる
+/// （Rustのバージョンによらず同じ値になるよう、標準のハッシュではなくFNV-1aを使う）
+pub fn dedupe_key(workspace_id: u64, start: DateTime<Utc>, description: &str) -> String {
+    const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
     
-    // 最良の候補を返す（スコアが閾値以上の場合）
-    if !match_candidates.is_empty() && match_candidates[0].2 >= 0.5 {
-        let best_match = &match_candidates[0];
-        info!("選択されたプロジェクト: {} (ID: {}, スコア: {:.2})", 
-              best_match.1, best_match.0, best_match.2);
-        Ok(Some(best_match.0))
-    } else {
-        debug!("適切なプロジェクトが見つかりませんでした");
-        Ok(None)
+    let input = format!("{}\n{}\n{}", workspace_id, start.timestamp(), description.trim());
+    let hash = input.bytes().fold(FNV_OFFSET_BASIS, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+    });
+    format!("{:016x}", hash)
+}
+
+/// 同じ重複防止キーを持つ登録済みのエントリを探す
+fn find_duplicate_entry<'a>(entries: &'a [TogglTimeEntry], key: &str) -> Option<&'a TogglTimeEntry> {
+    entries.iter().find(|entry| {
+        DateTime::parse_from_rfc3339(&entry.start)
+            .is_ok_and(|start| dedupe_key(entry.workspace_id, start.with_timezone(&Utc), &entry.description) == key)
+    })
+}
+
+/// 未設定（0）のワークスペースIDを、APIトークンで利用できるワークスペースから決める
+///
+/// ユーザーの既定のワークスペース（`/me`）があればそれを使い、取得できない場合は
+/// ワークスペースが1つだけならそれを、複数ある場合は最初のものを使う
+pub async fn resolve_workspace_id(api_token: &str, workspace_id: u64) -> Result<u64> {
+    if workspace_id != 0 {
+        return Ok(workspace_id);
     }
+    
+    resolve_workspace_id_with_client(&TogglClient::new(api_token, 0)).await
 }
 
-/// 文字列を安全に切り詰める（UTF-8文字境界を保持）
-fn truncate_string_safely(s: &str, max_len: usize) -> String {
-    if s.len() <= max_len {
-        return s.to_string();
+/// ワークスペースIDが未設定のクライアントで、使うワークスペースを決める
+async fn resolve_workspace_id_with_client(client: &TogglClient) -> Result<u64> {
+    match client.get_me().await {
+        Ok(TogglUser { default_workspace_id: Some(default_id), .. }) if default_id != 0 => {
+            info!("workspace_id is not configured, using the default workspace of the Toggl user ({})", default_id);
+            return Ok(default_id);
+        }
+        Ok(_) => debug!("Toggl user has no default workspace, choosing from the workspace list"),
+        Err(e) => debug!("Failed to retrieve the Toggl user profile: {:#}", e),
     }
     
-    let mut char_indices = s.char_indices();
-    let mut last_valid_index = 0;
+    let workspaces = client.get_workspaces().await
+        .context("Toggl workspace is not configured and workspaces could not be retrieved; run --wizard")?;
     
-    // 文字の境界を保持しながら最大長に近い位置を探す
-    while let Some((idx, _)) = char_indices.next() {
-        if idx > max_len {
-            break;
+    match workspaces.as_slice() {
+        [] => Err(anyhow::anyhow!("No Toggl workspace is available for this API token; run --wizard")),
+        [only] => {
+            info!("workspace_id is not configured, using the only workspace '{}' ({})", only.name, only.id);
+            Ok(only.id)
+        }
+        [first, ..] => {
+            warn!("workspace_id is not configured, using the first of {} workspaces '{}' ({}); set toggl.workspace_id to choose another",
+                  workspaces.len(), first.name, first.id);
+            Ok(first.id)
         }
-        last_valid_index = idx;
     }
-    
-    // 切り詰めた文字列に「...」を追加
-    format!("{}...", &s[..last_valid_index])
 }
 
-/// ユーザーに活動候補を提示する（コマンドライン用）
-pub fn present_activity_choices(analysis: &AnalysisResult) -> Result<String> {
-    println!("活動推定の確度が低いため、以下から選択してください：");
-    println!("0: [{}] (確度: {:.2})", analysis.activity, analysis.confidence);
-    
-    for (i, alt) in analysis.alternatives.iter().enumerate() {
-        println!(
-            "{}: [{}] (確度: {:.2})",
-            i + 1,
-            alt.activity,
-            alt.confidence
-        );
+/// ワークスペース一覧を表形式の文字列に整形する
+pub fn format_workspaces_table(workspaces: &[TogglWorkspace]) -> String {
+    let mut table = format!("{:<12} {:<12} {}\n", "ID", "組織ID", "名前");
+    for workspace in workspaces {
+        table.push_str(&format!(
+            "{:<12} {:<12} {}\n",
+            workspace.id, workspace.organization_id, workspace.name
+        ));
     }
-    
-    println!("{}: [新規活動を入力]", analysis.alternatives.len() + 1);
-    
-    // 標準入力から選択を受け取る
-    print!("選択（数字）: ");
-    std::io::Write::flush(&mut std::io::stdout()).unwrap();
-    
-    let mut input = String::new();
-    std::io::stdin().read_line(&mut input)?;
-    
-    let choice = input.trim().parse::<usize>().unwrap_or(0);
-    
-    if choice == 0 {
-        return Ok(analysis.activity.clone());
-    } else if choice <= analysis.alternatives.len() {
-        return Ok(analysis.alternatives[choice - 1].activity.clone());
-    } else {
-        print!("新しい活動名を入力: ");
-        std::io::Write::flush(&mut std::io::stdout()).unwrap();
-        
-        let mut new_activity = String::new();
-        std::io::stdin().read_line(&mut new_activity)?;
-        
-        return Ok(new_activity.trim().to_string());
+    table
+}
+
+/// プロジェクト一覧を表形式の文字列に整形する
+pub fn format_projects_table(projects: &[TogglProject]) -> String {
+    let mut table = format!("{:<12} {:<14} {:<20} {:<8} {:<10} {}\n", "ID", "ワークスペースID", "クライアント", "色", "状態", "名前");
+    for project in projects {
+        let client = project.client_name.clone()
+            .or_else(|| project.cid.map(|cid| cid.to_string()))
+            .unwrap_or_else(|| "-".to_string());
+        let color = project.color.as_deref().unwrap_or("-");
+        let status = if project.active { "active" } else { "archived" };
+        table.push_str(&format!(
+            "{:<12} {:<14} {:<20} {:<8} {:<10} {}\n",
+            project.id, project.wid, client, color, status, project.name
+        ));
     }
+    table
 }
 
-/// main.rsの分析関数から呼び出す用の関数
-pub async fn register_to_toggl(config: &AppConfig, analysis: &AnalysisResult) -> Result<()> {
-    // Togglクライアントの初期化
-    let toggl_client = TogglClient::new(
-        &config.toggl.api_token,
-        config.toggl.workspace_id,
-    );
-    
-    // 時間ブロック設定を取得
-    let time_block_division = config.general.time_block_division;
-    let minutes_per_block = 60 / time_block_division as u64;
+/// プロジェクトごとの記録時間と概算金額（`--report`）
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProjectReportRow {
+    /// プロジェクトID（プロジェクトなしのエントリは None）
+    pub project_id: Option<u64>,
+
+    /// 表示名
+    pub name: String,
+
+    /// 記録時間の合計
+    pub tracked: Duration,
+
+    /// 見積もり時間
+    pub estimated_hours: Option<f64>,
+
+    /// 時間単価
+    pub rate: Option<f64>,
+
+    /// 時間単価の通貨
+    pub currency: Option<String>,
+}
+
+impl ProjectReportRow {
+    /// 概算金額（記録時間 × 時間単価、単価が設定されていない場合は None）
+    pub fn cost(&self) -> Option<f64> {
+        self.rate.map(|rate| self.tracked.num_seconds() as f64 / 3600.0 * rate)
+    }
+}
+
+/// タイムエントリをプロジェクトごとの記録時間にまとめる（記録時間の長い順）
+///
+/// 実行中のエントリは `now` までの時間を数える
+pub fn summarize_project_hours(entries: &[TogglTimeEntry], projects: &[TogglProject], now: DateTime<Utc>) -> Vec<ProjectReportRow> {
+    let mut totals: HashMap<Option<u64>, Duration> = HashMap::new();
+    for entry in entries.iter().filter(|entry| entry.server_deleted_at.is_none()) {
+        let tracked = if entry.duration >= 0 {
+            Duration::seconds(entry.duration)
+        } else {
+            DateTime::parse_from_rfc3339(&entry.start)
+                .map_or_else(|_| Duration::zero(), |start| now - start.with_timezone(&Utc))
+        };
+        *totals.entry(entry.project_id).or_insert_with(Duration::zero) += tracked.max(Duration::zero());
+    }
+
+    let mut rows: Vec<ProjectReportRow> = totals.into_iter()
+        .map(|(project_id, tracked)| {
+            let project = project_id.and_then(|id| projects.iter().find(|project| project.id == id));
+            let name = match (project, project_id) {
+                (Some(project), _) => project.display_name(),
+                (None, Some(id)) => format!("(ID: {})", id),
+                (None, None) => "(プロジェクトなし)".to_string(),
+            };
+            ProjectReportRow {
+                project_id,
+                name,
+                tracked,
+                estimated_hours: project.and_then(|project| project.estimated_hours),
+                rate: project.and_then(|project| project.rate),
+                currency: project.and_then(|project| project.currency.clone()),
+            }
+        })
+        .collect();
+    rows.sort_by(|a, b| b.tracked.cmp(&a.tracked).then_with(|| a.name.cmp(&b.name)));
+    rows
+}
+
+/// プロジェクト別の集計を表形式の文字列に整形する（単価のないプロジェクトの金額は空欄、金額の合計は通貨ごと）
+pub fn format_project_report(rows: &[ProjectReportRow]) -> String {
+    let hours = |duration: Duration| format!("{:.2}h", duration.num_seconds() as f64 / 3600.0);
+    let money = |amount: f64, currency: Option<&str>| match currency {
+        Some(currency) => format!("{:.2} {}", amount, currency),
+        None => format!("{:.2}", amount),
+    };
+
+    let mut table = format!("{:<10} {:<10} {:<16} {}\n", "時間", "見積", "金額", "プロジェクト");
+    let mut costs: std::collections::BTreeMap<Option<&str>, f64> = std::collections::BTreeMap::new();
+    for row in rows {
+        let estimate = row.estimated_hours.map(|estimate| format!("{:.1}h", estimate)).unwrap_or_default();
+        let cost = row.cost().map(|cost| {
+            *costs.entry(row.currency.as_deref()).or_default() += cost;
+            money(cost, row.currency.as_deref())
+        }).unwrap_or_default();
+        table.push_str(&format!("{:<10} {:<10} {:<16} {}\n", hours(row.tracked), estimate, cost, row.name));
+    }
+
+    let total = rows.iter().map(|row| row.tracked).fold(Duration::zero(), |total, tracked| total + tracked);
+    let total_cost = costs.iter()
+        .map(|(currency, cost)| money(*cost, *currency))
+        .collect::<Vec<_>>()
+        .join(", ");
+    table.push_str(&format!("{:<10} {:<10} {:<16} {}\n", hours(total), "", total_cost, "合計"));
+    table
+}
+
+/// 活動からのプロジェクト推論の結果（候補・スコア・理由）を整形する（`--infer`）
+pub fn format_project_inference(projects: &[TogglProject], analysis: &AnalysisResult) -> String {
+    let candidates = rank_projects(projects, analysis);
+    let selected = best_project_match(projects, analysis, PROJECT_MATCH_THRESHOLD);
+
+    let mut output = format!("活動: {}\n", analysis.activity);
+    if let Some(window_title) = &analysis.window_title {
+        output.push_str(&format!("ウィンドウタイトル: {}\n", window_title));
+    }
+    if candidates.is_empty() {
+        output.push_str("一致するプロジェクトはありません\n");
+    }
+    for (i, candidate) in candidates.iter().enumerate() {
+        let marker = if Some(candidate.project.id) == selected { "  <- 選択" } else { "" };
+        output.push_str(&format!(
+            "{:>2}. {:.2}  {} (ID: {}){}\n",
+            i + 1, candidate.score, candidate.project.display_name(), candidate.project.id, marker
+        ));
+        for reason in &candidate.reasons {
+            output.push_str(&format!("      - {}\n", reason));
+        }
+    }
+    match candidates.iter().find(|candidate| Some(candidate.project.id) == selected) {
+        Some(candidate) => output.push_str(&format!("登録されるプロジェクト: {}\n", candidate.project.display_name())),
+        None => output.push_str(&format!(
+            "スコアが {:.2} 以上の候補がないため、プロジェクトなしで登録されます\n", PROJECT_MATCH_THRESHOLD
+        )),
+    }
+    output
+}
+
+/// タグ一覧を表形式の文字列に整形する（名前順）
+pub fn format_tags_table(tags: &[TogglTag]) -> String {
+    let mut sorted: Vec<&TogglTag> = tags.iter().collect();
+    sorted.sort_by_key(|tag| tag.name.to_lowercase());
     
-    // 活動の開始時刻を決定（時間ブロックの境界に合わせる）
-    let current_minute = analysis.timestamp.minute() as u64;
-    let block_index = current_minute / minutes_per_block;
-    let start_minute = block_index * minutes_per_block;
+    let mut table = format!("{:<12} {}\n", "ID", "名前");
+    for tag in sorted {
+        table.push_str(&format!("{:<12} {}\n", tag.id, tag.name));
+    }
+    table
+}
+
+/// 予定のカレンダーに対応付けられたタグ
+fn calendar_tags(config: Option<&AppConfig>, event: Option<&crate::data_collector::CalendarEvent>) -> Option<Vec<String>> {
+    let settings = config?.google_calendar.as_ref()?;
+    let event = event?;
     
-    let start_time = analysis.timestamp
-        .with_minute(start_minute as u32)
-        .unwrap_or(analysis.timestamp)
-        .with_second(0)
-        .unwrap_or(analysis.timestamp)
-        .with_nanosecond(0)
-        .unwrap_or(analysis.timestamp);
+    settings.tag_for_calendar(&event.calendar_id)
+        .map(|tag| vec![tag.to_string()])
+}
+
+/// エントリを請求対象にするかを決める
+///
+/// プロジェクトに請求対象かどうかが設定されている場合（有料プラン）は、請求対象外も含めてその設定を優先し、
+/// 設定されていない場合は `default_billable` を使う
+async fn resolve_billable(toggl_client: &TogglClient, project_id: Option<u64>, default_billable: bool) -> bool {
+    let Some(project_id) = project_id else {
+        return default_billable;
+    };
     
-    let stop_time = start_time + Duration::minutes(minutes_per_block as i64);
+    let projects = match toggl_client.get_projects_cached().await {
+        Ok(projects) => projects,
+        Err(e) => {
+            debug!("Failed to look up project billable flag: {}", e);
+            return default_billable;
+        }
+    };
     
-    // プライベートブラウジングのフラグを確認（AnalysisResultに含まれていない場合はfalse）
-    let is_private_browsing = analysis.window_title
-        .as_ref()
-        .map(|title| title.to_lowercase().contains("private") || title.to_lowercase().contains("incognito"))
-        .unwrap_or(false);
+    match projects.iter().find(|project| project.id == project_id) {
+        Some(project) => {
+            if let Some(client_id) = project.cid {
+                debug!("プロジェクト {} のクライアント: {} (ID: {})",
+                       project.name, project.client_name.as_deref().unwrap_or("-"), client_id);
+            }
+            project.billable.unwrap_or(default_billable)
+        }
+        None => default_billable,
+    }
+}
+
+/// 主催者メールアドレスのドメインから組織名にあたるラベルを取り出す
+///
+/// 例: `pm@acme.example.com` → `acme`。Gmailなど個人向けドメインは組織を表さないため除外する。
+fn organizer_domain_label(email: &str) -> Option<String> {
+    const PERSONAL_DOMAINS: [&str; 4] = ["gmail", "googlemail", "outlook", "icloud"];
+
+    let domain = email.rsplit_once('@')?.1.to_lowercase();
+    let label = domain.split('.').next()?.to_string();
+    if label.len() < 3 || PERSONAL_DOMAINS.contains(&label.as_str()) {
+        return None;
+    }
+    Some(label)
+}
+
+/// 開始時刻からの長さが `increment_minutes` の倍数になるように終了時刻を丸める
+///
+/// 長さが0より大きい場合は最低でも1単位分を確保し、終了時刻が開始時刻より前になることはない
+fn round_stop_time(
+    start: DateTime<Utc>,
+    stop: DateTime<Utc>,
+    increment_minutes: u32,
+    mode: RoundingMode,
+) -> DateTime<Utc> {
+    let increment = increment_minutes as i64 * 60;
+    let duration = (stop - start).num_seconds();
+    if increment == 0 || duration <= 0 {
+        return stop.max(start);
+    }
     
-    // ActivityResultにis_private_browsingフィールドが無いため、拡張AnalysisResultを作成
-    let extended_analysis = ExtendedAnalysisResult {
-        base: analysis,
-        is_private_browsing,
-        config: Some(config),
+    let units = match mode {
+        RoundingMode::Up => (duration + increment - 1) / increment,
+        RoundingMode::Nearest => (duration + increment / 2) / increment,
     };
-    
-    // 詳細なRegister to Toggl関数を呼び出す
-    register_to_toggl_impl(
-        &toggl_client,
-        &extended_analysis,
-        config.toggl.workspace_id,
-        start_time,
-        stop_time,
-        true, // デフォルトでプライベートブラウジングはスキップ
-    ).await
+    start + Duration::seconds(units.max(1) * increment)
 }
 
-// AnalysisResultを拡張して必要なフィールドを追加
-struct ExtendedAnalysisResult<'a> {
-    base: &'a AnalysisResult,
-    is_private_browsing: bool,
-    config: Option<&'a AppConfig>,
+/// 直前のエントリに統合する場合の終了時刻
+///
+/// 統合後のエントリ全体の長さを丸め、直前のエントリが丸めで延びていた分より短くならないようにする
+fn merged_stop_time(
+    entry_start: DateTime<Utc>,
+    previous_stop: DateTime<Utc>,
+    stop: DateTime<Utc>,
+    increment_minutes: u32,
+    mode: RoundingMode,
+) -> DateTime<Utc> {
+    round_stop_time(entry_start, stop, increment_minutes, mode).max(previous_stop)
 }
 
-/// RFC3339形式の日時文字列を生成（ミリ秒なし）
-fn format_datetime_for_toggl(dt: &DateTime<Utc>) -> String {
-    // TogglのAPIはミリ秒を含むと400エラーを返すので、ミリ秒なしのフォーマットを使用
-    // RFC3339/ISO8601形式で、タイムゾーンをZに設定する
-    // 例: 2023-04-10T15:30:45Z
-    dt.format("%Y-%m-%dT%H:%M:%SZ").to_string()
+/// 登録するエントリの終了時刻の丸め設定
+#[derive(Debug, Clone, Copy)]
+struct StopRounding {
+    /// 丸める単位（分、0で丸めない）
+    minutes: u32,
+
+    /// 丸め方
+    mode: RoundingMode,
+
+    /// 丸めた終了時刻の上限（他のアプリのエントリと重ならないようブロックを縮めた場合、その直前まで）
+    limit: Option<DateTime<Utc>>,
 }
 
-/// イベント名の類似度を評価する
-async fn evaluate_activity_similarity(
-    api_key: &str,
-    activity1: &str,
-    activity2: &str,
-) -> Result<f32> {
-    debug!("AIを使用してイベント名の類似度を評価: '{}' vs '{}'", activity1, activity2);
-    
-    // OpenAI APIクライアント設定
-    let client = reqwest::Client::new();
-    let url = "https://api.openai.com/v1/chat/completions";
-    
-    // APIリクエスト作成
-    let request_body = serde_json::json!({
-        "model": "gpt-3.5-turbo",
-        "messages": [
-            {
-                "role": "system",
-                "content": "あなたはイベント名の類似度を評価するAIアシスタントです。2つのイベント名を比較し、それらの意味的な類似性を0から1の小数値で評価してください。0は全く関連がない、1は完全に同じ意味を持つことを示します。最終的な回答は数値のみを出力してください。"
-            },
-            {
-                "role": "user",
-                "content": format!("以下の2つのイベント名の類似度を0から1のスケールで評価してください。回答は数値のみを出力してください。\nイベント1: {}\nイベント2: {}", activity1, activity2)
-            }
-        ],
-        "temperature": 0.85
-    });
-    
-    // APIリクエスト送信
-    let response = client
-        .post(url)
-        .header("Content-Type", "application/json")
-        .header("Authorization", format!("Bearer {}", api_key))
-        .json(&request_body)
-        .send()
-        .await
-        .context("OpenAI APIリクエスト失敗")?;
-    
-    // レスポンスステータスチェック
-    let status = response.status();
-    if !status.is_success() {
-        let err_text = response.text().await.unwrap_or_default();
-        return Err(anyhow::anyhow!(
-            "類似度評価APIエラー: HTTP {}, response: {}",
-            status,
-            err_text
-        ));
+impl StopRounding {
+    /// 新規に作成するエントリの終了時刻
+    fn round(&self, start: DateTime<Utc>, stop: DateTime<Utc>) -> DateTime<Utc> {
+        self.clamp(round_stop_time(start, stop, self.minutes, self.mode))
     }
-    
-    // レスポンス解析
-    let response_json: serde_json::Value = response
-        .json()
-        .await
-        .context("APIレスポンスJSONの解析に失敗")?;
-    
-    // レスポンスからテキスト抽出
-    let similarity_text = response_json["choices"][0]["message"]["content"]
-        .as_str()
-        .ok_or_else(|| anyhow::anyhow!("APIレスポンスが予期しない形式です"))?
-        .trim();
-    
-    // テキストを数値に変換
-    let similarity: f32 = match similarity_text.parse() {
-        Ok(val) => val,
-        Err(e) => {
-            debug!("類似度のパースに失敗: '{}'。正確な数値のみが含まれていない可能性があります。", similarity_text);
-            
-            // 数値だけを抽出する試み
-            let numeric_chars: String = similarity_text.chars()
-                .filter(|c| c.is_digit(10) || *c == '.')
-                .collect();
-            
-            match numeric_chars.parse() {
-                Ok(val) => {
-                    debug!("数値抽出成功: {}", val);
-                    val
-                },
-                Err(_) => {
-                    return Err(anyhow::anyhow!("類似度のパースに失敗: '{}'", similarity_text));
-                }
-            }
+
+    /// 直前のエントリに統合する場合の終了時刻
+    fn merged(&self, entry_start: DateTime<Utc>, previous_stop: DateTime<Utc>, stop: DateTime<Utc>) -> DateTime<Utc> {
+        self.clamp(merged_stop_time(entry_start, previous_stop, stop, self.minutes, self.mode))
+    }
+
+    fn clamp(&self, stop: DateTime<Utc>) -> DateTime<Utc> {
+        self.limit.map_or(stop, |limit| stop.min(limit))
+    }
+}
+
+/// 統合先の候補（終了済みで、プロジェクトが同じで、終了時刻が現在のブロックの開始に近いエントリ）を終了時刻が近い順に返す
+///
+/// どちらもプロジェクトなしの場合も同じプロジェクトとみなす。終了時刻も合わせて返す
+fn merge_candidates(
+    entries: &[TogglTimeEntry],
+    project_id: Option<u64>,
+    start_time: DateTime<Utc>,
+) -> Vec<(&TogglTimeEntry, DateTime<Utc>)> {
+    // 時間差は開始時刻を秒単位に丸めて計算する
+    let start_time = start_time.with_nanosecond(0).unwrap_or(start_time);
+    let gap = |stop: DateTime<Utc>| (start_time - stop).num_seconds().abs();
+    let mut candidates: Vec<_> = entries.iter()
+        .filter(|entry| entry.project_id == project_id)
+        .filter_map(|entry| {
+            let stop = DateTime::parse_from_rfc3339(entry.stop.as_deref()?).ok()?;
+            Some((entry, stop.with_timezone(&Utc)))
+        })
+        .filter(|(_, stop)| gap(*stop) <= MERGE_MAX_GAP_SECS)
+        .collect();
+    candidates.sort_by_key(|(_, stop)| gap(*stop));
+    candidates
+}
+
+/// 統合先のエントリを1つ選ぶ（終了時刻が最も近い候補のうち、活動が同じとみなせる最初のもの）
+async fn find_merge_target<'a>(
+    entries: &'a [TogglTimeEntry],
+    description: &str,
+    project_id: Option<u64>,
+    start_time: DateTime<Utc>,
+    openai: Option<&crate::config::OpenAIConfig>,
+) -> Option<(&'a TogglTimeEntry, DateTime<Utc>)> {
+    for (entry, stop) in merge_candidates(entries, project_id, start_time) {
+        debug!("マージ候補: {} (ID: {}, 開始: {}, 終了: {})", entry.description, entry.id, entry.start, stop);
+        if is_same_activity(openai, description, &entry.description).await {
+            return Some((entry, stop));
         }
-    };
-    
-    // 0～1の範囲に収める
-    let similarity = similarity.max(0.0).min(1.0);
-    
-    debug!("類似度評価結果: {:.2} ('{}'と'{}'の間)", similarity, activity1, activity2);
-    
-    Ok(similarity)
+    }
+    None
 }
 
-/// 活動記録をTogglに登録する（内部実装）
-async fn register_to_toggl_impl(
+/// 直前のエントリが同じ説明のまま途切れずに続いている場合に、その終了時刻を延長する（`toggl.continuous_same_activity`）
+///
+/// 説明の完全一致だけを見るため、類似度評価のAPIは呼ばない。延長したエントリを返す。
+/// 直前のエントリがこのツールで作成したものでない場合や、プロジェクトが異なる場合、
+/// 間に他のアプリで作成したエントリが挟まっている場合は延長しない
+async fn extend_continuous_entry(
     toggl_client: &TogglClient,
-    analysis: &ExtendedAnalysisResult<'_>,
-    workspace_id: u64,
+    description: &str,
+    project_id: Option<u64>,
     start_time: DateTime<Utc>,
     stop_time: DateTime<Utc>,
-    should_skip_private: bool,
-) -> Result<()> {
-    // プライベートブラウジングは記録しない設定の場合はスキップ
-    if should_skip_private && analysis.is_private_browsing {
-        info!("プライベートブラウジング中の活動はスキップします");
-        return Ok(());
+    rounding: StopRounding,
+) -> Option<TogglTimeEntry> {
+    let lookback = start_time - Duration::hours(CONTINUOUS_LOOKBACK_HOURS);
+    let entries = match toggl_client.get_time_entries(&lookback, &start_time).await {
+        Ok(entries) => entries,
+        Err(e) => {
+            debug!("直前のエントリ取得に失敗: {}", e);
+            return None;
+        }
+    };
+
+    let parse = |time: &str| DateTime::parse_from_rfc3339(time).ok().map(|time| time.with_timezone(&Utc));
+    let (previous, previous_start, previous_stop) = entries.iter()
+        .filter_map(|entry| Some((entry, parse(&entry.start)?, parse(entry.stop.as_deref()?)?)))
+        .max_by_key(|(_, _, stop)| *stop)?;
+
+    let gap_secs = (start_time.with_nanosecond(0).unwrap_or(start_time) - previous_stop).num_seconds();
+    if previous.description != description || gap_secs > CONTINUOUS_MAX_GAP_SECS || previous_stop >= stop_time {
+        return None;
+    }
+    if previous.project_id != project_id || !is_own_entry(previous, toggl_client.created_with()) {
+        debug!("直前のエントリ (ID: {}) はプロジェクトが異なるか他のアプリで作成されたため延長しません", previous.id);
+        return None;
+    }
+    if has_foreign_entry_between(&entries, previous_stop, start_time, toggl_client.created_with(), Utc::now()) {
+        debug!("直前のエントリ (ID: {}) との間に他のアプリのエントリがあるため延長しません", previous.id);
+        return None;
     }
 
-    // 活動の信頼度が低い場合もスキップ
-    if analysis.base.confidence < 0.5 {
-        info!("活動の信頼度が低いためスキップします: {:.2}", analysis.base.confidence);
-        return Ok(());
+    let merged_stop = rounding.merged(previous_start, previous_stop, stop_time);
+    let update_body = serde_json::json!({
+        "stop": format_datetime_for_toggl(&merged_stop)
+    });
+    match toggl_client.update_time_entry(previous, update_body).await {
+        Ok(updated) => {
+            info!("同じ活動が続いているため直前のエントリを延長しました (ID: {}, 終了: {})", updated.id, format_datetime_for_toggl(&merged_stop));
+            Some(updated)
+        }
+        Err(e) => {
+            debug!("エントリ延長失敗: {}", e);
+            None
+        }
     }
+}
 
-    debug!("Togglに記録を開始: {}", analysis.base.activity);
-    debug!("開始時間: {}", format_datetime_for_toggl(&start_time));
-    debug!("終了時間: {}", format_datetime_for_toggl(&stop_time));
-    debug!("信頼度: {:.2}", analysis.base.confidence);
+/// プロジェクト推論の候補
+struct ProjectCandidate<'a> {
+    project: &'a TogglProject,
+    score: f64,
+    reasons: Vec<String>,
+}
+
+/// 照合に使う活動名（組み込みのカテゴリ名は他の言語のカテゴリ名も含める、小文字）
+fn activity_variants(analysis: &AnalysisResult) -> Vec<String> {
+    std::iter::once(analysis.activity.to_lowercase())
+        .chain(Category::translated_labels(&analysis.activity).into_iter().map(str::to_lowercase))
+        .collect()
+}
+
+/// 分析結果と1つのプロジェクトの一致度（スコアとその理由）を計算する
+///
+/// 活動名との一致（完全一致 > 部分一致 > 単語の一致）に、ウィンドウタイトル・カレンダーの予定・
+/// 予定の場所と主催者の一致を加算する
+fn score_project(project: &TogglProject, analysis: &AnalysisResult) -> (f64, Vec<String>) {
+    let project_name_lower = project.name.to_lowercase();
+
+    // 組み込みのカテゴリ名は他の言語のカテゴリ名でも照合する（プロジェクト名と言語が異なる場合）
+    let activity_variants = activity_variants(analysis);
+
+    // 1-3. 活動名との一致（言語違いのカテゴリ名を含め、最もスコアの高いもの）
+    let (mut score, mut match_reasons) = activity_variants.iter()
+        .filter_map(|activity| activity_name_score(&project_name_lower, activity))
+        .max_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal))
+        .map_or((0.0, Vec::new()), |(score, reason)| (score, vec![reason]));
+
+    // 4. ウィンドウタイトルを考慮
+    if let Some(window_title) = &analysis.window_title {
+        if window_title.to_lowercase().contains(&project_name_lower) {
+            score += 0.2;
+            match_reasons.push("ウィンドウタイトルがプロジェクト名を含む".to_string());
+        }
+    }
+
+    let Some(event) = &analysis.calendar_event else {
+        return (score, match_reasons);
+    };
+
+    // 5. カレンダーイベントを考慮
+    if event.title.to_lowercase().contains(&project_name_lower) {
+        score += 0.3;
+        match_reasons.push("カレンダーイベントがプロジェクト名を含む".to_string());
+    }
+
+    // 6. イベントの場所と主催者を考慮
+    if let Some(location) = &event.location {
+        if location.to_lowercase().contains(&project_name_lower) {
+            score += 0.1;
+            match_reasons.push("イベントの場所がプロジェクト名を含む".to_string());
+        }
+    }
+    if let Some(domain) = event.organizer_email.as_deref().and_then(organizer_domain_label) {
+        let client_name_lower = project.client_name.as_ref().map(|name| name.to_lowercase());
+        if project_name_lower.contains(domain.as_str()) {
+            score += 0.2;
+            match_reasons.push("主催者のドメインがプロジェクト名と一致".to_string());
+        } else if client_name_lower.as_ref().is_some_and(|client| client.contains(domain.as_str())) {
+            score += 0.2;
+            match_reasons.push("主催者のドメインがクライアント名と一致".to_string());
+        }
+    }
+
+    (score, match_reasons)
+}
+
+/// 分析結果と各プロジェクトの一致度を計算し、スコアの高い順に並べる
+fn rank_projects<'a>(projects: &'a [TogglProject], analysis: &AnalysisResult) -> Vec<ProjectCandidate<'a>> {
+    debug!("推論に使用する情報:");
+    debug!("- 活動名: {}", analysis.activity);
+    if let Some(ref wt) = analysis.window_title {
+        debug!("- ウィンドウタイトル: {}", wt);
+    }
+    if let Some(ref event) = analysis.calendar_event {
+        debug!("- カレンダーイベント: {}", event.title);
+        if let Some(ref location) = event.location {
+            debug!("- イベントの場所: {}", location);
+        }
+        if let Some(ref organizer) = event.organizer_email {
+            debug!("- 主催者: {}", organizer);
+        }
+    }
+
+    // 各プロジェクトとの類似度を計算し、有意義なスコアがあれば候補に追加
+    let mut match_candidates: Vec<ProjectCandidate> = projects.iter()
+        .filter_map(|project| {
+            let (score, reasons) = score_project(project, analysis);
+            (score > 0.0).then_some(ProjectCandidate { project, score, reasons })
+        })
+        .collect();
+
+    prefer_subprojects(projects, analysis, &mut match_candidates);
     
-    if let Some(ref window_title) = analysis.base.window_title {
-        debug!("ウィンドウタイトル: {}", window_title);
+    // スコアの高い順にソート
+    match_candidates.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(std::cmp::Ordering::Equal));
+    match_candidates
+}
+
+/// 単語に分割する（記号だけの語は除く）
+fn significant_words(text: &str) -> Vec<&str> {
+    text.split_whitespace()
+        .filter(|word| word.chars().any(char::is_alphanumeric))
+        .collect()
+}
+
+/// サブプロジェクトの祖先（親、親の親…）のID
+fn project_ancestors(parents: &HashMap<u64, u64>, id: u64) -> Vec<u64> {
+    let mut ancestors = Vec::new();
+    let mut current = id;
+    while let Some(&parent) = parents.get(&current) {
+        // 親子関係が循環している場合は打ち切る
+        if parent == id || ancestors.contains(&parent) {
+            break;
+        }
+        ancestors.push(parent);
+        current = parent;
     }
+    ancestors
+}
+
+/// 親子のプロジェクトがどちらも一致し、スコアが近い場合は、より具体的な子プロジェクトを優先する
+///
+/// 子プロジェクトが一致しない場合や、親プロジェクトの名前が活動名と完全に一致する場合は親プロジェクトを優先する
+fn prefer_subprojects(projects: &[TogglProject], analysis: &AnalysisResult, candidates: &mut [ProjectCandidate]) {
+    let parents: HashMap<u64, u64> = projects.iter()
+        .filter_map(|project| project.parent_project_id.map(|parent| (project.id, parent)))
+        .collect();
+    let scores: HashMap<u64, f64> = candidates.iter()
+        .map(|candidate| (candidate.project.id, candidate.score))
+        .collect();
+    let activity_variants = activity_variants(analysis);
+    let exact_matches: Vec<u64> = candidates.iter()
+        .filter(|candidate| activity_variants.contains(&candidate.project.name.to_lowercase()))
+        .map(|candidate| candidate.project.id)
+        .collect();
     
-    if let Some(ref calendar_event) = analysis.base.calendar_event {
-        debug!("カレンダーイベント: {}", calendar_event.title);
+    for candidate in candidates.iter_mut() {
+        let ancestors = project_ancestors(&parents, candidate.project.id);
+        if ancestors.iter().any(|id| exact_matches.contains(id)) {
+            continue;
+        }
+        let best_ancestor = ancestors.iter()
+            .filter_map(|id| scores.get(id).copied())
+            .reduce(f64::max);
+        if let Some(ancestor_score) = best_ancestor {
+            if candidate.score + SUBPROJECT_PREFERENCE_MARGIN >= ancestor_score {
+                // 深いプロジェクトほど優先されるよう、階層の深さに応じてわずかに上乗せする
+                candidate.score = candidate.score.max(ancestor_score + 0.01 * ancestors.len() as f64);
+                candidate.reasons.push("親プロジェクトより具体的なサブプロジェクト".to_string());
+            }
+        }
     }
+}
 
-    // プロジェクトIDの推論
-    let project_id = infer_project_id(toggl_client, analysis.base).await?;
-    if let Some(id) = project_id {
-        debug!("プロジェクトID: {}", id);
-    } else {
-        debug!("プロジェクトID: なし");
+/// プロジェクト名と活動名の一致度（一致しない場合はNone）
+fn activity_name_score(project_name_lower: &str, activity_lower: &str) -> Option<(f64, String)> {
+    // 1. 完全一致の場合 (highest priority)
+    if project_name_lower == activity_lower {
+        return Some((1.0, "活動名と完全一致".to_string()));
     }
+    // 2. 部分文字列マッチング
+    if project_name_lower.contains(activity_lower) {
+        return Some((0.8, "プロジェクト名が活動名を含む".to_string()));
+    }
+    if activity_lower.contains(project_name_lower) {
+        return Some((0.7, "活動名がプロジェクト名を含む".to_string()));
+    }
+    // 3. 単語レベルでの一致を検出
+    // 「Client / Feature X」の「/」のような記号だけの語は数えない
+    let project_words: Vec<&str> = significant_words(project_name_lower);
+    let activity_words: Vec<&str> = significant_words(activity_lower);
+    let matching_words = project_words.iter()
+        .filter(|pword| activity_words.contains(pword))
+        .count();
+    if matching_words == 0 {
+        return None;
+    }
+    // 一致する単語の割合でスコア付け（単語一致は完全一致より低い優先度）
+    let word_match_score = matching_words as f64 / project_words.len().max(1) as f64;
+    Some((word_match_score * 0.6, format!("{}個の単語が一致", matching_words)))
+}
 
-    // 直前のタイムブロックのエントリを取得して同名エントリの有無を確認（マージ処理）
-    // 時間ブロック単位で確認するため、検索期間は少し長めにとる
-    let one_hour_ago = start_time - Duration::hours(1);
-    debug!("直前のエントリ検索中 (期間: {} ～ {})", 
-           format_datetime_for_toggl(&one_hour_ago), 
-           format_datetime_for_toggl(&start_time));
+/// プロジェクトIDを推論する
+async fn infer_project_id(
+    toggl_client: &TogglClient, 
+    analysis: &AnalysisResult
+) -> Result<Option<(u64, String)>> {
+    debug!("プロジェクトID推論開始");
     
-    // OpenAI APIキー取得
-    let openai_api_key = match &analysis.config {
-        Some(config) => {
-            match &config.openai {
-                Some(openai_config) => {
-                    debug!("OpenAI APIキーが利用可能です（類似度評価に使用）");
-                    Some(openai_config.api_key.clone())
-                },
-                None => {
-                    debug!("OpenAI設定がないため、類似度評価をスキップします");
-                    None
-                }
+    // ルールでプロジェクトが指定されている場合はそれを優先する
+    if let Some(ref name) = analysis.project {
+        match toggl_client.find_project_by_name(name).await? {
+            Some(id) => {
+                info!("ルールで指定されたプロジェクトを使用します: {} (ID: {})", name, id);
+                return Ok(Some((id, name.clone())));
             }
-        },
-        None => {
-            debug!("アプリ設定がないため、類似度評価をスキップします");
-            None
+            None => warn!("Project '{}' specified by rule was not found, inferring instead", name),
         }
-    };
+    }
+    
+    // プロジェクト一覧を取得
+    let projects = toggl_client.get_projects_cached().await?;
+    debug!("取得したプロジェクト数: {}", projects.len());
 
-    match toggl_client.get_time_entries(&one_hour_ago, &start_time).await {
-        Ok(entries) => {
-            if !entries.is_empty() {
-                debug!("直前の時間エントリ数: {}", entries.len());
-                
-                // 直前のエントリを逆順（新しいものから）でチェック
-                for entry in entries.iter().rev() {
-                    debug!("エントリ確認: {} (開始: {}, 終了: {:?})", 
-                           entry.description, entry.start, entry.stop);
-                    
-                    // 同じプロジェクト（またはどちらもプロジェクトなし）かチェック
-                    let same_project = match (project_id, entry.project_id) {
-                        (Some(p1), Some(p2)) => p1 == p2,
-                        (None, None) => true,
-                        _ => false
-                    };
+    let project = best_project_match(&projects, analysis, PROJECT_MATCH_THRESHOLD)
+        .and_then(|id| projects.iter().find(|project| project.id == id));
+    Ok(project.map(|project| (project.id, project.name.clone())))
+}
 
-                    if entry.stop.is_some() && same_project {
-                        let current_activity = &analysis.base.activity;
-                        let previous_activity = &entry.description;
-
-                        // アクティビティ名が一致するか、類似しているかチェック
-                        let activities_match = if current_activity == previous_activity {
-                            // 完全一致の場合
-                            debug!("アクティビティ名が完全一致: '{}'", current_activity);
-                            true
-                        } else if let Some(api_key) = &openai_api_key {
-                            // 類似度評価（APIキーがある場合のみ）
-                            debug!("AIを使用して類似度評価を実行します");
-                            
-                            match evaluate_activity_similarity(api_key, current_activity, previous_activity).await {
-                                Ok(similarity) => {
-                                    // 類似度閾値（0.85はかなり類似していることを意味する）
-                                    let similarity_threshold = 0.10;
-                                    let is_similar = similarity >= similarity_threshold;
-                                    
-                                    if is_similar {
-                                        info!("アクティビティが類似していると判断: '{}'と'{}' (類似度: {:.2})", 
-                                             current_activity, previous_activity, similarity);
-                                    } else {
-                                        debug!("アクティビティの類似度が低い: '{}'と'{}' (類似度: {:.2})", 
-                                              current_activity, previous_activity, similarity);
-                                    }
-                                    
-                                    is_similar
-                                },
-                                Err(e) => {
-                                    debug!("類似度評価中にエラー発生: {}", e);
-                                    false
-                                }
-                            }
-                        } else {
-                            // APIキーがない場合は完全一致のみ
-                            debug!("OpenAI APIキーが利用できないため、完全一致のみ確認");
-                            false
-                        };
-
-                        if activities_match {
-                            if let Ok(last_stop) = chrono::DateTime::parse_from_rfc3339(&entry.stop.clone().unwrap()) {
-                                let last_stop_utc = last_stop.with_timezone(&chrono::Utc);
-
-                                // 詳細なデバッグ情報
-                                debug!("===== マージ検討の詳細情報 =====");
-                                debug!("前回エントリID: {}", entry.id);
-                                debug!("前回エントリ説明: '{}'", entry.description);
-                                debug!("前回エントリ開始時間: {}", entry.start);
-                                debug!("前回エントリ終了時間: {}", entry.stop.clone().unwrap_or_default());
-                                debug!("前回エントリ終了時間(UTC): {}", last_stop_utc);
-                                debug!("現在エントリ開始時間(UTC): {}", start_time);
-                                debug!("現在エントリ終了時間(UTC): {}", stop_time);
-                                debug!("現在エントリ説明: '{}'", analysis.base.activity);
-
-                                // 時間差計算の前に start_time を秒単位に丸める
-                                let start_time_truncated = start_time.with_nanosecond(0).unwrap_or(start_time);
-                                debug!("現在エントリ開始時間(丸め後): {}", start_time_truncated);
-
-                                // 直前のイベントの終了時間と現在のイベントの開始時間がほぼ等しいか確認
-                                let secs_diff = (start_time_truncated - last_stop_utc).num_seconds();
-                                debug!("前回終了時間との差: {}秒", secs_diff);
-                                debug!("マージ条件: 時間差が15分以内? {}", secs_diff.abs() <= 900);
-
-                                // 連続する時間ブロックかどうかを確認（数秒の誤差を許容）
-                                if secs_diff.abs() <= 900 {
-                                    info!("連続する類似イベントをマージします (ID: {})", entry.id);
-                                    
-                                    // マージ用のJSONボディを構築
-                                    let update_body = serde_json::json!({
-                                        "stop": format_datetime_for_toggl(&stop_time)
-                                    });
-                                    
-                                    // エントリを更新
-                                    let url = format!("https://api.track.toggl.com/api/v9/workspaces/{}/time_entries/{}", 
-                                                     workspace_id, entry.id);
-                                    
-                                    match toggl_client.client.put(&url)
-                                        .headers(toggl_client.auth_headers())
-                                        .json(&update_body)
-                                        .send()
-                                        .await {
-                                        Ok(response) => {
-                                            let status = response.status();
-                                            if status.is_success() {
-                                                info!("タイムエントリを更新しました (ID: {})", entry.id);
-                                                return Ok(());
-                                            } else {
-                                                let err_text = response.text().await.unwrap_or_default();
-                                                debug!("エントリ更新失敗: HTTP {} {}", status, err_text);
-                                            }
-                                        },
-                                        Err(e) => {
-                                            debug!("エントリ更新リクエスト失敗: {}", e);
-                                        }
-                                    }
-                                    
-                                    // マージ試行後は処理を続行（失敗しても新規エントリを作成）
-                                    break;
-                                }
-                            }
-                        }
-                    }
-                }
-            }
-        },
-        Err(e) => {
-            debug!("直前のエントリ取得に失敗: {}", e);
+/// 一致度が閾値以上で最も高いプロジェクトのIDを選ぶ
+fn best_project_match(projects: &[TogglProject], analysis: &AnalysisResult, threshold: f64) -> Option<u64> {
+    let match_candidates = rank_projects(projects, analysis);
+
+    // 候補をログに出力
+    debug!("プロジェクト候補リスト:");
+    for (i, candidate) in match_candidates.iter().enumerate() {
+        debug!("候補{}: {} (ID: {}, スコア: {:.2})", i+1, candidate.project.display_name(), candidate.project.id, candidate.score);
+        for reason in &candidate.reasons {
+            debug!("  - {}", reason);
         }
     }
 
-    // TimeEntryリクエストの作成（マージできない場合は新規作成）
-    let time_entry = TimeEntry {
-        description: analysis.base.activity.clone(),
-        wid: workspace_id,
-        pid: project_id,
-        start: format_datetime_for_toggl(&start_time),
-        stop: Some(format_datetime_for_toggl(&stop_time)),
-        duration: Some((stop_time - start_time).num_seconds()),
-        created_with: Some("toggl_linux_rs".to_string()),
-        tags: None, // タグを削除
-        event_metadata: Some(serde_json::json!({
-            "origin_feature": "linux_rs_activity",
-            "visible_goals_count": 0
-        })),
-    };
+    // 最良の候補を返す（スコアが閾値以上の場合）
+    match match_candidates.first() {
+        Some(best_match) if best_match.score >= threshold => {
+            info!("選択されたプロジェクト: {} (ID: {}, スコア: {:.2})",
+                  best_match.project.display_name(), best_match.project.id, best_match.score);
+            Some(best_match.project.id)
+        }
+        _ => {
+            debug!("適切なプロジェクトが見つかりませんでした");
+            None
+        }
+    }
+}
 
-    // 時間記録の登録
-    debug!("Togglへ時間記録を送信...");
-    let entry_id = toggl_client.create_time_entry(time_entry).await?;
-    info!("Togglへの時間記録を完了しました (ID: {})", entry_id);
+/// ユーザーに活動候補を提示する（コマンドライン用）
+pub fn present_activity_choices(analysis: &AnalysisResult) -> Result<String> {
+    choose_activity(analysis, &mut std::io::stdin().lock())
+}
 
-    Ok(())
-} 
\ No newline at end of file
+/// 候補を表示し、`input` から読んだ選択に応じた活動名を返す
+fn choose_activity(analysis: &AnalysisResult, input: &mut impl std::io::BufRead) -> Result<String> {
+    println!("活動推定の確度が低いため、以下から選択してください：");
+    println!("0: [{}] (確度: {:.2})", analysis.activity, analysis.confidence);
+    
+    for (i, alt) in analysis.alternatives.iter().enumerate() {
+        println!(
+            "{}: [{}] (確度: {:.2})",
+            i + 1,
+            alt.activity,
+            alt.confidence
+        );
+    }
+    
+    println!("{}: [新規活動を入力]", analysis.alternatives.len() + 1);
+    
+    // 標準入力から選択を受け取る
+    print!("選択（数字）: ");
+    std::io::Write::flush(&mut std::io::stdout()).unwrap();
+    
+    let mut choice = String::new();
+    input.read_line(&mut choice)?;
+    
+    let choice = choice.trim().parse::<usize>().unwrap_or(0);
+    
+    if choice == 0 {
+        Ok(analysis.activity.clone())
+    } else if choice <= analysis.alternatives.len() {
+        Ok(analysis.alternatives[choice - 1].activity.clone())
+    } else {
+        print!("新しい活動名を入力: ");
+        std::io::Write::flush(&mut std::io::stdout()).unwrap();
+        
+        let mut new_activity = String::new();
+        input.read_line(&mut new_activity)?;
+        
+        Ok(new_activity.trim().to_string())
+    }
+}
+
+/// ユーザーが選んだ活動を確度1.0の分析結果にする（空の場合は登録しないためNone）
+pub fn confirm_activity(analysis: &AnalysisResult, activity: String) -> Option<AnalysisResult> {
+    if activity.is_empty() {
+        return None;
+    }
+    Some(AnalysisResult {
+        activity,
+        confidence: 1.0,
+        ..analysis.clone()
+    })
+}
+
+/// 分析結果の時間ブロック全体に活動を登録する（バックフィルでは同じクライアントを使い回す）
+pub async fn register_to_toggl(
+    toggl_client: &TogglClient,
+    config: &AppConfig,
+    analysis: &AnalysisResult,
+) -> Result<RegistrationOutcome> {
+    // 活動の開始・終了時刻を決定（設定したタイムゾーンの時間ブロックの境界に合わせ、UTCで登録する）
+    let (start_time, stop_time) = config.general.block_bounds(analysis.timestamp);
+    register_range_with_client(toggl_client, config, analysis, start_time, stop_time).await
+}
+
+/// 時間ブロックの一部（アイドルの時間帯を除いた部分など）に活動を登録する
+pub async fn register_range_to_toggl(
+    config: &AppConfig,
+    analysis: &AnalysisResult,
+    start_time: DateTime<Utc>,
+    stop_time: DateTime<Utc>,
+) -> Result<RegistrationOutcome> {
+    register_range_with_client(&TogglClient::from_config(config), config, analysis, start_time, stop_time).await
+}
+
+async fn register_range_with_client(
+    toggl_client: &TogglClient,
+    config: &AppConfig,
+    analysis: &AnalysisResult,
+    start_time: DateTime<Utc>,
+    stop_time: DateTime<Utc>,
+) -> Result<RegistrationOutcome> {
+    // プライベートブラウジングのフラグを確認（ウィンドウタイトルとクラスから判定）
+    let is_private_browsing = is_private_browsing(config, analysis);
+    
+    // ActivityResultにis_private_browsingフィールドが無いため、拡張AnalysisResultを作成
+    let extended_analysis = ExtendedAnalysisResult {
+        base: analysis,
+        is_private_browsing,
+        config: Some(config),
+        notify: send_notification,
+    };
+    
+    // 詳細なRegister to Toggl関数を呼び出す
+    register_to_toggl_impl(
+        toggl_client,
+        &extended_analysis,
+        config.toggl.workspace_id,
+        start_time,
+        stop_time,
+        config.general.skip_private_browsing,
+    ).await
+}
+
+/// 時間ブロックを活動とアイドルの時間帯に分けた結果（`general.idle_behavior`）
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdleSplit {
+    /// 活動を登録する時間帯（ブロック全体がアイドルの場合は None）
+    pub active: Option<(DateTime<Utc>, DateTime<Utc>)>,
+
+    /// アイドルとして登録する時間帯（アイドル時間を登録しない設定やアイドルがない場合は None）
+    pub idle: Option<(DateTime<Utc>, DateTime<Utc>)>,
+}
+
+/// 時間ブロックを活動とアイドルの時間帯に分ける（`idle_behavior = "record"` かつ `tracking_mode = "block"` のみ）
+///
+/// アイドル時間はアイドルのサンプル数 × 収集間隔とし、アイドルのサンプルが多いほうの半分の端
+/// （前半ならブロックの先頭、後半なら末尾）に置く
+pub fn split_idle_block(
+    config: &AppConfig,
+    data: &[CollectedData],
+    block_start: DateTime<Utc>,
+    block_stop: DateTime<Utc>,
+) -> IdleSplit {
+    let whole_block = IdleSplit { active: Some((block_start, block_stop)), idle: None };
+    if config.general.idle_behavior != IdleBehavior::Record || config.general.tracking_mode != TrackingMode::Block {
+        return whole_block;
+    }
+
+    let idle_samples: Vec<DateTime<Utc>> = data.iter()
+        .filter(|d| d.is_idle)
+        .map(|d| d.timestamp)
+        .collect();
+    let idle_secs = idle_samples.len() as u64 * config.general.collect_interval_secs;
+    let idle_length = Duration::seconds(idle_secs as i64).min(block_stop - block_start);
+    if idle_length <= Duration::zero() {
+        return whole_block;
+    }
+
+    let middle = block_start + (block_stop - block_start) / 2;
+    let leading = idle_samples.iter().filter(|timestamp| **timestamp < middle).count() * 2 > idle_samples.len();
+    let (idle, active) = if leading {
+        ((block_start, block_start + idle_length), (block_start + idle_length, block_stop))
+    } else {
+        ((block_stop - idle_length, block_stop), (block_start, block_stop - idle_length))
+    };
+    IdleSplit {
+        active: Some(active).filter(|(start, stop)| start < stop),
+        idle: Some(idle),
+    }
+}
+
+/// アイドルだった時間帯を `general.idle_description` のエントリとして登録する（`idle_behavior = "record"`）
+pub async fn register_idle_to_toggl(
+    config: &AppConfig,
+    start_time: DateTime<Utc>,
+    stop_time: DateTime<Utc>,
+) -> Result<RegistrationOutcome> {
+    let toggl_client = TogglClient::from_config(config);
+    register_idle_entry(&toggl_client, config, start_time, stop_time).await
+}
+
+/// アイドルのエントリを登録する（内部実装）
+async fn register_idle_entry(
+    toggl_client: &TogglClient,
+    config: &AppConfig,
+    start_time: DateTime<Utc>,
+    stop_time: DateTime<Utc>,
+) -> Result<RegistrationOutcome> {
+    let description = config.general.idle_description.trim().to_string();
+    let project_id = match config.general.idle_project.as_deref() {
+        Some(name) => {
+            let project_id = toggl_client.find_project_by_name(name).await?;
+            if project_id.is_none() {
+                warn!("Idle project '{}' was not found, registering the idle entry without a project", name);
+            }
+            project_id
+        }
+        None => None,
+    };
+    let project_name = project_id.and(config.general.idle_project.clone());
+
+    // 手動の記録など、他のアプリで作成されたエントリと重なる部分は登録しない
+    let (start_time, stop_time) = if config.toggl.avoid_overlaps {
+        let lookback_start = start_time - Duration::hours(OVERLAP_LOOKBACK_HOURS);
+        match toggl_client.get_time_entries(&lookback_start, &stop_time).await {
+            Ok(entries) => match uncovered_block_range(&entries, start_time, stop_time, toggl_client.created_with(), Utc::now()) {
+                None => {
+                    info!("アイドルの時間帯全体が既存のエントリで記録済みのためスキップします");
+                    return Ok(RegistrationOutcome::Skipped { reason: SkipReason::AlreadyTracked });
+                }
+                Some(range) => range,
+            },
+            Err(e) => {
+                warn!("Failed to fetch time entries to check for overlaps, registering the whole idle time: {:#}", e);
+                (start_time, stop_time)
+            }
+        }
+    } else {
+        (start_time, stop_time)
+    };
+
+    // クラッシュ後の再起動などで同じブロックを再登録しないよう、同じキーのエントリがあれば作成しない
+    let key = dedupe_key(config.toggl.workspace_id, start_time, &description);
+    match toggl_client.get_time_entries(&start_time, &stop_time).await {
+        Ok(entries) => {
+            if let Some(existing) = find_duplicate_entry(&entries, &key) {
+                info!("アイドルのエントリが登録済みのため作成しません (ID: {}, key: {})", existing.id, key);
+                return Ok(RegistrationOutcome::Duplicate {
+                    entry_id: existing.id,
+                    project_id,
+                    project_name,
+                });
+            }
+        }
+        Err(e) => {
+            debug!("重複確認のためのエントリ取得に失敗: {}", e);
+        }
+    }
+
+    let billable = resolve_billable(toggl_client, project_id, config.toggl.default_billable).await;
+    let time_entry = TimeEntry {
+        description: description.clone(),
+        wid: config.toggl.workspace_id,
+        pid: project_id,
+        start: format_datetime_for_toggl(&start_time),
+        stop: Some(format_datetime_for_toggl(&stop_time)),
+        duration: Some((stop_time - start_time).num_seconds()),
+        created_with: Some(toggl_client.created_with().to_string()),
+        tags: None,
+        billable: Some(billable),
+        event_metadata: Some(serde_json::json!({
+            "origin_feature": "linux_rs_idle",
+            "visible_goals_count": 0,
+            "dedupe_key": key
+        })),
+    };
+
+    let entry_id = toggl_client.create_time_entry(time_entry).await?;
+    info!("アイドル時間を '{}' として登録しました (ID: {}, {} ～ {})",
+          description, entry_id, format_datetime_for_toggl(&start_time), format_datetime_for_toggl(&stop_time));
+
+    Ok(RegistrationOutcome::Created {
+        entry_id,
+        project_id,
+        project_name,
+    })
+}
+
+/// ストリームモード: 実行中のエントリを維持し、活動が変わったときだけ停止・再開する
+pub async fn stream_to_toggl(
+    config: &AppConfig,
+    analysis: &AnalysisResult,
+    start_time: DateTime<Utc>,
+    stop_time: DateTime<Utc>,
+) -> Result<RegistrationOutcome> {
+    stream_with_client(&TogglClient::from_config(config), config, analysis, start_time, stop_time).await
+}
+
+/// ストリームモードで登録しないブロックになった場合に、このツールが開始した実行中のエントリを停止する
+///
+/// 離席中やプライベートブラウジング中の時間が実行中のエントリに記録され続けないようにする
+pub async fn stop_streamed_entry(config: &AppConfig) -> Result<()> {
+    stop_own_running_entry(&TogglClient::from_config(config)).await
+}
+
+/// 実行中のエントリがこのツールで開始したものであれば停止する（手動で開始したタイマーなどには触れない）
+async fn stop_own_running_entry(toggl_client: &TogglClient) -> Result<()> {
+    match toggl_client.get_running_time_entry().await? {
+        Some(running) if is_own_entry(&running, toggl_client.created_with()) => {
+            info!("実行中のエントリを停止します (ID: {}, '{}')", running.id, running.description);
+            toggl_client.stop_time_entry(&running).await?;
+        }
+        Some(running) => debug!("他のアプリで開始されたエントリのため停止しません (ID: {})", running.id),
+        None => {}
+    }
+    Ok(())
+}
+
+/// 指定したクライアントで実行中のエントリを維持する（`start_time`〜`stop_time` は分析した時間ブロック）
+async fn stream_with_client(
+    toggl_client: &TogglClient,
+    config: &AppConfig,
+    analysis: &AnalysisResult,
+    start_time: DateTime<Utc>,
+    stop_time: DateTime<Utc>,
+) -> Result<RegistrationOutcome> {
+    // 手動で開始したタイマーなど、他のアプリの実行中のエントリは停止も更新もしない
+    // （新しいエントリを開始するとTogglが実行中のエントリを停止するため、開始もしない）
+    let running = toggl_client.get_running_time_entry().await?;
+    if let Some(foreign) = running.as_ref().filter(|entry| !is_own_entry(entry, toggl_client.created_with())) {
+        info!("他のアプリで開始されたエントリが実行中のためスキップします (ID: {}, '{}')", foreign.id, foreign.description);
+        return Ok(RegistrationOutcome::Skipped { reason: SkipReason::AlreadyTracked });
+    }
+    
+    let extended_analysis = ExtendedAnalysisResult {
+        base: analysis,
+        is_private_browsing: is_private_browsing(config, analysis),
+        config: Some(config),
+        notify: send_notification,
+    };
+    let prepared = match prepare_entry(toggl_client, &extended_analysis, start_time, stop_time, config.general.skip_private_browsing).await? {
+        Ok(prepared) => prepared,
+        Err(reason) => {
+            stop_own_running_entry(toggl_client).await?;
+            return Ok(RegistrationOutcome::Skipped { reason });
+        }
+    };
+    let PreparedEntry { base, description, project_id, project_name } = prepared;
+    
+    // 時間ブロック全体が手動の記録などで覆われている場合は、自分のエントリを止めて記録しない
+    if config.toggl.avoid_overlaps {
+        let lookback_start = start_time - Duration::hours(OVERLAP_LOOKBACK_HOURS);
+        match toggl_client.get_time_entries(&lookback_start, &stop_time).await {
+            Ok(entries) if uncovered_block_range(&entries, start_time, stop_time, toggl_client.created_with(), Utc::now()).is_none() => {
+                info!("時間ブロック全体が既存のエントリで記録済みのためスキップします");
+                stop_own_running_entry(toggl_client).await?;
+                return Ok(RegistrationOutcome::Skipped { reason: SkipReason::AlreadyTracked });
+            }
+            Ok(_) => {}
+            Err(e) => warn!("Failed to fetch time entries to check for overlaps: {:#}", e),
+        }
+    }
+    
+    if let Some(running) = running {
+        let same_project = running.project_id == project_id;
+        let same_activity = is_same_activity(config.similarity_openai_config(), &description, &running.description).await;
+        
+        if same_project && same_activity {
+            debug!("実行中のエントリを継続します (ID: {}, '{}')", running.id, running.description);
+            return Ok(RegistrationOutcome::Continued {
+                entry_id: running.id,
+                project_id,
+                project_name,
+            });
+        }
+        
+        // 活動は同じでプロジェクトの推論だけが変わった場合は、停止せずにプロジェクトを付け替える
+        if same_activity {
+            let patch = serde_json::json!({ "project_id": project_id });
+            if let Some(updated) = toggl_client.update_running_entry(patch).await? {
+                info!("実行中のエントリのプロジェクトを更新しました (ID: {}, '{}')", updated.id, updated.description);
+                return Ok(RegistrationOutcome::Continued {
+                    entry_id: updated.id,
+                    project_id,
+                    project_name,
+                });
+            }
+        }
+        
+        info!("活動が変化したため実行中のエントリを停止します (ID: {}, '{}' -> '{}')",
+              running.id, running.description, description);
+        toggl_client.stop_time_entry(&running).await?;
+    }
+    
+    let billable = resolve_billable(toggl_client, project_id, config.toggl.default_billable).await;
+    let tags = calendar_tags(Some(config), base.calendar_event.as_ref());
+    let started = toggl_client.start_time_entry(project_id, &description, billable, tags).await?;
+    info!("実行中のエントリを開始しました (ID: {}, '{}')", started.id, description);
+    
+    Ok(RegistrationOutcome::Created {
+        entry_id: started.id,
+        project_id,
+        project_name,
+    })
+}
+
+/// ウィンドウタイトルまたはクラスに設定されたマーカーが含まれる場合はプライベートブラウジングとみなす
+fn is_private_browsing(config: &AppConfig, analysis: &AnalysisResult) -> bool {
+    let candidates = [analysis.window_title.as_deref(), analysis.window_class.as_deref()];
+    
+    candidates.iter().flatten().any(|text| {
+        let text = text.to_lowercase();
+        config.general.private_browsing_markers
+            .iter()
+            .any(|marker| !marker.is_empty() && text.contains(&marker.to_lowercase()))
+    })
+}
+
+/// デスクトップ通知を送る関数（`send_notification` と同じ引数）
+type Notifier = fn(&str, &str, Option<&str>) -> Result<()>;
+
+// AnalysisResultを拡張して必要なフィールドを追加
+struct ExtendedAnalysisResult<'a> {
+    base: &'a AnalysisResult,
+    is_private_browsing: bool,
+    config: Option<&'a AppConfig>,
+    /// 登録に成功したときの通知に使う関数（`toggl.notify_on_register`）
+    notify: Notifier,
+}
+
+/// 登録に成功したことを控えめな通知で知らせる（`toggl.notify_on_register` が有効な場合のみ）
+fn notify_registered(
+    analysis: &ExtendedAnalysisResult<'_>,
+    description: &str,
+    duration: Duration,
+    project_name: Option<&str>,
+) {
+    if !analysis.config.is_some_and(|config| config.toggl.notify_on_register) {
+        return;
+    }
+
+    let mut message = format!("記録しました: {} ({}分)", description, duration.num_minutes());
+    if let Some(project_name) = project_name {
+        message.push_str(&format!(" [{}]", project_name));
+    }
+    if let Err(e) = (analysis.notify)("toggl_linux_rs", &message, Some("low")) {
+        warn!("Failed to send notification: {:#}", e);
+    }
+}
+
+/// 活動内容が空の場合に、ウィンドウクラス（なければ `UNKNOWN_ACTIVITY`）を説明にした分析結果を返す
+///
+/// 空の説明のエントリはTogglに拒否されることがあるため、登録前に置き換える
+fn with_fallback_activity(result: &AnalysisResult) -> Option<AnalysisResult> {
+    if !result.activity.trim().is_empty() {
+        return None;
+    }
+    let activity = result.window_class.as_deref()
+        .map(str::trim)
+        .filter(|class| !class.is_empty())
+        .unwrap_or(UNKNOWN_ACTIVITY);
+    warn!("Analysis produced an empty activity, registering it as '{}'", activity);
+    Some(AnalysisResult {
+        activity: activity.to_string(),
+        ..result.clone()
+    })
+}
+
+/// `toggl.description_template` のプレースホルダーを分析結果の値で置き換える
+///
+/// 値のないプレースホルダーは、隣り合う区切り文字（" — " や空になった括弧）とともに取り除く。
+/// すべて空になった場合は活動内容をそのまま使う
+fn render_description(template: &str, result: &AnalysisResult) -> String {
+    /// テンプレートを分割した断片
+    enum Piece {
+        Text(String),
+        Value(String),
+        Missing,
+    }
+
+    let field = |name: &str| -> Option<Option<String>> {
+        let value = match name {
+            "activity" => Some(result.activity.clone()),
+            "window_title" => result.window_title.clone(),
+            "window_class" => result.window_class.clone(),
+            "calendar_title" => result.calendar_event.as_ref().map(|event| event.title.clone()),
+            "confidence" => Some(format!("{:.2}", result.confidence)),
+            _ => return None,
+        };
+        Some(value.filter(|value| !value.trim().is_empty()))
+    };
+
+    // 未知のプレースホルダーはそのまま残す
+    let mut pieces = Vec::new();
+    let mut rest = template;
+    while let Some((open, close)) = rest.find('{').and_then(|open| Some((open, open + rest[open..].find('}')?))) {
+        pieces.push(Piece::Text(rest[..open].to_string()));
+        pieces.push(match field(&rest[open + 1..close]) {
+            Some(Some(value)) => Piece::Value(value),
+            Some(None) => Piece::Missing,
+            None => Piece::Text(rest[open..=close].to_string()),
+        });
+        rest = &rest[close + 1..];
+    }
+    pieces.push(Piece::Text(rest.to_string()));
+
+    let (empty_brackets, separators, spaces) = DESCRIPTION_SEPARATOR_RE.get_or_init(|| (
+        regex::Regex::new(r"\(\s*\)|\[\s*\]|「\s*」|（\s*）").unwrap(),
+        regex::Regex::new(r"[-—–|:/,・]+").unwrap(),
+        regex::Regex::new(r"\s{2,}").unwrap(),
+    ));
+    let is_separator = |c: char| c.is_whitespace() || separators.is_match(c.encode_utf8(&mut [0; 4]));
+
+    // 値と値の間の固定の文字列ごとに、値のない項目を取り除いた区切りを決める
+    let mut description = String::new();
+    let mut texts: Vec<String> = Vec::new();
+    let mut missing = false;
+    let flush = |description: &mut String, texts: &mut Vec<String>, missing: &mut bool, at_end: bool| {
+        let joined = texts.concat();
+        if !*missing {
+            description.push_str(&joined);
+        } else {
+            let cleaned = empty_brackets.replace_all(&joined, "");
+            // " — {window_class} — " のように区切りが重なる場合は、前の値の直後の区切りだけを残す
+            let gap = if separators.find_iter(&cleaned).count() > 1 { texts[0].clone() } else { cleaned.into_owned() };
+            let mut gap = spaces.replace_all(&gap, " ").into_owned();
+            if description.is_empty() {
+                gap = gap.trim_start_matches(is_separator).to_string();
+            }
+            if at_end {
+                gap = gap.trim_end_matches(is_separator).to_string();
+            }
+            description.push_str(&gap);
+        }
+        texts.clear();
+        *missing = false;
+    };
+    for piece in pieces {
+        match piece {
+            Piece::Text(text) => texts.push(text),
+            Piece::Missing => missing = true,
+            Piece::Value(value) => {
+                flush(&mut description, &mut texts, &mut missing, false);
+                description.push_str(&value);
+            }
+        }
+    }
+    flush(&mut description, &mut texts, &mut missing, true);
+
+    let description = description.trim();
+    if description.is_empty() {
+        return truncate_string_safely(&result.activity, MAX_DESCRIPTION_LEN - 3);
+    }
+    truncate_string_safely(description, MAX_DESCRIPTION_LEN - 3)
+}
+
+/// カレンダーイベントが時間ブロックに占める割合（0.0-1.0）を求める
+fn calendar_overlap_ratio(
+    event: &crate::data_collector::CalendarEvent,
+    start_time: DateTime<Utc>,
+    stop_time: DateTime<Utc>,
+) -> f64 {
+    let block_secs = (stop_time - start_time).num_seconds();
+    if block_secs <= 0 {
+        return 0.0;
+    }
+    
+    let overlap_start = event.start_time.max(start_time);
+    let overlap_end = event.end_time.min(stop_time);
+    let overlap_secs = (overlap_end - overlap_start).num_seconds().max(0);
+    
+    overlap_secs as f64 / block_secs as f64
+}
+
+/// タイムスタンプを含む時間ブロックの開始・終了時刻を求める
+///
+/// ブロックの境界はUTCからのオフセット `utc_offset` のローカル時刻で揃えるため、
+/// +5:30 や +5:45 のようなタイムゾーンでもローカル時刻の :00/:15/:30/:45 がブロックの境界になる
+pub fn block_bounds(
+    timestamp: DateTime<Utc>,
+    minutes_per_block: u64,
+    utc_offset: FixedOffset,
+) -> (DateTime<Utc>, DateTime<Utc>) {
+    let block_secs = (minutes_per_block.max(1) * 60) as i64;
+    let offset_secs = utc_offset.local_minus_utc() as i64;
+    let local_secs = timestamp.timestamp() + offset_secs;
+    let start_secs = local_secs - local_secs.rem_euclid(block_secs) - offset_secs;
+    
+    let start_time = DateTime::<Utc>::from_timestamp(start_secs, 0).unwrap_or(timestamp);
+    let stop_time = start_time + Duration::seconds(block_secs);
+    
+    (start_time, stop_time)
+}
+
+/// RFC3339形式の日時文字列を生成（ミリ秒なし）
+fn format_datetime_for_toggl(dt: &DateTime<Utc>) -> String {
+    // TogglのAPIはミリ秒を含むと400エラーを返すので、ミリ秒なしのフォーマットを使用
+    // RFC3339/ISO8601形式で、タイムゾーンをZに設定する
+    // 例: 2023-04-10T15:30:45Z
+    dt.format("%Y-%m-%dT%H:%M:%SZ").to_string()
+}
+
+/// イベント名の類似度を評価する
+async fn evaluate_activity_similarity(
+    openai: &crate::config::OpenAIConfig,
+    activity1: &str,
+    activity2: &str,
+) -> Result<f32> {
+    debug!("AIを使用してイベント名の類似度を評価: '{}' vs '{}'", activity1, activity2);
+    
+    // OpenAI APIクライアント設定
+    let client = crate::utils::http_client();
+    let url = format!("{}/chat/completions", openai.api_base.trim_end_matches('/'));
+    
+    // APIリクエスト作成
+    let request_body = serde_json::json!({
+        "model": "gpt-3.5-turbo",
+        "messages": [
+            {
+                "role": "system",
+                "content": "あなたはイベント名の類似度を評価するAIアシスタントです。2つのイベント名を比較し、それらの意味的な類似性を0から1の小数値で評価してください。0は全く関連がない、1は完全に同じ意味を持つことを示します。最終的な回答は数値のみを出力してください。"
+            },
+            {
+                "role": "user",
+                "content": format!("以下の2つのイベント名の類似度を0から1のスケールで評価してください。回答は数値のみを出力してください。\nイベント1: {}\nイベント2: {}", activity1, activity2)
+            }
+        ],
+        "temperature": 0.85
+    });
+    
+    // APIリクエスト送信
+    // ローカルのOpenAI互換サーバーではAPIキーを空にできるため、その場合は認証ヘッダーを送らない
+    let mut request = client.post(&url).json(&request_body);
+    if !openai.api_key.is_empty() {
+        request = request.bearer_auth(&openai.api_key);
+    }
+    let response = request
+        .send()
+        .await
+        .context("OpenAI APIリクエスト失敗")?;
+    
+    // レスポンスステータスチェック
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        return Err(crate::analysis::OpenAIHttpError { status, body }.into());
+    }
+    
+    // レスポンス解析
+    let response_json: serde_json::Value = response
+        .json()
+        .await
+        .context("APIレスポンスJSONの解析に失敗")?;
+    
+    // レスポンスからテキスト抽出
+    let similarity_text = response_json["choices"][0]["message"]["content"]
+        .as_str()
+        .ok_or_else(|| anyhow::anyhow!("APIレスポンスが予期しない形式です"))?
+        .trim();
+    
+    // テキストを数値に変換
+    let similarity: f32 = match similarity_text.parse() {
+        Ok(val) => val,
+        Err(_) => {
+            debug!("類似度のパースに失敗: '{}'。正確な数値のみが含まれていない可能性があります。", similarity_text);
+            
+            // 数値だけを抽出する試み
+            let numeric_chars: String = similarity_text.chars()
+                .filter(|c| c.is_ascii_digit() || *c == '.')
+                .collect();
+            
+            match numeric_chars.parse() {
+                Ok(val) => {
+                    debug!("数値抽出成功: {}", val);
+                    val
+                },
+                Err(_) => {
+                    return Err(anyhow::anyhow!("類似度のパースに失敗: '{}'", similarity_text));
+                }
+            }
+        }
+    };
+    
+    // 0～1の範囲に収める
+    let similarity = similarity.clamp(0.0, 1.0);
+    
+    debug!("類似度評価結果: {:.2} ('{}'と'{}'の間)", similarity, activity1, activity2);
+    
+    Ok(similarity)
+}
+
+/// 比較用に活動名を正規化する（大文字・小文字と空白の違いを無視する）
+fn normalize_activity(activity: &str) -> String {
+    activity.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase()
+}
+
+/// 2つの活動が同一とみなせるかを判定する（正規化した文字列の一致、またはAIによる類似度評価）
+async fn is_same_activity(
+    openai: Option<&crate::config::OpenAIConfig>,
+    current_activity: &str,
+    previous_activity: &str,
+) -> bool {
+    if normalize_activity(current_activity) == normalize_activity(previous_activity) {
+        // 完全一致の場合
+        debug!("アクティビティ名が一致: '{}'", current_activity);
+        return true;
+    }
+    
+    let Some(openai) = openai else {
+        // 類似度評価を使えない場合は文字列の一致のみ
+        debug!("類似度評価を使えないため、文字列の一致のみ確認");
+        return false;
+    };
+    
+    // 類似度評価（APIキーがある場合のみ）
+    debug!("AIを使用して類似度評価を実行します");
+    
+    // 認証エラー後は分析と同じく休止し、休止中は呼び出さない
+    let similarity = crate::analysis::OPENAI_AUTH_PAUSE.guard(
+        evaluate_activity_similarity(openai, current_activity, previous_activity),
+        crate::analysis::is_openai_unauthorized,
+    );
+    match similarity.await {
+        Ok(similarity) => {
+            let is_similar = similarity >= SIMILARITY_THRESHOLD;
+            
+            if is_similar {
+                info!("アクティビティが類似していると判断: '{}'と'{}' (類似度: {:.2})", 
+                     current_activity, previous_activity, similarity);
+            } else {
+                debug!("アクティビティの類似度が低い: '{}'と'{}' (類似度: {:.2})", 
+                      current_activity, previous_activity, similarity);
+            }
+            
+            is_similar
+        },
+        Err(e) => {
+            debug!("類似度評価中にエラー発生: {}", e);
+            false
+        }
+    }
+}
+
+/// 登録する説明とプロジェクト（ブロックモードとストリームモードで共通の前処理の結果）
+struct PreparedEntry {
+    /// 予定名や空の活動の置き換えを反映した分析結果
+    base: AnalysisResult,
+    
+    /// `toggl.description_template` を適用した説明
+    description: String,
+    
+    /// 推論したプロジェクトのID
+    project_id: Option<u64>,
+    
+    /// 推論したプロジェクトの名前
+    project_name: Option<String>,
+}
+
+/// 登録前の共通処理（プライベートブラウジングと確度の確認、予定名・空の活動の置き換え、説明のテンプレート、プロジェクトの推論）
+///
+/// 登録しない場合はスキップの理由を返す
+async fn prepare_entry(
+    toggl_client: &TogglClient,
+    analysis: &ExtendedAnalysisResult<'_>,
+    start_time: DateTime<Utc>,
+    stop_time: DateTime<Utc>,
+    should_skip_private: bool,
+) -> Result<std::result::Result<PreparedEntry, SkipReason>> {
+    // プライベートブラウジングは記録しない設定の場合はスキップ
+    if should_skip_private && analysis.is_private_browsing {
+        info!("プライベートブラウジング中の活動はスキップします");
+        return Ok(Err(SkipReason::PrivateBrowsing));
+    }
+
+    // 活動の信頼度が低い場合もスキップ
+    let confidence_threshold = analysis.config
+        .map_or_else(crate::config::default_confidence_threshold, |config| config.general.confidence_threshold);
+    if analysis.base.confidence < confidence_threshold {
+        info!("活動の信頼度が低いためスキップします: {:.2}", analysis.base.confidence);
+        return Ok(Err(SkipReason::LowConfidence));
+    }
+
+    // カレンダーイベントがブロックの大半を占める場合は、予定名を説明とプロジェクト推論に使う
+    let calendar_override = analysis.config
+        .filter(|config| config.general.prefer_calendar_title)
+        .and(analysis.base.calendar_event.as_ref())
+        .filter(|event| calendar_overlap_ratio(event, start_time, stop_time) > CALENDAR_OVERLAP_THRESHOLD)
+        .map(|event| {
+            info!("カレンダーイベントの予定名を説明に使用します: '{}'", event.title);
+            AnalysisResult {
+                activity: event.title.clone(),
+                ..analysis.base.clone()
+            }
+        });
+    let base = calendar_override
+        .or_else(|| with_fallback_activity(analysis.base))
+        .unwrap_or_else(|| analysis.base.clone());
+    let description = analysis.config
+        .map_or_else(|| base.activity.clone(), |config| render_description(&config.toggl.description_template, &base));
+
+    // プロジェクトIDの推論
+    let project = infer_project_id(toggl_client, &base).await?;
+    let project_id = project.as_ref().map(|(id, _)| *id);
+    let project_name = project.map(|(_, name)| name);
+
+    // 請求対象などのプロジェクトには、より高い確度を求める（`[project_confidence]`）
+    if let (Some(config), Some(name)) = (analysis.config, project_name.as_deref()) {
+        let required = config.min_confidence_for_project(name);
+        if base.confidence < required {
+            info!("プロジェクト '{}' には確度 {:.2} 以上が必要なためスキップします: {:.2}", name, required, base.confidence);
+            return Ok(Err(SkipReason::LowConfidence));
+        }
+    }
+
+    Ok(Ok(PreparedEntry { base, description, project_id, project_name }))
+}
+
+/// 活動記録をTogglに登録する（内部実装）
+async fn register_to_toggl_impl(
+    toggl_client: &TogglClient,
+    analysis: &ExtendedAnalysisResult<'_>,
+    workspace_id: u64,
+    start_time: DateTime<Utc>,
+    stop_time: DateTime<Utc>,
+    should_skip_private: bool,
+) -> Result<RegistrationOutcome> {
+    let prepared = match prepare_entry(toggl_client, analysis, start_time, stop_time, should_skip_private).await? {
+        Ok(prepared) => prepared,
+        Err(reason) => return Ok(RegistrationOutcome::Skipped { reason }),
+    };
+    let PreparedEntry { base, description, project_id, project_name } = prepared;
+
+    debug!("Togglに記録を開始: {}", description);
+    debug!("開始時間: {}", format_datetime_for_toggl(&start_time));
+    debug!("終了時間: {}", format_datetime_for_toggl(&stop_time));
+    debug!("信頼度: {:.2}", base.confidence);
+    
+    if let Some(ref window_title) = base.window_title {
+        debug!("ウィンドウタイトル: {}", window_title);
+    }
+    
+    if let Some(ref calendar_event) = base.calendar_event {
+        debug!("カレンダーイベント: {}", calendar_event.title);
+    }
+    if let Some(id) = project_id {
+        debug!("プロジェクトID: {}", id);
+    } else {
+        debug!("プロジェクトID: なし");
+    }
+
+    // 手動の記録など、他のアプリで作成されたエントリと重なる部分は登録しない
+    // （取得したエントリは、統合の際に間に他のアプリのエントリが挟まっていないかの確認にも使う）
+    let mut overlap_entries = Vec::new();
+    let original_stop_time = stop_time;
+    let (start_time, stop_time) = if analysis.config.is_some_and(|config| config.toggl.avoid_overlaps) {
+        let lookback_start = start_time - Duration::hours(OVERLAP_LOOKBACK_HOURS);
+        match toggl_client.get_time_entries(&lookback_start, &stop_time).await {
+            Ok(entries) => match uncovered_block_range(&entries, start_time, stop_time, toggl_client.created_with(), Utc::now()) {
+                None => {
+                    info!("時間ブロック全体が既存のエントリで記録済みのためスキップします");
+                    return Ok(RegistrationOutcome::Skipped { reason: SkipReason::AlreadyTracked });
+                }
+                Some((from, to)) => {
+                    if (from, to) != (start_time, stop_time) {
+                        info!("既存のエントリと重ならないよう {} ～ {} に縮めて登録します",
+                              format_datetime_for_toggl(&from), format_datetime_for_toggl(&to));
+                    }
+                    overlap_entries = entries;
+                    (from, to)
+                }
+            },
+            Err(e) => {
+                warn!("Failed to fetch time entries to check for overlaps, registering the whole block: {:#}", e);
+                (start_time, stop_time)
+            }
+        }
+    } else {
+        (start_time, stop_time)
+    };
+
+    // エントリの長さの丸め設定（終了側を縮めた場合は、丸めで他のアプリのエントリに重ならないようにする）
+    let (round_minutes, round_mode) = analysis.config
+        .map_or((0, RoundingMode::Up), |config| (config.toggl.round_duration_minutes, config.toggl.round_duration_mode));
+    if round_minutes > 0 {
+        debug!("エントリの長さを{}分単位で丸めます ({:?})", round_minutes, round_mode);
+    }
+    let rounding = StopRounding {
+        minutes: round_minutes,
+        mode: round_mode,
+        limit: (stop_time < original_stop_time).then_some(stop_time),
+    };
+
+    // 同じ活動が続いている場合は、経過時間に関係なく直前のエントリを延長する
+    if analysis.config.is_some_and(|config| config.toggl.continuous_same_activity) {
+        if let Some(extended) = extend_continuous_entry(toggl_client, &description, project_id, start_time, stop_time, rounding).await {
+            let project_name = project_name.filter(|_| extended.project_id == project_id);
+            notify_registered(analysis, &description, stop_time - start_time, project_name.as_deref());
+            return Ok(RegistrationOutcome::Merged {
+                entry_id: extended.id,
+                project_id: extended.project_id,
+                project_name,
+            });
+        }
+    }
+
+    // 直前のタイムブロックのエントリを取得して同名エントリの有無を確認（マージ処理）
+    // 時間ブロック単位で確認するため、検索期間は少し長めにとる
+    // 直前のエントリはその頃に作成・更新されているはずなので、差分取得できる範囲なら期間全体ではなく差分を取得する
+    let one_hour_ago = start_time - Duration::hours(1);
+    let use_since = one_hour_ago >= Utc::now() - Duration::days(TOGGL_SINCE_MAX_AGE_DAYS);
+    debug!("直前のエントリ検索中 (期間: {} ～ {})", 
+           format_datetime_for_toggl(&one_hour_ago), 
+           format_datetime_for_toggl(&start_time));
+    
+    // OpenAI設定取得
+    let openai_config = match &analysis.config {
+        Some(config) => {
+            match config.similarity_openai_config() {
+                Some(openai_config) => {
+                    debug!("OpenAI APIキーが利用可能です（類似度評価に使用）");
+                    Some(openai_config)
+                },
+                None => {
+                    debug!("OpenAI設定がないか privacy が full でないため、類似度評価をスキップします");
+                    None
+                }
+            }
+        },
+        None => {
+            debug!("アプリ設定がないため、類似度評価をスキップします");
+            None
+        }
+    };
+
+    let previous_entries = if use_since {
+        toggl_client.get_time_entries_since(one_hour_ago).await.map(|entries| {
+            // 差分には現在のブロック以降に始まるエントリも含まれうるため、期間で取得した場合と揃える
+            entries.into_iter()
+                .filter(|entry| DateTime::parse_from_rfc3339(&entry.start).is_ok_and(|start| start < start_time))
+                .collect::<Vec<_>>()
+        })
+    } else {
+        toggl_client.get_time_entries(&one_hour_ago, &start_time).await
+    };
+    match previous_entries {
+        Ok(entries) => {
+            debug!("直前の時間エントリ数: {}", entries.len());
+            let merge_target = find_merge_target(&entries, &description, project_id, start_time, openai_config).await
+                .filter(|(entry, last_stop)| {
+                    // 手動の記録などをまたいで統合しない
+                    let blocked = has_foreign_entry_between(&overlap_entries, *last_stop, start_time, toggl_client.created_with(), Utc::now());
+                    if blocked {
+                        info!("統合先のエントリ (ID: {}) との間に他のアプリのエントリがあるため統合しません", entry.id);
+                    }
+                    !blocked
+                });
+            if let Some((entry, last_stop)) = merge_target {
+                info!("連続する類似イベントをマージします (ID: {})", entry.id);
+
+                // 丸める場合は統合後のエントリ全体の長さを丸める
+                let merged_stop = match DateTime::parse_from_rfc3339(&entry.start) {
+                    Ok(entry_start) => rounding.merged(entry_start.with_timezone(&Utc), last_stop, stop_time),
+                    Err(_) => stop_time,
+                };
+                let update_body = serde_json::json!({
+                    "stop": format_datetime_for_toggl(&merged_stop)
+                });
+
+                // マージに失敗した場合は新規エントリを作成する
+                match toggl_client.update_time_entry(entry, update_body).await {
+                    Ok(updated) => {
+                        info!("タイムエントリを更新しました (ID: {})", updated.id);
+                        notify_registered(analysis, &description, stop_time - start_time, project_name.as_deref());
+                        return Ok(RegistrationOutcome::Merged {
+                            entry_id: updated.id,
+                            project_id,
+                            project_name,
+                        });
+                    },
+                    Err(e) => {
+                        debug!("エントリ更新失敗: {}", e);
+                    }
+                }
+            }
+        },
+        Err(e) => {
+            debug!("直前のエントリ取得に失敗: {}", e);
+        }
+    }
+
+    // クラッシュ後の再起動などで同じブロックを再登録しないよう、同じキーのエントリがあれば作成しない
+    // （既存のエントリからは説明で計算するため、テンプレートを適用した説明からキーを作る）
+    let key = dedupe_key(workspace_id, start_time, &description);
+    match toggl_client.get_time_entries(&start_time, &stop_time).await {
+        Ok(entries) => {
+            if let Some(existing) = find_duplicate_entry(&entries, &key) {
+                info!("同じ時間ブロックのエントリが登録済みのため作成しません (ID: {}, key: {})", existing.id, key);
+                return Ok(RegistrationOutcome::Duplicate {
+                    entry_id: existing.id,
+                    project_id,
+                    project_name,
+                });
+            }
+        }
+        Err(e) => {
+            debug!("重複確認のためのエントリ取得に失敗: {}", e);
+        }
+    }
+
+    // TimeEntryリクエストの作成（マージできない場合は新規作成）
+    let default_billable = analysis.config.is_some_and(|config| config.toggl.default_billable);
+    let billable = resolve_billable(toggl_client, project_id, default_billable).await;
+    let stop_time = rounding.round(start_time, stop_time);
+    let time_entry = TimeEntry {
+        description: description.clone(),
+        wid: workspace_id,
+        pid: project_id,
+        start: format_datetime_for_toggl(&start_time),
+        stop: Some(format_datetime_for_toggl(&stop_time)),
+        duration: Some((stop_time - start_time).num_seconds()),
+        created_with: Some(toggl_client.created_with().to_string()),
+        tags: calendar_tags(analysis.config, base.calendar_event.as_ref()),
+        billable: Some(billable),
+        event_metadata: Some(serde_json::json!({
+            "origin_feature": "linux_rs_activity",
+            "visible_goals_count": 0,
+            "dedupe_key": key
+        })),
+    };
+
+    // 時間記録の登録
+    debug!("Togglへ時間記録を送信...");
+    let entry_id = toggl_client.create_time_entry(time_entry).await?;
+    info!("Togglへの時間記録を完了しました (ID: {})", entry_id);
+    notify_registered(analysis, &description, stop_time - start_time, project_name.as_deref());
+
+    Ok(RegistrationOutcome::Created {
+        entry_id,
+        project_id,
+        project_name,
+    })
+} 
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::data_collector::CalendarEvent;
+    use chrono::TimeZone;
+
+    fn project(id: u64, name: &str, client_name: &str) -> TogglProject {
+        TogglProject {
+            id,
+            name: name.to_string(),
+            wid: 1,
+            cid: Some(id * 10),
+            parent_project_id: None,
+            client_name: Some(client_name.to_string()),
+            color: None,
+            billable: None,
+            rate: None,
+            currency: None,
+            estimated_hours: None,
+            active: true,
+        }
+    }
+
+    #[test]
+    fn duplicate_own_entries_are_selected_for_pruning() {
+        let entry = |id: u64, created_with: &str, start: &str, description: &str, project_id: Option<u64>| -> TogglTimeEntry {
+            serde_json::from_value(serde_json::json!({
+                "id": id,
+                "workspace_id": 1,
+                "project_id": project_id,
+                "description": description,
+                "start": start,
+                "stop": "2024-01-01T09:15:00Z",
+                "duration": 900,
+                "tags": null,
+                "created_with": created_with,
+            })).unwrap()
+        };
+        let mut running = entry(9, CREATED_WITH, "2024-01-01T09:00:00Z", "Coding", Some(1));
+        running.stop = None;
+        let entries = vec![
+            entry(3, CREATED_WITH, "2024-01-01T09:00:00Z", "Coding", Some(1)),
+            entry(1, CREATED_WITH, "2024-01-01T09:00:00Z", "Coding", Some(1)),
+            // 同じ時刻をタイムゾーン付きで表したものも同じグループ
+            entry(5, "toggl_linux_rs/0.1.0", "2024-01-01T18:00:00+09:00", "Coding", Some(1)),
+            // プロジェクトや説明、開始時刻が違うものは重複ではない
+            entry(2, CREATED_WITH, "2024-01-01T09:00:00Z", "Coding", Some(2)),
+            entry(4, CREATED_WITH, "2024-01-01T09:00:00Z", "Email", Some(1)),
+            entry(6, CREATED_WITH, "2024-01-01T09:05:00Z", "Coding", Some(1)),
+            // 手動で作成したエントリは重複していても削除しない
+            entry(7, "Toggl Track", "2024-01-01T09:00:00Z", "Coding", Some(1)),
+            entry(8, "Toggl Track", "2024-01-01T09:00:00Z", "Coding", Some(1)),
+            running,
+        ];
+
+        let duplicates = select_duplicate_entries(&entries, CREATED_WITH);
+        assert_eq!(duplicates.iter().map(|entry| entry.id).collect::<Vec<_>>(), [3, 5]);
+    }
+
+    #[test]
+    fn project_report_computes_cost_only_for_rated_projects() {
+        let projects: Vec<TogglProject> = serde_json::from_value(serde_json::json!([
+            { "id": 1, "name": "Website", "wid": 1, "cid": null, "active": true, "rate": 80.0, "currency": "USD", "estimated_hours": 40 },
+            { "id": 2, "name": "Internal", "wid": 1, "cid": null, "active": true, "rate": null, "currency": null },
+        ])).unwrap();
+        let entry = |id: u64, project_id: Option<u64>, duration: i64| -> TogglTimeEntry {
+            serde_json::from_value(serde_json::json!({
+                "id": id,
+                "workspace_id": 1,
+                "project_id": project_id,
+                "description": "Work",
+                "start": "2024-01-01T09:00:00Z",
+                "stop": "2024-01-01T10:00:00Z",
+                "duration": duration,
+                "tags": null,
+            })).unwrap()
+        };
+        let entries = vec![
+            entry(1, Some(1), 5400),
+            entry(2, Some(1), 1800),
+            entry(3, Some(2), 3600),
+            entry(4, None, 900),
+        ];
+
+        let rows = summarize_project_hours(&entries, &projects, Utc::now());
+        assert_eq!(rows.iter().map(|row| row.name.as_str()).collect::<Vec<_>>(), ["Website", "Internal", "(プロジェクトなし)"]);
+        assert_eq!(rows[0].tracked, Duration::hours(2));
+        assert_eq!(rows[0].cost(), Some(160.0));
+        assert_eq!(rows[1].cost(), None);
+        assert_eq!(rows[2].cost(), None);
+
+        let report = format_project_report(&rows);
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines[1], format!("{:<10} {:<10} {:<16} {}", "2.00h", "40.0h", "160.00 USD", "Website"));
+        assert_eq!(lines[2], format!("{:<10} {:<10} {:<16} {}", "1.00h", "", "", "Internal"));
+        assert_eq!(lines[4], format!("{:<10} {:<10} {:<16} {}", "3.25h", "", "160.00 USD", "合計"));
+    }
+
+    #[test]
+    fn organizer_domain_disambiguates_same_named_projects_by_client() {
+        let projects = vec![project(1, "Website", "Globex"), project(2, "Website", "Acme Corp")];
+        let now = Utc::now();
+        let analysis = AnalysisResult {
+            activity: "Website".to_string(),
+            confidence: 0.9,
+            timestamp: now,
+            alternatives: Vec::new(),
+            window_title: None,
+            window_class: None,
+            calendar_event: Some(CalendarEvent {
+                id: "event-1".to_string(),
+                title: "Website kickoff".to_string(),
+                start_time: now,
+                end_time: now + Duration::minutes(30),
+                calendar_id: "primary".to_string(),
+                description: None,
+                location: None,
+                organizer_email: Some("pm@acme.example.com".to_string()),
+                is_video_call: true,
+                response_status: None,
+            }),
+            analyzed_locally: false,
+            project: None,
+            openai_api_key: None,
+        };
+
+        let ranked = rank_projects(&projects, &analysis);
+        assert_eq!(ranked[0].project.id, 2);
+        assert!(ranked[0].score > ranked[1].score);
+        assert_eq!(ranked[0].project.display_name(), "Website [Acme Corp]");
+    }
+
+    #[test]
+    fn matching_subproject_wins_over_its_parent() {
+        let parent = project(1, "Client", "Acme Corp");
+        let child = TogglProject { parent_project_id: Some(1), ..project(2, "Client / Feature X", "Acme Corp") };
+        let projects = vec![parent, child];
+        let analysis = AnalysisResult {
+            activity: "Client Feature X".to_string(),
+            confidence: 0.9,
+            timestamp: Utc::now(),
+            alternatives: Vec::new(),
+            window_title: Some("Client - Feature X spec - Google Docs".to_string()),
+            window_class: None,
+            calendar_event: None,
+            analyzed_locally: false,
+            project: None,
+            openai_api_key: None,
+        };
+
+        let ranked = rank_projects(&projects, &analysis);
+        assert_eq!(ranked[0].project.id, 2, "{:?}", ranked.iter().map(|c| (c.project.id, c.score)).collect::<Vec<_>>());
+
+        // 子プロジェクトが一致しない場合は親プロジェクトになる
+        let analysis = AnalysisResult { activity: "Client billing".to_string(), ..analysis };
+        let ranked = rank_projects(&projects, &analysis);
+        assert_eq!(ranked[0].project.id, 1);
+
+        // 親プロジェクトの名前と完全に一致する場合は、部分一致の子プロジェクトより親プロジェクトを選ぶ
+        let analysis = AnalysisResult { activity: "Client".to_string(), window_title: None, ..analysis };
+        let ranked = rank_projects(&projects, &analysis);
+        assert_eq!(ranked[0].project.id, 1, "{:?}", ranked.iter().map(|c| (c.project.id, c.score)).collect::<Vec<_>>());
+        assert_eq!(ranked[0].score, 1.0);
+    }
+
+    #[test]
+    fn english_category_label_matches_japanese_project_name() {
+        let projects = vec![project(1, "プログラミング", "Acme Corp"), project(2, "Website", "Acme Corp")];
+        let analysis = AnalysisResult {
+            activity: "Programming".to_string(),
+            confidence: 0.9,
+            timestamp: Utc::now(),
+            alternatives: Vec::new(),
+            window_title: None,
+            window_class: None,
+            calendar_event: None,
+            analyzed_locally: true,
+            project: None,
+            openai_api_key: None,
+        };
+
+        let ranked = rank_projects(&projects, &analysis);
+        assert_eq!(ranked.len(), 1);
+        assert_eq!(ranked[0].project.id, 1);
+        assert_eq!(ranked[0].score, 1.0);
+    }
+
+    fn analysis_for(activity: &str) -> AnalysisResult {
+        AnalysisResult {
+            activity: activity.to_string(),
+            confidence: 0.9,
+            timestamp: Utc::now(),
+            alternatives: Vec::new(),
+            window_title: None,
+            window_class: None,
+            calendar_event: None,
+            analyzed_locally: true,
+            project: None,
+            openai_api_key: None,
+        }
+    }
+
+    #[test]
+    fn project_score_prefers_exact_then_substring_then_word_overlap() {
+        let website = project(1, "Website", "Acme Corp");
+
+        // 完全一致（大文字・小文字は区別しない）
+        assert_eq!(score_project(&website, &analysis_for("website")), (1.0, vec!["活動名と完全一致".to_string()]));
+
+        // プロジェクト名が活動名を含む
+        let redesign = project(2, "Website Redesign", "Acme Corp");
+        assert_eq!(score_project(&redesign, &analysis_for("Redesign")), (0.8, vec!["プロジェクト名が活動名を含む".to_string()]));
+
+        // 活動名がプロジェクト名を含む
+        assert_eq!(score_project(&website, &analysis_for("Website bug fixes")), (0.7, vec!["活動名がプロジェクト名を含む".to_string()]));
+
+        // 単語の一致は、プロジェクト名の単語のうち一致した割合 × 0.6（記号だけの語は数えない）
+        let client_feature = project(3, "Acme / Billing API", "Acme Corp");
+        let (score, reasons) = score_project(&client_feature, &analysis_for("Billing export for Acme"));
+        assert!((score - 0.4).abs() < 1e-9, "{}", score);
+        assert_eq!(reasons, vec!["2個の単語が一致".to_string()]);
+
+        assert_eq!(score_project(&website, &analysis_for("Email")), (0.0, Vec::new()));
+    }
+
+    #[test]
+    fn project_inference_preview_lists_ranked_candidates_with_reasons() {
+        let projects = vec![
+            project(1, "Website", "Acme Corp"),
+            project(2, "Website Redesign", "Globex"),
+            project(3, "Recruiting", "Acme Corp"),
+        ];
+        let mut analysis = analysis_for("Website bug fixes");
+        analysis.window_title = Some("Website Redesign - Figma".to_string());
+
+        let output = format_project_inference(&projects, &analysis);
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines[0], "活動: Website bug fixes");
+        assert_eq!(lines[2], " 1. 0.90  Website [Acme Corp] (ID: 1)  <- 選択", "{}", output);
+        assert!(lines[3].contains("活動名がプロジェクト名を含む"), "{}", output);
+        assert!(lines[4].contains("ウィンドウタイトルがプロジェクト名を含む"), "{}", output);
+        assert!(output.contains("Website Redesign [Globex] (ID: 2)"), "{}", output);
+        assert!(!output.contains("Recruiting"), "{}", output);
+        assert!(output.ends_with("登録されるプロジェクト: Website [Acme Corp]\n"), "{}", output);
+
+        let output = format_project_inference(&projects, &analysis_for("Email"));
+        assert!(output.contains("一致するプロジェクトはありません"), "{}", output);
+        assert!(output.contains("プロジェクトなしで登録されます"), "{}", output);
+    }
+
+    #[test]
+    fn window_title_and_calendar_event_boost_the_project_score() {
+        let website = project(1, "Website", "Acme Corp");
+        let mut analysis = analysis_for("Email");
+        analysis.window_title = Some("Re: Website launch - Inbox - Mozilla Thunderbird".to_string());
+        let (score, reasons) = score_project(&website, &analysis);
+        assert!((score - 0.2).abs() < 1e-9, "{}", score);
+        assert_eq!(reasons, vec!["ウィンドウタイトルがプロジェクト名を含む".to_string()]);
+
+        let now = Utc::now();
+        analysis.calendar_event = Some(CalendarEvent {
+            id: "event-1".to_string(),
+            title: "Website review".to_string(),
+            start_time: now,
+            end_time: now + Duration::minutes(30),
+            calendar_id: "primary".to_string(),
+            description: None,
+            location: Some("Website war room".to_string()),
+            organizer_email: None,
+            is_video_call: false,
+            response_status: None,
+        });
+        let (score, reasons) = score_project(&website, &analysis);
+        assert!((score - 0.6).abs() < 1e-9, "{}", score);
+        assert_eq!(reasons, vec![
+            "ウィンドウタイトルがプロジェクト名を含む".to_string(),
+            "カレンダーイベントがプロジェクト名を含む".to_string(),
+            "イベントの場所がプロジェクト名を含む".to_string(),
+        ]);
+
+        // 活動名の一致が弱くても、予定の一致で閾値を超える
+        let projects = vec![project(2, "Recruiting", "Acme Corp"), website];
+        assert_eq!(best_project_match(&projects, &analysis, PROJECT_MATCH_THRESHOLD), Some(1));
+        analysis.calendar_event = None;
+        assert_eq!(best_project_match(&projects, &analysis, PROJECT_MATCH_THRESHOLD), None);
+        assert_eq!(best_project_match(&projects, &analysis, 0.2), Some(1));
+    }
+
+    fn at(hour: u32, minute: u32, second: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 5, 13, hour, minute, second).unwrap()
+    }
+
+    #[test]
+    fn round_up_extends_to_the_next_increment() {
+        assert_eq!(round_stop_time(at(10, 0, 0), at(10, 15, 0), 6, RoundingMode::Up), at(10, 18, 0));
+        assert_eq!(round_stop_time(at(10, 0, 0), at(10, 12, 0), 6, RoundingMode::Up), at(10, 12, 0));
+        assert_eq!(round_stop_time(at(10, 0, 0), at(10, 0, 20), 15, RoundingMode::Up), at(10, 15, 0));
+        // 0分は丸めない
+        assert_eq!(round_stop_time(at(10, 0, 0), at(10, 7, 31), 0, RoundingMode::Up), at(10, 7, 31));
+    }
+
+    #[test]
+    fn round_nearest_never_moves_stop_before_start() {
+        assert_eq!(round_stop_time(at(10, 0, 0), at(10, 14, 0), 6, RoundingMode::Nearest), at(10, 12, 0));
+        assert_eq!(round_stop_time(at(10, 0, 0), at(10, 15, 0), 6, RoundingMode::Nearest), at(10, 18, 0));
+        // 1単位の半分未満でも0分にはしない
+        assert_eq!(round_stop_time(at(10, 0, 0), at(10, 1, 0), 15, RoundingMode::Nearest), at(10, 15, 0));
+        assert_eq!(round_stop_time(at(10, 0, 0), at(9, 59, 0), 15, RoundingMode::Nearest), at(10, 0, 0));
+    }
+
+    #[test]
+    fn merging_rounds_the_whole_entry_without_gaps() {
+        // 10:00-10:15 のブロックは6分単位の切り上げで 10:18 まで登録済み
+        let previous_stop = round_stop_time(at(10, 0, 0), at(10, 15, 0), 6, RoundingMode::Up);
+        assert_eq!(previous_stop, at(10, 18, 0));
+
+        // 次のブロック（10:15-10:30）を統合すると全体の30分が丸められ、直前の終了時刻以降になる
+        let merged = merged_stop_time(at(10, 0, 0), previous_stop, at(10, 30, 0), 6, RoundingMode::Up);
+        assert_eq!(merged, at(10, 30, 0));
+
+        // 統合後の丸めが直前の終了時刻より短くなる場合は縮めない
+        let merged = merged_stop_time(at(10, 0, 0), at(10, 30, 0), at(10, 20, 0), 15, RoundingMode::Nearest);
+        assert_eq!(merged, at(10, 30, 0));
+    }
+
+    /// Toggl APIのモックサーバーを起動する（`handler` はメソッド・パス・ボディからステータスとボディを返す）
+    pub(crate) async fn spawn_toggl_server<F>(handler: F) -> String
+    where
+        F: Fn(&str, &str, &str) -> (&'static str, String) + Send + Sync + 'static,
+    {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handler = Arc::new(handler);
+        tokio::spawn(async move {
+            loop {
+                let Ok((mut socket, _)) = listener.accept().await else {
+                    return;
+                };
+                let handler = handler.clone();
+                tokio::spawn(async move {
+                    // ヘッダーと Content-Length 分のボディを読み切る
+                    let mut request = Vec::new();
+                    let mut buf = [0u8; 4096];
+                    let (head_len, content_length) = loop {
+                        let len = socket.read(&mut buf).await.unwrap_or(0);
+                        if len == 0 {
+                            return;
+                        }
+                        request.extend_from_slice(&buf[..len]);
+                        if let Some(pos) = request.windows(4).position(|window| window == b"\r\n\r\n") {
+                            let head = String::from_utf8_lossy(&request[..pos]).to_lowercase();
+                            let content_length = head.lines()
+                                .find_map(|line| line.strip_prefix("content-length:"))
+                                .and_then(|value| value.trim().parse::<usize>().ok())
+                                .unwrap_or(0);
+                            break (pos + 4, content_length);
+                        }
+                    };
+                    while request.len() < head_len + content_length {
+                        let len = socket.read(&mut buf).await.unwrap_or(0);
+                        if len == 0 {
+                            break;
+                        }
+                        request.extend_from_slice(&buf[..len]);
+                    }
+
+                    let head = String::from_utf8_lossy(&request[..head_len]).into_owned();
+                    let body = String::from_utf8_lossy(&request[head_len..]).into_owned();
+                    let mut request_line = head.lines().next().unwrap_or_default().split(' ');
+                    let method = request_line.next().unwrap_or_default();
+                    let path = request_line.next().unwrap_or_default();
+
+                    let (status, response_body) = handler(method, path, &body);
+                    let response = format!(
+                        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        status,
+                        response_body.len(),
+                        response_body
+                    );
+                    let _ = socket.write_all(response.as_bytes()).await;
+                });
+            }
+        });
+        format!("http://{}", addr)
+    }
+
+    /// 受け取ったリクエストの1行目（メソッドとパス）を記録し、固定のレスポンスを返すモックサーバーを起動する
+    async fn spawn_toggl_stub(status: &'static str, body: &'static str) -> (String, Arc<Mutex<Vec<String>>>) {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        let api_base = spawn_toggl_server(move |method, path, _| {
+            recorded.lock().unwrap().push(format!("{} {}", method, path));
+            (status, body.to_string())
+        }).await;
+        (api_base, requests)
+    }
+
+    /// 作成したエントリとして返すレスポンス
+    const CREATED_ENTRY_BODY: &str = r#"{"id":1,"workspace_id":1,"project_id":null,"description":"","start":"","stop":null,"duration":0,"tags":null}"#;
+
+    #[tokio::test]
+    async fn rapid_time_entry_creation_is_spaced_by_rate_limiter() {
+        let (api_base, _) = spawn_toggl_stub("200 OK", CREATED_ENTRY_BODY).await;
+        let client = TogglClient::new("token", 1)
+            .with_api_base(&api_base)
+            .with_rate_limiter(Arc::new(RateLimiter::new(10.0, 1.0)));
+        let entry = || TimeEntry {
+            description: "Coding".to_string(),
+            wid: 1,
+            pid: None,
+            start: "2024-01-01T00:00:00Z".to_string(),
+            stop: None,
+            duration: Some(60),
+            tags: None,
+            billable: None,
+            created_with: None,
+            event_metadata: None,
+        };
+
+        let started = Instant::now();
+        for _ in 0..4 {
+            client.create_time_entry(entry()).await.unwrap();
+        }
+
+        // 1件目はすぐに送信され、残りの3件は100msずつ間隔を空けて送信される
+        assert!(started.elapsed() >= std::time::Duration::from_millis(280), "elapsed: {:?}", started.elapsed());
+    }
+
+    #[tokio::test]
+    async fn unset_workspace_is_resolved_without_requesting_workspace_zero() {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        let api_base = spawn_toggl_server(move |method, path, _| {
+            recorded.lock().unwrap().push(format!("{} {}", method, path));
+            match path {
+                "/me" => ("200 OK", r#"{"id":1,"fullname":"Taro","default_workspace_id":null}"#.to_string()),
+                "/workspaces" => (
+                    "200 OK",
+                    r#"[{"id":7,"name":"Personal","organization_id":1},{"id":9,"name":"Team","organization_id":2}]"#.to_string(),
+                ),
+                _ => ("404 Not Found", String::new()),
+            }
+        }).await;
+        let client = TogglClient::new("token", 0)
+            .with_api_base(&api_base)
+            .with_rate_limiter(Arc::new(RateLimiter::new(1000.0, 1000.0)));
+
+        // 既定のワークスペースがない場合は一覧の最初のものを使う
+        assert_eq!(resolve_workspace_id_with_client(&client).await.unwrap(), 7);
+
+        // 未設定のままのクライアントは /workspaces/0 にリクエストを送らずにエラーにする
+        let err = client.get_projects(true).await.unwrap_err();
+        assert!(err.to_string().contains("workspace_id = 0"), "{:#}", err);
+        let entry = TimeEntry {
+            description: "Coding".to_string(),
+            wid: 0,
+            pid: None,
+            start: "2024-01-01T00:00:00Z".to_string(),
+            stop: None,
+            duration: Some(60),
+            tags: None,
+            billable: None,
+            created_with: None,
+            event_metadata: None,
+        };
+        assert!(client.create_time_entry(entry).await.is_err());
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(*requests, ["GET /me", "GET /workspaces"]);
+    }
+
+    #[test]
+    fn exhausted_quota_headers_block_until_reset() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(throttle_from_headers(reqwest::StatusCode::OK, &headers), None);
+
+        headers.insert("X-Toggl-Quota-Remaining", HeaderValue::from_static("0"));
+        headers.insert("X-Toggl-Quota-Resets-In", HeaderValue::from_static("12"));
+        assert_eq!(
+            throttle_from_headers(reqwest::StatusCode::OK, &headers),
+            Some(std::time::Duration::from_secs(12))
+        );
+
+        let mut headers = HeaderMap::new();
+        headers.insert(reqwest::header::RETRY_AFTER, HeaderValue::from_static("5"));
+        assert_eq!(
+            throttle_from_headers(reqwest::StatusCode::TOO_MANY_REQUESTS, &headers),
+            Some(std::time::Duration::from_secs(5))
+        );
+    }
+
+    #[tokio::test]
+    async fn similarity_request_is_sent_to_the_configured_api_base() {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        let api_base = spawn_toggl_server(move |method, path, body| {
+            recorded.lock().unwrap().push((method.to_string(), path.to_string(), body.to_string()));
+            ("200 OK", r#"{"choices":[{"message":{"role":"assistant","content":"0.9"}}]}"#.to_string())
+        }).await;
+        let mut config = crate::config::create_default_config();
+        let openai = config.openai.as_mut().unwrap();
+        openai.api_base = format!("{}/v1/", api_base);
+
+        let similarity = evaluate_activity_similarity(openai, "Coding", "Programming").await.unwrap();
+        assert_eq!(similarity, 0.9);
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        let (method, path, body) = &requests[0];
+        assert_eq!((method.as_str(), path.as_str()), ("POST", "/v1/chat/completions"));
+        let body: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert!(body["model"].is_string(), "{}", body);
+        let prompt = body["messages"][1]["content"].as_str().unwrap();
+        assert!(prompt.contains("Coding") && prompt.contains("Programming"), "{}", prompt);
+    }
+
+    #[tokio::test]
+    async fn rejected_similarity_key_is_an_openai_auth_error() {
+        let api_base = spawn_toggl_server(|_, _, _| {
+            ("401 Unauthorized", r#"{"error":{"message":"Incorrect API key provided"}}"#.to_string())
+        }).await;
+        let mut config = crate::config::create_default_config();
+        let openai = config.openai.as_mut().unwrap();
+        openai.api_base = api_base;
+
+        // 休止するかどうかは `OPENAI_AUTH_PAUSE.guard` が判定する（utilsのテストを参照）
+        let error = evaluate_activity_similarity(openai, "Coding", "Email").await.unwrap_err();
+        assert!(crate::analysis::is_openai_unauthorized(&error), "{:#}", error);
+    }
+
+    #[tokio::test]
+    async fn delete_time_entry_sends_delete_to_workspace_entry() {
+        let (api_base, requests) = spawn_toggl_stub("200 OK", "").await;
+        let client = TogglClient::new("token", 7).with_api_base(&api_base);
+
+        client.delete_time_entry(42).await.unwrap();
+
+        assert_eq!(
+            requests.lock().unwrap().as_slice(),
+            ["DELETE /workspaces/7/time_entries/42"]
+        );
+    }
+
+    #[tokio::test]
+    async fn running_entry_is_stopped_and_updated_in_its_own_workspace() {
+        let (api_base, requests) = spawn_toggl_stub("200 OK", CREATED_ENTRY_BODY).await;
+        let client = TogglClient::new("token", 7).with_api_base(&api_base);
+        let running: TogglTimeEntry = serde_json::from_value(serde_json::json!({
+            "id": 42,
+            "workspace_id": 9,
+            "project_id": null,
+            "description": "Manual tracking",
+            "start": "2024-01-01T09:00:00Z",
+            "stop": null,
+            "duration": -1,
+            "tags": null,
+        })).unwrap();
+
+        client.stop_time_entry(&running).await.unwrap();
+        client.update_time_entry(&running, serde_json::json!({ "description": "Coding" })).await.unwrap();
+
+        // ワークスペースが不明な場合は設定したワークスペースを使う
+        let unknown = TogglTimeEntry { workspace_id: 0, ..running };
+        client.stop_time_entry(&unknown).await.unwrap();
+
+        assert_eq!(
+            requests.lock().unwrap().as_slice(),
+            [
+                "PATCH /workspaces/9/time_entries/42/stop",
+                "PUT /workspaces/9/time_entries/42",
+                "PATCH /workspaces/7/time_entries/42/stop",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn deleting_missing_time_entry_is_not_an_error() {
+        let (api_base, _) = spawn_toggl_stub("404 Not Found", r#""Time entry not found""#).await;
+        let client = TogglClient::new("token", 7).with_api_base(&api_base);
+
+        assert!(client.delete_time_entry(42).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn fetched_entries_are_deleted_in_their_own_workspace() {
+        let (api_base, requests) = spawn_toggl_stub("200 OK", "").await;
+        let client = TogglClient::new("token", 7).with_api_base(&api_base);
+        let entry: TogglTimeEntry = serde_json::from_value(serde_json::json!({
+            "id": 42,
+            "workspace_id": 9,
+            "project_id": null,
+            "description": "Coding",
+            "start": "2024-01-01T09:00:00Z",
+            "stop": "2024-01-01T09:15:00Z",
+            "duration": 900,
+            "tags": null,
+            "created_with": CREATED_WITH,
+        })).unwrap();
+
+        assert!(client.delete_fetched_time_entry(&entry).await.unwrap());
+        assert_eq!(requests.lock().unwrap().as_slice(), ["DELETE /workspaces/9/time_entries/42"]);
+
+        // 見つからなかった場合は削除したものとして数えない
+        let (api_base, _) = spawn_toggl_stub("404 Not Found", r#""Time entry not found""#).await;
+        let client = TogglClient::new("token", 7).with_api_base(&api_base);
+        assert!(!client.delete_fetched_time_entry(&entry).await.unwrap());
+    }
+
+    /// 作成したエントリを保持し、`start_date`〜`end_date`（終了は含まない）で絞り込んで返すモックサーバーを起動する
+    pub(crate) async fn spawn_stateful_toggl_server() -> (String, Arc<Mutex<Vec<serde_json::Value>>>) {
+        let entries = Arc::new(Mutex::new(Vec::<serde_json::Value>::new()));
+        let stored = entries.clone();
+        let api_base = spawn_toggl_server(move |method, path, body| {
+            let mut stored = stored.lock().unwrap();
+            match method {
+                "POST" if path.ends_with("/time_entries") => {
+                    let mut entry: serde_json::Value = serde_json::from_str(body).unwrap();
+                    entry["id"] = serde_json::json!(stored.len() as u64 + 1);
+                    stored.push(entry.clone());
+                    ("200 OK", entry.to_string())
+                }
+                "PUT" if path.contains("/time_entries/") => {
+                    let id: u64 = path.rsplit('/').next().unwrap().parse().unwrap();
+                    let patch: serde_json::Value = serde_json::from_str(body).unwrap();
+                    let entry = stored.iter_mut().find(|entry| entry["id"] == id).unwrap();
+                    for (key, value) in patch.as_object().unwrap() {
+                        entry[key] = value.clone();
+                    }
+                    ("200 OK", entry.to_string())
+                }
+                "GET" if path.starts_with("/me/time_entries?since=") => {
+                    ("200 OK", serde_json::to_string(&*stored).unwrap())
+                }
+                "GET" if path.starts_with("/me/time_entries?") => {
+                    let param = |name: &str| {
+                        let query = path.split_once('?').map(|(_, query)| query).unwrap_or_default();
+                        let value = query.split('&').find_map(|pair| pair.strip_prefix(&format!("{}=", name)))?;
+                        DateTime::parse_from_rfc3339(&urlencoding::decode(value).ok()?).ok()
+                    };
+                    let (from, to) = (param("start_date").unwrap(), param("end_date").unwrap());
+                    let matching: Vec<&serde_json::Value> = stored.iter()
+                        .filter(|entry| {
+                            let start = DateTime::parse_from_rfc3339(entry["start"].as_str().unwrap()).unwrap();
+                            start >= from && start < to
+                        })
+                        .collect();
+                    ("200 OK", serde_json::to_string(&matching).unwrap())
+                }
+                _ => ("200 OK", "[]".to_string()),
+            }
+        }).await;
+        (api_base, entries)
+    }
+
+    /// 他のアプリ（Togglアプリでの手動の記録）で作成したエントリとしてサーバーに保存する
+    fn manual_entry(id: u64, start: DateTime<Utc>, stop: Option<DateTime<Utc>>) -> serde_json::Value {
+        serde_json::json!({
+            "id": id,
+            "workspace_id": 1,
+            "project_id": null,
+            "description": "Manual tracking",
+            "start": format_datetime_for_toggl(&start),
+            "stop": stop.map(|stop| format_datetime_for_toggl(&stop)),
+            "duration": stop.map_or(-1, |stop| (stop - start).num_seconds()),
+            "tags": null,
+            "created_with": "Toggl Track",
+        })
+    }
+
+    fn block_sample(timestamp: DateTime<Utc>, is_idle: bool) -> CollectedData {
+        CollectedData {
+            timestamp,
+            window: crate::data_collector::WindowInfo {
+                id: "0x1".to_string(),
+                title: "main.rs - Visual Studio Code".to_string(),
+                class: Some("code".to_string()),
+                pid: None,
+                timestamp,
+                geometry: None,
+                terminal: None,
+            },
+            calendar_events: Vec::new(),
+            is_idle,
+            visible_windows: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn partially_idle_block_is_split_only_when_idle_time_is_recorded() {
+        let start = Utc.with_ymd_and_hms(2024, 1, 1, 9, 0, 0).unwrap();
+        let stop = start + Duration::minutes(15);
+        // 10分作業した後、5分離席した
+        let data: Vec<CollectedData> = (0..15)
+            .map(|minute| block_sample(start + Duration::minutes(minute), minute >= 10))
+            .collect();
+        let mut config = crate::config::create_default_config();
+
+        // skip（既定）ではブロック全体を活動として登録する
+        let split = split_idle_block(&config, &data, start, stop);
+        assert_eq!(split, IdleSplit { active: Some((start, stop)), idle: None });
+
+        config.general.idle_behavior = IdleBehavior::Record;
+        let split = split_idle_block(&config, &data, start, stop);
+        assert_eq!(split.active, Some((start, start + Duration::minutes(10))));
+        assert_eq!(split.idle, Some((start + Duration::minutes(10), stop)));
+
+        // ブロックの前半がアイドルだった場合は先頭に置く
+        let returned: Vec<CollectedData> = (0..15)
+            .map(|minute| block_sample(start + Duration::minutes(minute), minute < 3))
+            .collect();
+        let split = split_idle_block(&config, &returned, start, stop);
+        assert_eq!(split.idle, Some((start, start + Duration::minutes(3))));
+        assert_eq!(split.active, Some((start + Duration::minutes(3), stop)));
+
+        // ブロック全体がアイドルなら活動は登録しない
+        let away: Vec<CollectedData> = (0..15).map(|minute| block_sample(start + Duration::minutes(minute), true)).collect();
+        assert_eq!(split_idle_block(&config, &away, start, stop), IdleSplit { active: None, idle: Some((start, stop)) });
+
+        // ストリームモードでは分けない
+        config.general.tracking_mode = TrackingMode::Stream;
+        assert_eq!(split_idle_block(&config, &data, start, stop).idle, None);
+    }
+
+    #[tokio::test]
+    async fn idle_portion_is_registered_with_the_idle_description_and_project() {
+        let created = Arc::new(Mutex::new(Vec::<serde_json::Value>::new()));
+        let stored = created.clone();
+        let api_base = spawn_toggl_server(move |method, path, body| {
+            match method {
+                "GET" if path.contains("/projects") => (
+                    "200 OK",
+                    serde_json::json!([{ "id": 5, "name": "Available", "wid": 41, "active": true }]).to_string(),
+                ),
+                "POST" if path.ends_with("/time_entries") => {
+                    let mut entry: serde_json::Value = serde_json::from_str(body).unwrap();
+                    entry["id"] = serde_json::json!(77);
+                    stored.lock().unwrap().push(entry.clone());
+                    ("200 OK", entry.to_string())
+                }
+                _ => ("200 OK", "[]".to_string()),
+            }
+        }).await;
+        // プロジェクト一覧のキャッシュを他のテストと共有しないワークスペース
+        let client = TogglClient::new("token", 41)
+            .with_api_base(&api_base)
+            .with_rate_limiter(Arc::new(RateLimiter::new(1000.0, 1000.0)));
+        let mut config = crate::config::create_default_config();
+        config.toggl.workspace_id = 41;
+        config.general.idle_behavior = IdleBehavior::Record;
+        config.general.idle_description = "Idle".to_string();
+        config.general.idle_project = Some("available".to_string());
+        let start = Utc.with_ymd_and_hms(2024, 1, 1, 9, 10, 0).unwrap();
+        let stop = start + Duration::minutes(5);
+
+        let outcome = register_idle_entry(&client, &config, start, stop).await.unwrap();
+        assert!(matches!(outcome, RegistrationOutcome::Created { entry_id: 77, project_id: Some(5), .. }), "{:?}", outcome);
+
+        let created = created.lock().unwrap();
+        assert_eq!(created.len(), 1);
+        assert_eq!(created[0]["description"], "Idle");
+        assert_eq!(created[0]["project_id"], 5);
+        assert_eq!(created[0]["start"], format_datetime_for_toggl(&start));
+        assert_eq!(created[0]["stop"], format_datetime_for_toggl(&stop));
+        assert_eq!(created[0]["duration"], 300);
+    }
+
+    #[tokio::test]
+    async fn idle_portion_avoids_manual_entries() {
+        let (api_base, entries) = spawn_stateful_toggl_server().await;
+        let client = TogglClient::new("token", 1)
+            .with_api_base(&api_base)
+            .with_rate_limiter(Arc::new(RateLimiter::new(1000.0, 1000.0)));
+        let mut config = crate::config::create_default_config();
+        config.general.idle_behavior = IdleBehavior::Record;
+        config.toggl.avoid_overlaps = true;
+        let start = Utc.with_ymd_and_hms(2024, 1, 1, 9, 10, 0).unwrap();
+        let stop = start + Duration::minutes(5);
+
+        // 手動のエントリがアイドルの時間帯全体を覆っている
+        entries.lock().unwrap().push(manual_entry(100, start - Duration::minutes(10), Some(stop)));
+        let outcome = register_idle_entry(&client, &config, start, stop).await.unwrap();
+        assert!(matches!(outcome, RegistrationOutcome::Skipped { reason: SkipReason::AlreadyTracked }), "{:?}", outcome);
+        assert_eq!(entries.lock().unwrap().len(), 1);
+
+        // 前半だけを覆っている場合は、後半だけを登録する
+        entries.lock().unwrap()[0] = manual_entry(100, start - Duration::minutes(10), Some(start + Duration::minutes(2)));
+        let outcome = register_idle_entry(&client, &config, start, stop).await.unwrap();
+        assert!(matches!(outcome, RegistrationOutcome::Created { .. }), "{:?}", outcome);
+        let created = entries.lock().unwrap()[1].clone();
+        assert_eq!(created["start"], format_datetime_for_toggl(&(start + Duration::minutes(2))));
+        assert_eq!(created["stop"], format_datetime_for_toggl(&stop));
+        assert_eq!(created["duration"], 180);
+    }
+
+    #[tokio::test]
+    async fn avoid_overlaps_skips_blocks_covered_by_manual_entries_and_trims_partial_overlaps() {
+        let (api_base, entries) = spawn_stateful_toggl_server().await;
+        let client = TogglClient::new("token", 1)
+            .with_api_base(&api_base)
+            .with_rate_limiter(Arc::new(RateLimiter::new(1000.0, 1000.0)));
+        let mut config = crate::config::create_default_config();
+        config.openai = None;
+        config.toggl.avoid_overlaps = true;
+        let start = Utc.with_ymd_and_hms(2024, 1, 1, 9, 0, 0).unwrap();
+        let stop = start + Duration::minutes(15);
+        let base = AnalysisResult {
+            activity: "Coding".to_string(),
+            confidence: 0.9,
+            timestamp: start,
+            alternatives: Vec::new(),
+            window_title: None,
+            window_class: None,
+            calendar_event: None,
+            analyzed_locally: true,
+            project: None,
+            openai_api_key: None,
+        };
+        let analysis = ExtendedAnalysisResult { base: &base, is_private_browsing: false, config: Some(&config), notify: send_notification };
+
+        // ブロックより前から続く手動のエントリがブロック全体を覆っている
+        entries.lock().unwrap().push(manual_entry(100, start - Duration::minutes(30), Some(stop + Duration::minutes(5))));
+        let outcome = register_to_toggl_impl(&client, &analysis, 1, start, stop, false).await.unwrap();
+        assert!(matches!(outcome, RegistrationOutcome::Skipped { reason: SkipReason::AlreadyTracked }), "{:?}", outcome);
+        assert_eq!(entries.lock().unwrap().len(), 1);
+
+        // 手動のエントリがブロックの前半だけを覆っている場合は、後半だけを登録する
+        entries.lock().unwrap()[0] = manual_entry(100, start - Duration::minutes(30), Some(start + Duration::minutes(5)));
+        let outcome = register_to_toggl_impl(&client, &analysis, 1, start, stop, false).await.unwrap();
+        assert!(matches!(outcome, RegistrationOutcome::Created { .. }), "{:?}", outcome);
+        let created = entries.lock().unwrap()[1].clone();
+        assert_eq!(created["start"], format_datetime_for_toggl(&(start + Duration::minutes(5))));
+        assert_eq!(created["stop"], format_datetime_for_toggl(&stop));
+        assert_eq!(created["duration"], 600);
+    }
+
+    #[tokio::test]
+    async fn avoid_overlaps_does_not_round_into_or_merge_across_manual_entries() {
+        let (api_base, entries) = spawn_stateful_toggl_server().await;
+        let client = TogglClient::new("token", 1)
+            .with_api_base(&api_base)
+            .with_rate_limiter(Arc::new(RateLimiter::new(1000.0, 1000.0)));
+        let mut config = crate::config::create_default_config();
+        config.openai = None;
+        config.toggl.avoid_overlaps = true;
+        config.toggl.round_duration_minutes = 15;
+        config.toggl.round_duration_mode = RoundingMode::Up;
+        let start = Utc.with_ymd_and_hms(2024, 1, 1, 9, 0, 0).unwrap();
+        let stop = start + Duration::minutes(15);
+        let minutes = |m: i64| start + Duration::minutes(m);
+        let base = analysis_for("Coding");
+        let analysis = ExtendedAnalysisResult { base: &base, is_private_browsing: false, config: Some(&config), notify: send_notification };
+
+        // 後半を手動のエントリが覆っている場合、切り上げても手動のエントリの開始を越えない
+        entries.lock().unwrap().push(manual_entry(100, minutes(10), Some(minutes(20))));
+        let outcome = register_to_toggl_impl(&client, &analysis, 1, start, stop, false).await.unwrap();
+        assert!(matches!(outcome, RegistrationOutcome::Created { .. }), "{:?}", outcome);
+        let created = entries.lock().unwrap()[1].clone();
+        assert_eq!(created["start"], format_datetime_for_toggl(&start));
+        assert_eq!(created["stop"], format_datetime_for_toggl(&minutes(10)));
+
+        // 8:55に終わった自分のエントリと縮めたブロック（9:05～）の間に手動のエントリ（9:00～9:05）がある場合は統合も延長もしない
+        for continuous in [false, true] {
+            config.toggl.continuous_same_activity = continuous;
+            let analysis = ExtendedAnalysisResult { base: &base, is_private_browsing: false, config: Some(&config), notify: send_notification };
+            {
+                let mut entries = entries.lock().unwrap();
+                entries.clear();
+                entries.push(serde_json::json!({
+                    "id": 1,
+                    "workspace_id": 1,
+                    "project_id": null,
+                    "description": "Coding",
+                    "start": format_datetime_for_toggl(&minutes(-15)),
+                    "stop": format_datetime_for_toggl(&minutes(-5)),
+                    "duration": 600,
+                    "tags": null,
+                    "created_with": CREATED_WITH,
+                }));
+                entries.push(manual_entry(2, start, Some(minutes(5))));
+            }
+            let outcome = register_to_toggl_impl(&client, &analysis, 1, start, stop, false).await.unwrap();
+            assert!(matches!(outcome, RegistrationOutcome::Created { .. }), "{:?}", outcome);
+            let entries = entries.lock().unwrap();
+            assert_eq!(entries[0]["stop"], format_datetime_for_toggl(&minutes(-5)));
+            assert_eq!(entries[2]["start"], format_datetime_for_toggl(&minutes(5)));
+        }
+    }
+
+    #[test]
+    fn uncovered_block_range_ignores_own_entries_and_picks_the_longest_gap() {
+        let start = Utc.with_ymd_and_hms(2024, 1, 1, 9, 0, 0).unwrap();
+        let stop = start + Duration::minutes(15);
+        let minutes = |m: i64| start + Duration::minutes(m);
+        let entries: Vec<TogglTimeEntry> = [
+            manual_entry(1, minutes(2), Some(minutes(4))),
+            manual_entry(2, minutes(12), None),
+        ].into_iter().map(|entry| serde_json::from_value(entry).unwrap()).collect();
+
+        // 実行中のエントリは現在時刻まで続いているものとして扱う
+        assert_eq!(uncovered_block_range(&entries, start, stop, CREATED_WITH, minutes(20)), Some((minutes(4), minutes(12))));
+        assert_eq!(uncovered_block_range(&entries, start, stop, CREATED_WITH, minutes(13)), Some((minutes(4), minutes(12))));
+
+        // 自分で作成したエントリとは重なってもよい
+        let own: Vec<TogglTimeEntry> = entries.into_iter()
+            .map(|entry| TogglTimeEntry { created_with: Some(default_created_with()), ..entry })
+            .collect();
+        assert_eq!(uncovered_block_range(&own, start, stop, &default_created_with(), minutes(20)), Some((start, stop)));
+    }
+
+    #[tokio::test]
+    async fn entry_without_stop_is_created_running() {
+        let entry = TimeEntry {
+            description: "Coding".to_string(),
+            wid: 1,
+            pid: None,
+            start: "2024-01-01T09:00:00Z".to_string(),
+            stop: None,
+            duration: None,
+            tags: None,
+            billable: Some(false),
+            created_with: Some(default_created_with()),
+            event_metadata: None,
+        };
+        let body = entry.request_body().unwrap();
+        assert_eq!(body["duration"], -1);
+        assert_eq!(body["workspace_id"], 1);
+        for omitted in ["stop", "project_id", "tags", "event_metadata"] {
+            assert!(body.get(omitted).is_none(), "{} should be omitted: {}", omitted, body);
+        }
+
+        let (api_base, _) = spawn_stateful_toggl_server().await;
+        let client = TogglClient::new("token", 1)
+            .with_api_base(&api_base)
+            .with_rate_limiter(Arc::new(RateLimiter::new(1000.0, 1000.0)));
+        let id = client.create_time_entry(entry).await.unwrap();
+
+        let start = Utc.with_ymd_and_hms(2024, 1, 1, 9, 0, 0).unwrap();
+        let entries = client.get_time_entries(&start, &(start + Duration::minutes(1))).await.unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].id, id);
+        assert!(entries[0].stop.is_none());
+        assert!(entries[0].duration < 0);
+    }
+
+    #[tokio::test]
+    async fn registering_the_same_block_twice_creates_one_entry() {
+        let (api_base, entries) = spawn_stateful_toggl_server().await;
+        let client = TogglClient::new("token", 1)
+            .with_api_base(&api_base)
+            .with_rate_limiter(Arc::new(RateLimiter::new(1000.0, 1000.0)));
+        let start = Utc.with_ymd_and_hms(2024, 1, 1, 9, 0, 0).unwrap();
+        let stop = start + Duration::minutes(15);
+        let base = AnalysisResult {
+            activity: "Coding".to_string(),
+            confidence: 0.9,
+            timestamp: start,
+            alternatives: Vec::new(),
+            window_title: None,
+            window_class: None,
+            calendar_event: None,
+            analyzed_locally: true,
+            project: None,
+            openai_api_key: None,
+        };
+        let analysis = ExtendedAnalysisResult { base: &base, is_private_browsing: false, config: None, notify: send_notification };
+
+        let first = register_to_toggl_impl(&client, &analysis, 1, start, stop, false).await.unwrap();
+        let second = register_to_toggl_impl(&client, &analysis, 1, start, stop, false).await.unwrap();
+
+        assert!(matches!(first, RegistrationOutcome::Created { entry_id: 1, .. }));
+        assert!(matches!(second, RegistrationOutcome::Duplicate { entry_id: 1, .. }));
+        assert_eq!(entries.lock().unwrap().len(), 1);
+        assert_eq!(
+            entries.lock().unwrap()[0]["event_metadata"]["dedupe_key"],
+            dedupe_key(1, start, "Coding")
+        );
+    }
+
+    #[tokio::test]
+    async fn empty_activity_is_registered_with_a_fallback_description() {
+        let (api_base, entries) = spawn_stateful_toggl_server().await;
+        let client = TogglClient::new("token", 1)
+            .with_api_base(&api_base)
+            .with_rate_limiter(Arc::new(RateLimiter::new(1000.0, 1000.0)));
+        let start = Utc.with_ymd_and_hms(2024, 1, 1, 9, 0, 0).unwrap();
+        let stop = start + Duration::minutes(15);
+        let base = AnalysisResult {
+            activity: "  ".to_string(),
+            confidence: 0.9,
+            timestamp: start,
+            alternatives: Vec::new(),
+            window_title: Some(String::new()),
+            window_class: Some("gimp".to_string()),
+            calendar_event: None,
+            analyzed_locally: true,
+            project: None,
+            openai_api_key: None,
+        };
+        let analysis = ExtendedAnalysisResult { base: &base, is_private_browsing: false, config: None, notify: send_notification };
+        register_to_toggl_impl(&client, &analysis, 1, start, stop, false).await.unwrap();
+
+        let unknown = AnalysisResult { window_class: None, ..base.clone() };
+        let analysis = ExtendedAnalysisResult { base: &unknown, is_private_browsing: false, config: None, notify: send_notification };
+        register_to_toggl_impl(&client, &analysis, 1, stop, stop + Duration::minutes(15), false).await.unwrap();
+
+        let entries = entries.lock().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0]["description"], "gimp");
+        assert_eq!(entries[1]["description"], UNKNOWN_ACTIVITY);
+    }
+
+    #[tokio::test]
+    async fn created_with_includes_version_unless_overridden() {
+        let (api_base, entries) = spawn_stateful_toggl_server().await;
+        let client = TogglClient::new("token", 1)
+            .with_api_base(&api_base)
+            .with_rate_limiter(Arc::new(RateLimiter::new(1000.0, 1000.0)));
+        let start = Utc.with_ymd_and_hms(2024, 1, 1, 9, 0, 0).unwrap();
+        let stop = start + Duration::minutes(15);
+        let base = AnalysisResult {
+            activity: "Coding".to_string(),
+            confidence: 0.9,
+            timestamp: start,
+            alternatives: Vec::new(),
+            window_title: None,
+            window_class: None,
+            calendar_event: None,
+            analyzed_locally: true,
+            project: None,
+            openai_api_key: None,
+        };
+        let analysis = ExtendedAnalysisResult { base: &base, is_private_browsing: false, config: None, notify: send_notification };
+        register_to_toggl_impl(&client, &analysis, 1, start, stop, false).await.unwrap();
+
+        let mut config = crate::config::create_default_config();
+        config.toggl.workspace_id = 1;
+        config.toggl.created_with = Some("my-tracker".to_string());
+        let overridden = TogglClient::from_config(&config)
+            .with_api_base(&api_base)
+            .with_rate_limiter(Arc::new(RateLimiter::new(1000.0, 1000.0)));
+        let other = AnalysisResult { activity: "Review".to_string(), ..base.clone() };
+        let analysis = ExtendedAnalysisResult { base: &other, is_private_browsing: false, config: None, notify: send_notification };
+        register_to_toggl_impl(&overridden, &analysis, 1, stop, stop + Duration::minutes(15), false).await.unwrap();
+
+        // 作成元を指定せずに作成したエントリにもクライアントの作成元を付ける
+        let entry = TimeEntry {
+            description: "Email".to_string(),
+            wid: 1,
+            pid: None,
+            start: "2024-01-01T10:00:00Z".to_string(),
+            stop: Some("2024-01-01T10:15:00Z".to_string()),
+            duration: Some(900),
+            tags: None,
+            billable: None,
+            created_with: None,
+            event_metadata: None,
+        };
+        client.create_time_entry(entry.clone()).await.unwrap();
+        overridden.create_time_entry(entry).await.unwrap();
+
+        let entries = entries.lock().unwrap();
+        let created_with = entries[0]["created_with"].as_str().unwrap();
+        assert!(created_with.contains(env!("CARGO_PKG_VERSION")), "{}", created_with);
+        assert_eq!(created_with, default_created_with());
+        assert_eq!(entries[1]["created_with"], "my-tracker");
+        assert_eq!(entries[2]["created_with"], default_created_with());
+        assert_eq!(entries[3]["created_with"], "my-tracker");
+    }
+
+    #[tokio::test]
+    async fn time_entries_since_sends_unix_timestamp_and_skips_deleted_entries() {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        let api_base = spawn_toggl_server(move |_, path, _| {
+            recorded.lock().unwrap().push(path.to_string());
+            ("200 OK", r#"[
+                {"id":1,"workspace_id":1,"project_id":null,"description":"Coding","start":"2024-01-01T09:00:00Z","stop":"2024-01-01T09:15:00Z","duration":900,"tags":null},
+                {"id":2,"workspace_id":1,"project_id":null,"description":"Gone","start":"2024-01-01T09:15:00Z","stop":"2024-01-01T09:30:00Z","duration":900,"tags":null,"server_deleted_at":"2024-01-01T09:31:00Z"}
+            ]"#.to_string())
+        }).await;
+        let client = TogglClient::new("token", 1)
+            .with_api_base(&api_base)
+            .with_rate_limiter(Arc::new(RateLimiter::new(1000.0, 1000.0)));
+
+        let since = Utc::now() - Duration::hours(1);
+        let entries = client.get_time_entries_since(since).await.unwrap();
+        assert_eq!(entries.iter().map(|entry| entry.id).collect::<Vec<_>>(), vec![1]);
+
+        // Togglが受け付けない古い since は受け付ける範囲に切り上げる
+        let too_old = Utc::now() - Duration::days(365);
+        client.get_time_entries_since(too_old).await.unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests[0], format!("/me/time_entries?since={}", since.timestamp()));
+        let clamped: i64 = requests[1].strip_prefix("/me/time_entries?since=").unwrap().parse().unwrap();
+        assert!(clamped >= (Utc::now() - Duration::days(TOGGL_SINCE_MAX_AGE_DAYS)).timestamp() - 5, "{}", requests[1]);
+    }
+
+    #[test]
+    fn last_own_entry_matches_any_version_of_this_tool() {
+        let entry = |id: u64, start: &str, created_with: Option<&str>| TogglTimeEntry {
+            id,
+            workspace_id: 1,
+            project_id: None,
+            description: "Coding".to_string(),
+            start: start.to_string(),
+            stop: None,
+            duration: 60,
+            created_with: created_with.map(str::to_string),
+            server_deleted_at: None,
+        };
+        let entries = vec![
+            entry(1, "2024-01-01T09:00:00Z", Some("toggl_linux_rs")),
+            entry(2, "2024-01-01T10:00:00Z", Some("toggl_linux_rs/0.0.9")),
+            entry(3, "2024-01-01T11:00:00Z", Some("toggl_linux_rs_fork")),
+            entry(4, "2024-01-01T12:00:00Z", Some("TogglTrack")),
+        ];
+
+        assert_eq!(find_last_own_entry(&entries, &[], &default_created_with()).map(|e| e.id), Some(2));
+        assert_eq!(find_last_own_entry(&entries, &[], "TogglTrack").map(|e| e.id), Some(4));
+    }
+
+    #[test]
+    fn description_template_renders_present_fields() {
+        let result = AnalysisResult {
+            activity: "Code review".to_string(),
+            confidence: 0.875,
+            timestamp: Utc::now(),
+            alternatives: Vec::new(),
+            window_title: Some("PR #42 - GitHub".to_string()),
+            window_class: Some("firefox".to_string()),
+            calendar_event: Some(CalendarEvent {
+                id: "event".to_string(),
+                title: "Weekly sync".to_string(),
+                start_time: Utc::now(),
+                end_time: Utc::now(),
+                calendar_id: "primary".to_string(),
+                description: None,
+                location: None,
+                organizer_email: None,
+                is_video_call: false,
+                response_status: None,
+            }),
+            analyzed_locally: true,
+            project: None,
+            openai_api_key: None,
+        };
+
+        assert_eq!(render_description("{activity}", &result), "Code review");
+        assert_eq!(
+            render_description("{activity} — {window_class} [{calendar_title}] ({confidence})", &result),
+            "Code review — firefox [Weekly sync] (0.88)"
+        );
+        assert_eq!(render_description("{window_title} {unknown}", &result), "PR #42 - GitHub {unknown}");
+
+        let long = AnalysisResult { activity: "あ".repeat(2000), ..result };
+        assert!(render_description("{activity}", &long).len() <= MAX_DESCRIPTION_LEN);
+    }
+
+    #[test]
+    fn description_template_omits_absent_fields_with_their_separators() {
+        let result = AnalysisResult {
+            activity: "Code review".to_string(),
+            confidence: 0.9,
+            timestamp: Utc::now(),
+            alternatives: Vec::new(),
+            window_title: None,
+            window_class: None,
+            calendar_event: None,
+            analyzed_locally: true,
+            project: None,
+            openai_api_key: None,
+        };
+
+        assert_eq!(render_description("{activity} — {window_class}", &result), "Code review");
+        assert_eq!(render_description("{calendar_title}: {activity}", &result), "Code review");
+        assert_eq!(render_description("{activity} ({window_class}) - {confidence}", &result), "Code review - 0.90");
+        assert_eq!(render_description("{activity} | {window_class} | {window_title} | {confidence}", &result), "Code review | 0.90");
+        assert_eq!(render_description("[{calendar_title}] {activity}", &result), "Code review");
+        // すべての項目が空の場合は活動内容を使う
+        assert_eq!(render_description("{window_class}", &result), "Code review");
+    }
+
+    #[test]
+    fn tags_response_is_parsed_and_listed_by_name() {
+        let tags: Vec<TogglTag> = serde_json::from_str(include_str!("../tests/fixtures/toggl_tags.json")).unwrap();
+
+        assert_eq!(tags.len(), 2);
+        assert_eq!(tags[0].id, 15678901);
+        assert_eq!(tags[0].name, "meeting");
+        assert_eq!(
+            format_tags_table(&tags),
+            format!("{:<12} 名前\n15678902     deep-work\n15678901     meeting\n", "ID")
+        );
+    }
+
+    #[tokio::test]
+    async fn continuous_same_activity_extends_one_entry_across_blocks() {
+        let (api_base, entries) = spawn_stateful_toggl_server().await;
+        let client = TogglClient::new("token", 1)
+            .with_api_base(&api_base)
+            .with_rate_limiter(Arc::new(RateLimiter::new(1000.0, 1000.0)));
+        let mut config = crate::config::create_default_config();
+        config.openai = None;
+        config.toggl.continuous_same_activity = true;
+        let start = Utc.with_ymd_and_hms(2024, 1, 1, 9, 0, 0).unwrap();
+        let base = AnalysisResult {
+            activity: "Writing the design doc".to_string(),
+            confidence: 0.9,
+            timestamp: start,
+            alternatives: Vec::new(),
+            window_title: None,
+            window_class: None,
+            calendar_event: None,
+            analyzed_locally: true,
+            project: None,
+            openai_api_key: None,
+        };
+        let analysis = ExtendedAnalysisResult { base: &base, is_private_browsing: false, config: Some(&config), notify: send_notification };
+
+        let mut outcomes = Vec::new();
+        for block in 0..3 {
+            let block_start = start + Duration::minutes(15 * block);
+            let block_stop = block_start + Duration::minutes(15);
+            outcomes.push(register_to_toggl_impl(&client, &analysis, 1, block_start, block_stop, false).await.unwrap());
+        }
+
+        assert!(matches!(outcomes[0], RegistrationOutcome::Created { entry_id: 1, .. }));
+        assert!(outcomes[1..].iter().all(|outcome| matches!(outcome, RegistrationOutcome::Merged { entry_id: 1, .. })));
+        let entries = entries.lock().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0]["stop"], format_datetime_for_toggl(&(start + Duration::minutes(45))));
+    }
+
+    #[tokio::test]
+    async fn continuous_same_activity_does_not_extend_entries_of_another_project() {
+        let (api_base, entries) = spawn_stateful_toggl_server().await;
+        let client = TogglClient::new("token", 1)
+            .with_api_base(&api_base)
+            .with_rate_limiter(Arc::new(RateLimiter::new(1000.0, 1000.0)));
+        let mut config = crate::config::create_default_config();
+        config.openai = None;
+        config.toggl.continuous_same_activity = true;
+        let start = Utc.with_ymd_and_hms(2024, 1, 1, 9, 0, 0).unwrap();
+        let base = analysis_for("Writing the design doc");
+        let analysis = ExtendedAnalysisResult { base: &base, is_private_browsing: false, config: Some(&config), notify: send_notification };
+
+        // 直前のブロックに、同じ説明で別のプロジェクトのエントリがある
+        let mut previous = manual_entry(100, start - Duration::minutes(15), Some(start));
+        previous["description"] = serde_json::json!("Writing the design doc");
+        previous["project_id"] = serde_json::json!(5);
+        previous["created_with"] = serde_json::json!(CREATED_WITH);
+        entries.lock().unwrap().push(previous);
+
+        let outcome = register_to_toggl_impl(&client, &analysis, 1, start, start + Duration::minutes(15), false).await.unwrap();
+        assert!(matches!(outcome, RegistrationOutcome::Created { .. }), "{:?}", outcome);
+        let entries = entries.lock().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0]["stop"], format_datetime_for_toggl(&start));
+        assert_eq!(entries[1]["start"], format_datetime_for_toggl(&start));
+    }
+
+    #[tokio::test]
+    async fn activity_chosen_at_the_prompt_is_the_one_registered() {
+        let (api_base, entries) = spawn_stateful_toggl_server().await;
+        let client = TogglClient::new("token", 1)
+            .with_api_base(&api_base)
+            .with_rate_limiter(Arc::new(RateLimiter::new(1000.0, 1000.0)));
+        let mut config = crate::config::create_default_config();
+        config.openai = None;
+        config.toggl.workspace_id = 1;
+        let start = Utc.with_ymd_and_hms(2024, 1, 1, 9, 0, 0).unwrap();
+        let mut analysis = analysis_for("Browsing");
+        analysis.confidence = 0.3;
+        analysis.alternatives = vec![
+            crate::analysis::ActivityCandidate { activity: "Research".to_string(), confidence: 0.25 },
+            crate::analysis::ActivityCandidate { activity: "Email".to_string(), confidence: 0.2 },
+        ];
+
+        assert_eq!(choose_activity(&analysis, &mut "0\n".as_bytes()).unwrap(), "Browsing");
+        assert_eq!(choose_activity(&analysis, &mut "3\nCode review\n".as_bytes()).unwrap(), "Code review");
+        assert!(confirm_activity(&analysis, choose_activity(&analysis, &mut "3\n\n".as_bytes()).unwrap()).is_none());
+
+        let chosen = choose_activity(&analysis, &mut "1\n".as_bytes()).unwrap();
+        let confirmed = confirm_activity(&analysis, chosen).unwrap();
+        let outcome = register_range_with_client(&client, &config, &confirmed, start, start + Duration::minutes(15)).await.unwrap();
+
+        assert!(matches!(outcome, RegistrationOutcome::Created { .. }), "{:?}", outcome);
+        let entries = entries.lock().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0]["description"], "Research");
+    }
+
+    #[tokio::test]
+    async fn class_only_privacy_never_requests_a_similarity_score() {
+        let (api_base, entries) = spawn_stateful_toggl_server().await;
+        let client = TogglClient::new("token", 1)
+            .with_api_base(&api_base)
+            .with_rate_limiter(Arc::new(RateLimiter::new(1000.0, 1000.0)));
+        let similarity_requests = Arc::new(Mutex::new(0));
+        let counted = similarity_requests.clone();
+        let openai_base = spawn_toggl_server(move |_, _, _| {
+            *counted.lock().unwrap() += 1;
+            ("500 Internal Server Error", String::new())
+        }).await;
+        let mut config = crate::config::create_default_config();
+        config.openai.as_mut().unwrap().api_base = openai_base;
+        config.toggl.workspace_id = 1;
+        let start = Utc::now() - Duration::hours(2);
+
+        // 説明が異なる直前のエントリがあると、full では類似度を問い合わせる
+        for (privacy, expected_requests) in [(crate::config::PrivacyMode::ClassOnly, 0), (crate::config::PrivacyMode::Full, 1)] {
+            config.general.privacy = privacy;
+            entries.lock().unwrap().clear();
+            *similarity_requests.lock().unwrap() = 0;
+            let first = analysis_for("editing main.rs - toggl_linux_rs");
+            register_range_with_client(&client, &config, &first, start, start + Duration::minutes(15)).await.unwrap();
+            let second = analysis_for("coding");
+            register_range_with_client(&client, &config, &second, start + Duration::minutes(15), start + Duration::minutes(30)).await.unwrap();
+
+            assert_eq!(*similarity_requests.lock().unwrap(), expected_requests, "{:?}", privacy);
+            assert_eq!(entries.lock().unwrap().len(), 2);
+        }
+
+        // 大文字・小文字や空白の違いだけなら類似度評価なしで同じ活動とみなす
+        assert!(is_same_activity(None, "Code  review", "code review").await);
+        assert!(!is_same_activity(None, "Code review", "Email").await);
+    }
+
+    #[tokio::test]
+    async fn events_from_a_mapped_calendar_tag_the_registered_entry() {
+        let (api_base, entries) = spawn_stateful_toggl_server().await;
+        let client = TogglClient::new("token", 1)
+            .with_api_base(&api_base)
+            .with_rate_limiter(Arc::new(RateLimiter::new(1000.0, 1000.0)));
+        let mut config = crate::config::create_default_config();
+        config.openai = None;
+        config.toggl.workspace_id = 1;
+        config.google_calendar = Some(crate::config::GoogleCalendarSettings::Single(crate::config::GoogleCalendarConfig {
+            enabled: true,
+            client_id: String::new(),
+            client_secret: String::new(),
+            refresh_token: String::new(),
+            calendar_ids: "primary,team@example.com".to_string(),
+            calendar_tags: HashMap::from([("team@example.com".to_string(), "meeting".to_string())]),
+            exclude_event_patterns: Vec::new(),
+        }));
+        let start = Utc.with_ymd_and_hms(2024, 1, 1, 9, 0, 0).unwrap();
+        let event = |calendar_id: &str, start: DateTime<Utc>| crate::data_collector::CalendarEvent {
+            id: format!("{}-{}", calendar_id, start.timestamp()),
+            title: "Sprint planning".to_string(),
+            start_time: start,
+            end_time: start + Duration::minutes(15),
+            calendar_id: calendar_id.to_string(),
+            description: None,
+            location: None,
+            organizer_email: None,
+            is_video_call: false,
+            response_status: None,
+        };
+
+        let mut mapped = analysis_for("Meeting");
+        mapped.calendar_event = Some(event("team@example.com", start));
+        register_range_with_client(&client, &config, &mapped, start, start + Duration::minutes(15)).await.unwrap();
+
+        let later = start + Duration::hours(2);
+        let mut unmapped = analysis_for("Planning");
+        unmapped.calendar_event = Some(event("primary", later));
+        register_range_with_client(&client, &config, &unmapped, later, later + Duration::minutes(15)).await.unwrap();
+
+        let entries = entries.lock().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0]["tags"], serde_json::json!(["meeting"]));
+        assert!(entries[1].get("tags").is_none_or(|tags| tags.is_null()), "{}", entries[1]);
+    }
+
+    #[tokio::test]
+    async fn localized_private_windows_are_skipped_unless_the_skip_is_disabled() {
+        let (api_base, entries) = spawn_stateful_toggl_server().await;
+        let client = TogglClient::new("token", 1)
+            .with_api_base(&api_base)
+            .with_rate_limiter(Arc::new(RateLimiter::new(1000.0, 1000.0)));
+        let mut config = crate::config::create_default_config();
+        config.openai = None;
+        config.toggl.workspace_id = 1;
+        let start = Utc.with_ymd_and_hms(2024, 1, 1, 9, 0, 0).unwrap();
+        let private = |title: &str, class: &str| AnalysisResult {
+            window_title: Some(title.to_string()),
+            window_class: Some(class.to_string()),
+            ..analysis_for("Browsing")
+        };
+
+        // ドイツ語・日本語のタイトルや、タイトルに印のないウィンドウクラスでも判定する
+        for analysis in [
+            private("Neuer Tab - Privater Modus - Mozilla Firefox", "firefox"),
+            private("新しいタブ - Google Chrome（シークレット）", "google-chrome"),
+            private("Mozilla Firefox", "Firefox-Private"),
+        ] {
+            let outcome = register_range_with_client(&client, &config, &analysis, start, start + Duration::minutes(15)).await.unwrap();
+            assert!(matches!(outcome, RegistrationOutcome::Skipped { reason: SkipReason::PrivateBrowsing }), "{:?}", outcome);
+        }
+        assert!(entries.lock().unwrap().is_empty());
+
+        // スキップを無効にすると通常どおり登録する
+        config.general.skip_private_browsing = false;
+        let analysis = private("Neuer Tab - Privater Modus - Mozilla Firefox", "firefox");
+        let outcome = register_range_with_client(&client, &config, &analysis, start, start + Duration::minutes(15)).await.unwrap();
+        assert!(matches!(outcome, RegistrationOutcome::Created { .. }), "{:?}", outcome);
+        assert_eq!(entries.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn low_confidence_cycle_is_audited_with_its_skip_reason() {
+        let (api_base, requests) = spawn_toggl_stub("200 OK", CREATED_ENTRY_BODY).await;
+        let client = TogglClient::new("token", 1)
+            .with_api_base(&api_base)
+            .with_rate_limiter(Arc::new(RateLimiter::new(1000.0, 1000.0)));
+        let temp = tempfile::tempdir().unwrap();
+        let audit_log = temp.path().join("audit.jsonl");
+        let mut config = crate::config::create_default_config();
+        config.openai = None;
+        config.general.audit_log_path = Some(audit_log.to_string_lossy().into_owned());
+        let start = Utc.with_ymd_and_hms(2024, 1, 1, 9, 0, 0).unwrap();
+        let mut base = analysis_for("Browsing");
+        base.confidence = 0.3;
+        let analysis = ExtendedAnalysisResult { base: &base, is_private_browsing: false, config: Some(&config), notify: send_notification };
+
+        let outcome = register_to_toggl_impl(&client, &analysis, 1, start, start + Duration::minutes(15), false).await.unwrap();
+        crate::audit::write_audit_record(&config, Some(&base), &outcome).unwrap();
+
+        assert!(requests.lock().unwrap().is_empty());
+        let audit = std::fs::read_to_string(&audit_log).unwrap();
+        let records: Vec<serde_json::Value> = audit.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0]["decision"], "skipped");
+        assert_eq!(records[0]["reason"], "low_confidence");
+        assert_eq!(records[0]["activity"], "Browsing");
+        assert_eq!(records[0]["confidence"], 0.3);
+    }
+
+    #[tokio::test]
+    async fn calendar_title_replaces_the_description_only_when_the_meeting_covers_the_block() {
+        let (api_base, entries) = spawn_stateful_toggl_server().await;
+        let client = TogglClient::new("token", 1)
+            .with_api_base(&api_base)
+            .with_rate_limiter(Arc::new(RateLimiter::new(1000.0, 1000.0)));
+        let mut config = crate::config::create_default_config();
+        config.openai = None;
+        config.general.prefer_calendar_title = true;
+        let at = |hour: u32, minute: u32| Utc.with_ymd_and_hms(2024, 1, 1, hour, minute, 0).unwrap();
+        let meeting = |start: DateTime<Utc>, end: DateTime<Utc>| CalendarEvent {
+            id: "event".to_string(),
+            title: "Design review".to_string(),
+            start_time: start,
+            end_time: end,
+            calendar_id: "primary".to_string(),
+            description: None,
+            location: None,
+            organizer_email: None,
+            is_video_call: true,
+            response_status: None,
+        };
+
+        // ブロック全体が会議
+        let mut full = analysis_for("Zoom Meeting");
+        full.calendar_event = Some(meeting(at(8, 55), at(9, 20)));
+        let analysis = ExtendedAnalysisResult { base: &full, is_private_browsing: false, config: Some(&config), notify: send_notification };
+        register_to_toggl_impl(&client, &analysis, 1, at(9, 0), at(9, 15), false).await.unwrap();
+
+        // 会議がブロックの1/3だけ重なる場合は、分析した活動のまま
+        let mut partial = analysis_for("Coding");
+        partial.calendar_event = Some(meeting(at(9, 25), at(10, 0)));
+        let analysis = ExtendedAnalysisResult { base: &partial, is_private_browsing: false, config: Some(&config), notify: send_notification };
+        register_to_toggl_impl(&client, &analysis, 1, at(9, 15), at(9, 30), false).await.unwrap();
+
+        let entries = entries.lock().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0]["description"], "Design review");
+        assert_eq!(entries[1]["description"], "Coding");
+    }
+
+    #[tokio::test]
+    async fn reregistering_a_block_with_a_description_template_creates_one_entry() {
+        let (api_base, entries) = spawn_stateful_toggl_server().await;
+        let client = TogglClient::new("token", 1)
+            .with_api_base(&api_base)
+            .with_rate_limiter(Arc::new(RateLimiter::new(1000.0, 1000.0)));
+        let mut config = crate::config::create_default_config();
+        config.openai = None;
+        config.toggl.description_template = "{activity} ({window_class})".to_string();
+        let start = Utc.with_ymd_and_hms(2024, 1, 1, 9, 0, 0).unwrap();
+        let mut base = analysis_for("Coding");
+        base.window_class = Some("code".to_string());
+        let analysis = ExtendedAnalysisResult { base: &base, is_private_browsing: false, config: Some(&config), notify: send_notification };
+
+        let first = register_to_toggl_impl(&client, &analysis, 1, start, start + Duration::minutes(15), false).await.unwrap();
+        let second = register_to_toggl_impl(&client, &analysis, 1, start, start + Duration::minutes(15), false).await.unwrap();
+
+        assert!(matches!(first, RegistrationOutcome::Created { entry_id: 1, .. }), "{:?}", first);
+        assert!(matches!(second, RegistrationOutcome::Duplicate { entry_id: 1, .. }), "{:?}", second);
+        let entries = entries.lock().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0]["description"], "Coding (code)");
+    }
+
+    #[tokio::test]
+    async fn ignored_windows_never_reach_the_registered_description() {
+        let (api_base, entries) = spawn_stateful_toggl_server().await;
+        let client = TogglClient::new("token", 1)
+            .with_api_base(&api_base)
+            .with_rate_limiter(Arc::new(RateLimiter::new(1000.0, 1000.0)));
+        let mut config = crate::config::create_default_config();
+        config.openai = None;
+        config.general.ignore_windows = vec!["KeePassXC".to_string()];
+        config.toggl.description_template = "{activity} ({window_title})".to_string();
+        let start = Utc.with_ymd_and_hms(2024, 1, 1, 9, 0, 0).unwrap();
+
+        // パスワードマネージャーを開いていた時間の方が長いブロック
+        let data: Vec<CollectedData> = (0..14)
+            .map(|minute| {
+                let mut sample = block_sample(start + Duration::minutes(minute), false);
+                if minute < 8 {
+                    sample.window.title = "Vault - KeePassXC".to_string();
+                    sample.window.class = Some("KeePassXC".to_string());
+                }
+                sample
+            })
+            .filter_map(|mut sample| {
+                sample.window = crate::data_collector::recordable_window(sample.window, &config.general.ignore_windows)?;
+                Some(sample)
+            })
+            .collect();
+        assert_eq!(data.len(), 6);
+
+        let base = crate::analysis::analyze_locally(
+            &data,
+            config.general.language,
+            config.general.fallback_activity,
+            config.general.full_confidence_samples,
+        ).unwrap();
+        let analysis = ExtendedAnalysisResult { base: &base, is_private_browsing: false, config: Some(&config), notify: send_notification };
+        let outcome = register_to_toggl_impl(&client, &analysis, 1, start, start + Duration::minutes(15), false).await.unwrap();
+
+        assert!(matches!(outcome, RegistrationOutcome::Created { .. }), "{:?}", outcome);
+        let entries = entries.lock().unwrap();
+        assert_eq!(entries.len(), 1);
+        let description = entries[0]["description"].as_str().unwrap();
+        assert!(description.to_lowercase().contains("main.rs - visual studio code"), "{}", description);
+        assert!(!entries[0].to_string().to_lowercase().contains("keepassxc"), "{}", entries[0]);
+    }
+
+    #[tokio::test]
+    async fn entries_are_fetched_and_patched_through_the_client() {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        let api_base = spawn_toggl_server(move |method, path, body| {
+            recorded.lock().unwrap().push((format!("{} {}", method, path), body.to_string()));
+            let stop = if method == "PUT" { "2024-01-01T09:30:00Z" } else { "2024-01-01T09:15:00Z" };
+            let entry = serde_json::json!({
+                "id": 42,
+                "workspace_id": 7,
+                "project_id": 3,
+                "description": "Coding",
+                "start": "2024-01-01T09:00:00Z",
+                "stop": stop,
+                "duration": 900,
+                "tags": ["focus"],
+            });
+            ("200 OK", entry.to_string())
+        }).await;
+        let client = TogglClient::new("token", 1)
+            .with_api_base(&api_base)
+            .with_rate_limiter(Arc::new(RateLimiter::new(1000.0, 1000.0)));
+
+        let entry = client.get_time_entry(42).await.unwrap();
+        assert_eq!((entry.id, entry.workspace_id, entry.project_id), (42, 7, Some(3)));
+
+        let patch = serde_json::json!({ "stop": "2024-01-01T09:30:00Z", "duration": 1800 });
+        let updated = client.update_time_entry(&entry, patch.clone()).await.unwrap();
+        assert_eq!(updated.stop.as_deref(), Some("2024-01-01T09:30:00Z"));
+
+        // 取得したエントリのワークスペースに、パッチの内容だけを送る
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests[0].0, "GET /me/time_entries/42");
+        assert_eq!(requests[1].0, "PUT /workspaces/7/time_entries/42");
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&requests[1].1).unwrap(), patch);
+    }
+
+    #[tokio::test]
+    async fn running_entry_is_patched_only_while_one_is_running() {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let running = Arc::new(Mutex::new(Some(serde_json::json!({
+            "id": 42,
+            "workspace_id": 7,
+            "project_id": null,
+            "description": "Coding",
+            "start": "2024-01-01T09:00:00Z",
+            "stop": null,
+            "duration": -1704099600,
+            "tags": null,
+        }))));
+        let (recorded, current) = (requests.clone(), running.clone());
+        let api_base = spawn_toggl_server(move |method, path, body| {
+            recorded.lock().unwrap().push((format!("{} {}", method, path), body.to_string()));
+            let mut current = current.lock().unwrap();
+            match (method, current.as_mut()) {
+                ("GET", _) => ("200 OK", current.clone().unwrap_or_default().to_string()),
+                ("PUT", Some(entry)) => {
+                    let patch: serde_json::Value = serde_json::from_str(body).unwrap();
+                    entry["project_id"] = patch["project_id"].clone();
+                    ("200 OK", entry.to_string())
+                }
+                _ => ("404 Not Found", String::new()),
+            }
+        }).await;
+        let client = TogglClient::new("token", 1)
+            .with_api_base(&api_base)
+            .with_rate_limiter(Arc::new(RateLimiter::new(1000.0, 1000.0)));
+
+        let updated = client.update_running_entry(serde_json::json!({ "project_id": 3 })).await.unwrap().unwrap();
+        assert_eq!((updated.id, updated.project_id, updated.stop), (42, Some(3), None));
+        assert_eq!(requests.lock().unwrap()[1].0, "PUT /workspaces/7/time_entries/42");
+
+        // 実行中のエントリがなければ何も送らない
+        *running.lock().unwrap() = None;
+        requests.lock().unwrap().clear();
+        assert!(client.update_running_entry(serde_json::json!({ "project_id": 3 })).await.unwrap().is_none());
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].0, "GET /me/time_entries/current");
+    }
+
+    /// 実行中のエントリを1つだけ保持するストリームモード用のサーバー（記録したリクエストと実行中のエントリを返す）
+    async fn spawn_stream_server() -> (String, Arc<Mutex<Vec<String>>>, Arc<Mutex<Option<serde_json::Value>>>) {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let running = Arc::new(Mutex::new(None::<serde_json::Value>));
+        let (recorded, current) = (requests.clone(), running.clone());
+        let api_base = spawn_toggl_server(move |method, path, body| {
+            recorded.lock().unwrap().push(format!("{} {}", method, path));
+            let mut current = current.lock().unwrap();
+            match method {
+                "GET" if path == "/me/time_entries/current" => ("200 OK", current.clone().unwrap_or_default().to_string()),
+                "GET" => ("200 OK", "[]".to_string()),
+                "POST" => {
+                    let body: serde_json::Value = serde_json::from_str(body).unwrap();
+                    let id = recorded.lock().unwrap().iter().filter(|request| request.starts_with("POST")).count();
+                    let entry = serde_json::json!({
+                        "id": id,
+                        "workspace_id": 53,
+                        "project_id": null,
+                        "description": body["description"],
+                        "start": body["start"],
+                        "stop": null,
+                        "duration": -1,
+                        "tags": null,
+                        "created_with": body["created_with"],
+                    });
+                    *current = Some(entry.clone());
+                    ("200 OK", entry.to_string())
+                }
+                "PATCH" if path.ends_with("/stop") => match current.take() {
+                    Some(mut entry) => {
+                        entry["stop"] = serde_json::json!("2024-01-01T09:30:00Z");
+                        ("200 OK", entry.to_string())
+                    }
+                    None => ("404 Not Found", String::new()),
+                },
+                _ => ("404 Not Found", String::new()),
+            }
+        }).await;
+        (api_base, requests, running)
+    }
+
+    /// ストリームモードのテスト用のクライアントと設定
+    fn stream_client(api_base: &str) -> (TogglClient, AppConfig) {
+        let client = TogglClient::new("token", 53)
+            .with_api_base(api_base)
+            .with_rate_limiter(Arc::new(RateLimiter::new(1000.0, 1000.0)));
+        let mut config = crate::config::create_default_config();
+        config.openai = None;
+        config.toggl.workspace_id = 53;
+        config.general.tracking_mode = TrackingMode::Stream;
+        (client, config)
+    }
+
+    #[tokio::test]
+    async fn stream_mode_keeps_the_running_entry_until_the_activity_changes() {
+        let (api_base, requests, running) = spawn_stream_server().await;
+        let (client, config) = stream_client(&api_base);
+
+        let start = Utc::now() - Duration::minutes(15);
+        let stop = Utc::now();
+        let started = stream_with_client(&client, &config, &analysis_for("Coding"), start, stop).await.unwrap();
+        assert!(matches!(started, RegistrationOutcome::Created { entry_id: 1, .. }), "{:?}", started);
+
+        // 同じ活動が続く間は実行中のエントリをそのまま使う
+        let continued = stream_with_client(&client, &config, &analysis_for("Coding"), start, stop).await.unwrap();
+        assert!(matches!(continued, RegistrationOutcome::Continued { entry_id: 1, .. }), "{:?}", continued);
+
+        // 活動が変わったら停止して新しいエントリを開始する
+        let switched = stream_with_client(&client, &config, &analysis_for("Meeting"), start, stop).await.unwrap();
+        assert!(matches!(switched, RegistrationOutcome::Created { entry_id: 2, .. }), "{:?}", switched);
+        assert_eq!(running.lock().unwrap().as_ref().unwrap()["description"], "Meeting");
+
+        let requests = requests.lock().unwrap();
+        let writes: Vec<&String> = requests.iter().filter(|request| !request.starts_with("GET")).collect();
+        assert_eq!(writes, [
+            "POST /workspaces/53/time_entries",
+            "PATCH /workspaces/53/time_entries/1/stop",
+            "POST /workspaces/53/time_entries",
+        ]);
+    }
+
+    #[tokio::test]
+    async fn stream_mode_leaves_a_foreign_running_entry_alone() {
+        let (api_base, requests, running) = spawn_stream_server().await;
+        let (client, config) = stream_client(&api_base);
+        let manual = serde_json::json!({
+            "id": 99,
+            "workspace_id": 53,
+            "project_id": null,
+            "description": "Pairing with Hanako",
+            "start": "2024-01-01T09:00:00Z",
+            "stop": null,
+            "duration": -1,
+            "tags": null,
+            "created_with": "Toggl Track for Web",
+        });
+        *running.lock().unwrap() = Some(manual.clone());
+        let (start, stop) = (Utc::now() - Duration::minutes(15), Utc::now());
+
+        let outcome = stream_with_client(&client, &config, &analysis_for("Coding"), start, stop).await.unwrap();
+        assert!(matches!(outcome, RegistrationOutcome::Skipped { reason: SkipReason::AlreadyTracked }), "{:?}", outcome);
+        stop_own_running_entry(&client).await.unwrap();
+
+        assert_eq!(running.lock().unwrap().as_ref(), Some(&manual));
+        assert!(requests.lock().unwrap().iter().all(|request| request.starts_with("GET")), "{:?}", requests);
+    }
+
+    #[tokio::test]
+    async fn skipped_stream_blocks_stop_our_own_running_entry() {
+        let (api_base, requests, running) = spawn_stream_server().await;
+        let (client, config) = stream_client(&api_base);
+        let (start, stop) = (Utc::now() - Duration::minutes(15), Utc::now());
+
+        // アイドルのブロックでは分析せずに自分のエントリを停止する
+        stream_with_client(&client, &config, &analysis_for("Coding"), start, stop).await.unwrap();
+        assert!(running.lock().unwrap().is_some());
+        stop_own_running_entry(&client).await.unwrap();
+        assert!(running.lock().unwrap().is_none());
+
+        // 確度が低いブロックやプライベートブラウジングでも停止する
+        stream_with_client(&client, &config, &analysis_for("Coding"), start, stop).await.unwrap();
+        let unsure = AnalysisResult { confidence: 0.2, ..analysis_for("Coding") };
+        let outcome = stream_with_client(&client, &config, &unsure, start, stop).await.unwrap();
+        assert!(matches!(outcome, RegistrationOutcome::Skipped { reason: SkipReason::LowConfidence }), "{:?}", outcome);
+        assert!(running.lock().unwrap().is_none());
+
+        stream_with_client(&client, &config, &analysis_for("Coding"), start, stop).await.unwrap();
+        let private = AnalysisResult { window_title: Some("Private Browsing - Firefox".to_string()), ..analysis_for("Coding") };
+        let outcome = stream_with_client(&client, &config, &private, start, stop).await.unwrap();
+        assert!(matches!(outcome, RegistrationOutcome::Skipped { reason: SkipReason::PrivateBrowsing }), "{:?}", outcome);
+        assert!(running.lock().unwrap().is_none());
+
+        let stops = requests.lock().unwrap().iter().filter(|request| request.ends_with("/stop")).count();
+        assert_eq!(stops, 3);
+    }
+
+    #[tokio::test]
+    async fn project_billable_setting_overrides_the_default_in_both_directions() {
+        let api_base = spawn_toggl_server(|_, path, _| match path {
+            path if path.contains("/projects") => (
+                "200 OK",
+                r#"[{"id":1,"name":"Internal","wid":47,"cid":null,"active":true,"billable":false},
+                    {"id":2,"name":"Consulting","wid":47,"cid":3,"active":true,"billable":true},
+                    {"id":3,"name":"Free plan","wid":47,"cid":null,"active":true}]"#.to_string(),
+            ),
+            _ => ("404 Not Found", String::new()),
+        }).await;
+        let client = TogglClient::new("token", 47)
+            .with_api_base(&api_base)
+            .with_rate_limiter(Arc::new(RateLimiter::new(1000.0, 1000.0)));
+
+        // 請求対象外のプロジェクトは既定値がtrueでも請求対象にしない
+        assert!(!resolve_billable(&client, Some(1), true).await);
+        assert!(resolve_billable(&client, Some(2), false).await);
+        // 設定がないプロジェクトやプロジェクトなしは既定値を使う
+        assert!(resolve_billable(&client, Some(3), true).await);
+        assert!(!resolve_billable(&client, Some(3), false).await);
+        assert!(resolve_billable(&client, None, true).await);
+    }
+
+    #[tokio::test]
+    async fn archived_projects_are_never_inferred() {
+        let queries = Arc::new(Mutex::new(Vec::new()));
+        let recorded = queries.clone();
+        // アクティブのみを要求しても、アーカイブ済みのプロジェクトが混ざったレスポンスを返す
+        let api_base = spawn_toggl_server(move |_, path, _| match path {
+            path if path.contains("/projects") => {
+                recorded.lock().unwrap().push(path.to_string());
+                (
+                    "200 OK",
+                    r#"[{"id":1,"name":"toggl_linux_rs","wid":61,"cid":null,"active":false},
+                        {"id":2,"name":"Email","wid":61,"cid":null,"active":true}]"#.to_string(),
+                )
+            }
+            _ => ("404 Not Found", String::new()),
+        }).await;
+        let client = TogglClient::new("token", 61)
+            .with_api_base(&api_base)
+            .with_rate_limiter(Arc::new(RateLimiter::new(1000.0, 1000.0)));
+
+        let inferred = infer_project_id(&client, &analysis_for("toggl_linux_rs の開発")).await.unwrap();
+        assert_eq!(inferred, None);
+        let by_rule = AnalysisResult { project: Some("toggl_linux_rs".to_string()), ..analysis_for("開発") };
+        assert_eq!(infer_project_id(&client, &by_rule).await.unwrap(), None);
+
+        // --all-projects ではアーカイブ済みのプロジェクトも一覧に出す
+        let all: Vec<u64> = client.get_projects(false).await.unwrap().iter().map(|project| project.id).collect();
+        assert_eq!(all, vec![1, 2]);
+
+        let queries = queries.lock().unwrap();
+        assert!(queries[0].ends_with("active=true"), "{:?}", queries);
+        assert!(queries.last().unwrap().ends_with("active=both"), "{:?}", queries);
+    }
+
+    #[tokio::test]
+    async fn consecutive_inferences_fetch_the_project_list_once() {
+        let fetches = Arc::new(Mutex::new(0));
+        let counted = fetches.clone();
+        let api_base = spawn_toggl_server(move |_, path, _| match path {
+            path if path.contains("/projects") => {
+                *counted.lock().unwrap() += 1;
+                (
+                    "200 OK",
+                    r#"[{"id":1,"name":"toggl_linux_rs","wid":59,"cid":null,"active":true}]"#.to_string(),
+                )
+            }
+            _ => ("404 Not Found", String::new()),
+        }).await;
+        let client = TogglClient::new("token", 59)
+            .with_api_base(&api_base)
+            .with_rate_limiter(Arc::new(RateLimiter::new(1000.0, 1000.0)))
+            .with_project_cache_ttl(std::time::Duration::from_secs(600));
+        let analysis = analysis_for("toggl_linux_rs の開発");
+
+        let first = infer_project_id(&client, &analysis).await.unwrap();
+        let second = infer_project_id(&client, &analysis).await.unwrap();
+        assert_eq!(first.map(|(id, _)| id), Some(1));
+        assert_eq!(second.map(|(id, _)| id), Some(1));
+        assert_eq!(*fetches.lock().unwrap(), 1);
+
+        // キャッシュにないプロジェクト名は新しく作成された可能性があるため再取得する
+        let by_rule = AnalysisResult { project: Some("New project".to_string()), ..analysis.clone() };
+        infer_project_id(&client, &by_rule).await.unwrap();
+        assert_eq!(*fetches.lock().unwrap(), 2);
+
+        client.invalidate_project_cache();
+        infer_project_id(&client, &analysis).await.unwrap();
+        assert_eq!(*fetches.lock().unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn project_confidence_override_gates_sensitive_projects() {
+        let created = Arc::new(Mutex::new(Vec::new()));
+        let recorded = created.clone();
+        let api_base = spawn_toggl_server(move |method, path, body| match method {
+            "GET" if path.contains("/projects") => (
+                "200 OK",
+                r#"[{"id":10,"name":"Acme Corp","wid":1,"cid":null,"active":true},{"id":11,"name":"Email","wid":1,"cid":null,"active":true}]"#.to_string(),
+            ),
+            "POST" if path.ends_with("/time_entries") => {
+                recorded.lock().unwrap().push(serde_json::from_str::<serde_json::Value>(body).unwrap());
+                ("200 OK", CREATED_ENTRY_BODY.to_string())
+            }
+            _ => ("200 OK", "[]".to_string()),
+        }).await;
+        let client = TogglClient::new("token", 1)
+            .with_api_base(&api_base)
+            .with_rate_limiter(Arc::new(RateLimiter::new(1000.0, 1000.0)));
+        let mut config = crate::config::create_default_config();
+        config.openai = None;
+        config.project_confidence = HashMap::from([
+            ("acme corp".to_string(), 0.9),
+            ("Email".to_string(), 0.3),
+        ]);
+        let start = Utc.with_ymd_and_hms(2024, 1, 1, 9, 0, 0).unwrap();
+        let stop = start + Duration::minutes(15);
+        let result = |project: &str, confidence: f64| AnalysisResult {
+            activity: format!("{} work", project),
+            confidence,
+            timestamp: start,
+            alternatives: Vec::new(),
+            window_title: None,
+            window_class: None,
+            calendar_event: None,
+            analyzed_locally: true,
+            project: Some(project.to_string()),
+            openai_api_key: None,
+        };
+
+        let client_work = result("Acme Corp", 0.8);
+        let analysis = ExtendedAnalysisResult { base: &client_work, is_private_browsing: false, config: Some(&config), notify: send_notification };
+        let outcome = register_to_toggl_impl(&client, &analysis, 1, start, stop, false).await.unwrap();
+        assert!(matches!(outcome, RegistrationOutcome::Skipped { reason: SkipReason::LowConfidence }));
+
+        // 全体の閾値より低い指定は無視し、全体の閾値（0.5）を使う
+        let email = result("Email", 0.6);
+        let analysis = ExtendedAnalysisResult { base: &email, is_private_browsing: false, config: Some(&config), notify: send_notification };
+        let outcome = register_to_toggl_impl(&client, &analysis, 1, start, stop, false).await.unwrap();
+        assert!(matches!(outcome, RegistrationOutcome::Created { project_id: Some(11), .. }));
+
+        let created = created.lock().unwrap();
+        assert_eq!(created.len(), 1);
+        assert_eq!(created[0]["project_id"], 11);
+    }
+
+    #[tokio::test]
+    async fn merges_into_the_closest_matching_candidate_only() {
+        let (api_base, entries) = spawn_stateful_toggl_server().await;
+        let entry = |id: u64, project_id: Option<u64>, description: &str, start: &str, stop: &str| serde_json::json!({
+            "id": id, "workspace_id": 1, "project_id": project_id, "description": description,
+            "start": start, "stop": stop, "duration": 0, "tags": null
+        });
+        entries.lock().unwrap().extend([
+            // 活動は同じだが、間隔が空きすぎている
+            entry(1, None, "Writing docs", "2024-01-01T09:00:00Z", "2024-01-01T09:40:00Z"),
+            // 終了時刻は最も近いが、活動が異なる
+            entry(2, None, "Email", "2024-01-01T09:45:00Z", "2024-01-01T10:00:00Z"),
+            // 統合先
+            entry(3, None, "Writing docs", "2024-01-01T09:30:00Z", "2024-01-01T09:50:00Z"),
+            // 活動は同じだが、プロジェクトが異なる
+            entry(4, Some(7), "Writing docs", "2024-01-01T09:46:00Z", "2024-01-01T09:59:00Z"),
+        ]);
+        let client = TogglClient::new("token", 1)
+            .with_api_base(&api_base)
+            .with_rate_limiter(Arc::new(RateLimiter::new(1000.0, 1000.0)));
+        let start = Utc.with_ymd_and_hms(2024, 1, 1, 10, 0, 0).unwrap();
+        let base = AnalysisResult {
+            activity: "Writing docs".to_string(),
+            confidence: 0.9,
+            timestamp: start,
+            alternatives: Vec::new(),
+            window_title: None,
+            window_class: None,
+            calendar_event: None,
+            analyzed_locally: true,
+            project: None,
+            openai_api_key: None,
+        };
+        let analysis = ExtendedAnalysisResult { base: &base, is_private_browsing: false, config: None, notify: send_notification };
+
+        let outcome = register_to_toggl_impl(&client, &analysis, 1, start, start + Duration::minutes(15), false).await.unwrap();
+
+        assert!(matches!(outcome, RegistrationOutcome::Merged { entry_id: 3, .. }));
+        let entries = entries.lock().unwrap();
+        assert_eq!(entries.len(), 4);
+        let stops: Vec<&str> = entries.iter().map(|entry| entry["stop"].as_str().unwrap()).collect();
+        assert_eq!(stops, [
+            "2024-01-01T09:40:00Z",
+            "2024-01-01T10:00:00Z",
+            format_datetime_for_toggl(&(start + Duration::minutes(15))).as_str(),
+            "2024-01-01T09:59:00Z",
+        ]);
+    }
+
+    #[tokio::test]
+    async fn notify_on_register_sends_one_notification_per_registration() {
+        static NOTIFICATIONS: Mutex<Vec<(String, Option<String>)>> = Mutex::new(Vec::new());
+        fn record(_title: &str, message: &str, urgency: Option<&str>) -> Result<()> {
+            NOTIFICATIONS.lock().unwrap().push((message.to_string(), urgency.map(str::to_string)));
+            Ok(())
+        }
+
+        let (api_base, _) = spawn_stateful_toggl_server().await;
+        let client = TogglClient::new("token", 1)
+            .with_api_base(&api_base)
+            .with_rate_limiter(Arc::new(RateLimiter::new(1000.0, 1000.0)));
+        let mut config = crate::config::create_default_config();
+        config.openai = None;
+        config.toggl.notify_on_register = true;
+        let start = Utc.with_ymd_and_hms(2024, 1, 1, 9, 0, 0).unwrap();
+        let base = AnalysisResult {
+            activity: "Programming".to_string(),
+            confidence: 0.9,
+            timestamp: start,
+            alternatives: Vec::new(),
+            window_title: None,
+            window_class: None,
+            calendar_event: None,
+            analyzed_locally: true,
+            project: None,
+            openai_api_key: None,
+        };
+        let analysis = ExtendedAnalysisResult { base: &base, is_private_browsing: false, config: Some(&config), notify: record };
+
+        let outcome = register_to_toggl_impl(&client, &analysis, 1, start, start + Duration::minutes(15), false).await.unwrap();
+
+        assert!(matches!(outcome, RegistrationOutcome::Created { .. }));
+        assert_eq!(
+            *NOTIFICATIONS.lock().unwrap(),
+            [("記録しました: Programming (15分)".to_string(), Some("low".to_string()))]
+        );
+    }
+
+    #[test]
+    fn blocks_align_to_local_quarter_hours_in_half_hour_offset_timezones() {
+        let ist = FixedOffset::east_opt(5 * 3600 + 30 * 60).unwrap();
+        // 2024-01-01 09:52:10 +05:30
+        let timestamp = ist.with_ymd_and_hms(2024, 1, 1, 9, 52, 10).unwrap().with_timezone(&Utc);
+
+        let (start, stop) = block_bounds(timestamp, 15, ist);
+        assert_eq!(start, ist.with_ymd_and_hms(2024, 1, 1, 9, 45, 0).unwrap());
+        assert_eq!(stop, ist.with_ymd_and_hms(2024, 1, 1, 10, 0, 0).unwrap());
+
+        // 1時間のブロックもローカル時刻の正時で区切る（UTCでは毎時30分）
+        let (start, stop) = block_bounds(timestamp, 60, ist);
+        assert_eq!(start, ist.with_ymd_and_hms(2024, 1, 1, 9, 0, 0).unwrap());
+        assert_eq!(format_datetime_for_toggl(&start), "2024-01-01T03:30:00Z");
+        assert_eq!(stop, ist.with_ymd_and_hms(2024, 1, 1, 10, 0, 0).unwrap());
+
+        // ネパール（+5:45）でも同様
+        let npt = FixedOffset::east_opt(5 * 3600 + 45 * 60).unwrap();
+        let timestamp = npt.with_ymd_and_hms(2024, 1, 1, 9, 14, 59).unwrap().with_timezone(&Utc);
+        let (start, _) = block_bounds(timestamp, 15, npt);
+        assert_eq!(start, npt.with_ymd_and_hms(2024, 1, 1, 9, 0, 0).unwrap());
+    }
+
+    #[test]
+    fn blocks_stay_contiguous_across_daylight_saving_transitions() {
+        let mut general = crate::config::create_default_config().general;
+        general.timezone = Some("America/New_York".to_string());
+        let at = |hour: u32, minute: u32| Utc.with_ymd_and_hms(2024, 3, 10, hour, minute, 0).unwrap();
+
+        // 2024-03-10 02:00 EST に 03:00 EDT へ進む: 01:50 EST と 03:05 EDT は隣り合う15分ブロック
+        assert_eq!(general.block_bounds(at(6, 50)), (at(6, 45), at(7, 0)));
+        assert_eq!(general.block_bounds(at(7, 5)), (at(7, 0), at(7, 15)));
+
+        // 1時間のブロックもローカル時刻の正時で区切り、切り替えの前後で隙間も重なりもない
+        general.time_block_division = 1;
+        assert_eq!(general.block_bounds(at(6, 50)), (at(6, 0), at(7, 0)));
+        assert_eq!(general.block_bounds(at(7, 5)), (at(7, 0), at(8, 0)));
+
+        // 2024-11-03 02:00 EDT に 01:00 EST へ戻る: 繰り返す01時台はそれぞれ別のブロック
+        let at = |hour: u32, minute: u32| Utc.with_ymd_and_hms(2024, 11, 3, hour, minute, 0).unwrap();
+        assert_eq!(general.block_bounds(at(5, 30)), (at(5, 0), at(6, 0)));
+        assert_eq!(general.block_bounds(at(6, 30)), (at(6, 0), at(7, 0)));
+    }
+
+    #[test]
+    fn project_and_workspace_lists_are_formatted_as_tables() {
+        let mut archived = project(2, "Old site", "Acme");
+        archived.active = false;
+        archived.client_name = None;
+        archived.color = Some("#06aaf5".to_string());
+        let mut internal = project(3, "Internal", "");
+        internal.cid = None;
+        internal.client_name = None;
+
+        let table = format_projects_table(&[project(1, "Website", "Acme"), archived, internal]);
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("ID"));
+        assert_eq!(lines[1].split_whitespace().collect::<Vec<_>>(), ["1", "1", "Acme", "-", "active", "Website"]);
+        // クライアント名がなければクライアントID、クライアントもなければ「-」
+        assert_eq!(lines[2].split_whitespace().collect::<Vec<_>>(), ["2", "1", "20", "#06aaf5", "archived", "Old", "site"]);
+        assert_eq!(lines[3].split_whitespace().collect::<Vec<_>>(), ["3", "1", "-", "-", "active", "Internal"]);
+
+        let workspace = TogglWorkspace { id: 7, name: "Personal".to_string(), organization_id: 9 };
+        let table = format_workspaces_table(&[workspace]);
+        assert_eq!(table.lines().nth(1).unwrap().split_whitespace().collect::<Vec<_>>(), ["7", "9", "Personal"]);
+    }
+
+    #[tokio::test]
+    async fn listing_projects_with_a_rejected_token_is_an_auth_error() {
+        let (api_base, _) = spawn_toggl_stub("401 Unauthorized", "Incorrect username and/or password").await;
+        let client = TogglClient::new("bad-token", 1)
+            .with_api_base(&api_base)
+            .with_rate_limiter(Arc::new(RateLimiter::new(1000.0, 1000.0)));
+
+        for err in [client.get_projects(true).await.unwrap_err(), client.get_workspaces().await.unwrap_err()] {
+            assert!(matches!(err.downcast_ref::<TogglError>(), Some(TogglError::Unauthorized(_))), "{:#}", err);
+        }
+    }
+
+    #[test]
+    fn parses_me_response_fixture() {
+        let user: TogglUser = serde_json::from_str(include_str!("../tests/fixtures/toggl_me.json")).unwrap();
+
+        assert_eq!(user.id, 1234567);
+        assert_eq!(user.fullname, "Taro Yamada");
+        assert_eq!(user.default_workspace_id, Some(4567890));
+        assert_eq!(user.timezone.as_deref(), Some("Asia/Tokyo"));
+    }
+}
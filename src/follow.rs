@@ -0,0 +1,133 @@
+use anyhow::Result;
+use chrono::{DateTime, Local, Utc};
+use std::io::{IsTerminal, Write};
+use std::time::Duration;
+
+use crate::config::AppConfig;
+use crate::data_collector::{self, WindowInfo};
+
+/// 同じウィンドウを見続けている時間を追跡する
+#[derive(Debug, Default)]
+struct WindowTracker {
+    current: Option<(String, String)>,
+    since: Option<DateTime<Utc>>,
+}
+
+impl WindowTracker {
+    /// 観測したウィンドウを記録し、そのウィンドウを見続けている時間を返す（タイトルが変わった場合も切り替えとみなす）
+    fn observe(&mut self, window: &WindowInfo, now: DateTime<Utc>) -> chrono::Duration {
+        let key = (window.id.clone(), window.title.clone());
+        if self.current.as_ref() != Some(&key) {
+            self.current = Some(key);
+            self.since = Some(now);
+        }
+        now - self.since.unwrap_or(now)
+    }
+}
+
+/// 1回分の観測結果を1行に整形する
+fn format_status_line(
+    now: DateTime<Local>,
+    window: &WindowInfo,
+    is_idle: bool,
+    is_ignored: bool,
+    elapsed: chrono::Duration,
+) -> String {
+    let secs = elapsed.num_seconds().max(0);
+    let state = match (is_ignored, is_idle) {
+        (true, _) => "ignored",
+        (false, true) => "idle",
+        (false, false) => "active",
+    };
+    // ignore_windows に一致するウィンドウはタイトルを表示しない
+    let title = if is_ignored { "-" } else { window.title.as_str() };
+
+    format!(
+        "{} | {:<7} | {:02}:{:02}:{:02} | {} | {}",
+        now.format("%H:%M:%S"),
+        state,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        window.class.as_deref().unwrap_or("-"),
+        title,
+    )
+}
+
+/// 登録は行わずに、アクティブウィンドウとアイドル状態を収集間隔ごとに表示する（Ctrl-Cで終了）
+///
+/// 端末では1行を上書きして表示し、パイプやファイルへの出力では1サンプルごとに1行を出力する
+pub async fn run_follow(config: &AppConfig) -> Result<()> {
+    let interactive = std::io::stdout().is_terminal();
+    let idle_threshold = Duration::from_secs(config.general.idle_threshold_secs);
+    let mut interval = tokio::time::interval(Duration::from_secs(config.general.collect_interval_secs));
+    let mut tracker = WindowTracker::default();
+
+    // 収集処理のデバッグログで表示が崩れないよう、警告以上のみ出力する
+    log::set_max_level(log::LevelFilter::Warn);
+
+    loop {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {
+                if interactive {
+                    println!();
+                }
+                return Ok(());
+            }
+            _ = interval.tick() => {}
+        }
+
+        let now = Utc::now();
+        let line = match data_collector::get_active_window() {
+            Ok(window) => {
+                let is_idle = data_collector::idle_duration().is_some_and(|idle| idle > idle_threshold);
+                let is_ignored = data_collector::is_ignored_window(&window, &config.general.ignore_windows);
+                let elapsed = tracker.observe(&window, now);
+                format_status_line(now.with_timezone(&Local), &window, is_idle, is_ignored, elapsed)
+            }
+            Err(e) => format!("{} | failed to get the active window: {:#}", now.with_timezone(&Local).format("%H:%M:%S"), e),
+        };
+
+        if interactive {
+            // 毎回端末の幅を取り直すため、リサイズ後も1行に収まる
+            let width = console::Term::stdout().size_checked().map_or(80, |(_, columns)| columns as usize);
+            print!("\r\x1b[2K{}", console::truncate_str(&line, width.saturating_sub(1), "…"));
+            std::io::stdout().flush()?;
+        } else {
+            println!("{}", line);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn poll_is_formatted_as_single_status_line() {
+        let started = Utc.with_ymd_and_hms(2024, 1, 1, 0, 30, 0).unwrap();
+        let window = WindowInfo {
+            id: "62914563".to_string(),
+            title: "follow.rs - toggl_linux_rs - Visual Studio Code".to_string(),
+            class: Some("code".to_string()),
+            pid: None,
+            timestamp: started,
+            geometry: None,
+            terminal: None,
+        };
+        let mut tracker = WindowTracker::default();
+        tracker.observe(&window, started);
+        let elapsed = tracker.observe(&window, started + chrono::Duration::seconds(75));
+
+        let now = Local.with_ymd_and_hms(2024, 1, 1, 9, 31, 15).unwrap();
+        assert_eq!(
+            format_status_line(now, &window, false, false, elapsed),
+            "09:31:15 | active  | 00:01:15 | code | follow.rs - toggl_linux_rs - Visual Studio Code"
+        );
+        assert_eq!(
+            format_status_line(now, &window, true, true, elapsed),
+            "09:31:15 | ignored | 00:01:15 | code | -"
+        );
+    }
+}
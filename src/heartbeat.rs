@@ -0,0 +1,152 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::config::AppConfig;
+
+/// ハートビートファイルの既定のファイル名（データディレクトリ直下）
+const DEFAULT_HEARTBEAT_FILE: &str = "heartbeat.json";
+
+/// デーモンの稼働状況（監視用にJSONで書き出す）
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Heartbeat {
+    /// デーモンのプロセスID
+    pub pid: u32,
+
+    /// ファイルを最後に更新した時刻
+    pub updated_at: DateTime<Utc>,
+
+    /// 最後にデータを収集した時刻
+    pub last_collect_at: Option<DateTime<Utc>>,
+
+    /// 最後に分析を実行した時刻
+    pub last_analysis_at: Option<DateTime<Utc>>,
+}
+
+/// 収集・分析のたびにハートビートファイルを更新する
+pub struct HeartbeatWriter {
+    path: PathBuf,
+    heartbeat: Heartbeat,
+}
+
+impl HeartbeatWriter {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            heartbeat: Heartbeat {
+                pid: std::process::id(),
+                updated_at: Utc::now(),
+                last_collect_at: None,
+                last_analysis_at: None,
+            },
+        }
+    }
+
+    /// ハートビートファイルのパス
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// データ収集を記録する（systemdのウォッチドッグにも通知する）
+    pub fn record_collect(&mut self, at: DateTime<Utc>) -> Result<()> {
+        self.heartbeat.last_collect_at = Some(at);
+        notify_systemd("WATCHDOG=1");
+        self.write(at)
+    }
+
+    /// 分析の実行を記録する
+    pub fn record_analysis(&mut self, at: DateTime<Utc>) -> Result<()> {
+        self.heartbeat.last_analysis_at = Some(at);
+        self.write(at)
+    }
+
+    /// 一時ファイルに書いてから置き換え、読み手が書きかけの内容を見ないようにする
+    fn write(&mut self, at: DateTime<Utc>) -> Result<()> {
+        self.heartbeat.updated_at = at;
+        let json = serde_json::to_string_pretty(&self.heartbeat)
+            .context("Failed to serialize heartbeat")?;
+
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, json)
+            .with_context(|| format!("Failed to write heartbeat file {:?}", tmp_path))?;
+        fs::rename(&tmp_path, &self.path)
+            .with_context(|| format!("Failed to replace heartbeat file {:?}", self.path))?;
+        Ok(())
+    }
+}
+
+/// 設定されたハートビートファイルのパス（未設定の場合はデータディレクトリ直下）
+pub fn heartbeat_path(config: &AppConfig) -> PathBuf {
+    match &config.general.heartbeat_path {
+        Some(path) => PathBuf::from(path),
+        None => config.general.data_dir_path().join(DEFAULT_HEARTBEAT_FILE),
+    }
+}
+
+/// systemdの管理下で動いている場合（`NOTIFY_SOCKET` が設定されている場合）に状態を通知する
+///
+/// `Type=notify` と `WatchdogSec=` を設定したサービスで、停止したデーモンを再起動させるために使う
+pub fn notify_systemd(state: &str) {
+    let Some(socket) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+
+    if let Err(e) = send_notify(&socket, state) {
+        debug!("Failed to notify systemd ({}): {}", state, e);
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn send_notify(socket: &std::ffi::OsStr, state: &str) -> std::io::Result<()> {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+    let bytes = socket.as_bytes();
+    // "@" で始まる場合は抽象名前空間のソケット
+    let addr = match bytes.strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(Path::new(socket))?,
+    };
+
+    let datagram = UnixDatagram::unbound()?;
+    datagram.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_notify(_socket: &std::ffi::OsStr, _state: &str) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn heartbeat_file_advances_after_each_tick() {
+        let temp = tempfile::tempdir().unwrap();
+        let mut writer = HeartbeatWriter::new(temp.path().join(DEFAULT_HEARTBEAT_FILE));
+        let read = |path: &Path| -> Heartbeat {
+            serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap()
+        };
+
+        let first = Utc::now();
+        writer.record_collect(first).unwrap();
+        let heartbeat = read(writer.path());
+        assert_eq!(heartbeat.pid, std::process::id());
+        assert_eq!(heartbeat.last_collect_at, Some(first));
+        assert_eq!(heartbeat.last_analysis_at, None);
+
+        let second = first + chrono::Duration::seconds(60);
+        writer.record_collect(second).unwrap();
+        writer.record_analysis(second).unwrap();
+        let heartbeat = read(writer.path());
+        assert_eq!(heartbeat.last_collect_at, Some(second));
+        assert_eq!(heartbeat.last_analysis_at, Some(second));
+        assert!(heartbeat.updated_at > first);
+    }
+}
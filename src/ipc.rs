@@ -0,0 +1,156 @@
+use anyhow::{anyhow, Context, Result};
+use log::{info, warn};
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::config::AppConfig;
+
+/// 即時分析を要求するコマンド
+const TRIGGER_COMMAND: &str = "TRIGGER";
+
+/// コマンドを送らずに接続したままのクライアントでデーモンのループが止まらないよう、読み取りを打ち切るまでの時間
+const COMMAND_READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// デーモンとの通信に使うUnixドメインソケットのパス
+pub fn socket_path(config: &AppConfig) -> PathBuf {
+    config.general.data_dir_path().join("control.sock")
+}
+
+/// デーモン側: 制御用ソケットで待ち受けを開始する
+///
+/// 前回のデーモンが残したソケットファイルは、接続できない場合に限り削除する
+pub async fn bind_listener(config: &AppConfig) -> Result<UnixListener> {
+    let path = socket_path(config);
+    
+    if path.exists() {
+        if UnixStream::connect(&path).await.is_ok() {
+            return Err(anyhow!("Another daemon is already listening on {:?}", path));
+        }
+        std::fs::remove_file(&path)
+            .with_context(|| format!("Failed to remove stale socket {:?}", path))?;
+    }
+    
+    let listener = UnixListener::bind(&path)
+        .with_context(|| format!("Failed to bind control socket {:?}", path))?;
+    info!("Listening for control commands on {:?}", path);
+    Ok(listener)
+}
+
+/// デーモン側: 1行のコマンドを読み取る（接続が閉じられた場合はNone、一定時間内に届かない場合はエラー）
+pub async fn read_command(stream: &mut UnixStream) -> Result<Option<String>> {
+    read_command_within(stream, COMMAND_READ_TIMEOUT).await
+}
+
+async fn read_command_within(stream: &mut UnixStream, timeout: Duration) -> Result<Option<String>> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    let read = tokio::time::timeout(timeout, reader.read_line(&mut line)).await
+        .map_err(|_| anyhow!("Timed out waiting for control command"))?
+        .context("Failed to read control command")?;
+    
+    if read == 0 {
+        return Ok(None);
+    }
+    Ok(Some(line.trim().to_string()))
+}
+
+/// デーモン側: コマンドが即時分析の要求かどうか
+pub fn is_trigger_command(command: &str) -> bool {
+    command.eq_ignore_ascii_case(TRIGGER_COMMAND)
+}
+
+/// デーモン側: 処理結果を返す
+pub async fn write_response(stream: &mut UnixStream, result: &Result<()>) -> Result<()> {
+    let response = match result {
+        Ok(()) => "OK\n".to_string(),
+        Err(e) => format!("ERROR {}\n", e),
+    };
+    stream.write_all(response.as_bytes()).await
+        .context("Failed to write control response")?;
+    Ok(())
+}
+
+/// CLI側: 実行中のデーモンに即時分析を要求する
+pub async fn send_trigger(config: &AppConfig) -> Result<()> {
+    let path = socket_path(config);
+    
+    let mut stream = match UnixStream::connect(&path).await {
+        Ok(stream) => stream,
+        Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::ConnectionRefused) => {
+            warn!("No daemon is listening on {:?}", path);
+            return Err(anyhow!("デーモンが実行されていません（{:?} に接続できません）。--daemon で起動してください", path));
+        }
+        Err(e) => return Err(e).with_context(|| format!("Failed to connect to {:?}", path)),
+    };
+    
+    stream.write_all(format!("{}\n", TRIGGER_COMMAND).as_bytes()).await
+        .context("Failed to send trigger command")?;
+    
+    let mut reader = BufReader::new(stream);
+    let mut response = String::new();
+    reader.read_line(&mut response).await
+        .context("Failed to read daemon response")?;
+    
+    match response.trim() {
+        "OK" => Ok(()),
+        "" => Err(anyhow!("Daemon closed the connection without a response")),
+        other => Err(anyhow!("Daemon reported an error: {}", other.trim_start_matches("ERROR ").trim())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config_in(dir: &std::path::Path) -> AppConfig {
+        let mut config = crate::config::create_default_config();
+        config.general.data_dir = dir.to_string_lossy().into_owned();
+        config
+    }
+
+    #[tokio::test]
+    async fn trigger_round_trips_through_the_control_socket() {
+        let temp = tempfile::tempdir().unwrap();
+        let config = config_in(temp.path());
+        let listener = bind_listener(&config).await.unwrap();
+
+        let daemon = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let command = read_command(&mut stream).await.unwrap().unwrap();
+            assert!(is_trigger_command(&command));
+            write_response(&mut stream, &Err(anyhow!("no samples"))).await.unwrap();
+
+            let (mut stream, _) = listener.accept().await.unwrap();
+            read_command(&mut stream).await.unwrap().unwrap();
+            write_response(&mut stream, &Ok(())).await.unwrap();
+        });
+
+        let error = send_trigger(&config).await.unwrap_err();
+        assert_eq!(error.to_string(), "Daemon reported an error: no samples");
+        send_trigger(&config).await.unwrap();
+        daemon.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn silent_clients_do_not_block_the_daemon() {
+        let temp = tempfile::tempdir().unwrap();
+        let config = config_in(temp.path());
+        let listener = bind_listener(&config).await.unwrap();
+
+        // 接続したままコマンドを送らないクライアント
+        let _client = UnixStream::connect(socket_path(&config)).await.unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let error = read_command_within(&mut stream, Duration::from_millis(50)).await.unwrap_err();
+        assert!(error.to_string().contains("Timed out"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn trigger_without_a_daemon_is_reported() {
+        let temp = tempfile::tempdir().unwrap();
+        let error = send_trigger(&config_in(temp.path())).await.unwrap_err();
+        assert!(error.to_string().contains("--daemon"), "{}", error);
+    }
+}
@@ -1,21 +1,33 @@
 use anyhow::{Context, Result};
 use clap::Parser;
-use log::{error, info};
-use std::path::PathBuf;
+use log::{debug, error, info, warn};
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 use tokio::time;
-use chrono::{self, Local, Timelike};
-use std::io::Write;
+use chrono::{self, Local, NaiveDate, Timelike};
+use std::io::{IsTerminal, Write};
+use rand::{rngs::StdRng, Rng, SeedableRng};
 
+mod audit;
+mod backfill;
 mod config;
+mod config_watch;
 mod data_collector;
+mod diagnose;
 mod analysis;
 mod event;
+mod follow;
+mod heartbeat;
+mod ipc;
+mod rules;
+mod summary;
 mod utils;
+mod wayland_idle;
 mod wizard;
 
-use config::AppConfig;
-use wizard::ConfigWizard;
+use audit::{RegistrationOutcome, SkipReason};
+use config::{AppConfig, LogTimezone, TrackingMode};
+use wizard::{ConfigWizard, WizardSection};
 
 /// Linux automatic activity tracking with Toggl integration
 #[derive(Parser, Debug)]
@@ -25,10 +37,18 @@ struct Args {
     #[clap(short, long, value_parser, default_value = "config.toml")]
     config: PathBuf,
 
+    /// Log output format (json emits one JSON object per line)
+    #[clap(long, value_enum, value_name = "FORMAT", default_value = "text")]
+    log_format: utils::LogFormat,
+
     /// Run in daemon mode
     #[clap(short, long)]
     daemon: bool,
 
+    /// Run the daemon without analysis or registration (only collect data into the local database)
+    #[clap(long, conflicts_with = "analyze_only")]
+    collect_only: bool,
+
     /// Analyze existing logs without collecting new data
     #[clap(long)]
     analyze_only: bool,
@@ -37,22 +57,150 @@ struct Args {
     #[clap(long)]
     wizard: bool,
     
+    /// Edit only one section of the existing config with the wizard
+    #[clap(long, value_enum, value_name = "SECTION")]
+    reconfigure: Option<WizardSection>,
+    
     /// Add to XFCE autostart
     #[clap(long)]
     add_to_autostart: bool,
+    
+    /// Remove the XFCE autostart entry and the systemd user unit
+    #[clap(long)]
+    uninstall: bool,
+    
+    /// With --uninstall, also delete the data directory and the config file (asks for confirmation)
+    #[clap(long, requires = "uninstall")]
+    purge: bool,
+    
+    /// List Toggl workspaces available to the configured API token
+    #[clap(long)]
+    list_workspaces: bool,
+    
+    /// List Toggl projects in the configured workspace
+    #[clap(long)]
+    list_projects: bool,
+    
+    /// List tags in the configured workspace (useful for writing tag rules)
+    #[clap(long)]
+    list_tags: bool,
+    
+    /// Include archived projects in --list-projects
+    #[clap(long)]
+    all_projects: bool,
+    
+    /// Print list output as JSON
+    #[clap(long)]
+    json: bool,
+    
+    /// Print the analysis prompt, raw model response and parsed result without registering
+    #[clap(long)]
+    explain: bool,
+    
+    /// Print tracked hours and estimated cost (hours × project rate) per Toggl project (requires --from)
+    #[clap(long, requires = "from")]
+    report: bool,
+
+    /// Register stored historical data to Toggl (requires --from)
+    #[clap(long, requires = "from")]
+    backfill: bool,
+    
+    /// Re-analyze stored data with the current analyzer and print the result (requires --from)
+    #[clap(long, requires = "from", conflicts_with = "backfill")]
+    replay: bool,
+    
+    /// Register unregistered blocks to Toggl while replaying
+    #[clap(long, requires = "replay")]
+    register: bool,
+    
+    /// First local date to backfill, replay, report or prune (YYYY-MM-DD)
+    #[clap(long, value_name = "DATE")]
+    from: Option<NaiveDate>,
+    
+    /// Last local date to backfill, replay, report or prune (YYYY-MM-DD, defaults to today)
+    #[clap(long, value_name = "DATE")]
+    to: Option<NaiveDate>,
+    
+    /// Ask the running daemon to analyze and register the current block now
+    #[clap(long)]
+    trigger: bool,
+    
+    /// Check dependencies, credentials and the data directory, and print a report
+    #[clap(long)]
+    diagnose: bool,
+    
+    /// Skip all Google Calendar fetching regardless of the configured credentials
+    #[clap(long)]
+    no_calendar: bool,
+
+    /// Reload the config file while the daemon is running whenever it changes (invalid changes are ignored)
+    #[clap(long)]
+    watch_config: bool,
+    
+    /// Delete the Toggl time entry with the given ID
+    #[clap(long, value_name = "ID", conflicts_with = "delete_last")]
+    delete_entry: Option<u64>,
+    
+    /// Delete the most recent entry registered by toggl_linux_rs (asks for confirmation)
+    #[clap(long)]
+    delete_last: bool,
+    
+    /// Delete duplicate entries created by toggl_linux_rs between --from and --to, keeping one per group
+    #[clap(long, requires = "from")]
+    prune_duplicates: bool,
+
+    /// Do not ask for confirmation before --prune-duplicates deletes entries
+    #[clap(long, requires = "prune_duplicates")]
+    yes: bool,
+
+    /// Show the active window and idle state live without registering anything (Ctrl-C to exit)
+    #[clap(long)]
+    follow: bool,
+    
+    /// Send a sample desktop notification and report whether it succeeded
+    #[clap(long)]
+    test_notification: bool,
+    
+    /// Show which project an activity would be registered to, with scores and reasons, without registering
+    #[clap(long, value_name = "ACTIVITY")]
+    infer: Option<String>,
+
+    /// Window title to use with --infer
+    #[clap(long, value_name = "TITLE", requires = "infer")]
+    window: Option<String>,
+
+    /// Print time spent per focus/communication/break bucket for today or this week
+    #[clap(long, value_enum, value_name = "PERIOD")]
+    summary: Option<summary::SummaryPeriod>,
+}
+
+/// `--delete-last` で自分が登録したエントリを探す範囲（時間）
+const DELETE_LAST_WINDOW_HOURS: i64 = 24;
+
+/// ログのタイムスタンプ形式（オフセットを含めて曖昧さをなくす）
+const LOG_TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M:%S%z";
+
+/// JSON形式のログのタイムスタンプ形式（RFC 3339）
+const JSON_LOG_TIMESTAMP_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.3f%:z";
+
+/// ログのタイムスタンプを設定したタイムゾーンで整形する
+fn format_log_timestamp(now: chrono::DateTime<chrono::Utc>, log_timezone: LogTimezone, timestamp_format: &str) -> String {
+    match log_timezone {
+        LogTimezone::Local => now.with_timezone(&Local).format(timestamp_format).to_string(),
+        LogTimezone::Utc => now.format(timestamp_format).to_string(),
+    }
 }
 
 /// アプリケーションのロギングを初期化
-fn init_logging() -> Result<()> {
+fn init_logging(log_timezone: LogTimezone, log_format: utils::LogFormat) -> Result<()> {
+    let timestamp_format = match log_format {
+        utils::LogFormat::Text => LOG_TIMESTAMP_FORMAT,
+        utils::LogFormat::Json => JSON_LOG_TIMESTAMP_FORMAT,
+    };
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("debug"))
-        .format(|buf, record| {
-            writeln!(
-                buf,
-                "[{}] {}: {}",
-                Local::now().format("%Y-%m-%d %H:%M:%S"),
-                record.level(),
-                record.args()
-            )
+        .format(move |buf, record| {
+            let timestamp = format_log_timestamp(chrono::Utc::now(), log_timezone, timestamp_format);
+            writeln!(buf, "{}", utils::format_log_record(log_format, &timestamp, record))
         })
         .init();
     
@@ -62,30 +210,178 @@ fn init_logging() -> Result<()> {
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // 初期化処理をカスタム関数に置き換え
-    init_logging()?;
-    
     let args = Args::parse();
     
+    // ロギング設定のために設定ファイルを先読み（ウィザード実行時など未作成の場合はデフォルト）
+    let early_config = config::load_config(&args.config).ok();
+    let log_timezone = early_config.as_ref()
+        .map(|config| config.general.log_timezone)
+        .unwrap_or_default();
+    init_logging(log_timezone, args.log_format)?;
+    
+    // プロキシはウィザードの通信にも使うため、最初のリクエストより前に設定する
+    utils::set_http_proxy(early_config.as_ref().and_then(|config| config.proxy.clone()));
+    
     // XFCE自動起動に追加
     if args.add_to_autostart {
         info!("Adding application to XFCE autostart");
         return utils::add_to_xfce_autostart();
     }
     
+    // 自動起動の設定を削除（--purge の場合はデータと設定ファイルも削除）
+    if args.uninstall {
+        return uninstall(early_config.as_ref(), &args.config, args.purge);
+    }
+    
+    // 通知のテストは設定ファイルがなくても実行できる
+    if args.test_notification {
+        return match utils::send_notification("toggl_linux_rs", "テスト通知です。この通知が表示されていれば通知は正しく動作しています", None) {
+            Ok(()) => {
+                println!("テスト通知を送信しました。デスクトップに表示されたことを確認してください");
+                Ok(())
+            }
+            Err(e) => {
+                println!("テスト通知の送信に失敗しました: {:#}", e);
+                println!("       -> {}", utils::NOTIFICATION_HINT);
+                Err(anyhow::anyhow!("Test notification failed"))
+            }
+        };
+    }
+    
     // 設定ウィザードを実行
-    if args.wizard {
+    if args.wizard || args.reconfigure.is_some() {
         info!("Starting configuration wizard");
+        // 既存の設定があれば各項目の既定値として使う
+        let existing = if args.config.exists() {
+            Some(config::load_config(&args.config)
+                .context("Failed to load existing configuration for the wizard")?)
+        } else {
+            None
+        };
         let wizard = ConfigWizard::new();
-        return wizard.run().await;
+        return wizard.run(existing, args.reconfigure, &args.config).await;
     }
     
     // 設定ファイルを読み込む
-    let config = config::load_config(&args.config)
-        .context("Failed to load configuration")?;
+    let mut config = prepare_config(&args.config, args.no_calendar)?;
+
+    // 旧バージョンの ./data/activity.db があればデータディレクトリへ移行
+    if let Err(e) = data_collector::migrate_legacy_storage(
+        Path::new(data_collector::LEGACY_DATA_DIR),
+        config.general.data_dir_path(),
+    ) {
+        error!("Failed to migrate legacy data directory: {}", e);
+    }
+    
+    // 電源断などでデータベースが破損していても起動できるよう、退避して作り直す
+    match data_collector::recover_corrupt_database(config.general.data_dir_path()) {
+        Ok(Some(recovery)) => {
+            let message = format!(
+                "データベースが破損していたため作り直しました（{} 行を復旧、破損したファイルは {} に退避しました）",
+                recovery.recovered_rows,
+                recovery.backup_path.display()
+            );
+            if let Err(e) = utils::send_notification("toggl_linux_rs", &message, Some("critical")) {
+                warn!("Failed to send notification: {:#}", e);
+            }
+        }
+        Ok(None) => {}
+        Err(e) => error!("Failed to check the database for corruption: {:#}", e),
+    }
+    
+    if args.no_calendar {
+        info!("Google Calendar integration disabled by --no-calendar");
+    }
+    
+    utils::set_mask_visible_chars(config.general.mask_visible_chars);
+    utils::set_http_timeouts(config.general.http_connect_timeout_secs, config.general.http_timeout_secs);
     
     info!("Starting toggl_linux_rs v{}", env!("CARGO_PKG_VERSION"));
     
+    if args.diagnose {
+        let results = diagnose::run_diagnostics(&config).await;
+        diagnose::print_report(&results);
+        let failed = results.iter().filter(|result| result.status == diagnose::CheckStatus::Fail).count();
+        if failed > 0 {
+            return Err(anyhow::anyhow!("{} diagnostic check(s) failed", failed));
+        }
+        return Ok(());
+    }
+    
+    if args.follow {
+        return follow::run_follow(&config).await;
+    }
+    
+    if let Some(period) = args.summary {
+        return summary::run_summary(&config, period);
+    }
+    
+    if args.trigger {
+        ipc::send_trigger(&config).await?;
+        println!("デーモンに即時分析を要求しました");
+        return Ok(());
+    }
+    
+    // ワークスペースIDが未設定の場合は、Togglにアクセスする前に利用可能なワークスペースから決める
+    // （オフライン起動などで決められない場合は、各リクエストが未設定のエラーを返す）
+    let deletes_entry = args.delete_entry.is_some() || args.delete_last;
+    let uses_workspace = args.list_projects || args.list_tags || args.infer.is_some() || args.analyze_only || args.daemon || args.backfill || args.register || args.report || args.prune_duplicates || deletes_entry;
+    if config.toggl.workspace_id == 0 && uses_workspace {
+        match event::resolve_workspace_id(&config.toggl.api_token, 0).await {
+            Ok(workspace_id) => config.toggl.workspace_id = workspace_id,
+            Err(e) => error!("{:#}", e),
+        }
+    }
+    
+    if args.list_workspaces || args.list_projects || args.list_tags {
+        return list_toggl_resources(&config, &args).await;
+    }
+    
+    if deletes_entry {
+        return delete_toggl_entry(&config, &args).await;
+    }
+
+    if let Some(activity) = &args.infer {
+        return preview_project_inference(&config, activity, args.window.as_deref()).await;
+    }
+    
+    if let (true, Some(from)) = (args.prune_duplicates, args.from) {
+        let to = args.to.unwrap_or_else(|| config.general.today());
+        return prune_duplicate_entries(&config, from, to, args.yes).await;
+    }
+
+    if let (true, Some(from)) = (args.report, args.from) {
+        let to = args.to.unwrap_or_else(|| config.general.today());
+        return print_project_report(&config, from, to).await;
+    }
+
+    if let (true, Some(from)) = (args.replay, args.from) {
+        let to = args.to.unwrap_or_else(|| config.general.today());
+        let (blocks, summary) = backfill::run_replay(&config, from, to, args.register).await?;
+        backfill::print_replay(&blocks);
+        if args.register {
+            println!(
+                "登録 {} 件、登録済み {} 件、スキップ {} 件、失敗 {} 件",
+                summary.registered, summary.already_registered, summary.skipped, summary.failed
+            );
+        }
+        return Ok(());
+    }
+    
+    if let (true, Some(from)) = (args.backfill, args.from) {
+        let to = args.to.unwrap_or_else(|| config.general.today());
+        let summary = backfill::run_backfill(&config, from, to).await?;
+        println!(
+            "バックフィル完了: 登録 {} 件、登録済み {} 件、スキップ {} 件、失敗 {} 件",
+            summary.registered, summary.already_registered, summary.skipped, summary.failed
+        );
+        return Ok(());
+    }
+    
+    if args.explain {
+        return explain_analysis(&config).await;
+    }
+    
     if args.analyze_only {
         // 過去のログファイルを分析するモード
         info!("Running in analyze-only mode");
@@ -93,10 +389,29 @@ async fn main() -> Result<()> {
         return Ok(());
     }
     
-    if args.daemon {
+    // 設定ファイルの変更を監視（デーモンモードのみ）
+    let config_reload = if args.watch_config && (args.collect_only || args.daemon) {
+        info!("Watching {:?} for configuration changes", args.config);
+        Some(ConfigReload {
+            watcher: config_watch::ConfigWatcher::new(&args.config, config_watch::CONFIG_WATCH_DEBOUNCE)?,
+            path: args.config.clone(),
+            no_calendar: args.no_calendar,
+        })
+    } else {
+        if args.watch_config {
+            warn!("--watch-config only takes effect with --daemon or --collect-only");
+        }
+        None
+    };
+
+    if args.collect_only {
+        // 収集のみのデーモンモードで実行（分析・登録は --analyze-only / --backfill に任せる）
+        info!("Running in collect-only daemon mode");
+        run_daemon(&config, DaemonMode::CollectOnly, config_reload).await?;
+    } else if args.daemon {
         // デーモンモードで実行
         info!("Running in daemon mode");
-        run_daemon(&config).await?;
+        run_daemon(&config, DaemonMode::Full, config_reload).await?;
     } else {
         // 一回だけ実行するモード
         info!("Running in one-shot mode");
@@ -106,6 +421,213 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
+/// Togglのワークスペース・プロジェクト・タグ一覧を表示する
+async fn list_toggl_resources(config: &AppConfig, args: &Args) -> Result<()> {
+    let client = event::TogglClient::new(&config.toggl.api_token, config.toggl.workspace_id);
+    let auth_hint = "Togglからの取得に失敗しました。config.tomlのapi_tokenとworkspace_idを確認してください";
+    
+    if args.list_workspaces {
+        let workspaces = client.get_workspaces().await.context(auth_hint)?;
+        if args.json {
+            println!("{}", serde_json::to_string_pretty(&workspaces)?);
+        } else if workspaces.is_empty() {
+            println!("ワークスペースが見つかりませんでした。");
+        } else {
+            print!("{}", event::format_workspaces_table(&workspaces));
+        }
+    }
+    
+    if args.list_projects {
+        let projects = client.get_projects(!args.all_projects).await.context(auth_hint)?;
+        if args.json {
+            println!("{}", serde_json::to_string_pretty(&projects)?);
+        } else if projects.is_empty() {
+            println!("ワークスペース {} にプロジェクトが見つかりませんでした。", config.toggl.workspace_id);
+        } else {
+            print!("{}", event::format_projects_table(&projects));
+        }
+    }
+    
+    if args.list_tags {
+        let tags = client.get_tags().await.context(auth_hint)?;
+        if args.json {
+            println!("{}", serde_json::to_string_pretty(&tags)?);
+        } else if tags.is_empty() {
+            println!("ワークスペース {} にタグが見つかりませんでした。", config.toggl.workspace_id);
+        } else {
+            print!("{}", event::format_tags_table(&tags));
+        }
+    }
+    
+    Ok(())
+}
+
+/// 活動内容（とウィンドウタイトル）から推論されるプロジェクトを、登録せずに表示する（`--infer`）
+async fn preview_project_inference(config: &AppConfig, activity: &str, window_title: Option<&str>) -> Result<()> {
+    let client = event::TogglClient::from_config(config);
+    let projects = client.get_projects_cached().await
+        .context("Togglからプロジェクトの取得に失敗しました。config.tomlのapi_tokenとworkspace_idを確認してください")?;
+
+    let analysis = analysis::AnalysisResult {
+        activity: activity.to_string(),
+        confidence: 1.0,
+        timestamp: chrono::Utc::now(),
+        alternatives: Vec::new(),
+        window_title: window_title.map(str::to_string),
+        window_class: None,
+        calendar_event: None,
+        analyzed_locally: true,
+        project: None,
+        openai_api_key: None,
+    };
+    print!("{}", event::format_project_inference(&projects, &analysis));
+    Ok(())
+}
+
+/// Togglに記録された時間をプロジェクトごとに集計し、時間単価があれば概算金額とあわせて表示する（`--report`）
+async fn print_project_report(config: &AppConfig, from: NaiveDate, to: NaiveDate) -> Result<()> {
+    let client = event::TogglClient::from_config(config);
+    let start = config.general.day_bounds(from).0;
+    let end = config.general.day_bounds(to).1;
+    let entries = client.get_time_entries(&start, &end).await
+        .context("Togglからタイムエントリの取得に失敗しました")?;
+    // アーカイブ済みのプロジェクトに記録した時間も名前と単価で表示する
+    let projects = client.get_projects(false).await
+        .context("Togglからプロジェクトの取得に失敗しました。config.tomlのapi_tokenとworkspace_idを確認してください")?;
+
+    println!("{} 〜 {} のプロジェクト別集計（{} 件）", from, to, entries.len());
+    print!("{}", event::format_project_report(&event::summarize_project_hours(&entries, &projects, chrono::Utc::now())));
+    Ok(())
+}
+
+/// Togglのタイムエントリを削除する（`--delete-entry` / `--delete-last`）
+async fn delete_toggl_entry(config: &AppConfig, args: &Args) -> Result<()> {
+    let client = event::TogglClient::from_config(config);
+    
+    if let Some(entry_id) = args.delete_entry {
+        client.delete_time_entry(entry_id).await?;
+        println!("エントリ {} を削除しました", entry_id);
+        return Ok(());
+    }
+    
+    let end = chrono::Utc::now();
+    let start = end - chrono::Duration::hours(DELETE_LAST_WINDOW_HOURS);
+    let entries = client.get_time_entries(&start, &end).await
+        .context("Failed to retrieve recent time entries")?;
+    let registered = data_collector::get_registered_activities_since(config.general.data_dir_path(), start)?;
+    
+    let Some(entry) = event::find_last_own_entry(&entries, &registered, client.created_with()) else {
+        println!("直近{}時間にtoggl_linux_rsが登録したエントリが見つかりませんでした。", DELETE_LAST_WINDOW_HOURS);
+        return Ok(());
+    };
+    
+    println!("ID: {}  開始: {}  説明: {}", entry.id, entry.start, entry.description);
+    let confirmed = dialoguer::Confirm::new()
+        .with_prompt("このエントリを削除しますか？")
+        .default(false)
+        .interact()?;
+    if !confirmed {
+        println!("削除を取り消しました。");
+        return Ok(());
+    }
+    
+    client.delete_fetched_time_entry(entry).await?;
+    println!("エントリ {} を削除しました", entry.id);
+    Ok(())
+}
+
+/// toggl_linux_rsが作成した重複エントリを、確認のうえ1件を残して削除する（`--prune-duplicates`）
+async fn prune_duplicate_entries(config: &AppConfig, from: NaiveDate, to: NaiveDate, yes: bool) -> Result<()> {
+    let client = event::TogglClient::from_config(config);
+    let start = config.general.day_bounds(from).0;
+    let end = config.general.day_bounds(to).1;
+    let entries = client.get_time_entries(&start, &end).await
+        .context("Failed to retrieve time entries")?;
+
+    let duplicates = event::select_duplicate_entries(&entries, client.created_with());
+    if duplicates.is_empty() {
+        println!("{} 〜 {} にtoggl_linux_rsが作成した重複エントリはありません。", from, to);
+        return Ok(());
+    }
+
+    println!("以下の重複エントリを削除します（同じ内容のエントリを1件ずつ残します）:");
+    for entry in &duplicates {
+        println!("ID: {}  開始: {}  説明: {}", entry.id, entry.start, entry.description);
+    }
+    if !yes {
+        let confirmed = dialoguer::Confirm::new()
+            .with_prompt(format!("{} 件のエントリを削除しますか？", duplicates.len()))
+            .default(false)
+            .interact()?;
+        if !confirmed {
+            println!("削除を取り消しました。");
+            return Ok(());
+        }
+    }
+
+    // 別のワークスペースのエントリも含まれうるため、エントリのワークスペースで削除する
+    let (mut deleted, mut missing) = (0, 0);
+    for entry in &duplicates {
+        match client.delete_fetched_time_entry(entry).await {
+            Ok(true) => deleted += 1,
+            Ok(false) => missing += 1,
+            Err(e) => error!("Failed to delete time entry {}: {:#}", entry.id, e),
+        }
+    }
+    println!("重複エントリを {} 件削除しました（見つからなかったもの {} 件、失敗 {} 件）",
+             deleted, missing, duplicates.len() - deleted - missing);
+    Ok(())
+}
+
+/// 自動起動の設定を削除し、`purge` の場合はデータディレクトリと設定ファイルも確認のうえ削除する
+fn uninstall(config: Option<&AppConfig>, config_path: &Path, purge: bool) -> Result<()> {
+    let mut removed = utils::remove_autostart(&utils::get_home_dir()?)?;
+    
+    if purge {
+        let data_dir = utils::resolve_data_dir(config.map_or("", |config| config.general.data_dir.as_str()))
+            .context("Failed to resolve data directory")?;
+        // 旧バージョンの ./data はカレントディレクトリからの相対パスで、無関係なディレクトリを指しうるため削除しない
+        // （起動時にデータディレクトリへ移行済み）
+        let targets: Vec<PathBuf> = [data_dir, config_path.to_path_buf()]
+            .into_iter()
+            .filter(|path| path.exists())
+            .collect();
+        
+        if !targets.is_empty() {
+            println!("以下を削除します:");
+            for target in &targets {
+                println!("  {}", target.display());
+            }
+            let confirmed = dialoguer::Confirm::new()
+                .with_prompt("収集したデータと設定を削除しますか？（元に戻せません）")
+                .default(false)
+                .interact()?;
+            if confirmed {
+                for target in targets {
+                    if target.is_dir() {
+                        std::fs::remove_dir_all(&target)
+                    } else {
+                        std::fs::remove_file(&target)
+                    }.with_context(|| format!("Failed to remove {:?}", target))?;
+                    removed.push(target);
+                }
+            } else {
+                println!("データと設定の削除を取り消しました。");
+            }
+        }
+    }
+    
+    if removed.is_empty() {
+        println!("削除するものはありませんでした。");
+    } else {
+        println!("削除しました:");
+        for path in &removed {
+            println!("  {}", path.display());
+        }
+    }
+    Ok(())
+}
+
 /// データ収集と分析を一度だけ実行する
 async fn run_once(config: &AppConfig) -> Result<()> {
     // アクティブウィンドウ情報を取得
@@ -115,8 +637,8 @@ async fn run_once(config: &AppConfig) -> Result<()> {
     info!("Current window: {}", window_info.title);
     
     // カレンダー情報があれば取得
-    if let Some(calendar_config) = &config.google_calendar {
-        match data_collector::get_calendar_events(calendar_config).await {
+    if let Some(calendar_settings) = config.calendar_settings() {
+        match data_collector::get_all_calendar_events(calendar_settings, &config.general.data_dir).await {
             Ok(events) => {
                 info!("Retrieved {} calendar events", events.len());
             }
@@ -129,17 +651,126 @@ async fn run_once(config: &AppConfig) -> Result<()> {
     Ok(())
 }
 
+/// 最近のデータの分析過程を表示する（Togglには登録しない）
+async fn explain_analysis(config: &AppConfig) -> Result<()> {
+    let mut recent_data = data_collector::get_recent_data(config.general.data_dir_path(), config.general.block_minutes())?;
+    analysis::compact_samples(&mut recent_data, config);
+    if recent_data.is_empty() {
+        println!("分析対象のデータがありません。");
+        return Ok(());
+    }
+    
+    let explanation = analysis::explain_analysis(config, &recent_data).await?;
+    
+    println!("===== プロンプト =====");
+    println!("{}", explanation.prompt);
+    
+    match &explanation.raw_response {
+        Some(raw_response) => {
+            println!("\n===== モデルのレスポンス =====");
+            println!("{}", raw_response);
+        }
+        None => println!("\nOpenAIが未設定のため、ローカル分析の結果を表示します。"),
+    }
+    
+    println!("\n===== 分析結果 =====");
+    println!("{}", serde_json::to_string_pretty(&explanation.result)?);
+    
+    Ok(())
+}
+
+/// デーモンの動作モード
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DaemonMode {
+    /// 収集・分析・登録をすべて行う
+    Full,
+
+    /// 収集のみ行い、OpenAIやTogglにはアクセスしない（`--collect-only`）
+    CollectOnly,
+}
+
+/// 設定ファイルを読み込んで検証し、データディレクトリを絶対パスに解決する
+///
+/// 起動時と、`--watch-config` で変更された設定ファイルを読み込み直す場合に使う
+fn prepare_config(path: &Path, no_calendar: bool) -> Result<AppConfig> {
+    let mut config = config::load_config(path)
+        .context("Failed to load configuration")?;
+    config.validate().context("Invalid configuration")?;
+    analysis::analyzer_from_config(&config).context("Invalid analyzer configuration")?;
+
+    // データディレクトリを実行時のカレントディレクトリに依存しない絶対パスに解決
+    config.general.data_dir = utils::resolve_data_dir(&config.general.data_dir)
+        .context("Failed to resolve data directory")?
+        .to_string_lossy()
+        .into_owned();
+
+    if no_calendar {
+        config.disable_calendar();
+    }
+
+    Ok(config)
+}
+
+/// `--watch-config` で設定ファイルを読み込み直すための情報
+struct ConfigReload {
+    watcher: config_watch::ConfigWatcher,
+    path: PathBuf,
+    no_calendar: bool,
+}
+
+/// 変更された設定ファイルを読み込み直し、成功した場合は `config` を置き換える
+///
+/// 読み込みや検証に失敗した場合は現在の設定を使い続けてfalseを返す。
+/// データディレクトリや時間ブロックなど、デーモンの起動時に決まる設定は再起動するまで現在の値を使う
+fn apply_config_change(config: &mut AppConfig, path: &Path, no_calendar: bool) -> bool {
+    let mut new_config = match prepare_config(path, no_calendar) {
+        Ok(new_config) => new_config,
+        Err(e) => {
+            error!("Ignoring invalid configuration change, keeping the current configuration: {:#}", e);
+            return false;
+        }
+    };
+
+    let current = &config.general;
+    let requires_restart = new_config.general.data_dir != current.data_dir
+        || new_config.general.heartbeat_path != current.heartbeat_path
+        || new_config.general.time_block_division != current.time_block_division
+        || new_config.general.collect_interval_secs != current.collect_interval_secs;
+    if requires_restart {
+        warn!("Changes to data_dir, heartbeat_path, time_block_division and collect_interval_secs take effect after a restart");
+    }
+    new_config.general.data_dir = current.data_dir.clone();
+    new_config.general.heartbeat_path = current.heartbeat_path.clone();
+    new_config.general.time_block_division = current.time_block_division;
+    new_config.general.collect_interval_secs = current.collect_interval_secs;
+
+    // 起動時にTogglから決めたワークスペースIDを引き継ぐ
+    if new_config.toggl.workspace_id == 0 {
+        new_config.toggl.workspace_id = config.toggl.workspace_id;
+    }
+
+    *config = new_config;
+    utils::set_mask_visible_chars(config.general.mask_visible_chars);
+    utils::set_http_timeouts(config.general.http_connect_timeout_secs, config.general.http_timeout_secs);
+    info!("Reloaded configuration from {:?}", path);
+    true
+}
+
 /// デーモンモードでデータ収集と分析を定期的に実行する
-async fn run_daemon(config: &AppConfig) -> Result<()> {
+///
+/// `config_reload` を渡した場合は、設定ファイルが変更されるたびに読み込み直す
+async fn run_daemon(config: &AppConfig, mode: DaemonMode, mut config_reload: Option<ConfigReload>) -> Result<()> {
+    let mut config = config.clone();
+    
     // 収集データの保存先を初期化
-    data_collector::init_storage().context("Failed to initialize storage")?;
+    data_collector::init_storage(config.general.data_dir_path()).context("Failed to initialize storage")?;
     
     // データコレクターを初期化
     let mut collector = data_collector::DataCollector::new(config.clone())
         .context("Failed to initialize data collector")?;
     
-    // インターネット接続を確認
-    if !utils::check_internet_connection() {
+    // インターネット接続を確認（収集のみの場合はAPIを使わないため不要）
+    if mode == DaemonMode::Full && !utils::check_internet_connection() {
         utils::send_notification(
             "toggl_linux_rs",
             "インターネット接続がありません。一部の機能が制限される場合があります。",
@@ -148,26 +779,44 @@ async fn run_daemon(config: &AppConfig) -> Result<()> {
         info!("No internet connection detected. Some features may be limited.");
     }
     
-    // 1分ごとのデータ収集タイマー
+    // データ収集タイマー（時間ブロックの長さとは独立した間隔）
     let collect_interval = Duration::from_secs(config.general.collect_interval_secs);
     
     // 時間ブロックの分割設定を取得（デフォルト：4=15分ごと）
     let time_block_division = config.general.time_block_division;
-    let minutes_per_block = 60 / time_block_division as u64;
+    let minutes_per_block = config.general.block_minutes();
     
     info!("Using time block division: {} blocks per hour ({} minutes per block)", 
           time_block_division, minutes_per_block);
     
-    // 次のタイムブロック終了時刻までの時間を計算
+    // 前回のデーモンで分析済みのブロック境界を読み込む
+    let last_analyzed_block_end = data_collector::get_last_analyzed_block_end(config.general.data_dir_path())
+        .unwrap_or_else(|e| {
+            error!("Failed to load last analyzed block: {}", e);
+            None
+        });
+    if let Some(last) = last_analyzed_block_end {
+        info!("Last analyzed block ended at {}", last.to_rfc3339());
+    }
+    
+    // 次の未分析のタイムブロック境界を計算
     let now = chrono::Utc::now();
-    let current_minute = now.minute() % 60;
-    let minutes_to_next_block = minutes_per_block - (current_minute as u64 % minutes_per_block);
-    let seconds_to_next_block = minutes_to_next_block * 60 - now.second() as u64;
+    let next_boundary = next_unanalyzed_boundary(&config, now, last_analyzed_block_end);
+    
+    // 複数インスタンスのAPI呼び出しが重ならないようにジッターを設定
+    // 分析は境界より前に実行しないよう、遅らせる方向のみ（ブロック長の半分まで）
+    let mut rng = StdRng::from_entropy();
+    let analysis_jitter = analysis_jitter(&mut rng, config.general.analysis_jitter_secs, minutes_per_block);
     
-    // 次のタイムブロック境界までの待機時間を設定
-    let initial_delay = Duration::from_secs(seconds_to_next_block);
-    info!("Scheduling first analysis in {} minutes and {} seconds", 
-          minutes_to_next_block, now.second());
+    // 次のタイムブロック境界までの待機時間を設定（起動直後の境界では収集間隔1回分のデータがたまるまで待つ）
+    let until_boundary = (next_boundary - now).to_std().unwrap_or_default();
+    let initial_delay = first_analysis_delay(until_boundary, analysis_jitter, collect_interval);
+    if initial_delay > until_boundary + analysis_jitter {
+        info!("Deferring the first analysis by {} seconds so that at least one collect interval of data exists",
+              (initial_delay - until_boundary - analysis_jitter).as_secs());
+    }
+    info!("Scheduling first analysis at {} (in {} seconds, jitter {} seconds)",
+          next_boundary.to_rfc3339(), initial_delay.as_secs(), analysis_jitter.as_secs());
     
     // 時間ブロックごとの分析タイマー
     let analysis_interval = Duration::from_secs(minutes_per_block * 60);
@@ -176,30 +825,124 @@ async fn run_daemon(config: &AppConfig) -> Result<()> {
         analysis_interval
     );
     
+    // 即時分析の要求を受け付ける制御用ソケット
+    let control_listener = ipc::bind_listener(&config).await
+        .context("Failed to start control socket")?;
+    
+    // 監視用のハートビートファイル
+    let mut heartbeat = heartbeat::HeartbeatWriter::new(heartbeat::heartbeat_path(&config));
+    info!("Writing heartbeat to {:?}", heartbeat.path());
+    heartbeat::notify_systemd("READY=1");
+    
     // メインループ
-    let mut collect_timer = time::interval(collect_interval);
+    let mut collect_base = time::Instant::now();
+    let mut next_collect = collect_base;
     let mut collected_data_count = 0;
+    // 保存先の永続的な障害は一度だけ通知する
+    let mut storage_failure_notified = false;
     
     loop {
         tokio::select! {
             // データ収集ループ
-            _ = collect_timer.tick() => {
+            _ = time::sleep_until(next_collect) => {
+                collect_base += collect_interval;
+                next_collect = jittered_instant(&mut rng, collect_base, config.general.collect_jitter_secs);
                 match collector.collect().await {
                     Ok(_) => {
+                        if storage_failure_notified {
+                            info!("Data directory is writable again, resuming storage");
+                            storage_failure_notified = false;
+                        }
                         collected_data_count += 1;
                         info!("Collected data point #{}", collected_data_count);
+                        if let Err(e) = heartbeat.record_collect(chrono::Utc::now()) {
+                            warn!("Failed to update heartbeat: {:#}", e);
+                        }
+                    }
+                    Err(e) if data_collector::is_persistent_storage_error(&e) => {
+                        if storage_failure_notified {
+                            debug!("Still unable to store collected data: {:#}", e);
+                        } else {
+                            error!("Unable to store collected data, nothing will be recorded until this is fixed: {:#}", e);
+                            let message = format!(
+                                "データを保存できません（{}）。データディレクトリの権限と空き容量を確認してください。",
+                                config.general.data_dir
+                            );
+                            if let Err(e) = utils::send_notification("toggl_linux_rs", &message, Some("critical")) {
+                                warn!("Failed to send storage failure notification: {}", e);
+                            }
+                            storage_failure_notified = true;
+                        }
                     }
                     Err(e) => {
-                        error!("Error collecting data: {}", e);
+                        error!("Error collecting data: {:#}", e);
                     }
                 }
             }
             
-            // 分析ループ (タイムブロック境界ごとに実行)
-            _ = analysis_timer.tick() => {
+            // 即時分析の要求（--trigger）
+            accepted = control_listener.accept() => {
+                let mut stream = match accepted {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        error!("Failed to accept control connection: {}", e);
+                        continue;
+                    }
+                };
+                
+                match ipc::read_command(&mut stream).await {
+                    Ok(Some(command)) if ipc::is_trigger_command(&command) => {
+                        info!("Received trigger request, running analysis now");
+                        let result = run_triggered_analysis(&config, mode).await;
+                        if let Err(e) = &result {
+                            error!("Error during triggered analysis: {}", e);
+                        }
+                        if mode == DaemonMode::Full {
+                            if let Err(e) = heartbeat.record_analysis(chrono::Utc::now()) {
+                                warn!("Failed to update heartbeat: {:#}", e);
+                            }
+                        }
+                        if let Err(e) = ipc::write_response(&mut stream, &result).await {
+                            error!("{}", e);
+                        }
+                    }
+                    Ok(Some(command)) => {
+                        let result = Err(anyhow::anyhow!("Unknown command: {}", command));
+                        if let Err(e) = ipc::write_response(&mut stream, &result).await {
+                            error!("{}", e);
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(e) => error!("{}", e),
+                }
+            }
+            
+            // 設定ファイルの変更（--watch-config）
+            Some(()) = async {
+                match config_reload.as_mut() {
+                    Some(reload) => reload.watcher.changed().await,
+                    None => std::future::pending().await,
+                }
+            } => {
+                let Some(reload) = &config_reload else { continue };
+                // 収集の状態（前回の収集時刻やアイドルの集計）を失わないよう、コレクターは作り直さない
+                if apply_config_change(&mut config, &reload.path, reload.no_calendar) {
+                    collector.apply_config(config.clone());
+                }
+            }
+
+            // 分析ループ (タイムブロック境界ごとに実行、収集のみの場合は実行しない)
+            _ = analysis_timer.tick(), if mode == DaemonMode::Full => {
                 let now = chrono::Utc::now();
                 info!("Running analysis at time block: {:02}:{:02}", now.hour(), now.minute());
                 
+                let finished = finished_block(&config, now, analysis_jitter);
+                let block_end = finished.1;
+                if is_block_analyzed(&config, block_end) {
+                    info!("Block ending at {} was already analyzed, skipping", block_end.to_rfc3339());
+                    continue;
+                }
+                
                 if collected_data_count > 0 {
                     // インターネット接続を確認
                     if !utils::check_internet_connection() {
@@ -208,63 +951,526 @@ async fn run_daemon(config: &AppConfig) -> Result<()> {
                     }
                     
                     info!("Running analysis on collected data");
-                    if let Err(e) = analyze_and_register(config).await {
+                    if let Err(e) = analyze_block(&config, false, finished).await {
                         error!("Error during analysis: {}", e);
                     }
+                    if let Err(e) = heartbeat.record_analysis(chrono::Utc::now()) {
+                        warn!("Failed to update heartbeat: {:#}", e);
+                    }
+                    
+                    if let Err(e) = data_collector::set_last_analyzed_block_end(config.general.data_dir_path(), block_end) {
+                        error!("Failed to save last analyzed block: {}", e);
+                    }
+                } else {
+                    info!("Skipping analysis of block ending at {}: no data has been collected since the daemon started",
+                          block_end.to_rfc3339());
                 }
             }
         }
     }
 }
 
+/// 起動時刻より後で、前回のデーモンが分析していない最初のブロック境界
+fn next_unanalyzed_boundary(
+    config: &AppConfig,
+    now: chrono::DateTime<chrono::Utc>,
+    last_analyzed_block_end: Option<chrono::DateTime<chrono::Utc>>,
+) -> chrono::DateTime<chrono::Utc> {
+    let block_duration = chrono::Duration::minutes(config.general.block_minutes() as i64);
+    let (_, mut next_boundary) = config.general.block_bounds(now);
+    if let Some(last) = last_analyzed_block_end {
+        while next_boundary <= last {
+            next_boundary += block_duration;
+        }
+    }
+    next_boundary
+}
+
+/// `block_end` で終わるブロックを分析済みか（再起動前のデーモンが分析したものを含む）
+fn is_block_analyzed(config: &AppConfig, block_end: chrono::DateTime<chrono::Utc>) -> bool {
+    data_collector::get_last_analyzed_block_end(config.general.data_dir_path())
+        .ok()
+        .flatten()
+        .is_some_and(|last| last >= block_end)
+}
+
+/// デーモン起動から最初の分析までの待機時間
+///
+/// 次のブロック境界（とジッター）まで待つが、起動直後の境界で分析すると収集データがないため、少なくとも収集間隔1回分は待つ
+fn first_analysis_delay(until_boundary: Duration, jitter: Duration, collect_interval: Duration) -> Duration {
+    (until_boundary + jitter).max(collect_interval)
+}
+
+/// 即時分析の要求（--trigger）に応じて分析・登録する（収集のみのモードではエラーを返す）
+async fn run_triggered_analysis(config: &AppConfig, mode: DaemonMode) -> Result<()> {
+    match mode {
+        DaemonMode::Full => analyze_and_register(config, false).await,
+        DaemonMode::CollectOnly => Err(anyhow::anyhow!(
+            "Daemon is running in collect-only mode; run --analyze-only or --backfill on the analyzing host"
+        )),
+    }
+}
+
+/// 分析タイマーのジッター（境界より前に分析しないよう0以上、ブロック長の半分まで）
+fn analysis_jitter(rng: &mut StdRng, max_jitter_secs: u64, minutes_per_block: u64) -> Duration {
+    Duration::from_secs(rng.gen_range(0..=max_jitter_secs.min(minutes_per_block * 30)))
+}
+
+/// 基準時刻に ±max_jitter_secs のランダムなずれを加える（現在時刻より前にはしない）
+fn jittered_instant(rng: &mut StdRng, base: time::Instant, max_jitter_secs: u64) -> time::Instant {
+    if max_jitter_secs == 0 {
+        return base;
+    }
+    
+    let max_jitter_ms = max_jitter_secs as i64 * 1000;
+    let offset_ms = rng.gen_range(-max_jitter_ms..=max_jitter_ms);
+    let jittered = if offset_ms >= 0 {
+        base + Duration::from_millis(offset_ms as u64)
+    } else {
+        base.checked_sub(Duration::from_millis(offset_ms.unsigned_abs())).unwrap_or(base)
+    };
+    
+    jittered.max(time::Instant::now())
+}
+
 /// 保存されたログファイルを分析する
 async fn analyze_logs(config: &AppConfig) -> Result<()> {
     info!("Analyzing saved logs");
-    analyze_and_register(config).await
+    // 端末から実行されている場合のみ確認プロンプトを表示する
+    let interactive = std::io::stdin().is_terminal();
+    analyze_and_register(config, interactive).await
 }
 
-/// データを分析し、条件に応じてTogglに登録する
-async fn analyze_and_register(config: &AppConfig) -> Result<()> {
-    // 最近のデータを取得
-    let recent_data = data_collector::get_recent_data()?;
+/// 最新のサンプルを含む時間ブロックを分析し、条件に応じてTogglに登録する
+async fn analyze_and_register(config: &AppConfig, interactive: bool) -> Result<()> {
+    let recent_data = data_collector::get_recent_data(config.general.data_dir_path(), config.general.block_minutes())?;
+    let Some(newest) = recent_data.first() else {
+        info!("No recent data to analyze");
+        record_audit(config, None, &RegistrationOutcome::Skipped { reason: SkipReason::NoData });
+        return Ok(());
+    };
+    
+    analyze_block(config, interactive, config.general.block_bounds(newest.timestamp)).await
+}
+
+/// 分析タイマーが `now` に発火したときに分析する、終了済みの時間ブロック
+///
+/// ジッターで遅れて発火しても、その間に始まった次のブロックではなく直前に終わったブロックを返す
+/// （タイマーのわずかなずれを吸収するため境界は丸める）
+fn finished_block(
+    config: &AppConfig,
+    now: chrono::DateTime<chrono::Utc>,
+    jitter: Duration,
+) -> (chrono::DateTime<chrono::Utc>, chrono::DateTime<chrono::Utc>) {
+    let scheduled_at = now - chrono::Duration::from_std(jitter).unwrap_or_default();
+    let (block_end, _) = config.general.block_bounds(scheduled_at + chrono::Duration::seconds(30));
+    config.general.block_bounds(block_end - chrono::Duration::seconds(1))
+}
+
+/// 時間ブロック（`block`）のデータを分析し、条件に応じてTogglに登録する
+///
+/// `interactive` の場合、確度が低い結果はユーザーに候補を提示して選択させる
+async fn analyze_block(
+    config: &AppConfig,
+    interactive: bool,
+    block: (chrono::DateTime<chrono::Utc>, chrono::DateTime<chrono::Utc>),
+) -> Result<()> {
+    let (block_start, block_stop) = block;
+    let mut recent_data = data_collector::get_data_between(config.general.data_dir_path(), block_start, block_stop)?;
+    analysis::compact_samples(&mut recent_data, config);
     
     if recent_data.is_empty() {
-        info!("No recent data to analyze");
+        info!("No data to analyze in block starting at {}", block_start.to_rfc3339());
+        record_audit(config, None, &RegistrationOutcome::Skipped { reason: SkipReason::NoData });
+        stop_stream(config).await;
         return Ok(());
     }
     
-    // 分析を実行
-    let analysis_result = match config.openai.is_some() {
-        true => {
-            info!("Using GPT-4o mini for analysis");
-            analysis::analyze_with_gpt(config, &recent_data).await?
-        }
-        false => {
-            info!("Using local analysis engine");
-            analysis::analyze_locally(&recent_data)?
-        }
+    // アイドル時間を登録する設定の場合は、ブロックを活動とアイドルの時間帯に分ける
+    let split = event::split_idle_block(config, &recent_data, block_start, block_stop);
+    
+    // ブロック全体がアイドルだった場合や、実際の活動時間が下限に満たない場合は登録しない
+    let (active_secs, enough_activity) = block_activity(config, &recent_data);
+    let active_range = split.active.filter(|_| active_secs > 0);
+    let Some(active_range) = active_range.filter(|_| enough_activity) else {
+        let reason = if active_range.is_none() {
+            info!("Block starting at {} was idle, skipping", block_start.to_rfc3339());
+            SkipReason::Idle
+        } else {
+            info!("Active time in block ({}s) is below min_active_secs ({}s), skipping",
+                  active_secs, config.general.min_active_secs);
+            SkipReason::InsufficientActivity
+        };
+        record_audit(config, None, &RegistrationOutcome::Skipped { reason });
+        stop_stream(config).await;
+        register_idle(config, split.idle).await;
+        return Ok(());
     };
     
+    // 分析を実行
+    let analyzer = analysis::analyzer_from_config(config)?;
+    info!("Using {} for analysis", analyzer.name());
+    let mut analysis_result = analyzer.analyze(&recent_data).await?;
+    
+    // 直前のブロックと同じ活動が続いていれば、登録判断の前に確度を平滑化する
+    analysis::smooth_with_previous_block(config, block_start, &mut analysis_result);
+    
     info!(
-        "Analysis result: activity='{}', confidence={}",
-        analysis_result.activity, analysis_result.confidence
+        "Analysis result: activity='{}', confidence={}, analyzed_locally={}",
+        analysis_result.activity, analysis_result.confidence, analysis_result.analyzed_locally
     );
     
     // 分析結果に基づいて登録処理
-    if analysis_result.confidence >= 0.5 {
+    let outcome = if analysis_result.confidence >= config.general.confidence_threshold {
         info!("Confidence above threshold, auto-registering");
-        match event::register_to_toggl(config, &analysis_result).await {
-            Ok(_) => {
-                info!("Successfully registered to Toggl");
-            }
-            Err(e) => {
-                error!("Failed to register to Toggl: {}", e);
+        register(config, &analysis_result, active_range).await
+    } else if interactive {
+        info!("Confidence below threshold, asking user for confirmation");
+        let activity = event::present_activity_choices(&analysis_result)?;
+        // ユーザーが確認した活動は確度1.0として登録する
+        match event::confirm_activity(&analysis_result, activity) {
+            Some(confirmed) => register(config, &confirmed, active_range).await,
+            None => {
+                info!("No activity entered, skipping registration");
+                stop_stream(config).await;
+                RegistrationOutcome::Skipped { reason: SkipReason::LowConfidence }
             }
         }
     } else {
         info!("Confidence below threshold, user confirmation required");
-        // ここで通知や対話的な確認を行う予定
+        stop_stream(config).await;
+        RegistrationOutcome::Skipped { reason: SkipReason::LowConfidence }
+    };
+    
+    // 次のブロックの平滑化とバックフィルの重複登録防止のため、分析結果と登録の有無を記録する
+    if let Err(e) = data_collector::record_block_analysis(
+        config.general.data_dir_path(),
+        block_start,
+        &analysis_result.activity,
+        analysis_result.confidence,
+        outcome.is_registered(),
+    ) {
+        error!("Failed to record block analysis: {}", e);
     }
     
+    record_audit(config, Some(&analysis_result), &outcome);
+    register_idle(config, split.idle).await;
+    
     Ok(())
 }
+
+/// 設定された記録方式でTogglに登録する（ブロックモードでは `range` の時間帯に登録する）
+async fn register(
+    config: &AppConfig,
+    analysis: &analysis::AnalysisResult,
+    range: (chrono::DateTime<chrono::Utc>, chrono::DateTime<chrono::Utc>),
+) -> RegistrationOutcome {
+    // 認証エラー後はトークンが直るまでTogglを呼ばない
+    let result = event::TOGGL_AUTH_PAUSE.guard(async {
+        match config.general.tracking_mode {
+            TrackingMode::Block => event::register_range_to_toggl(config, analysis, range.0, range.1).await,
+            TrackingMode::Stream => event::stream_to_toggl(config, analysis, range.0, range.1).await,
+        }
+    }, event::is_unauthorized);
+    match result.await {
+        Ok(outcome) => {
+            info!("Successfully registered to Toggl");
+            outcome
+        }
+        Err(e) => {
+            error!("Failed to register to Toggl: {}", e);
+            RegistrationOutcome::Failed { error: e.to_string() }
+        }
+    }
+}
+
+/// ストリームモードで登録しないブロックになった場合に、このツールが開始した実行中のエントリを停止する
+async fn stop_stream(config: &AppConfig) {
+    if config.general.tracking_mode != TrackingMode::Stream || event::TOGGL_AUTH_PAUSE.remaining().is_some() {
+        return;
+    }
+    if let Err(e) = event::stop_streamed_entry(config).await {
+        error!("Failed to stop the running entry: {:#}", e);
+    }
+}
+
+/// ブロック内のアイドルの時間帯をTogglに登録する（`general.idle_behavior = "record"`）
+async fn register_idle(config: &AppConfig, idle: Option<(chrono::DateTime<chrono::Utc>, chrono::DateTime<chrono::Utc>)>) {
+    let Some((start, stop)) = idle else {
+        return;
+    };
+    if event::TOGGL_AUTH_PAUSE.remaining().is_some() {
+        warn!("Toggl is paused after an authentication failure, not registering idle time");
+        return;
+    }
+    
+    let outcome = match event::register_idle_to_toggl(config, start, stop).await {
+        Ok(outcome) => outcome,
+        Err(e) => {
+            error!("Failed to register idle time to Toggl: {}", e);
+            RegistrationOutcome::Failed { error: e.to_string() }
+        }
+    };
+    record_audit(config, None, &outcome);
+}
+
+/// ブロック内の活動時間（非アイドルのサンプル数 × 収集間隔）と、それが `min_active_secs` 以上かどうか
+fn block_activity(config: &AppConfig, data: &[data_collector::CollectedData]) -> (u64, bool) {
+    let active_secs = data.iter().filter(|d| !d.is_idle).count() as u64
+        * config.general.collect_interval_secs;
+    (active_secs, active_secs >= config.general.min_active_secs)
+}
+
+/// 監査ログへの書き込み（失敗しても分析処理は継続する）
+fn record_audit(config: &AppConfig, analysis: Option<&analysis::AnalysisResult>, outcome: &RegistrationOutcome) {
+    if let Err(e) = audit::write_audit_record(config, analysis, outcome) {
+        error!("Failed to write audit log: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn log_timestamps_carry_their_utc_offset() {
+        let now = chrono::Utc.with_ymd_and_hms(2024, 3, 10, 7, 5, 0).unwrap();
+        assert_eq!(format_log_timestamp(now, LogTimezone::Utc, LOG_TIMESTAMP_FORMAT), "2024-03-10 07:05:00+0000");
+        assert_eq!(
+            format_log_timestamp(now, LogTimezone::Utc, JSON_LOG_TIMESTAMP_FORMAT),
+            "2024-03-10T07:05:00.000+00:00"
+        );
+
+        // ローカル時刻でもオフセットを付けるので、同じ時刻として読み戻せる
+        let local = format_log_timestamp(now, LogTimezone::Local, LOG_TIMESTAMP_FORMAT);
+        let parsed = chrono::DateTime::parse_from_str(&local, LOG_TIMESTAMP_FORMAT).unwrap();
+        assert_eq!(parsed, now);
+    }
+
+    #[tokio::test]
+    async fn jittered_tick_analyzes_only_the_block_that_ended() {
+        let temp = tempfile::tempdir().unwrap();
+        let mut config = config::create_default_config();
+        config.general.data_dir = temp.path().to_string_lossy().into_owned();
+        config.general.timezone = Some("UTC".to_string());
+        config.openai = None;
+        // 登録はせず、分析結果の記録だけを確認する
+        config.general.confidence_threshold = 1.1;
+        let collector = data_collector::DataCollector::new(config.clone()).unwrap();
+        let at = |minute: u32, second: u32| chrono::Utc.with_ymd_and_hms(2024, 1, 1, 9, minute, second).unwrap();
+        let sample = |timestamp: chrono::DateTime<chrono::Utc>, title: &str, class: &str| data_collector::CollectedData {
+            timestamp,
+            window: data_collector::WindowInfo {
+                id: "0x1".to_string(),
+                title: title.to_string(),
+                class: Some(class.to_string()),
+                pid: None,
+                timestamp,
+                geometry: None,
+                terminal: None,
+            },
+            calendar_events: Vec::new(),
+            is_idle: false,
+            visible_windows: Vec::new(),
+        };
+        let ended: Vec<_> = [at(5, 0), at(10, 0)].into_iter()
+            .map(|timestamp| sample(timestamp, "main.rs - toggl_linux_rs - Visual Studio Code", "Code"))
+            .collect();
+        let following: Vec<_> = [at(15, 10), at(15, 20), at(15, 30)].into_iter()
+            .map(|timestamp| sample(timestamp, "Slack | general", "Slack"))
+            .collect();
+        for data in ended.iter().chain(&following) {
+            collector.save_data(data).unwrap();
+        }
+
+        // 09:15の境界から50秒遅れて発火したタイマーは、09:00〜09:15のブロックを分析する
+        let block = finished_block(&config, at(15, 50), Duration::from_secs(45));
+        assert_eq!(block, (at(0, 0), at(15, 0)));
+        analyze_block(&config, false, block).await.unwrap();
+
+        let expected = analysis::analyze_locally(
+            &ended,
+            config.general.language,
+            config.general.fallback_activity,
+            config.general.full_confidence_samples,
+        ).unwrap();
+        let recorded = data_collector::get_block_analysis(temp.path(), at(0, 0)).unwrap().unwrap();
+        assert_eq!(recorded.activity, expected.activity);
+        assert!(data_collector::get_block_analysis(temp.path(), at(15, 0)).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn all_idle_block_is_audited_as_idle() {
+        let temp = tempfile::tempdir().unwrap();
+        let mut config = config::create_default_config();
+        config.general.data_dir = temp.path().to_string_lossy().into_owned();
+        let audit_log = temp.path().join("audit.jsonl");
+        config.general.audit_log_path = Some(audit_log.to_string_lossy().into_owned());
+        config.general.timezone = Some("UTC".to_string());
+        config.openai = None;
+        let collector = data_collector::DataCollector::new(config.clone()).unwrap();
+        let at = |minute: u32| chrono::Utc.with_ymd_and_hms(2024, 1, 1, 9, minute, 0).unwrap();
+        for minute in [2, 5, 8] {
+            collector.save_data(&data_collector::CollectedData {
+                timestamp: at(minute),
+                window: data_collector::WindowInfo {
+                    id: "0x1".to_string(),
+                    title: "main.rs - toggl_linux_rs".to_string(),
+                    class: Some("code".to_string()),
+                    pid: None,
+                    timestamp: at(minute),
+                    geometry: None,
+                    terminal: None,
+                },
+                calendar_events: Vec::new(),
+                is_idle: true,
+                visible_windows: Vec::new(),
+            }).unwrap();
+        }
+
+        analyze_block(&config, false, (at(0), at(15))).await.unwrap();
+
+        let audit = std::fs::read_to_string(&audit_log).unwrap();
+        let record: serde_json::Value = serde_json::from_str(audit.lines().next().unwrap()).unwrap();
+        assert_eq!(record["reason"], "idle", "{}", audit);
+    }
+
+    #[test]
+    fn blocks_below_min_active_secs_are_not_registered() {
+        let mut config = config::create_default_config();
+        config.general.collect_interval_secs = 60;
+        config.general.min_active_secs = 180;
+        let now = chrono::Utc::now();
+        let sample = |is_idle: bool| data_collector::CollectedData {
+            timestamp: now,
+            window: data_collector::WindowInfo {
+                id: "0x1".to_string(),
+                title: "main.rs - toggl_linux_rs".to_string(),
+                class: Some("code".to_string()),
+                pid: None,
+                timestamp: now,
+                geometry: None,
+                terminal: None,
+            },
+            calendar_events: Vec::new(),
+            is_idle,
+            visible_windows: Vec::new(),
+        };
+
+        // 下限ちょうどは登録する
+        let at_floor = vec![sample(false), sample(true), sample(false), sample(false)];
+        assert_eq!(block_activity(&config, &at_floor), (180, true));
+
+        // 1サンプル足りなければ登録しない
+        let below_floor = vec![sample(false), sample(true), sample(false)];
+        assert_eq!(block_activity(&config, &below_floor), (120, false));
+
+        // 0は無効
+        config.general.min_active_secs = 0;
+        assert_eq!(block_activity(&config, &[sample(true)]), (0, true));
+    }
+
+    #[tokio::test]
+    async fn collect_only_daemon_never_analyzes_or_registers() {
+        let temp = tempfile::tempdir().unwrap();
+        let mut config = config::create_default_config();
+        config.general.data_dir = temp.path().to_string_lossy().into_owned();
+        let audit_log = temp.path().join("audit.jsonl");
+        config.general.audit_log_path = Some(audit_log.to_string_lossy().into_owned());
+        data_collector::init_storage(config.general.data_dir_path()).unwrap();
+
+        let error = run_triggered_analysis(&config, DaemonMode::CollectOnly).await.unwrap_err();
+        assert!(error.to_string().contains("collect-only"), "{}", error);
+        assert!(!audit_log.exists());
+
+        // 通常のモードでは分析が実行され、判断が監査ログに残る
+        run_triggered_analysis(&config, DaemonMode::Full).await.unwrap();
+        let audit = std::fs::read_to_string(&audit_log).unwrap();
+        assert!(audit.contains("no_data"), "{}", audit);
+    }
+
+    #[test]
+    fn restarting_mid_block_does_not_reanalyze_the_previous_block() {
+        let temp = tempfile::tempdir().unwrap();
+        let mut config = config::create_default_config();
+        config.general.data_dir = temp.path().to_string_lossy().into_owned();
+        config.general.timezone = Some("UTC".to_string());
+        drop(data_collector::DataCollector::new(config.clone()).unwrap());
+        let at = |hour: u32, minute: u32| chrono::Utc.with_ymd_and_hms(2024, 1, 1, hour, minute, 0).unwrap();
+
+        // 09:00〜09:15のブロックを分析した後、09:15の分析が遅れている間にデーモンが再起動した
+        data_collector::set_last_analyzed_block_end(config.general.data_dir_path(), at(9, 15)).unwrap();
+        let last = data_collector::get_last_analyzed_block_end(config.general.data_dir_path()).unwrap();
+        assert_eq!(last, Some(at(9, 15)));
+
+        assert!(is_block_analyzed(&config, at(9, 15)));
+        assert!(!is_block_analyzed(&config, at(9, 30)));
+        assert_eq!(next_unanalyzed_boundary(&config, at(9, 14), last), at(9, 30));
+        assert_eq!(next_unanalyzed_boundary(&config, at(9, 20), last), at(9, 30));
+
+        // 記録がなければ次の境界から分析する
+        assert_eq!(next_unanalyzed_boundary(&config, at(9, 14), None), at(9, 15));
+    }
+
+    #[tokio::test]
+    async fn jitter_stays_within_its_bounds() {
+        let mut rng = StdRng::seed_from_u64(1077);
+
+        for _ in 0..1000 {
+            // 分析は境界より前にならず、ブロック長（15分）の半分を超えて遅れない
+            let jitter = analysis_jitter(&mut rng, 3600, 15);
+            assert!(jitter <= Duration::from_secs(15 * 30), "{:?}", jitter);
+            assert!(analysis_jitter(&mut rng, 20, 15) <= Duration::from_secs(20));
+
+            // 収集は基準時刻の ±10秒以内で、現在時刻より前にはならない
+            let now = time::Instant::now();
+            let base = now + Duration::from_secs(60);
+            let jittered = jittered_instant(&mut rng, base, 10);
+            assert!(jittered >= base - Duration::from_secs(10) && jittered <= base + Duration::from_secs(10));
+            assert!(jittered_instant(&mut rng, now, 10) >= now);
+        }
+        assert_eq!(analysis_jitter(&mut rng, 0, 15), Duration::ZERO);
+        let base = time::Instant::now() + Duration::from_secs(60);
+        assert_eq!(jittered_instant(&mut rng, base, 0), base);
+    }
+
+    #[test]
+    fn first_analysis_waits_for_one_collect_interval_after_start() {
+        let secs = Duration::from_secs;
+
+        // 起動の10秒後に境界が来る場合も、収集間隔（60秒）分のデータがたまるまで待つ
+        assert_eq!(first_analysis_delay(secs(10), secs(0), secs(60)), secs(60));
+        assert_eq!(first_analysis_delay(secs(10), secs(20), secs(60)), secs(60));
+
+        // 境界が十分先なら、境界とジッターのとおりに分析する
+        assert_eq!(first_analysis_delay(secs(300), secs(20), secs(60)), secs(320));
+        assert_eq!(first_analysis_delay(secs(40), secs(20), secs(60)), secs(60));
+    }
+
+    #[tokio::test]
+    async fn watched_config_changes_are_reloaded_after_the_debounce() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("config.toml");
+        let mut written = config::create_default_config();
+        written.general.data_dir = temp.path().to_string_lossy().into_owned();
+        std::fs::write(&path, toml::to_string_pretty(&written).unwrap()).unwrap();
+
+        let mut config = prepare_config(&path, false).unwrap();
+        let mut watcher = config_watch::ConfigWatcher::new(&path, Duration::from_millis(200)).unwrap();
+
+        written.general.confidence_threshold = 0.9;
+        std::fs::write(&path, toml::to_string_pretty(&written).unwrap()).unwrap();
+        time::timeout(Duration::from_secs(5), watcher.changed()).await.unwrap().unwrap();
+        assert!(apply_config_change(&mut config, &path, false));
+        assert_eq!(config.general.confidence_threshold, 0.9);
+
+        // 検証に失敗する変更は無視して、現在の設定を使い続ける
+        written.general.confidence_threshold = 0.3;
+        written.general.confidence_smoothing = 1.5;
+        std::fs::write(&path, toml::to_string_pretty(&written).unwrap()).unwrap();
+        time::timeout(Duration::from_secs(5), watcher.changed()).await.unwrap().unwrap();
+        assert!(!apply_config_change(&mut config, &path, false));
+        assert_eq!(config.general.confidence_threshold, 0.9);
+    }
+}
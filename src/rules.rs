@@ -0,0 +1,295 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use chrono::{Local, NaiveTime};
+use log::debug;
+use regex::Regex;
+
+use crate::analysis::{self, AnalysisResult, Analyzer};
+use crate::config::{AnalysisRule, FallbackActivity, Language};
+use crate::data_collector::CollectedData;
+
+/// 正規表現などを事前に解析済みのルール
+struct CompiledRule {
+    window_class: Option<Regex>,
+    window_title: Option<Regex>,
+    in_calendar_event: Option<bool>,
+    time_range: Option<(NaiveTime, NaiveTime)>,
+    activity: String,
+    confidence: f64,
+    project: Option<String>,
+}
+
+impl CompiledRule {
+    fn compile(index: usize, rule: &AnalysisRule) -> Result<Self> {
+        let regex = |pattern: &Option<String>, field: &str| {
+            pattern.as_deref()
+                .map(Regex::new)
+                .transpose()
+                .with_context(|| format!("Invalid {} regex in rule #{}", field, index + 1))
+        };
+        let time = |value: &Option<String>, field: &str| {
+            value.as_deref()
+                .map(|value| NaiveTime::parse_from_str(value, "%H:%M"))
+                .transpose()
+                .with_context(|| format!("Invalid {} time in rule #{} (expected HH:MM)", field, index + 1))
+        };
+        
+        if !(0.0..=1.0).contains(&rule.confidence) {
+            return Err(anyhow::anyhow!(
+                "confidence in rule #{} must be between 0.0 and 1.0 (got {})", index + 1, rule.confidence
+            ));
+        }
+        
+        let after = time(&rule.after, "after")?;
+        let before = time(&rule.before, "before")?;
+        let time_range = match (after, before) {
+            (None, None) => None,
+            (after, before) => Some((
+                after.unwrap_or(NaiveTime::MIN),
+                before.unwrap_or_else(|| NaiveTime::from_hms_opt(23, 59, 59).unwrap()),
+            )),
+        };
+        
+        Ok(Self {
+            window_class: regex(&rule.window_class, "window_class")?,
+            window_title: regex(&rule.window_title, "window_title")?,
+            in_calendar_event: rule.in_calendar_event,
+            time_range,
+            activity: rule.activity.clone(),
+            confidence: rule.confidence,
+            project: rule.project.clone(),
+        })
+    }
+    
+    /// 1つのサンプルがすべての条件を満たすかどうか
+    fn matches(&self, item: &CollectedData) -> bool {
+        if let Some(ref class) = self.window_class {
+            if !item.window.class.as_deref().is_some_and(|c| class.is_match(c)) {
+                return false;
+            }
+        }
+        
+        if let Some(ref title) = self.window_title {
+            if !title.is_match(&item.window.title) {
+                return false;
+            }
+        }
+        
+        if let Some(expected) = self.in_calendar_event {
+            let in_event = item.calendar_events.iter()
+                .any(|event| event.start_time <= item.timestamp && item.timestamp < event.end_time);
+            if in_event != expected {
+                return false;
+            }
+        }
+        
+        if let Some((after, before)) = self.time_range {
+            let time = item.timestamp.with_timezone(&Local).time();
+            let in_range = if after <= before {
+                after <= time && time < before
+            } else {
+                // 日付をまたぐ時間帯（例: 22:00〜02:00）
+                after <= time || time < before
+            };
+            if !in_range {
+                return false;
+            }
+        }
+        
+        true
+    }
+}
+
+/// ユーザー定義の `[[rule]]` で分類する分析エンジン
+///
+/// ルールは上から順に評価し、最初に一致したルールの活動を採用する。
+/// どのルールにも一致しない場合はキーワードによるローカル分析にフォールバックする
+pub struct RulesAnalyzer {
+    rules: Vec<CompiledRule>,
+    language: Language,
+    fallback: FallbackActivity,
+    full_confidence_samples: usize,
+}
+
+impl RulesAnalyzer {
+    pub fn new(
+        rules: &[AnalysisRule],
+        language: Language,
+        fallback: FallbackActivity,
+        full_confidence_samples: usize,
+    ) -> Result<Self> {
+        let rules = rules.iter()
+            .enumerate()
+            .map(|(index, rule)| CompiledRule::compile(index, rule))
+            .collect::<Result<_>>()?;
+        Ok(Self { rules, language, fallback, full_confidence_samples })
+    }
+}
+
+#[async_trait]
+impl Analyzer for RulesAnalyzer {
+    fn name(&self) -> &'static str {
+        "rules engine"
+    }
+    
+    async fn analyze(&self, data: &[CollectedData]) -> Result<AnalysisResult> {
+        let mut result = analysis::analyze_locally(data, self.language, self.fallback, self.full_confidence_samples)?;
+        
+        // アイドル中のサンプルは判定から除く（すべてアイドルの場合は全体で判定する）
+        let active: Vec<&CollectedData> = data.iter().filter(|item| !item.is_idle).collect();
+        let samples: Vec<&CollectedData> = if active.is_empty() { data.iter().collect() } else { active };
+        
+        for (index, rule) in self.rules.iter().enumerate() {
+            let matched = samples.iter().filter(|item| rule.matches(item)).count();
+            if matched * 2 > samples.len() {
+                debug!("Rule #{} matched {}/{} samples: '{}'", index + 1, matched, samples.len(), rule.activity);
+                result.activity = rule.activity.clone();
+                result.confidence = rule.confidence;
+                result.alternatives = Vec::new();
+                result.project = rule.project.clone();
+                return Ok(result);
+            }
+        }
+        
+        debug!("No rule matched, using keyword categorization");
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::default_full_confidence_samples;
+    use crate::data_collector::{CalendarEvent, WindowInfo};
+    use chrono::{Duration, TimeZone, Utc};
+
+    fn sample(class: &str, title: &str) -> CollectedData {
+        let timestamp = Utc::now();
+        CollectedData {
+            timestamp,
+            window: WindowInfo {
+                id: "0x1".to_string(),
+                title: title.to_string(),
+                class: Some(class.to_string()),
+                pid: None,
+                timestamp,
+                geometry: None,
+                terminal: None,
+            },
+            calendar_events: Vec::new(),
+            is_idle: false,
+            visible_windows: Vec::new(),
+        }
+    }
+
+    fn rule(activity: &str) -> AnalysisRule {
+        AnalysisRule {
+            window_class: None,
+            window_title: None,
+            in_calendar_event: None,
+            after: None,
+            before: None,
+            activity: activity.to_string(),
+            confidence: 0.9,
+            project: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn first_matching_rule_wins() {
+        let rules = vec![
+            AnalysisRule { window_class: Some("^code$".to_string()), project: Some("toggl_linux_rs".to_string()), ..rule("Development") },
+            AnalysisRule { window_class: Some("code".to_string()), ..rule("Second match") },
+        ];
+        let data = vec![sample("code", "main.rs"), sample("code", "rules.rs"), sample("firefox", "Docs")];
+
+        let result = RulesAnalyzer::new(&rules, Language::Ja, FallbackActivity::Other, default_full_confidence_samples()).unwrap().analyze(&data).await.unwrap();
+        assert_eq!(result.activity, "Development");
+        assert_eq!(result.confidence, 0.9);
+        assert_eq!(result.project.as_deref(), Some("toggl_linux_rs"));
+    }
+
+    #[tokio::test]
+    async fn falls_through_to_keyword_categorization_when_no_rule_matches() {
+        let rules = vec![AnalysisRule { window_class: Some("^slack$".to_string()), ..rule("Chat") }];
+        let data = vec![
+            sample("xterm", "bash - terminal"),
+            sample("xterm", "bash - terminal"),
+            sample("slack", "general"),
+        ];
+
+        let result = RulesAnalyzer::new(&rules, Language::Ja, FallbackActivity::Other, default_full_confidence_samples()).unwrap().analyze(&data).await.unwrap();
+        assert_eq!(result.activity, analysis::analyze_locally(&data, Language::Ja, FallbackActivity::Other, default_full_confidence_samples()).unwrap().activity);
+        assert!(result.project.is_none());
+    }
+
+    #[tokio::test]
+    async fn unmatched_rules_fall_through_to_the_configured_fallback_activity() {
+        let rules = vec![AnalysisRule { window_class: Some("^slack$".to_string()), ..rule("Chat") }];
+        let data = vec![sample("krita", "Storyboard.kra - Krita"), sample("krita", "Storyboard.kra - Krita")];
+
+        for (fallback, expected) in [
+            (FallbackActivity::Other, "Other activity"),
+            (FallbackActivity::WindowTitle, "Storyboard.kra - Krita"),
+            (FallbackActivity::WindowClass, "krita"),
+        ] {
+            let analyzer = RulesAnalyzer::new(&rules, Language::En, fallback, default_full_confidence_samples()).unwrap();
+            assert_eq!(analyzer.analyze(&data).await.unwrap().activity, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn regex_and_calendar_conditions_are_combined() {
+        let rules = vec![AnalysisRule {
+            window_title: Some(r"(?i)pull request #\d+".to_string()),
+            in_calendar_event: Some(false),
+            ..rule("Code review")
+        }];
+        let analyzer = RulesAnalyzer::new(&rules, Language::Ja, FallbackActivity::Other, default_full_confidence_samples()).unwrap();
+
+        let data = vec![sample("firefox", "Pull Request #42 - GitHub")];
+        assert_eq!(analyzer.analyze(&data).await.unwrap().activity, "Code review");
+
+        let mut in_meeting = sample("firefox", "Pull Request #42 - GitHub");
+        in_meeting.calendar_events.push(CalendarEvent {
+            id: "event-1".to_string(),
+            title: "Sync".to_string(),
+            start_time: in_meeting.timestamp - Duration::minutes(5),
+            end_time: in_meeting.timestamp + Duration::minutes(25),
+            calendar_id: "primary".to_string(),
+            description: None,
+            location: None,
+            organizer_email: None,
+            is_video_call: false,
+            response_status: None,
+        });
+        assert_ne!(analyzer.analyze(&[in_meeting]).await.unwrap().activity, "Code review");
+
+        let data = vec![sample("firefox", "Issue #42 - GitHub")];
+        assert_ne!(analyzer.analyze(&data).await.unwrap().activity, "Code review");
+    }
+
+    #[test]
+    fn time_ranges_may_wrap_past_midnight() {
+        let compiled = CompiledRule::compile(0, &AnalysisRule {
+            after: Some("22:00".to_string()),
+            before: Some("02:00".to_string()),
+            ..rule("Late night")
+        }).unwrap();
+
+        let at = |hour: u32| {
+            let mut item = sample("code", "main.rs");
+            item.timestamp = Local.with_ymd_and_hms(2024, 5, 13, hour, 30, 0).unwrap().with_timezone(&Utc);
+            item
+        };
+        assert!(compiled.matches(&at(23)));
+        assert!(compiled.matches(&at(1)));
+        assert!(!compiled.matches(&at(12)));
+    }
+
+    #[test]
+    fn invalid_regex_is_rejected() {
+        let rules = vec![AnalysisRule { window_title: Some("(unclosed".to_string()), ..rule("Broken") }];
+        assert!(RulesAnalyzer::new(&rules, Language::Ja, FallbackActivity::Other, default_full_confidence_samples()).is_err());
+    }
+}
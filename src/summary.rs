@@ -0,0 +1,139 @@
+use anyhow::Result;
+use chrono::{Datelike, Duration, NaiveDate};
+use std::collections::BTreeMap;
+
+use crate::analysis::Category;
+use crate::config::{AppConfig, Bucket};
+use crate::data_collector::{self, BlockAnalysis};
+
+/// `--summary` で集計する期間
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SummaryPeriod {
+    /// 今日
+    Day,
+    /// 今週（月曜日から今日まで）
+    Week,
+}
+
+impl Bucket {
+    /// 画面表示用の名前
+    fn label(self) -> &'static str {
+        match self {
+            Bucket::Focus => "集中",
+            Bucket::Communication => "コミュニケーション",
+            Bucket::Break => "休憩",
+            Bucket::Other => "その他",
+        }
+    }
+}
+
+/// 集計期間の最初と最後の日付（設定したタイムゾーンでの日付）
+fn period_dates(today: NaiveDate, period: SummaryPeriod) -> (NaiveDate, NaiveDate) {
+    match period {
+        SummaryPeriod::Day => (today, today),
+        SummaryPeriod::Week => {
+            let monday = today - Duration::days(i64::from(today.weekday().num_days_from_monday()));
+            (monday, today)
+        }
+    }
+}
+
+/// 時間ブロックの分析結果を集計区分ごとの合計時間にまとめる（1件を1ブロック分の長さとして数える）
+fn summarize_buckets(analyses: &[BlockAnalysis], config: &AppConfig) -> BTreeMap<Bucket, Duration> {
+    let block = Duration::minutes(config.general.block_minutes() as i64);
+    let mut totals = BTreeMap::new();
+    for analysis in analyses {
+        let bucket = config.bucket_for(Category::from_activity(&analysis.activity));
+        *totals.entry(bucket).or_insert_with(Duration::zero) += block;
+    }
+    totals
+}
+
+/// 今日または今週の作業時間を集計区分ごとに表示する
+pub fn run_summary(config: &AppConfig, period: SummaryPeriod) -> Result<()> {
+    let (first, last) = period_dates(config.general.today(), period);
+    let start = config.general.day_bounds(first).0;
+    let end = config.general.day_bounds(last).1;
+
+    let analyses = data_collector::get_block_analyses_between(config.general.data_dir_path(), start, end)?;
+    let totals = summarize_buckets(&analyses, config);
+    let total_minutes: i64 = totals.values().map(Duration::num_minutes).sum();
+
+    println!("{} 〜 {} の集計（{} ブロック）", first, last, analyses.len());
+    for (bucket, duration) in &totals {
+        let minutes = duration.num_minutes();
+        println!(
+            "{}\t{}時間{:02}分\t{:>3}%",
+            bucket.label(),
+            minutes / 60,
+            minutes % 60,
+            minutes * 100 / total_minutes.max(1),
+        );
+    }
+    if totals.is_empty() {
+        println!("分析済みの時間ブロックがありません");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::create_default_config;
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn analysis_rows_roll_up_to_bucket_totals() {
+        let temp = tempfile::tempdir().unwrap();
+        data_collector::init_storage(temp.path()).unwrap();
+        let mut config = create_default_config();
+        config.buckets.insert(Category::WebBrowsing, Bucket::Break);
+
+        let start = Utc.with_ymd_and_hms(2024, 1, 1, 9, 0, 0).unwrap();
+        let activities = [
+            "プログラミング",
+            "Programming",
+            "ターミナル作業",
+            "ミーティング",
+            "Slack - Google Chrome",
+            "ウェブブラウジング",
+            "その他の活動",
+        ];
+        for (i, activity) in activities.iter().enumerate() {
+            let block_start = start + Duration::minutes(15 * i as i64);
+            data_collector::record_block_analysis(temp.path(), block_start, activity, 0.9, true).unwrap();
+        }
+        // 同じブロックを再分析した場合は最新の結果だけを数える
+        data_collector::record_block_analysis(temp.path(), start, "YouTube - Mozilla Firefox", 0.9, false).unwrap();
+
+        let analyses = data_collector::get_block_analyses_between(temp.path(), start, start + Duration::hours(2)).unwrap();
+        let totals = summarize_buckets(&analyses, &config);
+
+        assert_eq!(analyses.len(), activities.len());
+        assert_eq!(totals[&Bucket::Focus], Duration::minutes(30));
+        assert_eq!(totals[&Bucket::Communication], Duration::minutes(30));
+        assert_eq!(totals[&Bucket::Break], Duration::minutes(30));
+        assert_eq!(totals[&Bucket::Other], Duration::minutes(15));
+    }
+
+    #[test]
+    fn buckets_table_overrides_default_bucket() {
+        let mut config = create_default_config();
+        config.buckets = toml::from_str("web_browsing = \"break\"\nchat = \"focus\"").unwrap();
+
+        assert_eq!(config.bucket_for(Category::WebBrowsing), Bucket::Break);
+        assert_eq!(config.bucket_for(Category::Chat), Bucket::Focus);
+        assert_eq!(config.bucket_for(Category::Meeting), Bucket::Communication);
+        assert!(toml::to_string(&config).unwrap().contains("web_browsing = \"break\""));
+    }
+
+    #[test]
+    fn week_starts_on_monday() {
+        let thursday = NaiveDate::from_ymd_opt(2024, 1, 4).unwrap();
+        assert_eq!(
+            period_dates(thursday, SummaryPeriod::Week),
+            (NaiveDate::from_ymd_opt(2024, 1, 1).unwrap(), thursday)
+        );
+    }
+}
@@ -1,9 +1,61 @@
 use anyhow::{Context, Result};
-use log::info;
+use chrono::{DateTime, NaiveDate, NaiveTime, TimeZone, Utc};
+use log::{info, warn};
 use std::fs::{self, File};
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
 use std::process::Command;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use crate::config::ProxyConfig;
+
+/// タイムゾーン `tz` における日付 `date` の開始・終了時刻をUTCで返す
+///
+/// 夏時間の切り替えで0時が存在しない日は、その日の最初に存在する時刻を開始とする
+pub fn local_day_bounds<Tz: TimeZone>(date: NaiveDate, tz: &Tz) -> (DateTime<Utc>, DateTime<Utc>) {
+    let start_of_day = |date: NaiveDate| {
+        let midnight = date.and_time(NaiveTime::MIN);
+        (0..24)
+            .find_map(|hour| tz.from_local_datetime(&(midnight + chrono::Duration::hours(hour))).earliest())
+            .map(|dt| dt.with_timezone(&Utc))
+            .unwrap_or_else(|| Utc.from_utc_datetime(&midnight))
+    };
+    
+    let next_day = date.succ_opt().unwrap_or(NaiveDate::MAX);
+    (start_of_day(date), start_of_day(next_day))
+}
+
+/// `--log-format` で選ぶログの出力形式
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogFormat {
+    /// 人が読むための1行形式
+    #[default]
+    Text,
+
+    /// 1行に1つのJSONオブジェクト（ログ収集基盤向け）
+    Json,
+}
+
+/// ログの1レコードを1行に整形する（末尾の改行は含まない）
+///
+/// JSON形式ではメッセージ中の引用符や改行もエスケープされるため、常に1行に収まる
+pub fn format_log_record(format: LogFormat, timestamp: &str, record: &log::Record) -> String {
+    match format {
+        LogFormat::Text => format!("[{}] {}: {}", timestamp, record.level(), record.args()),
+        LogFormat::Json => serde_json::json!({
+            "timestamp": timestamp,
+            "level": record.level().as_str(),
+            "target": record.target(),
+            "module": record.module_path(),
+            "message": record.args().to_string(),
+        }).to_string(),
+    }
+}
+
+/// シークレットをマスクする際に前後に残す文字数
+static MASK_VISIBLE_CHARS: AtomicUsize = AtomicUsize::new(4);
 
 /// ユーザーホームディレクトリのパスを取得
 pub fn get_home_dir() -> Result<PathBuf> {
@@ -20,40 +72,109 @@ pub fn get_data_dir() -> Result<PathBuf> {
     };
     
     let app_data_dir = data_dir.join("toggl_linux_rs");
+    create_private_dir(&app_data_dir)?;
+    
+    Ok(app_data_dir)
+}
+
+/// 設定のデータディレクトリを絶対パスに解決する
+///
+/// `~/` はホームディレクトリ、相対パスはXDGデータディレクトリを基準にするため、実行時のカレントディレクトリには依存しない
+pub fn resolve_data_dir(setting: &str) -> Result<PathBuf> {
+    let setting = setting.trim();
+    if setting == crate::data_collector::IN_MEMORY_DATA_DIR {
+        return Ok(PathBuf::from(setting));
+    }
+    if setting.is_empty() {
+        return get_data_dir();
+    }
+    if let Some(rest) = setting.strip_prefix("~/") {
+        return Ok(get_home_dir()?.join(rest));
+    }
     
-    if !app_data_dir.exists() {
-        fs::create_dir_all(&app_data_dir)
-            .context("Failed to create data directory")?;
+    let path = Path::new(setting);
+    if path.is_absolute() {
+        return Ok(path.to_path_buf());
     }
     
-    Ok(app_data_dir)
+    let relative: PathBuf = path.components()
+        .filter(|component| !matches!(component, Component::CurDir))
+        .collect();
+    Ok(get_data_dir()?.join(relative))
 }
 
-/// XDG設定ディレクトリを取得
-pub fn get_config_dir() -> Result<PathBuf> {
-    let config_dir = if let Ok(dir) = std::env::var("XDG_CONFIG_HOME") {
-        PathBuf::from(dir)
-    } else {
-        get_home_dir()?.join(".config")
-    };
+/// 既存のディレクトリが他のユーザーからもアクセスできる場合の警告を出したかどうか（データベースを開くたびに出さないため）
+static SHARED_DIR_WARNED: AtomicBool = AtomicBool::new(false);
+
+/// 所有者のみがアクセスできるディレクトリを作成する
+///
+/// 権限を0700にするのは新しく作成した場合だけで、既存のディレクトリの権限は変えずに
+/// 他のユーザーからアクセスできる場合は警告する
+pub fn create_private_dir(path: &Path) -> Result<()> {
+    if path.is_dir() {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(path)
+                .with_context(|| format!("Failed to read permissions of {:?}", path))?
+                .permissions()
+                .mode();
+            if mode & 0o077 != 0 && !SHARED_DIR_WARNED.swap(true, Ordering::Relaxed) {
+                warn!("{:?} is accessible by other users (mode {:o}); run `chmod 700` on it to keep activity data private",
+                      path, mode & 0o777);
+            }
+        }
+        return Ok(());
+    }
     
-    let app_config_dir = config_dir.join("toggl_linux_rs");
+    fs::create_dir_all(path)
+        .with_context(|| format!("Failed to create directory {:?}", path))?;
     
-    if !app_config_dir.exists() {
-        fs::create_dir_all(&app_config_dir)
-            .context("Failed to create config directory")?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o700))
+            .with_context(|| format!("Failed to set permissions on {:?}", path))?;
     }
     
-    Ok(app_config_dir)
+    Ok(())
 }
 
+/// ファイルを所有者のみが読み書きできるようにする（0600）
+pub fn restrict_file_permissions(path: &Path) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+            .with_context(|| format!("Failed to set permissions on {:?}", path))?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    
+    Ok(())
+}
+
+/// 通知を送信できない場合の対処方法
+pub const NOTIFICATION_HINT: &str = "Install notify-send (e.g. `sudo apt install libnotify-bin`) \
+    and make sure a notification daemon (e.g. dunst, xfce4-notifyd) is running in your desktop session";
+
 /// デスクトップ通知を送信
 pub fn send_notification(
     title: &str,
     message: &str,
     urgency: Option<&str>,
 ) -> Result<()> {
-    let mut cmd = Command::new("notify-send");
+    send_notification_with("notify-send", title, message, urgency)
+}
+
+/// 指定したコマンド（`notify-send` 互換）でデスクトップ通知を送信
+fn send_notification_with(
+    program: &str,
+    title: &str,
+    message: &str,
+    urgency: Option<&str>,
+) -> Result<()> {
+    let mut cmd = Command::new(program);
     
     cmd.arg("--app-name=toggl_linux_rs")
         .arg(title)
@@ -64,11 +185,11 @@ pub fn send_notification(
     }
     
     let output = cmd.output()
-        .context("Failed to execute notify-send")?;
+        .with_context(|| format!("Failed to execute {}", program))?;
     
     if !output.status.success() {
         let err = String::from_utf8_lossy(&output.stderr);
-        return Err(anyhow::anyhow!("Failed to send notification: {}", err));
+        return Err(anyhow::anyhow!("Failed to send notification ({}): {}", output.status, err.trim()));
     }
     
     Ok(())
@@ -87,17 +208,29 @@ pub fn check_internet_connection() -> bool {
     }
 }
 
+/// 自動起動用の `.desktop` ファイルのパス
+fn autostart_desktop_path(home: &Path) -> PathBuf {
+    home.join(".config").join("autostart").join("toggl_linux_rs.desktop")
+}
+
+/// systemdのユーザーユニット名
+const SYSTEMD_UNIT_NAME: &str = "toggl_linux_rs.service";
+
+/// systemdのユーザーユニットのパス
+fn systemd_unit_path(home: &Path) -> PathBuf {
+    home.join(".config").join("systemd").join("user").join(SYSTEMD_UNIT_NAME)
+}
+
 /// アプリケーションをXfceの自動起動に追加
 pub fn add_to_xfce_autostart() -> Result<()> {
-    let autostart_dir = get_home_dir()?.join(".config").join("autostart");
+    let desktop_file_path = autostart_desktop_path(&get_home_dir()?);
+    let autostart_dir = desktop_file_path.parent().unwrap_or(Path::new("."));
     
     if !autostart_dir.exists() {
-        fs::create_dir_all(&autostart_dir)
+        fs::create_dir_all(autostart_dir)
             .context("Failed to create autostart directory")?;
     }
     
-    let desktop_file_path = autostart_dir.join("toggl_linux_rs.desktop");
-    
     // 実行ファイルのパスを取得
     let executable_path = std::env::current_exe()
         .context("Failed to get executable path")?;
@@ -125,4 +258,490 @@ StartupNotify=false
     info!("Added to Xfce autostart at {:?}", desktop_file_path);
     
     Ok(())
-} 
\ No newline at end of file
+}
+
+/// 自動起動の設定（`.desktop` ファイルとsystemdのユーザーユニット）を削除し、削除したファイルを返す
+///
+/// 存在しないファイルは無視するため、何度実行してもよい
+pub fn remove_autostart(home: &Path) -> Result<Vec<PathBuf>> {
+    let mut removed = Vec::new();
+    
+    let desktop_file_path = autostart_desktop_path(home);
+    if desktop_file_path.exists() {
+        fs::remove_file(&desktop_file_path)
+            .with_context(|| format!("Failed to remove {:?}", desktop_file_path))?;
+        info!("Removed Xfce autostart entry {:?}", desktop_file_path);
+        removed.push(desktop_file_path);
+    }
+    
+    let unit_path = systemd_unit_path(home);
+    if unit_path.exists() {
+        // 動作中のサービスを止めてから削除する（systemctl を実行できなくても削除は続ける）
+        match Command::new("systemctl").args(["--user", "disable", "--now", SYSTEMD_UNIT_NAME]).output() {
+            Ok(output) if !output.status.success() => {
+                warn!("Failed to disable {}: {}", SYSTEMD_UNIT_NAME, String::from_utf8_lossy(&output.stderr).trim());
+            }
+            Ok(_) => {}
+            Err(e) => warn!("Failed to run systemctl: {}", e),
+        }
+        fs::remove_file(&unit_path)
+            .with_context(|| format!("Failed to remove {:?}", unit_path))?;
+        if let Err(e) = Command::new("systemctl").args(["--user", "daemon-reload"]).output() {
+            warn!("Failed to reload systemd user units: {}", e);
+        }
+        info!("Removed systemd user unit {:?}", unit_path);
+        removed.push(unit_path);
+    }
+    
+    Ok(removed)
+}
+
+/// HTTPの接続タイムアウト（秒）
+static HTTP_CONNECT_TIMEOUT_SECS: AtomicU64 = AtomicU64::new(5);
+
+/// HTTPリクエスト全体のタイムアウト（秒）
+static HTTP_TIMEOUT_SECS: AtomicU64 = AtomicU64::new(30);
+
+/// `[proxy]` の設定（未設定の場合は環境変数のプロキシを使う）
+static HTTP_PROXY: OnceLock<Option<ProxyConfig>> = OnceLock::new();
+
+/// プロセス全体で共有するHTTPクライアント
+static HTTP_CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+
+/// プロキシを通さない宛先（OAuthのリダイレクトを受けるローカルサーバーなど）
+const NO_PROXY_HOSTS: &str = "localhost,127.0.0.1,::1";
+
+/// HTTPのタイムアウトを設定（最初に `http_client` を呼ぶ前に設定する）
+pub fn set_http_timeouts(connect_timeout_secs: u64, timeout_secs: u64) {
+    HTTP_CONNECT_TIMEOUT_SECS.store(connect_timeout_secs, Ordering::Relaxed);
+    HTTP_TIMEOUT_SECS.store(timeout_secs, Ordering::Relaxed);
+}
+
+/// HTTPプロキシを設定（最初に `http_client` を呼ぶ前に設定する）
+pub fn set_http_proxy(proxy: Option<ProxyConfig>) {
+    if HTTP_PROXY.set(proxy).is_err() {
+        warn!("HTTP proxy is already configured, ignoring the new setting");
+    }
+}
+
+/// プロキシを通す通信の種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ProxyScope {
+    Http,
+    Https,
+    All,
+}
+
+/// 使用するプロキシのURL（`[proxy]` を優先し、なければ `HTTPS_PROXY`・`HTTP_PROXY`・`ALL_PROXY`）
+fn proxy_targets(config: Option<&ProxyConfig>, env: impl Fn(&str) -> Option<String>) -> Vec<(ProxyScope, String)> {
+    if let Some(proxy) = config {
+        return vec![(ProxyScope::All, proxy.url.clone())];
+    }
+    
+    // 小文字の変数を優先する（curlなどと同じ）
+    let var = |name: &str| env(&name.to_lowercase())
+        .or_else(|| env(name))
+        .filter(|value| !value.trim().is_empty());
+    [("HTTPS_PROXY", ProxyScope::Https), ("HTTP_PROXY", ProxyScope::Http), ("ALL_PROXY", ProxyScope::All)]
+        .into_iter()
+        .filter_map(|(name, scope)| var(name).map(|url| (scope, url)))
+        .collect()
+}
+
+/// プロキシを通さない宛先の一覧（`NO_PROXY` にローカルホストを加える）
+fn no_proxy_list(env: impl Fn(&str) -> Option<String>) -> String {
+    match env("no_proxy").or_else(|| env("NO_PROXY")).filter(|value| !value.trim().is_empty()) {
+        Some(list) => format!("{},{}", list, NO_PROXY_HOSTS),
+        None => NO_PROXY_HOSTS.to_string(),
+    }
+}
+
+/// HTTPクライアントに設定するプロキシを作成する
+fn build_proxies(config: Option<&ProxyConfig>, env: impl Fn(&str) -> Option<String>) -> Result<Vec<reqwest::Proxy>> {
+    let no_proxy = no_proxy_list(&env);
+    proxy_targets(config, &env).into_iter()
+        .map(|(scope, url)| {
+            let proxy = match scope {
+                ProxyScope::Http => reqwest::Proxy::http(&url),
+                ProxyScope::Https => reqwest::Proxy::https(&url),
+                ProxyScope::All => reqwest::Proxy::all(&url),
+            }.with_context(|| format!("Invalid proxy URL '{}'", url))?;
+            let proxy = match config.and_then(|proxy| proxy.username.as_deref()) {
+                Some(username) => {
+                    let password = config.and_then(|proxy| proxy.password.as_deref()).unwrap_or_default();
+                    proxy.basic_auth(username, password)
+                }
+                None => proxy,
+            };
+            Ok(proxy.no_proxy(reqwest::NoProxy::from_string(&no_proxy)))
+        })
+        .collect()
+}
+
+/// タイムアウトとプロキシを設定したHTTPクライアントを作成
+///
+/// タイムアウトは `reqwest::Error::is_timeout` で判別でき、呼び出し側では再試行可能なエラーとして扱う
+pub fn build_http_client(connect_timeout: Duration, timeout: Duration, proxy: Option<&ProxyConfig>) -> reqwest::Client {
+    // プロキシは明示的に設定するため、reqwestによる環境変数の自動検出は使わない
+    let mut builder = reqwest::Client::builder()
+        .connect_timeout(connect_timeout)
+        .timeout(timeout)
+        .no_proxy();
+    match build_proxies(proxy, |name| std::env::var(name).ok()) {
+        Ok(proxies) => {
+            for proxy in proxies {
+                builder = builder.proxy(proxy);
+            }
+        }
+        Err(e) => warn!("Ignoring the proxy configuration: {:#}", e),
+    }
+    
+    builder.build()
+        .unwrap_or_else(|e| {
+            warn!("Failed to build HTTP client with timeouts, using defaults: {}", e);
+            reqwest::Client::new()
+        })
+}
+
+/// Toggl・OpenAI・Googleへのリクエストに使う共有HTTPクライアント
+///
+/// 接続プールを共有するため、呼び出しごとにクライアントを作らずこれを使う
+pub fn http_client() -> reqwest::Client {
+    HTTP_CLIENT.get_or_init(|| build_http_client(
+        Duration::from_secs(HTTP_CONNECT_TIMEOUT_SECS.load(Ordering::Relaxed)),
+        Duration::from_secs(HTTP_TIMEOUT_SECS.load(Ordering::Relaxed)),
+        HTTP_PROXY.get().and_then(Option::as_ref),
+    )).clone()
+}
+
+/// シークレットのマスク時に前後に残す文字数を設定
+pub fn set_mask_visible_chars(chars: usize) {
+    MASK_VISIBLE_CHARS.store(chars, Ordering::Relaxed);
+}
+
+/// 文字列を安全に切り詰める（UTF-8文字境界を保持）
+pub fn truncate_string_safely(s: &str, max_len: usize) -> String {
+    if s.len() <= max_len {
+        return s.to_string();
+    }
+    
+    let mut last_valid_index = 0;
+    
+    // 文字の境界を保持しながら最大長に近い位置を探す
+    for (idx, _) in s.char_indices() {
+        if idx > max_len {
+            break;
+        }
+        last_valid_index = idx;
+    }
+    
+    // 切り詰めた文字列に「...」を追加
+    format!("{}...", &s[..last_valid_index])
+}
+
+/// ログ出力用にトークンやAPIキーをマスクする
+pub fn mask_token(token: &str) -> String {
+    let visible = MASK_VISIBLE_CHARS.load(Ordering::Relaxed);
+    let chars: Vec<char> = token.chars().collect();
+    
+    // 前後を残すと全体が見えてしまう長さのものは完全に隠す
+    if visible == 0 || chars.len() <= visible * 2 {
+        return "[TOKEN_TOO_SHORT_TO_MASK]".to_string();
+    }
+    
+    let visible_prefix: String = chars[..visible].iter().collect();
+    let visible_suffix: String = chars[chars.len() - visible..].iter().collect();
+    format!("{}...{}", visible_prefix, visible_suffix)
+}
+
+/// 認証エラー後にAPIの呼び出しを止めておく時間
+const AUTH_FAILURE_PAUSE: Duration = Duration::from_secs(60 * 60);
+
+/// 認証エラー後に一定時間APIの呼び出しを止めるための状態
+///
+/// 無効なトークンで毎ブロック失敗し続けないよう、通知を一度だけ出して呼び出しを休止する
+pub struct AuthPause {
+    service: &'static str,
+    until: Mutex<Option<Instant>>,
+    /// 休止したことを知らせる通知に使う関数（`send_notification` と同じ引数）
+    notify: fn(&str, &str, Option<&str>) -> Result<()>,
+}
+
+impl AuthPause {
+    pub const fn new(service: &'static str) -> Self {
+        Self::with_notifier(service, send_notification)
+    }
+    
+    /// 休止したことを `notify` で知らせる
+    pub const fn with_notifier(service: &'static str, notify: fn(&str, &str, Option<&str>) -> Result<()>) -> Self {
+        Self {
+            service,
+            until: Mutex::new(None),
+            notify,
+        }
+    }
+    
+    /// 休止中でなければ `request` を実行し、認証エラー（`is_unauthorized`）で失敗した場合は休止する
+    ///
+    /// 休止中は `request` を実行せずにエラーを返す
+    pub async fn guard<T>(
+        &self,
+        request: impl std::future::Future<Output = Result<T>>,
+        is_unauthorized: fn(&anyhow::Error) -> bool,
+    ) -> Result<T> {
+        if let Some(remaining) = self.remaining() {
+            return Err(anyhow::anyhow!(
+                "{} API is paused after an authentication failure ({} minutes remaining)",
+                self.service,
+                remaining.as_secs().div_ceil(60)
+            ));
+        }
+        
+        let result = request.await;
+        if result.as_ref().is_err_and(is_unauthorized) {
+            self.trip();
+        }
+        result
+    }
+    
+    /// 休止中であれば残り時間を返す
+    pub fn remaining(&self) -> Option<Duration> {
+        let until = self.until.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        until.and_then(|until| until.checked_duration_since(Instant::now()))
+    }
+    
+    /// 認証エラーを記録し、再設定を促す通知を送って呼び出しを休止する
+    pub fn trip(&self) {
+        *self.until.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) =
+            Some(Instant::now() + AUTH_FAILURE_PAUSE);
+        
+        warn!("{} rejected the credentials (HTTP 401); pausing {} calls for {} minutes",
+              self.service, self.service, AUTH_FAILURE_PAUSE.as_secs() / 60);
+        
+        let message = format!(
+            "{}の認証に失敗しました。トークンを確認し、--wizard を再実行してください。{}分間 {} への接続を停止します。",
+            self.service, AUTH_FAILURE_PAUSE.as_secs() / 60, self.service
+        );
+        if let Err(e) = (self.notify)("toggl_linux_rs", &message, Some("critical")) {
+            warn!("Failed to send authentication failure notification: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// テスト中に送られた休止の通知
+    static PAUSE_NOTIFICATIONS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    fn record_pause_notification(_: &str, message: &str, _: Option<&str>) -> Result<()> {
+        PAUSE_NOTIFICATIONS.lock().unwrap().push(message.to_string());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unauthorized_response_pauses_and_skips_later_calls() {
+        let pause = AuthPause::with_notifier("Example", record_pause_notification);
+        let is_unauthorized: fn(&anyhow::Error) -> bool = |error| error.to_string().contains("HTTP 401");
+
+        // 401以外の失敗では休止しない
+        assert!(pause.guard(async { Err::<(), _>(anyhow::anyhow!("HTTP 500")) }, is_unauthorized).await.is_err());
+        assert!(pause.remaining().is_none());
+
+        assert!(pause.guard(async { Err::<(), _>(anyhow::anyhow!("HTTP 401")) }, is_unauthorized).await.is_err());
+        assert!(pause.remaining().is_some_and(|remaining| remaining <= AUTH_FAILURE_PAUSE));
+        let notifications = PAUSE_NOTIFICATIONS.lock().unwrap().clone();
+        assert_eq!(notifications.len(), 1);
+        assert!(notifications[0].contains("Example"), "{}", notifications[0]);
+
+        // 休止中は呼び出さない
+        let called = AtomicBool::new(false);
+        let error = pause.guard(async { called.store(true, Ordering::SeqCst); Ok(()) }, is_unauthorized).await.unwrap_err();
+        assert!(!called.load(Ordering::SeqCst));
+        assert!(error.to_string().contains("paused"), "{}", error);
+    }
+
+    #[test]
+    fn json_log_record_is_a_single_escaped_json_line() {
+        let format = |format: LogFormat, timestamp: &str| format_log_record(
+            format,
+            timestamp,
+            &log::Record::builder()
+                .args(format_args!("window \"{}\"\nchanged", "a\\b"))
+                .level(log::Level::Warn)
+                .target("toggl_linux_rs::data_collector")
+                .module_path(Some("toggl_linux_rs::data_collector"))
+                .build(),
+        );
+
+        let line = format(LogFormat::Json, "2024-01-01T09:00:00+09:00");
+        assert!(!line.contains('\n'));
+        let json: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(json["timestamp"], "2024-01-01T09:00:00+09:00");
+        assert_eq!(json["level"], "WARN");
+        assert_eq!(json["target"], "toggl_linux_rs::data_collector");
+        assert_eq!(json["module"], "toggl_linux_rs::data_collector");
+        assert_eq!(json["message"], "window \"a\\b\"\nchanged");
+
+        assert_eq!(
+            format(LogFormat::Text, "2024-01-01 09:00:00+0900"),
+            "[2024-01-01 09:00:00+0900] WARN: window \"a\\b\"\nchanged"
+        );
+    }
+
+    #[test]
+    fn remove_autostart_deletes_desktop_file_and_is_idempotent() {
+        let home = tempfile::tempdir().unwrap();
+        let desktop_file_path = autostart_desktop_path(home.path());
+        fs::create_dir_all(desktop_file_path.parent().unwrap()).unwrap();
+        fs::write(&desktop_file_path, "[Desktop Entry]\n").unwrap();
+
+        assert_eq!(remove_autostart(home.path()).unwrap(), vec![desktop_file_path.clone()]);
+        assert!(!desktop_file_path.exists());
+        assert!(remove_autostart(home.path()).unwrap().is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn private_dir_is_created_with_owner_only_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path().join("nested").join("data");
+
+        create_private_dir(&dir).unwrap();
+
+        let mode = fs::metadata(&dir).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+
+        // 既存のディレクトリの権限は変えない
+        let shared = temp.path().join("shared");
+        fs::create_dir(&shared).unwrap();
+        fs::set_permissions(&shared, fs::Permissions::from_mode(0o755)).unwrap();
+        create_private_dir(&shared).unwrap();
+        assert_eq!(fs::metadata(&shared).unwrap().permissions().mode() & 0o777, 0o755);
+    }
+
+    #[test]
+    fn day_bounds_east_of_utc_start_on_the_previous_utc_day() {
+        let date = NaiveDate::from_ymd_opt(2024, 5, 13).unwrap();
+        let (start, end) = local_day_bounds(date, &chrono_tz::Asia::Tokyo);
+
+        assert_eq!(start.to_rfc3339(), "2024-05-12T15:00:00+00:00");
+        assert_eq!(end.to_rfc3339(), "2024-05-13T15:00:00+00:00");
+    }
+
+    #[test]
+    fn day_bounds_west_of_utc_end_on_the_next_utc_day() {
+        let date = NaiveDate::from_ymd_opt(2024, 1, 15).unwrap();
+        let (start, end) = local_day_bounds(date, &chrono_tz::America::New_York);
+
+        assert_eq!(start.to_rfc3339(), "2024-01-15T05:00:00+00:00");
+        assert_eq!(end.to_rfc3339(), "2024-01-16T05:00:00+00:00");
+    }
+
+    #[tokio::test]
+    async fn requests_to_a_stalled_server_time_out_within_the_budget() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // 接続を受け付けるが応答しないサーバー
+        tokio::spawn(async move {
+            let mut connections = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                connections.push(stream);
+            }
+        });
+
+        let client = build_http_client(Duration::from_millis(200), Duration::from_millis(300), None);
+        let started = Instant::now();
+        let error = client.get(format!("http://{}/", addr)).send().await.unwrap_err();
+
+        assert!(error.is_timeout(), "{}", error);
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn proxy_is_taken_from_config_before_environment_variables() {
+        let env = |name: &str| match name {
+            "HTTPS_PROXY" => Some("http://env-proxy:3128".to_string()),
+            "http_proxy" => Some("http://lower-proxy:3128".to_string()),
+            "NO_PROXY" => Some("intranet.example.com".to_string()),
+            _ => None,
+        };
+        let config = ProxyConfig {
+            url: "http://proxy.example.com:8080".to_string(),
+            username: None,
+            password: None,
+        };
+
+        assert_eq!(
+            proxy_targets(Some(&config), env),
+            vec![(ProxyScope::All, "http://proxy.example.com:8080".to_string())]
+        );
+        assert_eq!(
+            proxy_targets(None, env),
+            vec![
+                (ProxyScope::Https, "http://env-proxy:3128".to_string()),
+                (ProxyScope::Http, "http://lower-proxy:3128".to_string()),
+            ]
+        );
+        assert!(proxy_targets(None, |_| None).is_empty());
+        assert_eq!(no_proxy_list(env), "intranet.example.com,localhost,127.0.0.1,::1");
+    }
+
+    #[tokio::test]
+    async fn requests_go_through_the_configured_proxy_except_localhost() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // 受け取ったリクエストのヘッダーを記録して200を返すサーバー（プロキシと直接の宛先を兼ねる）
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let requests = std::sync::Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buf = vec![0u8; 4096];
+                let n = stream.read(&mut buf).await.unwrap_or(0);
+                recorded.lock().unwrap().push(String::from_utf8_lossy(&buf[..n]).to_string());
+                let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").await;
+            }
+        });
+
+        let proxy = ProxyConfig {
+            url: format!("http://127.0.0.1:{}", port),
+            username: Some("alice".to_string()),
+            password: Some("secret".to_string()),
+        };
+        let client = build_http_client(Duration::from_secs(2), Duration::from_secs(2), Some(&proxy));
+        client.get("http://api.toggl.invalid/me").send().await.unwrap();
+        client.get(format!("http://localhost:{}/callback", port)).send().await.unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert!(requests[0].starts_with("GET http://api.toggl.invalid/me HTTP/1.1"), "{}", requests[0]);
+        // "alice:secret" のBase64
+        assert!(requests[0].contains("proxy-authorization: Basic YWxpY2U6c2VjcmV0"), "{}", requests[0]);
+        assert!(requests[1].starts_with("GET /callback HTTP/1.1"), "{}", requests[1]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn failing_notify_send_surfaces_its_stderr() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let bin = tempfile::tempdir().unwrap();
+        let program = bin.path().join("notify-send");
+        fs::write(
+            &program,
+            "#!/bin/sh\necho 'GDBus.Error:org.freedesktop.DBus.Error.ServiceUnknown: The name org.freedesktop.Notifications was not provided' >&2\nexit 1\n",
+        ).unwrap();
+        fs::set_permissions(&program, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let error = send_notification_with(program.to_str().unwrap(), "title", "message", None).unwrap_err();
+        assert!(format!("{:#}", error).contains("org.freedesktop.Notifications was not provided"), "{:#}", error);
+
+        let missing = bin.path().join("missing-notify-send");
+        let error = send_notification_with(missing.to_str().unwrap(), "title", "message", None).unwrap_err();
+        assert!(format!("{:#}", error).starts_with("Failed to execute"), "{:#}", error);
+    }
+}
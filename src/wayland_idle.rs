@@ -0,0 +1,123 @@
+use log::{debug, warn};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use wayland_client::globals::{registry_queue_init, GlobalListContents};
+use wayland_client::protocol::{wl_registry::WlRegistry, wl_seat::WlSeat};
+use wayland_client::{Connection, Dispatch, QueueHandle};
+use wayland_protocols::ext::idle_notify::v1::client::ext_idle_notification_v1::{self, ExtIdleNotificationV1};
+use wayland_protocols::ext::idle_notify::v1::client::ext_idle_notifier_v1::ExtIdleNotifierV1;
+
+/// アイドル状態になったと通知されるまでの時間（この分だけアイドル時間の開始を遡る）
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// コンポジタから通知されたアイドル状態
+#[derive(Debug, Default)]
+struct IdleState {
+    /// アイドル状態になった時刻（操作中はNone）
+    idle_since: Option<Instant>,
+
+    /// コンポジタとの接続が切れたかどうか
+    disconnected: bool,
+}
+
+/// ext-idle-notify-v1でコンポジタからアイドル状態の通知を受け取る（Hyprland・swayなどwlrootsベースのコンポジタ）
+struct IdleMonitor {
+    state: Arc<Mutex<IdleState>>,
+}
+
+/// イベントキューの状態
+struct Handler {
+    state: Arc<Mutex<IdleState>>,
+}
+
+static MONITOR: OnceLock<Option<IdleMonitor>> = OnceLock::new();
+
+/// ext-idle-notify-v1で取得したアイドル時間（コンポジタが対応していない場合や接続が切れた場合はNone）
+///
+/// 初回の呼び出しで監視用のスレッドを起動し、以降は通知された状態から計算する
+pub fn idle_duration() -> Option<Duration> {
+    let monitor = MONITOR.get_or_init(IdleMonitor::start).as_ref()?;
+    let state = monitor.state.lock().ok()?;
+    if state.disconnected {
+        return None;
+    }
+    Some(state.idle_since.map_or(Duration::ZERO, |since| since.elapsed()))
+}
+
+impl IdleMonitor {
+    fn start() -> Option<Self> {
+        let connection = Connection::connect_to_env()
+            .map_err(|e| debug!("Failed to connect to the Wayland display: {}", e))
+            .ok()?;
+        let (globals, mut queue) = registry_queue_init::<Handler>(&connection)
+            .map_err(|e| debug!("Failed to list Wayland globals: {}", e))
+            .ok()?;
+        let qh = queue.handle();
+        let notifier: ExtIdleNotifierV1 = globals.bind(&qh, 1..=1, ())
+            .map_err(|e| debug!("The compositor does not support ext-idle-notify-v1: {}", e))
+            .ok()?;
+        let seat: WlSeat = globals.bind(&qh, 1..=1, ())
+            .map_err(|e| debug!("No Wayland seat is available: {}", e))
+            .ok()?;
+        notifier.get_idle_notification(NOTIFY_TIMEOUT.as_millis() as u32, &seat, &qh, ());
+
+        let state = Arc::new(Mutex::new(IdleState::default()));
+        let mut handler = Handler { state: state.clone() };
+        queue.roundtrip(&mut handler)
+            .map_err(|e| debug!("Failed to register the idle notification: {}", e))
+            .ok()?;
+
+        std::thread::Builder::new()
+            .name("wayland-idle".to_string())
+            .spawn(move || {
+                loop {
+                    if let Err(e) = queue.blocking_dispatch(&mut handler) {
+                        warn!("Lost the Wayland connection used for idle detection: {}", e);
+                        if let Ok(mut state) = handler.state.lock() {
+                            state.disconnected = true;
+                        }
+                        break;
+                    }
+                }
+            })
+            .map_err(|e| warn!("Failed to start the Wayland idle monitor: {}", e))
+            .ok()?;
+
+        debug!("Using ext-idle-notify-v1 for idle detection");
+        Some(Self { state })
+    }
+}
+
+impl Dispatch<ExtIdleNotificationV1, ()> for Handler {
+    fn event(
+        handler: &mut Self,
+        _: &ExtIdleNotificationV1,
+        event: ext_idle_notification_v1::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        let Ok(mut state) = handler.state.lock() else {
+            return;
+        };
+        match event {
+            ext_idle_notification_v1::Event::Idled => {
+                state.idle_since = Some(Instant::now().checked_sub(NOTIFY_TIMEOUT).unwrap_or_else(Instant::now));
+            }
+            ext_idle_notification_v1::Event::Resumed => state.idle_since = None,
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<WlRegistry, GlobalListContents> for Handler {
+    fn event(_: &mut Self, _: &WlRegistry, _: <WlRegistry as wayland_client::Proxy>::Event, _: &GlobalListContents, _: &Connection, _: &QueueHandle<Self>) {}
+}
+
+impl Dispatch<WlSeat, ()> for Handler {
+    fn event(_: &mut Self, _: &WlSeat, _: <WlSeat as wayland_client::Proxy>::Event, _: &(), _: &Connection, _: &QueueHandle<Self>) {}
+}
+
+impl Dispatch<ExtIdleNotifierV1, ()> for Handler {
+    fn event(_: &mut Self, _: &ExtIdleNotifierV1, _: <ExtIdleNotifierV1 as wayland_client::Proxy>::Event, _: &(), _: &Connection, _: &QueueHandle<Self>) {}
+}
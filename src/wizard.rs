@@ -1,16 +1,25 @@
 use anyhow::{Context, Result};
 use console::{style, Term};
 use dialoguer::{theme::ColorfulTheme, Confirm, Input, Select, MultiSelect};
+use std::collections::HashMap;
 use std::fs;
+use std::path::Path;
 use std::net::TcpListener;
-use std::io::{Read, Write};
+use std::io::{ErrorKind, Read, Write};
+use std::ops::RangeInclusive;
+use std::time::{Duration, Instant};
 use reqwest::Url;
 use serde::{Deserialize, Serialize};
 
-use crate::config::AppConfig;
-use crate::event::TogglClient;
+use crate::config::{AppConfig, GoogleCalendarSettings};
+use crate::event::{TogglClient, TogglUser};
+
+/// OAuthのリダイレクトを受けるローカルサーバーで試すポート（すべて使用中の場合はOSが選んだポートを使う）
+const OAUTH_PORTS: RangeInclusive<u16> = 8080..=8089;
+
+/// ブラウザでの認証を待つ時間（タブを閉じた場合などはこの時間で諦める）
+const OAUTH_TIMEOUT: Duration = Duration::from_secs(300);
 
-const REDIRECT_URI: &str = "http://localhost:8080";
 const OAUTH_SCOPES: &str = "https://www.googleapis.com/auth/calendar.readonly";
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -32,6 +41,45 @@ struct GoogleCalendar {
     summary: String,
     description: Option<String>,
     primary: Option<bool>,
+    #[serde(rename = "colorId")]
+    color_id: Option<String>,
+    #[serde(rename = "backgroundColor")]
+    background_color: Option<String>,
+}
+
+impl GoogleCalendar {
+    /// 一覧表示用の色ラベル
+    fn color_label(&self) -> String {
+        self.background_color.as_deref()
+            .or(self.color_id.as_deref())
+            .map(|color| format!(" [色: {}]", color))
+            .unwrap_or_default()
+    }
+}
+
+/// `--reconfigure` で個別に編集できる設定セクション
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum WizardSection {
+    /// 基本設定
+    General,
+    /// Toggl設定
+    Toggl,
+    /// OpenAI設定
+    Openai,
+    /// Googleカレンダー設定
+    GoogleCalendar,
+}
+
+impl WizardSection {
+    /// 画面表示用の名前
+    fn label(&self) -> &'static str {
+        match self {
+            WizardSection::General => "基本設定",
+            WizardSection::Toggl => "Toggl設定",
+            WizardSection::Openai => "OpenAI設定",
+            WizardSection::GoogleCalendar => "Google Calendar設定",
+        }
+    }
 }
 
 /// 対話型設定ウィザード
@@ -50,194 +98,443 @@ impl ConfigWizard {
     }
 
     /// ウィザードを実行
-    pub async fn run(&self) -> Result<()> {
+    ///
+    /// 既存の設定がある場合は各項目の既定値として使用し、`section` が指定された場合はそのセクションのみを編集する
+    pub async fn run(
+        &self,
+        existing: Option<AppConfig>,
+        section: Option<WizardSection>,
+        config_path: &Path,
+    ) -> Result<()> {
         self.term.clear_screen()?;
         
         println!("{}", style("toggl_linux_rs 設定ウィザード").bold().underlined());
-        println!("このウィザードでは、アプリケーションの設定を対話的に行います。\n");
         
+        let config = match (existing, section) {
+            (Some(existing), Some(section)) => {
+                println!("既存の設定のうち{}のみを編集します。\n", section.label());
+                self.reconfigure_section(existing, section).await?
+            }
+            (None, Some(_)) => {
+                return Err(anyhow::anyhow!(
+                    "{} が見つからないため、セクション単位の編集はできません。--wizard で新しく作成してください",
+                    config_path.display()
+                ));
+            }
+            (existing, None) => {
+                if existing.is_some() {
+                    println!("既存の設定を読み込みました。Enterを押すと現在の値を維持します。\n");
+                } else {
+                    println!("このウィザードでは、アプリケーションの設定を対話的に行います。\n");
+                }
+                self.configure_all(existing.as_ref()).await?
+            }
+        };
+        
+        // 設定ファイルを保存
+        self.save_config(&config, config_path)?;
+        
+        println!("\n{}", style("設定が完了しました！").green().bold());
+        println!("アプリケーションを実行するには: {} を実行してください", style("cargo run").cyan());
+        
+        Ok(())
+    }
+    
+    /// すべてのセクションを順に設定
+    async fn configure_all(&self, existing: Option<&AppConfig>) -> Result<AppConfig> {
         // 基本設定
-        let general_config = self.configure_general()?;
+        let mut general_config = self.configure_general(existing.map(|config| &config.general))?;
         
         // Toggl設定
-        let toggl_config = self.configure_toggl().await?;
+        let (toggl_config, toggl_user) = self.configure_toggl(existing.map(|config| &config.toggl)).await?;
+        propose_timezone(&mut general_config, toggl_user.as_ref());
         
         // OpenAI設定
-        let openai_config = self.configure_openai()?;
+        let openai_config = self.configure_openai(existing.and_then(|config| config.openai.as_ref()))?;
         
         // Googleカレンダー設定（オプション）
-        let google_config = self.configure_google_calendar().await?;
+        let google_config = self.configure_google_calendar(
+            existing.and_then(|config| config.google_calendar.as_ref())
+        ).await?;
         
         // 設定をマージ
-        let config = AppConfig {
+        Ok(AppConfig {
             general: general_config,
             toggl: toggl_config,
             openai: Some(openai_config),
             google_calendar: google_config,
-        };
-        
-        // 設定ファイルを保存
-        self.save_config(&config)?;
+            proxy: existing.and_then(|config| config.proxy.clone()),
+            rules: existing.map(|config| config.rules.clone()).unwrap_or_default(),
+            buckets: existing.map(|config| config.buckets.clone()).unwrap_or_default(),
+            project_confidence: existing.map(|config| config.project_confidence.clone()).unwrap_or_default(),
+        })
+    }
+    
+    /// 指定されたセクションのみを編集し、それ以外は既存の設定をそのまま残す
+    async fn reconfigure_section(&self, mut config: AppConfig, section: WizardSection) -> Result<AppConfig> {
+        match section {
+            WizardSection::General => {
+                config.general = self.configure_general(Some(&config.general))?;
+            }
+            WizardSection::Toggl => {
+                let (toggl, toggl_user) = self.configure_toggl(Some(&config.toggl)).await?;
+                config.toggl = toggl;
+                propose_timezone(&mut config.general, toggl_user.as_ref());
+            }
+            WizardSection::Openai => {
+                config.openai = Some(self.configure_openai(config.openai.as_ref())?);
+            }
+            WizardSection::GoogleCalendar => {
+                config.google_calendar = self.configure_google_calendar(config.google_calendar.as_ref()).await?;
+            }
+        }
         
-        println!("\n{}", style("設定が完了しました！").green().bold());
-        println!("アプリケーションを実行するには: {} を実行してください", style("cargo run").cyan());
+        Ok(config)
+    }
+    
+    /// シークレットの入力（既存の値がある場合は表示せずにEnterで維持できるようにする）
+    fn input_secret(&self, prompt: &str, existing: Option<&str>) -> Result<String> {
+        let mut input = Input::<String>::with_theme(&self.theme);
+        
+        match existing {
+            Some(existing) => {
+                input = input
+                    .with_prompt(format!("{}（Enterで現在の値を維持）", prompt))
+                    .default(existing.to_string())
+                    .show_default(false);
+            }
+            None => {
+                input = input.with_prompt(prompt);
+            }
+        }
         
-        Ok(())
+        Ok(input.interact_on(&self.term)?)
     }
     
     /// 基本設定
-    fn configure_general(&self) -> Result<crate::config::GeneralConfig> {
+    fn configure_general(&self, existing: Option<&crate::config::GeneralConfig>) -> Result<crate::config::GeneralConfig> {
         println!("\n{}", style("基本設定").bold());
         
         // 設定値を入力
         let data_dir: String = Input::with_theme(&self.theme)
             .with_prompt("データ保存ディレクトリのパスを入力してください")
-            .default("./data".into())
+            .default(existing.map_or_else(|| "~/.local/share/toggl_linux_rs".to_string(), |general| general.data_dir.clone()))
             .interact_text()?;
         
+        // 既存の設定がある場合はその他の値を維持する
+        if let Some(existing) = existing {
+            return Ok(crate::config::GeneralConfig {
+                data_dir,
+                ..existing.clone()
+            });
+        }
+        
         // 他の設定値は直接デフォルト値を使用
         Ok(crate::config::GeneralConfig {
             data_dir,
             confidence_threshold: 0.5,
+            confidence_smoothing: 0.5,
             collect_interval_secs: 60,
             time_block_division: 4,
+            collect_jitter_secs: 0,
+            analysis_jitter_secs: 0,
             idle_threshold_secs: 300, // デフォルトは5分
+            idle_behavior: crate::config::IdleBehavior::Skip,
+            idle_description: crate::config::default_idle_description(),
+            idle_project: None,
+            min_active_secs: 0,
+            activity_debounce_secs: 0,
+            suspend_gap_threshold_secs: 300,
+            skip_private_browsing: true,
+            private_browsing_markers: crate::config::default_private_browsing_markers(),
+            prefer_calendar_title: false,
+            calendar_first: false,
+            tracking_mode: crate::config::TrackingMode::Block,
+            analyzer: crate::config::AnalyzerKind::Auto,
+            privacy: crate::config::PrivacyMode::Full,
+            fallback_activity: crate::config::FallbackActivity::Other,
+            audit_log_path: None,
+            heartbeat_path: None,
+            language: crate::config::Language::Ja,
+            log_timezone: crate::config::LogTimezone::Local,
+            timezone: None,
+            mask_visible_chars: 4,
+            http_connect_timeout_secs: 5,
+            http_timeout_secs: 30,
+            ignore_windows: Vec::new(),
+            collect_visible_windows: false,
+            collect_window_geometry: false,
+            collect_terminal_context: false,
+            max_prompt_samples: 30,
+            full_confidence_samples: crate::config::default_full_confidence_samples(),
         })
     }
     
-    /// Toggl設定
-    async fn configure_toggl(&self) -> Result<crate::config::TogglConfig> {
+    /// Toggl設定（取得できた場合はTogglのユーザー情報も返す）
+    async fn configure_toggl(
+        &self,
+        existing: Option<&crate::config::TogglConfig>,
+    ) -> Result<(crate::config::TogglConfig, Option<TogglUser>)> {
         println!("\n{}", style("Toggl設定").bold());
         println!("Toggl APIトークンは、https://track.toggl.com/profile で取得できます。");
         
         // APIトークン入力
-        let api_token: String = Input::with_theme(&self.theme)
-            .with_prompt("Toggl APIトークン")
-            .interact_on(&self.term)?;
+        let api_token = self.input_secret(
+            "Toggl APIトークン",
+            existing.map(|toggl| toggl.api_token.as_str()),
+        )?;
+        // トークンを変更しなかった場合は、ファイルから読み込む設定を残す
+        let api_token_file = existing
+            .filter(|toggl| toggl.api_token == api_token)
+            .and_then(|toggl| toggl.api_token_file.clone());
+        let project_cache_ttl_secs = existing.map_or(600, |toggl| toggl.project_cache_ttl_secs);
+        let default_billable = existing.is_some_and(|toggl| toggl.default_billable);
+        let round_duration_minutes = existing.map_or(0, |toggl| toggl.round_duration_minutes);
+        let round_duration_mode = existing.map(|toggl| toggl.round_duration_mode).unwrap_or_default();
+        let description_template = existing.map_or_else(
+            crate::config::default_description_template,
+            |toggl| toggl.description_template.clone(),
+        );
+        let continuous_same_activity = existing.is_some_and(|toggl| toggl.continuous_same_activity);
+        let notify_on_register = existing.is_some_and(|toggl| toggl.notify_on_register);
+        let avoid_overlaps = existing.is_some_and(|toggl| toggl.avoid_overlaps);
+        let created_with = existing.and_then(|toggl| toggl.created_with.clone());
         
         // ワークスペース選択
         println!("\nTogglワークスペース一覧を取得中...");
         
         let client = TogglClient::new(&api_token, 0); // ダミーのワークスペースID
         
-        match client.get_workspaces().await {
+        // 既定のワークスペースとタイムゾーンの提案に使う（取得できなくても続行する）
+        let user = client.get_me().await.ok();
+        
+        let toggl_config = match client.get_workspaces().await {
             Ok(workspaces) => {
                 if workspaces.is_empty() {
                     println!("ワークスペースが見つかりませんでした。");
                     // デフォルト値を設定
-                    Ok(crate::config::TogglConfig {
+                    crate::config::TogglConfig {
                         api_token,
+                        api_token_file,
                         workspace_id: 0,
-                    })
+                        project_cache_ttl_secs,
+                        default_billable,
+                        round_duration_minutes,
+                        round_duration_mode,
+                        description_template,
+                        continuous_same_activity,
+                        notify_on_register,
+                        avoid_overlaps,
+                        created_with,
+                    }
                 } else {
                     let workspace_names: Vec<String> = workspaces
                         .iter()
                         .map(|w| format!("{} (ID: {})", w.name, w.id))
                         .collect();
                     
+                    // 既存の設定、なければTogglの既定のワークスペースを選択済みにする
+                    let position = |id: u64| workspaces.iter().position(|w| w.id == id);
+                    let default_index = existing
+                        .and_then(|toggl| position(toggl.workspace_id))
+                        .or_else(|| user.as_ref().and_then(|user| user.default_workspace_id).and_then(position))
+                        .unwrap_or(0);
+                    
                     let selection = Select::with_theme(&self.theme)
                         .with_prompt("使用するワークスペースを選択してください")
-                        .default(0)
+                        .default(default_index)
                         .items(&workspace_names)
                         .interact_on(&self.term)?;
                     
                     let selected_workspace = &workspaces[selection];
                     println!("選択されたワークスペース: {}", style(&selected_workspace.name).green());
                     
-                    Ok(crate::config::TogglConfig {
+                    crate::config::TogglConfig {
                         api_token,
+                        api_token_file,
                         workspace_id: selected_workspace.id,
-                    })
+                        project_cache_ttl_secs,
+                        default_billable,
+                        round_duration_minutes,
+                        round_duration_mode,
+                        description_template,
+                        continuous_same_activity,
+                        notify_on_register,
+                        avoid_overlaps,
+                        created_with,
+                    }
                 }
             }
             Err(e) => {
                 println!("ワークスペース一覧の取得に失敗しました: {}", e);
                 // 手動入力に切り替え
-                let workspace_id: u64 = Input::with_theme(&self.theme)
-                    .with_prompt("ワークスペースID（手動入力）")
-                    .interact_on(&self.term)?;
+                let mut input = Input::<u64>::with_theme(&self.theme)
+                    .with_prompt("ワークスペースID（手動入力）");
+                if let Some(existing) = existing {
+                    input = input.default(existing.workspace_id);
+                }
+                let workspace_id = input.interact_on(&self.term)?;
                 
-                Ok(crate::config::TogglConfig {
+                crate::config::TogglConfig {
                     api_token,
+                    api_token_file,
                     workspace_id,
-                })
+                    project_cache_ttl_secs,
+                    default_billable,
+                    round_duration_minutes,
+                    round_duration_mode,
+                    description_template,
+                    continuous_same_activity,
+                    notify_on_register,
+                    avoid_overlaps,
+                    created_with,
+                }
             }
-        }
+        };
+        
+        Ok((toggl_config, user))
     }
     
     /// OpenAI設定
-    fn configure_openai(&self) -> Result<crate::config::OpenAIConfig> {
+    fn configure_openai(&self, existing: Option<&crate::config::OpenAIConfig>) -> Result<crate::config::OpenAIConfig> {
         println!("\n{}", style("OpenAI設定").bold());
         println!("OpenAIのAPIキーは、https://platform.openai.com/api-keys で取得できます。");
         
         // APIキー入力
-        let api_key: String = Input::with_theme(&self.theme)
-            .with_prompt("OpenAI APIキー")
-            .interact_on(&self.term)?;
+        let api_key = self.input_secret(
+            "OpenAI APIキー",
+            existing.map(|openai| openai.api_key.as_str()),
+        )?;
         
         // モデル選択
-        let models = vec![
+        let mut models = vec![
             "gpt-4o-mini",
             "gpt-4-turbo",
             "gpt-4",
             "gpt-3.5-turbo",
         ];
         
+        // 一覧にないモデルが設定されている場合も選択肢に残す
+        let default_index = match existing.map(|openai| openai.model.as_str()) {
+            Some(model) => match models.iter().position(|m| *m == model) {
+                Some(index) => index,
+                None => {
+                    models.push(model);
+                    models.len() - 1
+                }
+            },
+            None => 0,
+        };
+        
         let selection = Select::with_theme(&self.theme)
             .with_prompt("使用するモデルを選択してください")
-            .default(0)
+            .default(default_index)
             .items(&models)
             .interact_on(&self.term)?;
         
         let model = models[selection].to_string();
         
+        // キーを変更しなかった場合は、ファイルから読み込む設定を残す
+        let api_key_file = existing
+            .filter(|openai| openai.api_key == api_key)
+            .and_then(|openai| openai.api_key_file.clone());
+
         Ok(crate::config::OpenAIConfig {
             api_key,
+            api_key_file,
             model,
+            api_base: existing.map_or_else(crate::config::default_openai_api_base, |openai| openai.api_base.clone()),
+            max_retries: existing.map_or(3, |openai| openai.max_retries),
         })
     }
     
     /// Googleカレンダー設定（オプション）
-    async fn configure_google_calendar(&self) -> Result<Option<crate::config::GoogleCalendarConfig>> {
+    async fn configure_google_calendar(
+        &self,
+        existing: Option<&GoogleCalendarSettings>,
+    ) -> Result<Option<GoogleCalendarSettings>> {
         println!("\n{}", style("Google Calendar設定（オプション）").bold());
         
         let use_google_calendar = Confirm::with_theme(&self.theme)
             .with_prompt("Google Calendarと連携しますか？")
-            .default(false)
+            .default(existing.is_some())
             .interact_on(&self.term)?;
         
         if !use_google_calendar {
             return Ok(None);
         }
         
+        // 既存の連携がある場合、OAuth認証は希望されたときだけやり直す
+        if let Some(existing) = existing {
+            let reauthorize = Confirm::with_theme(&self.theme)
+                .with_prompt("OAuth認証をやり直しますか？（いいえの場合は現在の認証情報とカレンダーを維持）")
+                .default(false)
+                .interact_on(&self.term)?;
+            
+            if !reauthorize {
+                return Ok(Some(existing.clone()));
+            }
+        }
+        
+        // 複数のアカウントがある場合は、認証をやり直すアカウントを選ぶ（他のアカウントはそのまま残す）
+        let accounts = existing.map_or(&[][..], |settings| settings.accounts());
+        let index = if accounts.len() > 1 {
+            let items: Vec<String> = accounts.iter()
+                .map(|account| format!("{} (カレンダー: {})", account.client_id, account.calendar_ids))
+                .collect();
+            Select::with_theme(&self.theme)
+                .with_prompt("認証をやり直すアカウントを選択してください")
+                .default(0)
+                .items(&items)
+                .interact_on(&self.term)?
+        } else {
+            0
+        };
+        
+        let account = self.configure_google_account(accounts.get(index)).await?;
+        Ok(Some(replace_google_account(existing, index, account)))
+    }
+    
+    /// Googleアカウントの認証とカレンダー選択
+    async fn configure_google_account(
+        &self,
+        previous: Option<&crate::config::GoogleCalendarConfig>,
+    ) -> Result<crate::config::GoogleCalendarConfig> {
         println!("Google Cloud Consoleでの準備が必要です：");
         println!("1. https://console.cloud.google.com/apis/dashboard で新しいプロジェクトを作成");
         println!("2. Google Calendar APIを有効化");
         println!("3. OAuth同意画面を設定（テスト用は外部を選択）");
-        println!("4. OAuth 2.0クライアントIDを作成（リダイレクトURIに {} を追加）", REDIRECT_URI);
-        println!("");
+        println!("4. OAuth 2.0クライアントIDを作成（アプリケーションの種類は「デスクトップアプリ」）");
+        println!("   ※「ウェブアプリケーション」の場合は、承認済みのリダイレクトURIに http://localhost:{} ～ http://localhost:{} を追加",
+                 OAUTH_PORTS.start(), OAUTH_PORTS.end());
+        println!();
         
         // クライアントID
-        let client_id: String = Input::with_theme(&self.theme)
-            .with_prompt("Google Cloud OAuth クライアントID")
-            .interact_on(&self.term)?;
+        let mut client_id_input = Input::<String>::with_theme(&self.theme)
+            .with_prompt("Google Cloud OAuth クライアントID");
+        if let Some(previous) = previous {
+            client_id_input = client_id_input.default(previous.client_id.clone());
+        }
+        let client_id = client_id_input.interact_on(&self.term)?;
         
         // クライアントシークレット
-        let client_secret: String = Input::with_theme(&self.theme)
-            .with_prompt("Google Cloud OAuth クライアントシークレット")
-            .interact_on(&self.term)?;
+        let client_secret = self.input_secret(
+            "Google Cloud OAuth クライアントシークレット",
+            previous.map(|account| account.client_secret.as_str()),
+        )?;
         
         // OAuth認証フローを実行
         println!("\n{}", style("OAuth認証を開始します...").green());
         
         // 認証コードを取得
-        let auth_code = self.get_oauth_authorization_code(&client_id)?;
-        
-        // 認証コードをトークンに交換
+        let (auth_code, redirect_uri) = self.get_oauth_authorization_code(&client_id)?;
+
+        // 認証コードをトークンに交換（リダイレクトURIは認証時と同じものを使う）
         let token = self.exchange_auth_code_for_token(
-            &client_id, 
-            &client_secret, 
-            &auth_code
+            &client_id,
+            &client_secret,
+            &auth_code,
+            &redirect_uri,
         ).await?;
         
         println!("{}", style("認証が完了しました！").green());
@@ -250,7 +547,7 @@ impl ConfigWizard {
             .iter()
             .map(|cal| {
                 let primary_label = if cal.primary.unwrap_or(false) { " (主カレンダー)" } else { "" };
-                format!("{}{} (ID: {})", cal.summary, primary_label, cal.id)
+                format!("{}{}{} (ID: {})", cal.summary, primary_label, cal.color_label(), cal.id)
             })
             .collect();
         
@@ -287,12 +584,15 @@ impl ConfigWizard {
                 .map(|cal| cal.id.clone())
                 .unwrap_or_else(|| "primary".to_string());
             
-            return Ok(Some(crate::config::GoogleCalendarConfig {
+            return Ok(crate::config::GoogleCalendarConfig {
+                enabled: true,
                 client_id,
                 client_secret,
                 refresh_token: token.refresh_token,
                 calendar_ids: primary_calendar,
-            }));
+                calendar_tags: previous.map(|account| account.calendar_tags.clone()).unwrap_or_default(),
+                exclude_event_patterns: previous.map(|account| account.exclude_event_patterns.clone()).unwrap_or_default(),
+            });
         }
         
         // 選択されたカレンダーIDをカンマ区切りで連結
@@ -306,21 +606,72 @@ impl ConfigWizard {
             println!(" - {}", calendars.items[idx].summary);
         }
         
-        Ok(Some(crate::config::GoogleCalendarConfig {
+        // カレンダーの色ごとの用途に合わせてタグを対応付ける
+        let selected_calendars: Vec<&GoogleCalendar> = selected.iter()
+            .map(|&idx| &calendars.items[idx])
+            .collect();
+        let calendar_tags = self.configure_calendar_tags(
+            &selected_calendars,
+            previous.map(|account| &account.calendar_tags),
+        )?;
+        
+        Ok(crate::config::GoogleCalendarConfig {
+            enabled: true,
             client_id,
             client_secret,
             refresh_token: token.refresh_token,
             calendar_ids,
-        }))
+            calendar_tags,
+            exclude_event_patterns: previous.map(|account| account.exclude_event_patterns.clone()).unwrap_or_default(),
+        })
+    }
+    
+    /// カレンダーごとにエントリへ付けるタグを設定
+    fn configure_calendar_tags(
+        &self,
+        calendars: &[&GoogleCalendar],
+        previous: Option<&HashMap<String, String>>,
+    ) -> Result<HashMap<String, String>> {
+        let mut calendar_tags = HashMap::new();
+        
+        let configure = Confirm::with_theme(&self.theme)
+            .with_prompt("カレンダーごとにタグを設定しますか？（例: 会議用カレンダー → meeting）")
+            .default(previous.is_some_and(|tags| !tags.is_empty()))
+            .interact_on(&self.term)?;
+        
+        if !configure {
+            return Ok(calendar_tags);
+        }
+        
+        for calendar in calendars {
+            let mut input = Input::<String>::with_theme(&self.theme)
+                .with_prompt(format!("{}{} のタグ（空欄でなし）", calendar.summary, calendar.color_label()))
+                .allow_empty(true);
+            if let Some(tag) = previous.and_then(|tags| tags.get(&calendar.id)) {
+                input = input.default(tag.clone());
+            }
+            
+            let tag = input.interact_on(&self.term)?;
+            let tag = tag.trim();
+            if !tag.is_empty() {
+                calendar_tags.insert(calendar.id.clone(), tag.to_string());
+            }
+        }
+        
+        Ok(calendar_tags)
     }
     
-    /// OAuth認証コードを取得
-    fn get_oauth_authorization_code(&self, client_id: &str) -> Result<String> {
+    /// OAuth認証コードを取得（認証コードと、認証に使ったリダイレクトURIを返す）
+    fn get_oauth_authorization_code(&self, client_id: &str) -> Result<(String, String)> {
+        // リダイレクトを受けるローカルサーバーを起動（使用中のポートは避ける）
+        let listener = bind_oauth_listener()?;
+        let redirect_uri = format!("http://localhost:{}", listener.local_addr()?.port());
+
         // 認証URLを構築
         let auth_url = format!(
             "https://accounts.google.com/o/oauth2/auth?client_id={}&redirect_uri={}&scope={}&response_type=code&access_type=offline&prompt=consent",
             client_id,
-            urlencoding::encode(REDIRECT_URI),
+            urlencoding::encode(&redirect_uri),
             urlencoding::encode(OAUTH_SCOPES)
         );
         
@@ -333,39 +684,11 @@ impl ConfigWizard {
             println!("{}", auth_url);
         }
         
-        // ローカルサーバーを起動してリダイレクトを待機
+        // リダイレクトを待機
         println!("Google認証ページでログインして、アクセスを許可してください...");
-        
-        let listener = TcpListener::bind("127.0.0.1:8080").context("ローカルサーバーの起動に失敗しました")?;
-        
-        // 最初の接続を受け入れる
-        let (mut stream, _) = listener.accept().context("リダイレクト待機中にエラーが発生しました")?;
-        
-        // リクエストを読み取る
-        let mut buffer = [0; 1024];
-        stream.read(&mut buffer).context("リクエストの読み取りに失敗しました")?;
-        
-        // リクエストからcodeパラメータを抽出
-        let request = String::from_utf8_lossy(&buffer[..]);
-        let uri = request.lines().next()
-            .ok_or_else(|| anyhow::anyhow!("リクエストの解析に失敗しました"))?
-            .split_whitespace()
-            .nth(1)
-            .ok_or_else(|| anyhow::anyhow!("URLの解析に失敗しました"))?;
-        
-        let url = Url::parse(&format!("http://localhost{}", uri))
-            .context("URLの解析に失敗しました")?;
-        
-        let code = url.query_pairs()
-            .find(|(key, _)| key == "code")
-            .map(|(_, value)| value.to_string())
-            .ok_or_else(|| anyhow::anyhow!("認証コードが見つかりませんでした"))?;
-        
-        // 成功ページを返す
-        let response = "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\n\r\n<html><body><h1>認証成功</h1><p>このウィンドウを閉じて、アプリケーションに戻ってください。</p></body></html>";
-        stream.write_all(response.as_bytes()).context("レスポンスの送信に失敗しました")?;
-        
-        Ok(code)
+        let code = wait_for_authorization_code(&listener, OAUTH_TIMEOUT)?;
+
+        Ok((code, redirect_uri))
     }
     
     /// 認証コードをトークンに交換
@@ -373,15 +696,16 @@ impl ConfigWizard {
         &self,
         client_id: &str,
         client_secret: &str,
-        auth_code: &str
+        auth_code: &str,
+        redirect_uri: &str,
     ) -> Result<GoogleOAuthToken> {
-        let client = reqwest::Client::new();
+        let client = crate::utils::http_client();
         
         let params = [
             ("client_id", client_id),
             ("client_secret", client_secret),
             ("code", auth_code),
-            ("redirect_uri", REDIRECT_URI),
+            ("redirect_uri", redirect_uri),
             ("grant_type", "authorization_code"),
         ];
         
@@ -404,7 +728,7 @@ impl ConfigWizard {
     
     /// カレンダー一覧を取得
     async fn get_calendar_list(&self, access_token: &str) -> Result<GoogleCalendarListResponse> {
-        let client = reqwest::Client::new();
+        let client = crate::utils::http_client();
         
         let response = client.get("https://www.googleapis.com/calendar/v3/users/me/calendarList")
             .header("Authorization", format!("Bearer {}", access_token))
@@ -424,28 +748,200 @@ impl ConfigWizard {
     }
     
     /// 設定ファイルを保存
-    fn save_config(&self, config: &AppConfig) -> Result<()> {
+    fn save_config(&self, config: &AppConfig, config_path: &Path) -> Result<()> {
         println!("\n設定内容を確認します：");
         
-        // 設定内容のプレビュー
-        let config_str = toml::to_string_pretty(config)?;
+        // 設定内容のプレビュー（シークレットはマスクして表示）
+        let preview_str = toml::to_string_pretty(&config.redacted())?;
         println!("{}", style("```").dim());
-        println!("{}", config_str);
+        println!("{}", preview_str);
         println!("{}", style("```").dim());
         
+        // ファイルから読み込んだシークレットは設定ファイルに書き出さない
+        let config_str = toml::to_string_pretty(&config.without_file_secrets())?;
+        
         let confirm = Confirm::with_theme(&self.theme)
-            .with_prompt("この設定をconfig.tomlに保存しますか？")
+            .with_prompt(format!("この設定を{}に保存しますか？", config_path.display()))
             .default(true)
             .interact_on(&self.term)?;
         
         if confirm {
-            fs::write("config.toml", config_str)
+            fs::write(config_path, config_str)
                 .context("設定ファイルの保存に失敗しました")?;
-            println!("設定ファイルを {} に保存しました", style("config.toml").yellow());
+            println!("設定ファイルを {} に保存しました", style(config_path.display()).yellow());
             Ok(())
         } else {
             println!("設定の保存をキャンセルしました");
             Ok(())
         }
     }
-} 
\ No newline at end of file
+} 
+/// タイムゾーンが未設定の場合、Togglのプロフィールのタイムゾーンを提案値として設定する
+fn propose_timezone(general: &mut crate::config::GeneralConfig, user: Option<&TogglUser>) {
+    if general.timezone.is_some() {
+        return;
+    }
+    let Some(timezone) = user.and_then(|user| user.timezone.as_deref()) else {
+        return;
+    };
+    if timezone.parse::<chrono_tz::Tz>().is_ok() {
+        println!("Togglのプロフィールからタイムゾーンを設定しました: {}", style(timezone).green());
+        general.timezone = Some(timezone.to_string());
+    }
+}
+
+/// OAuthのリダイレクトを受けるローカルサーバーを起動する
+///
+/// `OAUTH_PORTS` を順に試し、すべて使用中の場合はOSが選んだ空きポートを使う。
+/// Googleの「デスクトップアプリ」のクライアントはループバックアドレスの任意のポートへのリダイレクトを受け付ける
+fn bind_oauth_listener() -> Result<TcpListener> {
+    OAUTH_PORTS
+        .map(|port| TcpListener::bind(("127.0.0.1", port)))
+        .find_map(|result| result.ok())
+        .map_or_else(
+            || TcpListener::bind(("127.0.0.1", 0)),
+            Ok,
+        )
+        .context("ローカルサーバーの起動に失敗しました")
+}
+
+/// 認証コードを含むリダイレクトが届くまで接続を受け付ける
+///
+/// favicon などの認証コードを含まないリクエストには404を返して待ち続け、
+/// 認証が拒否された場合と `timeout` までに届かなかった場合はエラーを返す
+fn wait_for_authorization_code(listener: &TcpListener, timeout: Duration) -> Result<String> {
+    listener.set_nonblocking(true).context("ローカルサーバーの設定に失敗しました")?;
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        let mut stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                if Instant::now() >= deadline {
+                    return Err(anyhow::anyhow!(
+                        "{}秒以内に認証が完了しませんでした。ブラウザのタブを閉じた場合は、もう一度ウィザードを実行してください",
+                        timeout.as_secs()
+                    ));
+                }
+                std::thread::sleep(Duration::from_millis(100));
+                continue;
+            }
+            Err(e) => return Err(e).context("リダイレクト待機中にエラーが発生しました"),
+        };
+        stream.set_nonblocking(false).context("リクエストの読み取りに失敗しました")?;
+
+        // リクエスト行からパスとクエリを取り出す
+        let mut buffer = [0; 4096];
+        let len = stream.read(&mut buffer).context("リクエストの読み取りに失敗しました")?;
+        let request = String::from_utf8_lossy(&buffer[..len]);
+        let Some(url) = request.lines().next()
+            .and_then(|line| line.split_whitespace().nth(1))
+            .and_then(|uri| Url::parse(&format!("http://localhost{}", uri)).ok())
+        else {
+            continue;
+        };
+        let param = |name: &str| url.query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.to_string());
+
+        if let Some(code) = param("code") {
+            let response = "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\nConnection: close\r\n\r\n<html><body><h1>認証成功</h1><p>このウィンドウを閉じて、アプリケーションに戻ってください。</p></body></html>";
+            stream.write_all(response.as_bytes()).context("レスポンスの送信に失敗しました")?;
+            return Ok(code);
+        }
+
+        if let Some(error) = param("error") {
+            let response = "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\nConnection: close\r\n\r\n<html><body><h1>認証が完了しませんでした</h1><p>このウィンドウを閉じて、アプリケーションに戻ってください。</p></body></html>";
+            let _ = stream.write_all(response.as_bytes());
+            return Err(anyhow::anyhow!("Google認証が完了しませんでした: {}", error));
+        }
+
+        let _ = stream.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+    }
+}
+
+/// 認証をやり直したアカウントで `index` 番目のアカウントを置き換える（複数アカウントの設定は他のアカウントを残す）
+fn replace_google_account(
+    existing: Option<&GoogleCalendarSettings>,
+    index: usize,
+    account: crate::config::GoogleCalendarConfig,
+) -> GoogleCalendarSettings {
+    match existing {
+        Some(GoogleCalendarSettings::Multiple(accounts)) if index < accounts.len() => {
+            let mut accounts = accounts.clone();
+            accounts[index] = account;
+            GoogleCalendarSettings::Multiple(accounts)
+        }
+        _ => GoogleCalendarSettings::Single(account),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpStream;
+
+    fn send_request(port: u16, path: &str) -> String {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost:{}\r\n\r\n", path, port).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn oauth_callback_ignores_favicon_requests() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let browser = std::thread::spawn(move || {
+            let favicon = send_request(port, "/favicon.ico");
+            let callback = send_request(port, "/?code=4%2F0Adeu5B&scope=calendar.readonly");
+            (favicon, callback)
+        });
+
+        let code = wait_for_authorization_code(&listener, Duration::from_secs(10)).unwrap();
+        let (favicon, callback) = browser.join().unwrap();
+
+        assert_eq!(code, "4/0Adeu5B");
+        assert!(favicon.starts_with("HTTP/1.1 404"), "{}", favicon);
+        assert!(callback.starts_with("HTTP/1.1 200"), "{}", callback);
+    }
+
+    #[test]
+    fn oauth_callback_reports_denied_access_and_timeout() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let browser = std::thread::spawn(move || send_request(port, "/?error=access_denied"));
+
+        let error = wait_for_authorization_code(&listener, Duration::from_secs(10)).unwrap_err();
+        browser.join().unwrap();
+        assert!(error.to_string().contains("access_denied"), "{}", error);
+
+        let error = wait_for_authorization_code(&listener, Duration::from_millis(200)).unwrap_err();
+        assert!(error.to_string().contains("認証が完了しませんでした"), "{}", error);
+    }
+
+    #[test]
+    fn reauthorizing_one_account_keeps_the_others() {
+        let account = |client_id: &str, refresh_token: &str| crate::config::GoogleCalendarConfig {
+            enabled: true,
+            client_id: client_id.to_string(),
+            client_secret: "secret".to_string(),
+            refresh_token: refresh_token.to_string(),
+            calendar_ids: "primary".to_string(),
+            calendar_tags: HashMap::new(),
+            exclude_event_patterns: Vec::new(),
+        };
+        let existing = GoogleCalendarSettings::Multiple(vec![account("work", "old-work"), account("personal", "old-personal")]);
+
+        let updated = replace_google_account(Some(&existing), 1, account("personal", "new-personal"));
+        assert!(matches!(updated, GoogleCalendarSettings::Multiple(_)));
+        let tokens: Vec<&str> = updated.accounts().iter().map(|account| account.refresh_token.as_str()).collect();
+        assert_eq!(tokens, ["old-work", "new-personal"]);
+
+        // 単一アカウントや新規の連携は単一アカウントの形式のまま
+        let single = GoogleCalendarSettings::Single(account("work", "old-work"));
+        assert!(matches!(replace_google_account(Some(&single), 0, account("work", "new")), GoogleCalendarSettings::Single(_)));
+        assert!(matches!(replace_google_account(None, 0, account("work", "new")), GoogleCalendarSettings::Single(_)));
+    }
+}